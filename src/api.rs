@@ -1,16 +1,41 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
 use eventsource_stream::{Event, EventStream, EventStreamError};
-use futures_util::{Stream, StreamExt};
-use reqwest::header::{ACCEPT, CONTENT_TYPE};
-use reqwest::{Client, RequestBuilder};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{future, Stream, StreamExt};
+use reqwest::header::{HeaderValue, InvalidHeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Certificate, Client, Proxy, Request, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::config::Config;
+use crate::audio::{split_speech_input, MAX_SPEECH_INPUT};
+use crate::audit::AuditLog;
+use crate::auth::{sign_aws_request, uri_encode, AwsCredentials, OAuthTokenSource};
+use crate::chunks::estimate_tokens;
+use crate::config::{Api, Config, Provider};
+use crate::hooks::run_hook;
+use crate::ratelimit::RateLimiter;
+use crate::redact::{Redactor, Restorer};
+
+/// Headers whose values are redacted in the "--dry-run" output
+const SECRET_HEADERS: &[&str] = &["api-key", "authorization", "x-amz-security-token"];
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// The participant, e.g. to tell several personas with the same role apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Any other (provider specific) fields, sent as they are
+    #[serde(flatten, default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Message {
@@ -18,45 +43,182 @@ impl Message {
         Self {
             role,
             content: content.to_owned(),
+            name: None,
+            extra: serde_json::Map::new(),
         }
     }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Assistant,
+    /// replaces "system" for newer OpenAI models
+    Developer,
     System,
+    Tool,
     User,
+    /// any role this version doesn't know about, kept as it is
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl Role {
+    /// Whether messages with this role hold instructions, like a system prompt
+    pub fn is_instruction(&self) -> bool {
+        matches!(self, Role::System | Role::Developer)
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct ChatRequest {
     model: String,
-    messages: Vec<Message>,
+    messages: Vec<ChatMessage>,
+    /// GBNF grammar, a llama.cpp extension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grammar: Option<String>,
+    /// how long Ollama keeps the model loaded after the request, e.g. "30m"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u8>,
+}
+
+/// A message as sent to the API, with its content split into parts when it needs
+/// to be marked as cacheable (understood by Anthropic models behind OpenAI-style
+/// gateways)
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ChatMessage {
+    Plain(Message),
+    Cacheable {
+        role: Role,
+        content: Vec<ContentPart>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(flatten)]
+        extra: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+impl ChatMessage {
+    fn new(message: &Message, cacheable: bool) -> Self {
+        if !cacheable {
+            return ChatMessage::Plain(message.to_owned());
+        }
+        ChatMessage::Cacheable {
+            role: message.role.to_owned(),
+            content: vec![ContentPart {
+                kind: "text",
+                text: message.content.to_owned(),
+                cache_control: CacheControl { kind: "ephemeral" },
+            }],
+            name: message.name.to_owned(),
+            extra: message.extra.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ContentPart {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+    cache_control: CacheControl,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+/// Where to mark the conversation as cacheable: after the system prompt, which
+/// is the same for every request, and after the first user message, which holds
+/// any (large) piped input
+fn cache_breakpoints(messages: &[Message]) -> Vec<usize> {
+    let last_system = messages
+        .iter()
+        .rposition(|message| message.role.is_instruction());
+    let first_user = messages
+        .iter()
+        .position(|message| message.role == Role::User);
+    last_system.into_iter().chain(first_user).collect()
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    include_usage: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatResponse {
     choices: Vec<ChatResponseChoice>,
+    usage: Option<Usage>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatResponseChoice {
     message: Message,
+    finish_reason: Option<String>,
+    logprobs: Option<ChoiceLogprobs>,
 }
 
 #[derive(Debug, Deserialize)]
 struct EventData {
+    #[serde(default)]
     choices: Vec<ChatEventChoice>,
+    usage: Option<Usage>,
+    error: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatEventChoice {
-    delta: Delta,
+    #[serde(default)]
+    index: u32,
+    delta: Option<Delta>,
+    finish_reason: Option<String>,
+    logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChoiceLogprobs {
+    #[serde(default)]
+    content: Option<Vec<TokenLogprob>>,
+}
+
+/// A token of the response with its log probability, and the most likely
+/// alternatives if requested
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub top_logprobs: Vec<AlternativeLogprob>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AlternativeLogprob {
+    pub token: String,
+    pub logprob: f64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -64,11 +226,353 @@ struct Delta {
     content: Option<String>,
 }
 
+/// Token counts, as reported by the API (Bedrock, Anthropic and the Responses
+/// API use different field names)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Usage {
+    #[serde(alias = "inputTokens", alias = "input_tokens")]
+    pub prompt_tokens: u32,
+    #[serde(alias = "outputTokens", alias = "output_tokens")]
+    pub completion_tokens: u32,
+    #[serde(alias = "totalTokens")]
+    pub total_tokens: u32,
+    #[serde(alias = "input_tokens_details")]
+    prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(rename = "cacheReadInputTokens", alias = "cache_read_input_tokens")]
+    cache_read_input_tokens: u32,
+    #[serde(
+        rename = "cacheWriteInputTokens",
+        alias = "cache_creation_input_tokens"
+    )]
+    pub cache_write_tokens: u32,
+}
+
+impl Usage {
+    /// Prompt tokens read from the cache, however the API reports them
+    pub fn cached_tokens(&self) -> u32 {
+        let details = self.prompt_tokens_details.as_ref();
+        details.map_or(0, |details| details.cached_tokens) + self.cache_read_input_tokens
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+struct PromptTokensDetails {
+    cached_tokens: u32,
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    pub content: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+/// Items of a streamed response
+#[derive(Debug)]
+pub enum StreamEvent {
+    Token(String),
+    /// Tokens with their log probabilities, in place of "Token" when requested
+    Logprobs(Vec<TokenLogprob>),
+    Finish(String),
+    Usage(Usage),
+}
+
+/// Request body for the OpenAI Responses API, which keeps no state between
+/// requests ("store": false) so the whole conversation is sent every time
+#[derive(Debug, Serialize)]
+struct ResponsesRequest<'a> {
+    model: &'a str,
+    input: Vec<ResponsesInput<'a>>,
+    store: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponsesInput<'a> {
+    role: &'a Role,
+    content: &'a str,
+}
+
+/// A tool run by the provider, e.g. "web_search_preview" or "code_interpreter"
+fn builtin_tool(name: &str) -> serde_json::Value {
+    if name == "code_interpreter" {
+        serde_json::json!({ "type": name, "container": { "type": "auto" } })
+    } else {
+        serde_json::json!({ "type": name })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponsesResponse {
+    #[serde(default)]
+    output: Vec<OutputItem>,
+    status: Option<String>,
+    incomplete_details: Option<IncompleteDetails>,
+    usage: Option<Usage>,
+    error: Option<serde_json::Value>,
+}
+
+impl ResponsesResponse {
+    /// The text of the messages in the output, leaving out reasoning items and
+    /// built-in tool calls
+    fn text(&self) -> String {
+        self.output
+            .iter()
+            .flat_map(|item| match item {
+                OutputItem::Message { content } => content.as_slice(),
+                OutputItem::Other => &[],
+            })
+            .filter_map(|content| match content {
+                OutputContent::OutputText { text } => Some(text.as_str()),
+                OutputContent::Refusal { refusal } => Some(refusal.as_str()),
+                OutputContent::Other => None,
+            })
+            .collect()
+    }
+
+    /// The status as a Chat Completions "finish_reason"
+    fn finish_reason(&self) -> Option<String> {
+        match self.status.as_deref()? {
+            "completed" => Some(String::from("stop")),
+            "incomplete" => match self.incomplete_details.as_ref() {
+                Some(details) if details.reason == "max_output_tokens" => {
+                    Some(String::from("length"))
+                }
+                Some(details) => Some(details.reason.to_owned()),
+                None => Some(String::from("incomplete")),
+            },
+            status => Some(status.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputItem {
+    Message {
+        #[serde(default)]
+        content: Vec<OutputContent>,
+    },
+    /// e.g. "reasoning" or "web_search_call"
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputContent {
+    OutputText {
+        text: String,
+    },
+    Refusal {
+        refusal: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct IncompleteDetails {
+    reason: String,
+}
+
+/// The events of a streamed Responses API response that affect its output
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ResponsesEvent {
+    #[serde(
+        rename = "response.output_text.delta",
+        alias = "response.refusal.delta"
+    )]
+    Delta { delta: String },
+    #[serde(rename = "response.completed", alias = "response.incomplete")]
+    Completed { response: ResponsesResponse },
+    #[serde(rename = "response.failed")]
+    Failed { response: ResponsesResponse },
+    #[serde(rename = "error")]
+    Error { message: String },
+    #[serde(other)]
+    Other,
+}
+
+/// Request body for the Bedrock Converse API
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseRequest {
+    messages: Vec<ConverseMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    system: Vec<ConverseContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inference_config: Option<InferenceConfig>,
+}
+
+impl ConverseRequest {
+    fn new(messages: &[Message], config: &Config) -> Self {
+        let breakpoints = if config.prompt_caching {
+            cache_breakpoints(messages)
+        } else {
+            Vec::new()
+        };
+        let mut system = Vec::new();
+        let mut conversation = Vec::new();
+        for (index, message) in messages.iter().enumerate() {
+            let mut content = vec![ConverseContent::new(&message.content)];
+            if breakpoints.contains(&index) {
+                content.push(ConverseContent::cache_point());
+            }
+            match message.role {
+                Role::System | Role::Developer => system.extend(content),
+                _ => conversation.push(ConverseMessage {
+                    role: message.role.to_owned(),
+                    content,
+                }),
+            }
+        }
+        Self {
+            messages: conversation,
+            system,
+            inference_config: (config.temperature.is_some() || config.max_tokens.is_some())
+                .then_some(InferenceConfig {
+                    max_tokens: config.max_tokens,
+                    temperature: config.temperature,
+                }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ConverseMessage {
+    role: Role,
+    content: Vec<ConverseContent>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache_point: Option<CachePoint>,
+}
+
+impl ConverseContent {
+    fn new(text: &str) -> Self {
+        Self {
+            text: Some(text.to_owned()),
+            cache_point: None,
+        }
+    }
+
+    /// Marks the content blocks before it as cacheable
+    fn cache_point() -> Self {
+        Self {
+            text: None,
+            cache_point: Some(CachePoint {
+                kind: String::from("default"),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachePoint {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InferenceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseResponse {
+    output: ConverseOutput,
+    stop_reason: Option<String>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConverseOutput {
+    message: Option<ConverseMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentBlockDeltaEvent {
+    delta: ConverseContent,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageStopEvent {
+    stop_reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataEvent {
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BedrockException {
+    message: String,
+}
+
 #[derive(Debug, Error)]
 pub enum ApiError {
     #[error(transparent)]
     RequestFailed(#[from] reqwest::Error),
 
+    #[error(transparent)]
+    InvalidHeader(#[from] InvalidHeaderValue),
+
+    #[error("Could not read CA certificate \"{0}\"")]
+    UnreadableCaCert(String, #[source] std::io::Error),
+
+    #[error("AWS credentials not found: AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set")]
+    MissingAwsCredentials,
+
+    #[error("Could not read \"{0}\"")]
+    UnreadableFile(String, #[source] std::io::Error),
+
+    #[error("Invalid JSON schema in \"{0}\"")]
+    InvalidJsonSchema(String, #[source] serde_json::Error),
+
+    #[error("{0}")]
+    InvalidRedaction(String),
+
+    #[error("Could not record the request in the audit log: {0}")]
+    AuditLog(String),
+
+    #[error("Hook failed: {0}")]
+    Hook(String),
+
+    #[error("{0}")]
+    OverBudget(String),
+
+    #[error("{0} is not supported by this provider")]
+    Unsupported(&'static str),
+
+    #[error("Malformed event stream message")]
+    MalformedEventStream,
+
+    #[error("{0}")]
+    ServiceError(String),
+
     #[error(transparent)]
     ResponseStreamingFailed(#[from] EventStreamError<reqwest::Error>),
 
@@ -76,69 +580,1464 @@ pub enum ApiError {
     UnexpectedEventData(#[from] serde_json::Error),
 }
 
+impl ApiError {
+    /// Whether the connection failed, rather than the API rejecting the request,
+    /// so sending it again might work
+    pub fn is_interruption(&self) -> bool {
+        match self {
+            ApiError::RequestFailed(error) => {
+                error.is_timeout() || error.is_connect() || error.is_body() || error.is_decode()
+            }
+            ApiError::ResponseStreamingFailed(EventStreamError::Transport(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SpeechRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+    voice: &'a str,
+    response_format: &'a str,
+}
+
+/// A job of the Batch API
+#[derive(Debug, Deserialize)]
+pub struct BatchJob {
+    pub id: String,
+    pub status: String,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    pub request_counts: Option<BatchRequestCounts>,
+    /// why the whole batch failed, e.g. because of invalid input lines
+    pub errors: Option<BatchErrors>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchErrors {
+    #[serde(default)]
+    pub data: Vec<BatchError>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchError {
+    pub message: String,
+    pub line: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequestCounts {
+    pub total: u32,
+    pub completed: u32,
+    pub failed: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchRequestLine<'a> {
+    custom_id: &'a str,
+    method: &'a str,
+    url: &'a str,
+    body: ChatRequest,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadedFile {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Transcription {
+    text: String,
+}
+
 pub struct ApiClient {
+    client: Client,
     config: Config,
+    oauth: Option<OAuthTokenSource>,
+    limiter: Option<RateLimiter>,
+    grammar: Option<String>,
+    response_format: Option<serde_json::Value>,
+    redactor: Option<Mutex<Redactor>>,
+    audit: Option<AuditLog>,
+    over_budget: bool,
 }
 
 impl ApiClient {
-    pub fn new(config: &Config) -> Self {
-        Self {
+    pub fn new(config: &Config) -> Result<Self, ApiError> {
+        let mut builder =
+            Client::builder().danger_accept_invalid_certs(config.danger_accept_invalid_certs);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        if let Some(path) = &config.ca_cert {
+            let pem =
+                fs::read(path).map_err(|error| ApiError::UnreadableCaCert(path.into(), error))?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        if config.http2 {
+            // e.g. for local servers over plain HTTP, where it can't be negotiated
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(seconds) = config.tcp_keepalive {
+            builder = builder.tcp_keepalive(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = config.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(seconds));
+        }
+        let grammar = match &config.grammar_file {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .map_err(|error| ApiError::UnreadableFile(path.into(), error))?,
+            ),
+            None => None,
+        };
+        let response_format = response_format(config)?;
+        let constrained = grammar.is_some() || response_format.is_some();
+        if constrained && (config.provider == Provider::Bedrock || config.api == Api::Responses) {
+            return Err(ApiError::Unsupported("Constrained generation"));
+        }
+        let redactor = Redactor::new(&config.redact)
+            .map_err(|error| ApiError::InvalidRedaction(format!("{:#}", error)))?;
+        Ok(Self {
+            client: builder.build()?,
             config: config.to_owned(),
+            oauth: OAuthTokenSource::from_config(config),
+            limiter: RateLimiter::new(config.rpm, config.tpm),
+            grammar,
+            response_format,
+            redactor: redactor.map(Mutex::new),
+            audit: AuditLog::new(config),
+            over_budget: false,
+        })
+    }
+
+    /// Replaces the settings used for subsequent requests
+    pub fn set_config(&mut self, config: &Config) {
+        self.config = config.to_owned();
+        self.audit = AuditLog::new(config);
+    }
+
+    /// Lets requests over the budget through, once the user has confirmed them
+    pub fn allow_over_budget(&mut self, allowed: bool) {
+        self.over_budget = allowed;
+    }
+
+    pub async fn get_chat_completion(&self, messages: &[Message]) -> Result<Completion, ApiError> {
+        self.check_budget(messages)?;
+        let messages = self.redact(messages);
+        let request = self.record_request(&messages)?;
+        let mut completion = self.send_chat_completion(&messages).await?;
+        if let (Some(audit), Some(request), Some(usage)) = (&self.audit, request, &completion.usage)
+        {
+            audit
+                .record_usage(&request, usage)
+                .map_err(|error| ApiError::AuditLog(format!("{:#}", error)))?;
+        }
+        if let Some(mut restorer) = self.restorer() {
+            completion.content = restorer.push(&completion.content) + &restorer.finish();
         }
+        if let Some(hook) = &self.config.post_response_hook {
+            completion.content = run_hook(hook, &completion.content)
+                .map_err(|error| ApiError::Hook(format!("{:#}", error)))?;
+        }
+        Ok(completion)
     }
 
-    pub async fn get_chat_completion(&self, messages: &Vec<Message>) -> Result<String, ApiError> {
-        let response: ChatResponse = self
-            .prepare_request(false, messages)
-            .send()
+    async fn send_chat_completion(&self, messages: &[Message]) -> Result<Completion, ApiError> {
+        self.wait_for_rate_limit(messages).await;
+        if self.config.provider == Provider::Bedrock {
+            let response: ConverseResponse = self
+                .send_bedrock_request(false, &ConverseRequest::new(messages, &self.config))
+                .await?
+                .json()
+                .await?;
+            let content = response
+                .output
+                .message
+                .map(|message| message.content)
+                .unwrap_or_default();
+            let text: String = content.into_iter().filter_map(|c| c.text).collect();
+            return Ok(Completion {
+                content: trim_response(&text, messages),
+                finish_reason: response.stop_reason.map(bedrock_finish_reason),
+                usage: response.usage,
+                logprobs: Vec::new(),
+            });
+        }
+        if self.config.api == Api::Responses {
+            let request = self.prepare_request(false, messages).await?;
+            let response = check_status(request.send().await?).await?;
+            let response: ResponsesResponse = response.json().await?;
+            if let Some(error) = &response.error {
+                return Err(ApiError::ServiceError(error_message(error)));
+            }
+            return Ok(Completion {
+                content: trim_response(&response.text(), messages),
+                finish_reason: response.finish_reason(),
+                usage: response.usage,
+                logprobs: Vec::new(),
+            });
+        }
+        let request = self.prepare_request(false, messages).await?;
+        let response = check_status(request.send().await?).await?;
+        let response: ChatResponse = response.json().await?;
+        let (content, finish_reason, logprobs) = match response.choices.into_iter().next() {
+            Some(choice) => (
+                trim_response(&choice.message.content, messages),
+                choice.finish_reason,
+                token_logprobs(choice.logprobs),
+            ),
+            None => ("".into(), None, Vec::new()),
+        };
+        Ok(Completion {
+            content,
+            finish_reason,
+            usage: response.usage,
+            logprobs,
+        })
+    }
+
+    pub async fn stream_chat_completion(
+        &self,
+        messages: &[Message],
+    ) -> Result<BoxStream<'static, Result<StreamEvent, ApiError>>, ApiError> {
+        self.check_budget(messages)?;
+        let messages = self.redact(messages);
+        let request = self.record_request(&messages)?;
+        let mut events = self.start_stream(&messages).await?;
+        if let (Some(audit), Some(request)) = (&self.audit, request) {
+            events = audit_stream(events, audit.to_owned(), request);
+        }
+        if let Some(restorer) = self.restorer() {
+            events = restore_stream(events, restorer);
+        }
+        if let Some(hook) = &self.config.post_response_hook {
+            events = hook_stream(events, hook.to_owned());
+        }
+        Ok(events)
+    }
+
+    async fn start_stream(
+        &self,
+        messages: &[Message],
+    ) -> Result<BoxStream<'static, Result<StreamEvent, ApiError>>, ApiError> {
+        self.wait_for_rate_limit(messages).await;
+        if self.config.provider == Provider::Bedrock {
+            let request = ConverseRequest::new(messages, &self.config);
+            let response = self.send_bedrock_request(true, &request).await?;
+            return Ok(decode_bedrock_event_stream(response).boxed());
+        }
+        let response = self.prepare_request(true, messages).await?.send().await?;
+        let response = check_status(response).await?;
+        if self.config.api == Api::Responses {
+            let stream = EventStream::new(response.bytes_stream())
+                .map(parse_responses_event_data)
+                .flat_map(stream::iter);
+            return Ok(stream.boxed());
+        }
+        if is_json(&response) {
+            // some gateways ignore "stream": true and reply with a regular response
+            let response: ChatResponse = response.json().await?;
+            let mut events = Vec::new();
+            if let Some(choice) = response.choices.into_iter().next() {
+                let logprobs = token_logprobs(choice.logprobs);
+                if logprobs.is_empty() {
+                    events.push(Ok(StreamEvent::Token(choice.message.content)));
+                } else {
+                    events.push(Ok(StreamEvent::Logprobs(logprobs)));
+                }
+                if let Some(reason) = choice.finish_reason {
+                    events.push(Ok(StreamEvent::Finish(reason)));
+                }
+            }
+            if let Some(usage) = response.usage {
+                events.push(Ok(StreamEvent::Usage(usage)));
+            }
+            return Ok(stream::iter(events).boxed());
+        }
+        let stream = EventStream::new(response.bytes_stream())
+            .map(parse_event_data)
+            .flat_map(stream::iter);
+        Ok(stream.boxed())
+    }
+
+    async fn send_bedrock_request(
+        &self,
+        stream: bool,
+        converse: &ConverseRequest,
+    ) -> Result<Response, ApiError> {
+        let request = self.bedrock_request(stream, converse)?;
+        let response = self.client.execute(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await?;
+            let message = match serde_json::from_str::<BedrockException>(&body) {
+                Ok(exception) => exception.message,
+                Err(_) => body,
+            };
+            return Err(ApiError::ServiceError(format!("{}: {}", status, message)));
+        }
+        Ok(response)
+    }
+
+    fn bedrock_request(
+        &self,
+        stream: bool,
+        converse: &ConverseRequest,
+    ) -> Result<Request, ApiError> {
+        let action = if stream {
+            "converse-stream"
+        } else {
+            "converse"
+        };
+        let url = format!(
+            "{}/model/{}/{}",
+            self.config.api_url,
+            uri_encode(&self.config.model, true),
+            action
+        );
+        let body = self.hooked_body(converse)?;
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .build()?;
+        if let Some(key) = &self.config.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {}", key))?;
+            request.headers_mut().insert("authorization", value);
+        } else {
+            let credentials = AwsCredentials::from_env().ok_or(ApiError::MissingAwsCredentials)?;
+            let url = request.url();
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (host, None) => host.unwrap_or_default().to_owned(),
+                (None, Some(_)) => String::new(),
+            };
+            let region = self.config.region.as_deref().unwrap_or_default();
+            let headers = sign_aws_request(
+                &credentials,
+                region,
+                "bedrock",
+                &host,
+                url.path(),
+                &body,
+                SystemTime::now(),
+            );
+            for (name, value) in headers {
+                request
+                    .headers_mut()
+                    .insert(name, HeaderValue::from_str(&value)?);
+            }
+        }
+        Ok(request)
+    }
+
+    /// Describes the request that would be sent for "--dry-run", as JSON with the
+    /// method, URL, headers (with any secrets redacted) and body
+    pub async fn describe_request(
+        &self,
+        stream: bool,
+        messages: &[Message],
+    ) -> Result<String, ApiError> {
+        let messages = &self.redact(messages);
+        let request = if self.config.provider == Provider::Bedrock {
+            self.bedrock_request(stream, &ConverseRequest::new(messages, &self.config))?
+        } else {
+            self.prepare_request(stream, messages).await?.build()?
+        };
+        let headers: serde_json::Map<String, serde_json::Value> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                    "<redacted>"
+                } else {
+                    value.to_str().unwrap_or_default()
+                };
+                (name.to_string(), serde_json::Value::from(value))
+            })
+            .collect();
+        let body: serde_json::Value = match request.body().and_then(|body| body.as_bytes()) {
+            Some(bytes) => serde_json::from_slice(bytes)?,
+            None => serde_json::Value::Null,
+        };
+        Ok(serde_json::to_string_pretty(&serde_json::json!({
+            "method": request.method().as_str(),
+            "url": request.url().as_str(),
+            "headers": headers,
+            "body": body,
+        }))?)
+    }
+
+    /// Masks the text matching the "[redact]" rules in the messages, if any
+    fn redact<'a>(&self, messages: &'a [Message]) -> Cow<'a, [Message]> {
+        let Some(redactor) = &self.redactor else {
+            return Cow::Borrowed(messages);
+        };
+        let mut redactor = redactor.lock().unwrap();
+        let mut messages = messages.to_vec();
+        for message in &mut messages {
+            message.content = redactor.redact(&message.content);
+        }
+        Cow::Owned(messages)
+    }
+
+    fn restorer(&self) -> Option<Restorer> {
+        let redactor = self.redactor.as_ref()?;
+        redactor.lock().unwrap().restorer()
+    }
+
+    /// Records the prompt in the audit log, if any, as the request is sent,
+    /// returning the ID to record the usage with
+    fn record_request(&self, messages: &[Message]) -> Result<Option<String>, ApiError> {
+        let Some(audit) = &self.audit else {
+            return Ok(None);
+        };
+        audit
+            .record(&last_prompt(messages))
+            .map(Some)
+            .map_err(|error| ApiError::AuditLog(format!("{:#}", error)))
+    }
+
+    /// Fails if the request would go over the budget set in the profile, as
+    /// every request goes through here
+    fn check_budget(&self, messages: &[Message]) -> Result<(), ApiError> {
+        if self.over_budget {
+            return Ok(());
+        }
+        let tokens = messages
+            .iter()
+            .map(|message| estimate_tokens(&message.content))
+            .sum();
+        self.config
+            .check_budget(tokens)
+            .map_err(|error| ApiError::OverBudget(error.to_string()))
+    }
+
+    async fn wait_for_rate_limit(&self, messages: &[Message]) {
+        if let Some(limiter) = &self.limiter {
+            let tokens = messages
+                .iter()
+                .map(|message| estimate_tokens(&message.content))
+                .sum();
+            limiter.acquire(tokens).await;
+        }
+    }
+
+    /// Transcribes an audio file with the "/audio/transcriptions" endpoint
+    pub async fn transcribe(&self, path: &Path) -> Result<String, ApiError> {
+        if self.config.provider == Provider::Bedrock {
+            return Err(ApiError::Unsupported("Audio transcription"));
+        }
+        let audio = tokio::fs::read(path)
+            .await
+            .map_err(|error| ApiError::UnreadableFile(path.display().to_string(), error))?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let model = self.config.transcription_model.to_owned();
+        let form = Form::new()
+            .text("model", model.to_owned())
+            .part("file", Part::bytes(audio).file_name(file_name));
+        let request = self.endpoint("/audio/transcriptions", &model);
+        let response = self
+            .authorize(request)
             .await?
-            .json()
+            .multipart(form)
+            .send()
             .await?;
-        match response.choices.first() {
-            Some(choice) => Ok(choice.message.content.trim().into()),
-            None => Ok("".into()),
+        let transcription: Transcription = check_status(response).await?.json().await?;
+        Ok(transcription.text)
+    }
+
+    /// Turns text into speech (mp3) with the "/audio/speech" endpoint, a part at
+    /// a time when it's longer than the endpoint accepts, joining the audio
+    pub async fn speak(&self, text: &str) -> Result<Vec<u8>, ApiError> {
+        if self.config.provider == Provider::Bedrock {
+            return Err(ApiError::Unsupported("Text to speech"));
+        }
+        let model = &self.config.speech_model;
+        let mut audio = Vec::new();
+        for part in split_speech_input(text, MAX_SPEECH_INPUT) {
+            let request = self.endpoint("/audio/speech", model);
+            let response = self
+                .authorize(request)
+                .await?
+                .json(&SpeechRequest {
+                    model,
+                    input: &part,
+                    voice: &self.config.speech_voice,
+                    response_format: "mp3",
+                })
+                .send()
+                .await?;
+            audio.extend_from_slice(&check_status(response).await?.bytes().await?);
         }
+        Ok(audio)
     }
 
-    pub async fn stream_chat_completion(
+    /// Lists the models available from the "/models" endpoint
+    pub async fn list_models(&self) -> Result<Vec<String>, ApiError> {
+        let url = match self.config.provider {
+            Provider::OpenAi => format!("{}/models", self.config.api_url),
+            Provider::Azure => format!("{}/openai/models", self.config.api_url),
+            Provider::Bedrock => return Err(ApiError::Unsupported("Listing models")),
+        };
+        let mut request = self.client.get(url);
+        if let Some(api_version) = &self.config.api_version {
+            request = request.query(&[("api-version", api_version)]);
+        }
+        let response = self.authorize(request).await?.send().await?;
+        let list: ModelList = check_status(response).await?.json().await?;
+        Ok(list.data.into_iter().map(|model| model.id).collect())
+    }
+
+    /// Fetches a file, e.g. an image linked in a response
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>, ApiError> {
+        let response = self.client.get(url).send().await?;
+        Ok(check_status(response).await?.bytes().await?.to_vec())
+    }
+
+    /// Sends a prompt asking for a single token, to have a local server load the
+    /// model into memory before it's needed
+    pub async fn warm_up(&self) -> Result<(), ApiError> {
+        if self.config.api == Api::Responses {
+            return Err(ApiError::Unsupported("Warming up"));
+        }
+        let messages = vec![Message::new(Role::User, &String::from("Hi"))];
+        if self.config.provider == Provider::Bedrock {
+            let mut config = self.config.clone();
+            config.max_tokens = Some(1);
+            let request = ConverseRequest::new(&messages, &config);
+            self.send_bedrock_request(false, &request).await?;
+            return Ok(());
+        }
+        let mut body = self.chat_request(false, &messages);
+        body.max_tokens = Some(1);
+        let request = self.start_request(false).await?.json(&body);
+        check_status(request.send().await?).await?;
+        Ok(())
+    }
+
+    async fn prepare_request(
         &self,
-        messages: &Vec<Message>,
-    ) -> Result<impl Stream<Item = Result<Option<String>, ApiError>>, ApiError> {
-        let response = self.prepare_request(true, messages).send().await?;
-        let stream = EventStream::new(response.bytes_stream()).map(parse_event_data);
-        Ok(stream)
+        stream: bool,
+        messages: &[Message],
+    ) -> Result<RequestBuilder, ApiError> {
+        let request = self.start_request(stream).await?;
+        Ok(request.body(match self.config.api {
+            Api::Chat => self.hooked_body(&self.chat_request(stream, messages))?,
+            Api::Responses => self.hooked_body(&self.responses_request(stream, messages))?,
+        }))
+    }
+
+    /// The JSON body of a request, as rewritten by the "pre_request_hook" if any
+    fn hooked_body(&self, request: &impl Serialize) -> Result<Vec<u8>, ApiError> {
+        let body = serde_json::to_vec(request)?;
+        let Some(hook) = &self.config.pre_request_hook else {
+            return Ok(body);
+        };
+        let output = run_hook(hook, &String::from_utf8_lossy(&body))
+            .map_err(|error| ApiError::Hook(format!("{:#}", error)))?;
+        if serde_json::from_str::<serde_json::Value>(&output).is_err() {
+            return Err(ApiError::Hook(format!(
+                "\"{}\" did not print a JSON request",
+                hook
+            )));
+        }
+        Ok(output.into_bytes())
     }
 
-    fn prepare_request(&self, stream: bool, messages: &Vec<Message>) -> RequestBuilder {
-        let client = Client::new();
-        let mut request = client.post(self.config.api_url.clone() + "/chat/completions");
+    /// An authorized request to the endpoint of the API, without a body yet
+    async fn start_request(&self, stream: bool) -> Result<RequestBuilder, ApiError> {
+        let deployment = self.config.deployment.as_deref().unwrap_or_default();
+        let mut request = match self.config.api {
+            Api::Chat => self.endpoint("/chat/completions", deployment),
+            // the deployment is given as the model instead
+            Api::Responses => self.endpoint("/responses", ""),
+        };
         if stream {
             request = request.header(ACCEPT, "text/event-stream");
         }
-        if let Some(key) = &self.config.api_key {
-            request = request.bearer_auth(key);
+        Ok(self
+            .authorize(request)
+            .await?
+            .header(CONTENT_TYPE, "application/json"))
+    }
+
+    fn responses_request<'a>(
+        &'a self,
+        stream: bool,
+        messages: &'a [Message],
+    ) -> ResponsesRequest<'a> {
+        ResponsesRequest {
+            model: &self.config.model,
+            input: messages
+                .iter()
+                .map(|message| ResponsesInput {
+                    role: &message.role,
+                    content: &message.content,
+                })
+                .collect(),
+            store: false,
+            max_output_tokens: self.config.max_tokens,
+            stream,
+            temperature: self.config.temperature,
+            tools: self
+                .config
+                .builtin_tools
+                .iter()
+                .map(|name| builtin_tool(name))
+                .collect(),
         }
-        request
-            .header(CONTENT_TYPE, "application/json")
-            .json(&ChatRequest {
-                model: self.config.model.to_owned(),
-                messages: messages.to_owned(),
-                stream,
-                temperature: self.config.temperature,
+    }
+
+    fn chat_request(&self, stream: bool, messages: &[Message]) -> ChatRequest {
+        let breakpoints = if self.config.prompt_caching {
+            cache_breakpoints(messages)
+        } else {
+            Vec::new()
+        };
+        ChatRequest {
+            model: self.config.model.to_owned(),
+            messages: messages
+                .iter()
+                .enumerate()
+                .map(|(index, message)| ChatMessage::new(message, breakpoints.contains(&index)))
+                .collect(),
+            grammar: self.grammar.to_owned(),
+            keep_alive: self.config.keep_alive.to_owned(),
+            logprobs: self.config.logprobs.map(|_| true),
+            max_tokens: self.config.max_tokens,
+            response_format: self.response_format.to_owned(),
+            seed: self.config.seed,
+            stream,
+            stream_options: (stream && self.config.show_usage).then_some(StreamOptions {
+                include_usage: true,
+            }),
+            temperature: self.config.temperature,
+            top_logprobs: self.config.logprobs.filter(|&count| count > 0),
+        }
+    }
+
+    /// Uploads the conversations as a JSONL file and starts a job of the Batch API
+    /// to complete them, each identified by its "custom_id"
+    pub async fn submit_batch(
+        &self,
+        requests: &[(String, Vec<Message>)],
+    ) -> Result<BatchJob, ApiError> {
+        self.ensure_batch_support()?;
+        let mut jsonl = String::new();
+        for (custom_id, messages) in requests {
+            self.check_budget(messages)?;
+            let line = BatchRequestLine {
+                custom_id,
+                method: "POST",
+                url: "/v1/chat/completions",
+                body: self.chat_request(false, &self.redact(messages)),
+            };
+            jsonl.push_str(&serde_json::to_string(&line)?);
+            jsonl.push('\n');
+        }
+        let form = Form::new().text("purpose", "batch").part(
+            "file",
+            Part::bytes(jsonl.into_bytes()).file_name("batch.jsonl"),
+        );
+        let request = self.endpoint("/files", "");
+        let response = self
+            .authorize(request)
+            .await?
+            .multipart(form)
+            .send()
+            .await?;
+        let file: UploadedFile = check_status(response).await?.json().await?;
+        let request = self.endpoint("/batches", "");
+        let response = self
+            .authorize(request)
+            .await?
+            .json(&serde_json::json!({
+                "input_file_id": file.id,
+                "endpoint": "/v1/chat/completions",
+                "completion_window": "24h",
+            }))
+            .send()
+            .await?;
+        Ok(check_status(response).await?.json().await?)
+    }
+
+    pub async fn get_batch(&self, id: &str) -> Result<BatchJob, ApiError> {
+        self.ensure_batch_support()?;
+        let request = self
+            .client
+            .get(format!("{}/batches/{}", self.config.api_url, id));
+        let response = self.authorize(request).await?.send().await?;
+        Ok(check_status(response).await?.json().await?)
+    }
+
+    /// Downloads a file, e.g. the results of a batch job, as text
+    pub async fn get_file_content(&self, id: &str) -> Result<String, ApiError> {
+        self.ensure_batch_support()?;
+        let request = self
+            .client
+            .get(format!("{}/files/{}/content", self.config.api_url, id));
+        let response = self.authorize(request).await?.send().await?;
+        Ok(check_status(response).await?.text().await?)
+    }
+
+    fn ensure_batch_support(&self) -> Result<(), ApiError> {
+        match self.config.provider {
+            Provider::OpenAi => Ok(()),
+            _ => Err(ApiError::Unsupported("The Batch API")),
+        }
+    }
+
+    /// Starts a POST request to an OpenAI-style endpoint, e.g. "/chat/completions",
+    /// which Azure serves per deployment, or for the whole resource without one
+    fn endpoint(&self, path: &str, deployment: &str) -> RequestBuilder {
+        match self.config.provider {
+            Provider::Azure if deployment.is_empty() => {
+                let api_version = self.config.api_version.as_deref().unwrap_or_default();
+                self.client
+                    .post(format!("{}/openai{}", self.config.api_url, path))
+                    .query(&[("api-version", api_version)])
+            }
+            Provider::Azure => {
+                let api_version = self.config.api_version.as_deref().unwrap_or_default();
+                self.client
+                    .post(format!(
+                        "{}/openai/deployments/{}{}",
+                        self.config.api_url, deployment, path
+                    ))
+                    .query(&[("api-version", api_version)])
+            }
+            _ => self.client.post(self.config.api_url.clone() + path),
+        }
+    }
+
+    async fn authorize(&self, request: RequestBuilder) -> Result<RequestBuilder, ApiError> {
+        if self.oauth.is_some() && self.config.dry_run {
+            // no need to fetch a token that won't be shown
+            Ok(request.bearer_auth("oauth-token"))
+        } else if let Some(oauth) = &self.oauth {
+            Ok(request.bearer_auth(oauth.token(&self.client).await?))
+        } else if let Some(key) = &self.config.api_key {
+            Ok(match self.config.provider {
+                Provider::Azure => request.header("api-key", key),
+                _ => request.bearer_auth(key),
             })
+        } else {
+            Ok(request)
+        }
     }
 }
 
+/// Turns an HTTP error status into a ServiceError with the message from the body
+async fn check_status(response: Response) -> Result<Response, ApiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await?;
+    let message = match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(value) => error_message(value.get("error").unwrap_or(&value)),
+        Err(_) => body,
+    };
+    Err(ApiError::ServiceError(format!("{}: {}", status, message)))
+}
+
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
 fn parse_event_data(
     item: Result<Event, EventStreamError<reqwest::Error>>,
-) -> Result<Option<String>, ApiError> {
+) -> Vec<Result<StreamEvent, ApiError>> {
+    match parse_event(item) {
+        Ok(events) => events.into_iter().map(Ok).collect(),
+        Err(error) => vec![Err(error)],
+    }
+}
+
+fn parse_event(
+    item: Result<Event, EventStreamError<reqwest::Error>>,
+) -> Result<Vec<StreamEvent>, ApiError> {
     let event = item?;
     if event.data == "[DONE]" {
-        return Ok(None);
+        return Ok(Vec::new());
+    }
+    if event.event == "error" {
+        return Err(ApiError::ServiceError(event.data));
     }
     let data: EventData = serde_json::from_str(&event.data)?;
-    match data.choices.first() {
-        None => Ok(None),
-        Some(choice) => Ok(choice.delta.content.to_owned()),
+    if let Some(error) = data.error {
+        return Err(ApiError::ServiceError(error_message(&error)));
+    }
+    let mut events = Vec::new();
+    // only the first choice is displayed, in case the server generates several
+    for choice in data.choices.into_iter().filter(|choice| choice.index == 0) {
+        let logprobs = token_logprobs(choice.logprobs);
+        if !logprobs.is_empty() {
+            events.push(StreamEvent::Logprobs(logprobs));
+        } else if let Some(content) = choice.delta.and_then(|delta| delta.content) {
+            events.push(StreamEvent::Token(content));
+        }
+        if let Some(reason) = choice.finish_reason {
+            events.push(StreamEvent::Finish(reason));
+        }
+    }
+    if let Some(usage) = data.usage {
+        events.push(StreamEvent::Usage(usage));
+    }
+    Ok(events)
+}
+
+fn parse_responses_event_data(
+    item: Result<Event, EventStreamError<reqwest::Error>>,
+) -> Vec<Result<StreamEvent, ApiError>> {
+    match parse_responses_event(item) {
+        Ok(events) => events.into_iter().map(Ok).collect(),
+        Err(error) => vec![Err(error)],
+    }
+}
+
+fn parse_responses_event(
+    item: Result<Event, EventStreamError<reqwest::Error>>,
+) -> Result<Vec<StreamEvent>, ApiError> {
+    let event = item?;
+    let mut events = Vec::new();
+    match serde_json::from_str(&event.data)? {
+        ResponsesEvent::Delta { delta } => events.push(StreamEvent::Token(delta)),
+        ResponsesEvent::Completed { response } => {
+            if let Some(reason) = response.finish_reason() {
+                events.push(StreamEvent::Finish(reason));
+            }
+            if let Some(usage) = response.usage {
+                events.push(StreamEvent::Usage(usage));
+            }
+        }
+        ResponsesEvent::Failed { response } => {
+            let message = response
+                .error
+                .as_ref()
+                .map_or_else(|| String::from("The response failed"), error_message);
+            return Err(ApiError::ServiceError(message));
+        }
+        ResponsesEvent::Error { message } => return Err(ApiError::ServiceError(message)),
+        // reasoning, built-in tool calls and other progress events
+        ResponsesEvent::Other => {}
+    }
+    Ok(events)
+}
+
+/// The "response_format" of the request: a JSON schema read from
+/// "json_schema_file", or just the type given as "response_format"
+fn response_format(config: &Config) -> Result<Option<serde_json::Value>, ApiError> {
+    if let Some(path) = &config.json_schema_file {
+        let text = fs::read_to_string(path)
+            .map_err(|error| ApiError::UnreadableFile(path.into(), error))?;
+        let schema: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|error| ApiError::InvalidJsonSchema(path.into(), error))?;
+        return Ok(Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        })));
+    }
+    Ok(config
+        .response_format
+        .as_ref()
+        .map(|kind| serde_json::json!({ "type": kind })))
+}
+
+/// Puts the redacted text back in the tokens of a stream, including any held
+/// back when another event comes or the stream ends
+fn restore_stream(
+    events: BoxStream<'static, Result<StreamEvent, ApiError>>,
+    restorer: Restorer,
+) -> BoxStream<'static, Result<StreamEvent, ApiError>> {
+    let restorer = Arc::new(Mutex::new(restorer));
+    let at_end = Arc::clone(&restorer);
+    events
+        .flat_map(move |event| {
+            let mut restorer = restorer.lock().unwrap();
+            let events = match event {
+                Ok(StreamEvent::Token(token)) => {
+                    vec![Ok(StreamEvent::Token(restorer.push(&token)))]
+                }
+                event => vec![Ok(StreamEvent::Token(restorer.finish())), event],
+            };
+            stream::iter(events)
+        })
+        .chain(stream::once(async move {
+            Ok(StreamEvent::Token(at_end.lock().unwrap().finish()))
+        }))
+        .filter(|event| {
+            let empty = matches!(event, Ok(StreamEvent::Token(token)) if token.is_empty());
+            future::ready(!empty)
+        })
+        .boxed()
+}
+
+/// Collects the tokens of a stream to pipe the whole response through the
+/// "post_response_hook", which then comes as a single token at the end
+fn hook_stream(
+    events: BoxStream<'static, Result<StreamEvent, ApiError>>,
+    hook: String,
+) -> BoxStream<'static, Result<StreamEvent, ApiError>> {
+    let response = Arc::new(Mutex::new(String::new()));
+    let collected = Arc::clone(&response);
+    let hooked = stream::once(async move {
+        let response = response.lock().unwrap();
+        run_hook(&hook, &response)
+            .map(StreamEvent::Token)
+            .map_err(|error| ApiError::Hook(format!("{:#}", error)))
+    });
+    events
+        .filter(move |event| {
+            let token = match event {
+                Ok(StreamEvent::Token(token)) => Some(token),
+                _ => None,
+            };
+            if let Some(token) = token {
+                collected.lock().unwrap().push_str(token);
+            }
+            future::ready(token.is_none())
+        })
+        .chain(hooked)
+        .boxed()
+}
+
+/// Records the usage reported in a stream as a second entry for the request
+fn audit_stream(
+    events: BoxStream<'static, Result<StreamEvent, ApiError>>,
+    audit: AuditLog,
+    request: String,
+) -> BoxStream<'static, Result<StreamEvent, ApiError>> {
+    events
+        .map(move |event| match event {
+            Ok(StreamEvent::Usage(usage)) => match audit.record_usage(&request, &usage) {
+                Ok(()) => Ok(StreamEvent::Usage(usage)),
+                Err(error) => Err(ApiError::AuditLog(format!("{:#}", error))),
+            },
+            event => event,
+        })
+        .boxed()
+}
+
+/// The text of the last user message, the prompt of a request, rather than a
+/// prefilled assistant message after it
+fn last_prompt(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .rev()
+        .find(|message| message.role == Role::User)
+        .map(|message| message.content.to_owned())
+        .unwrap_or_default()
+}
+
+/// Trims the whitespace around a response, except at the start of one that
+/// continues a prefilled assistant message, e.g. " 42" after "The answer is"
+fn trim_response(content: &str, messages: &[Message]) -> String {
+    if messages.last().is_some_and(|m| m.role == Role::Assistant) {
+        content.trim_end().to_owned()
+    } else {
+        content.trim().to_owned()
+    }
+}
+
+fn token_logprobs(logprobs: Option<ChoiceLogprobs>) -> Vec<TokenLogprob> {
+    logprobs
+        .and_then(|logprobs| logprobs.content)
+        .unwrap_or_default()
+}
+
+fn error_message(error: &serde_json::Value) -> String {
+    match (error.get("message"), error.as_str()) {
+        (Some(serde_json::Value::String(message)), _) => message.to_owned(),
+        (_, Some(message)) => message.to_owned(),
+        _ => error.to_string(),
+    }
+}
+
+fn decode_bedrock_event_stream(
+    response: Response,
+) -> impl Stream<Item = Result<StreamEvent, ApiError>> {
+    response
+        .bytes_stream()
+        .scan(Vec::new(), |buffer: &mut Vec<u8>, chunk| {
+            let items = match chunk {
+                Ok(bytes) => {
+                    buffer.extend_from_slice(&bytes);
+                    decode_event_frames(buffer)
+                }
+                Err(error) => vec![Err(ApiError::from(error))],
+            };
+            future::ready(Some(stream::iter(items)))
+        })
+        .flatten()
+}
+
+/// Decodes and removes all complete messages in the AWS event stream encoding:
+/// total length (4 bytes), headers length (4), prelude CRC (4), headers, payload, CRC (4).
+/// A message with a CRC that doesn't match ends the stream, as the lengths can't be trusted.
+fn decode_event_frames(buffer: &mut Vec<u8>) -> Vec<Result<StreamEvent, ApiError>> {
+    let mut items = Vec::new();
+    while buffer.len() >= 12 {
+        let total_length = read_u32(&buffer[0..4]) as usize;
+        let headers_length = read_u32(&buffer[4..8]) as usize;
+        if crc32fast::hash(&buffer[0..8]) != read_u32(&buffer[8..12])
+            || total_length < headers_length + 16
+        {
+            buffer.clear();
+            items.push(Err(ApiError::MalformedEventStream));
+            break;
+        }
+        if buffer.len() < total_length {
+            break;
+        }
+        let frame: Vec<u8> = buffer.drain(..total_length).collect();
+        if crc32fast::hash(&frame[..total_length - 4]) != read_u32(&frame[total_length - 4..]) {
+            buffer.clear();
+            items.push(Err(ApiError::MalformedEventStream));
+            break;
+        }
+        let headers = parse_event_headers(&frame[12..12 + headers_length]);
+        let payload = &frame[12 + headers_length..total_length - 4];
+        if let Some(item) = parse_bedrock_event(&headers, payload).transpose() {
+            items.push(item);
+        }
+    }
+    items
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Parses event stream headers, keeping only string values
+fn parse_event_headers(mut bytes: &[u8]) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    while let Some((&name_length, rest)) = bytes.split_first() {
+        let name_length = name_length as usize;
+        if rest.len() <= name_length {
+            break;
+        }
+        let name = String::from_utf8_lossy(&rest[..name_length]).into_owned();
+        let value_type = rest[name_length];
+        let rest = &rest[name_length + 1..];
+        let value_length = match value_type {
+            0 | 1 => 0,
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 | 8 => 8,
+            9 => 16,
+            6 | 7 if rest.len() >= 2 => 2 + u16::from_be_bytes([rest[0], rest[1]]) as usize,
+            _ => break,
+        };
+        if rest.len() < value_length {
+            break;
+        }
+        if value_type == 7 {
+            let value = String::from_utf8_lossy(&rest[2..value_length]).into_owned();
+            headers.insert(name, value);
+        }
+        bytes = &rest[value_length..];
+    }
+    headers
+}
+
+fn parse_bedrock_event(
+    headers: &HashMap<String, String>,
+    payload: &[u8],
+) -> Result<Option<StreamEvent>, ApiError> {
+    let header = |name: &str| headers.get(name).map(String::as_str).unwrap_or_default();
+    match header(":message-type") {
+        "event" => match header(":event-type") {
+            "contentBlockDelta" => {
+                let event: ContentBlockDeltaEvent = serde_json::from_slice(payload)?;
+                Ok(event.delta.text.map(StreamEvent::Token))
+            }
+            "messageStop" => {
+                let event: MessageStopEvent = serde_json::from_slice(payload)?;
+                Ok(Some(StreamEvent::Finish(bedrock_finish_reason(
+                    event.stop_reason,
+                ))))
+            }
+            "metadata" => {
+                let event: MetadataEvent = serde_json::from_slice(payload)?;
+                Ok(event.usage.map(StreamEvent::Usage))
+            }
+            _ => Ok(None),
+        },
+        "exception" => {
+            let exception: BedrockException = serde_json::from_slice(payload)?;
+            Err(ApiError::ServiceError(format!(
+                "{}: {}",
+                header(":exception-type"),
+                exception.message
+            )))
+        }
+        _ => Err(ApiError::ServiceError(format!(
+            "{}: {}",
+            header(":error-code"),
+            header(":error-message")
+        ))),
+    }
+}
+
+/// Translates Bedrock stop reasons into the equivalent OpenAI finish reasons
+fn bedrock_finish_reason(stop_reason: String) -> String {
+    match stop_reason.as_str() {
+        "end_turn" | "stop_sequence" => String::from("stop"),
+        "max_tokens" => String::from("length"),
+        "guardrail_intervened" | "content_filtered" => String::from("content_filter"),
+        _ => stop_reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::config::config_from_source;
+
+    /// Encodes a message as AWS event stream frames do, with string headers only
+    fn event_frame(headers: &[(&str, &str)], payload: &[u8]) -> Vec<u8> {
+        let mut encoded_headers = Vec::new();
+        for (name, value) in headers {
+            encoded_headers.push(name.len() as u8);
+            encoded_headers.extend_from_slice(name.as_bytes());
+            encoded_headers.push(7);
+            encoded_headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            encoded_headers.extend_from_slice(value.as_bytes());
+        }
+        let total_length = (encoded_headers.len() + payload.len() + 16) as u32;
+        let mut frame = total_length.to_be_bytes().to_vec();
+        frame.extend_from_slice(&(encoded_headers.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&frame).to_be_bytes());
+        frame.extend_from_slice(&encoded_headers);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&crc32fast::hash(&frame).to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn sends_name_and_extra_fields_only_when_set() -> Result<(), serde_json::Error> {
+        let message = Message::new(Role::User, &String::from("Hi"));
+        assert_eq!(
+            serde_json::to_value(&message)?,
+            serde_json::json!({"role": "user", "content": "Hi"})
+        );
+        let mut message = message.with_name("alice");
+        message
+            .extra
+            .insert(String::from("tool_call_id"), serde_json::json!("call_1"));
+        let value = serde_json::to_value(&message)?;
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "role": "user",
+                "content": "Hi",
+                "name": "alice",
+                "tool_call_id": "call_1",
+            })
+        );
+        assert_eq!(serde_json::from_value::<Message>(value)?, message);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_cached_tokens() -> Result<(), serde_json::Error> {
+        let openai: Usage = serde_json::from_str(
+            r#"{"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12,
+                "prompt_tokens_details": {"cached_tokens": 8}}"#,
+        )?;
+        assert_eq!((openai.cached_tokens(), openai.cache_write_tokens), (8, 0));
+        let anthropic: Usage = serde_json::from_str(
+            r#"{"input_tokens": 10, "output_tokens": 2, "cache_read_input_tokens": 6,
+                "cache_creation_input_tokens": 4}"#,
+        )?;
+        assert_eq!(
+            (anthropic.cached_tokens(), anthropic.cache_write_tokens),
+            (6, 4)
+        );
+        let bedrock: Usage = serde_json::from_str(
+            r#"{"inputTokens": 10, "outputTokens": 2, "totalTokens": 12,
+                "cacheReadInputTokens": 5, "cacheWriteInputTokens": 3}"#,
+        )?;
+        assert_eq!(
+            (bedrock.cached_tokens(), bedrock.cache_write_tokens),
+            (5, 3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn marks_cache_breakpoints() -> Result<(), serde_json::Error> {
+        let messages = vec![
+            Message::new(Role::System, &String::from("Be brief.")),
+            Message::new(Role::User, &String::from("Hi")),
+            Message::new(Role::Assistant, &String::from("Hello")),
+            Message::new(Role::User, &String::from("Bye")),
+        ];
+        assert_eq!(cache_breakpoints(&messages), vec![0, 1]);
+        assert_eq!(cache_breakpoints(&messages[2..]), vec![1]);
+
+        let plain = serde_json::to_value(ChatMessage::new(&messages[1], false))?;
+        assert_eq!(plain, serde_json::json!({"role": "user", "content": "Hi"}));
+        let cacheable = serde_json::to_value(ChatMessage::new(&messages[0], true))?;
+        assert_eq!(
+            cacheable,
+            serde_json::json!({
+                "role": "system",
+                "content": [{
+                    "type": "text",
+                    "text": "Be brief.",
+                    "cache_control": {"type": "ephemeral"},
+                }],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_responses_api_replies() -> Result<(), serde_json::Error> {
+        let response: ResponsesResponse = serde_json::from_str(
+            r#"{
+                "id": "resp_1",
+                "status": "completed",
+                "output": [
+                    {"type": "reasoning", "id": "rs_1", "summary": []},
+                    {"type": "message", "role": "assistant", "content": [
+                        {"type": "output_text", "text": "Hello", "annotations": []},
+                        {"type": "refusal", "refusal": ", I can't help with that."}
+                    ]}
+                ],
+                "incomplete_details": null,
+                "usage": {"input_tokens": 10, "output_tokens": 5, "total_tokens": 15},
+                "error": null
+            }"#,
+        )?;
+        assert_eq!(response.text(), "Hello, I can't help with that.");
+        assert_eq!(response.finish_reason().as_deref(), Some("stop"));
+        assert_eq!(response.usage.map(|usage| usage.completion_tokens), Some(5));
+
+        let incomplete: ResponsesResponse = serde_json::from_str(
+            r#"{"status": "incomplete", "output": [],
+                "incomplete_details": {"reason": "max_output_tokens"}}"#,
+        )?;
+        assert_eq!(incomplete.text(), "");
+        assert_eq!(incomplete.finish_reason().as_deref(), Some("length"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_responses_api_events() {
+        let parse = |data: &str| {
+            parse_responses_event(Ok(Event {
+                event: String::new(),
+                data: data.to_owned(),
+                id: String::new(),
+                retry: None,
+            }))
+        };
+        let events = parse(r#"{"type": "response.output_text.delta", "delta": "Hi"}"#);
+        assert!(matches!(&events.unwrap()[..], [StreamEvent::Token(text)] if text == "Hi"));
+        let events = parse(r#"{"type": "response.reasoning_summary_text.delta", "delta": "Hm"}"#);
+        assert!(events.unwrap().is_empty());
+        let events = parse(
+            r#"{"type": "response.completed", "response": {"status": "completed",
+                "usage": {"input_tokens": 10, "output_tokens": 5, "total_tokens": 15}}}"#,
+        );
+        assert!(matches!(
+            &events.unwrap()[..],
+            [StreamEvent::Finish(reason), StreamEvent::Usage(usage)]
+                if reason == "stop" && usage.total_tokens == 15
+        ));
+        let events = parse(
+            r#"{"type": "response.incomplete", "response": {"status": "incomplete",
+                "incomplete_details": {"reason": "content_filter"}}}"#,
+        );
+        assert!(matches!(
+            &events.unwrap()[..],
+            [StreamEvent::Finish(reason)] if reason == "content_filter"
+        ));
+        let events = parse(
+            r#"{"type": "response.failed", "response": {"status": "failed",
+                "error": {"code": "server_error", "message": "Overloaded"}}}"#,
+        );
+        assert!(matches!(events, Err(ApiError::ServiceError(message)) if message == "Overloaded"));
+    }
+
+    #[test]
+    fn trims_responses_unless_prefilled() {
+        let mut messages = vec![Message::new(Role::User, &String::from("6 x 7?"))];
+        assert_eq!(trim_response("\n 42 \n", &messages), "42");
+        messages.push(Message::new(
+            Role::Assistant,
+            &String::from("The answer is"),
+        ));
+        assert_eq!(trim_response(" 42 \n", &messages), " 42");
+    }
+
+    #[test]
+    fn audits_the_user_prompt_rather_than_a_prefill() {
+        let mut messages = vec![
+            Message::new(Role::System, &String::from("Be brief")),
+            Message::new(Role::User, &String::from("6 x 7?")),
+        ];
+        assert_eq!(last_prompt(&messages), "6 x 7?");
+        messages.push(Message::new(
+            Role::Assistant,
+            &String::from("The answer is"),
+        ));
+        assert_eq!(last_prompt(&messages), "6 x 7?");
+    }
+
+    #[test]
+    fn wraps_response_formats() -> anyhow::Result<()> {
+        let mut schema_file = NamedTempFile::new()?;
+        schema_file.write_all(br#"{"type": "object"}"#)?;
+        let mut config = config_from_source(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+response_format = "json_object"
+"#,
+        )?;
+        assert_eq!(
+            response_format(&config)?,
+            Some(serde_json::json!({"type": "json_object"}))
+        );
+        config.json_schema_file = Some(schema_file.path().to_string_lossy().into_owned());
+        assert_eq!(
+            response_format(&config)?,
+            Some(serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {"name": "response", "schema": {"type": "object"}},
+            }))
+        );
+        config.response_format = None;
+        config.json_schema_file = None;
+        assert_eq!(response_format(&config)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_constrained_output_where_unsupported() -> anyhow::Result<()> {
+        let profiles = [
+            "provider = \"bedrock\"\nregion = \"us-east-1\"",
+            "api = \"responses\"",
+        ];
+        for profile in profiles {
+            let config = config_from_source(&format!(
+                "[default]\napi_url = \"http://localhost:11434/v1\"\nmodel = \"m\"\n\
+                response_format = \"json_object\"\n{}\n",
+                profile
+            ))?;
+            assert!(matches!(
+                ApiClient::new(&config),
+                Err(ApiError::Unsupported("Constrained generation"))
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_over_budget() -> anyhow::Result<()> {
+        let config = config_from_source(
+            "[default]\napi_url = \"http://localhost:11434/v1\"\nmodel = \"m\"\nbudget_tokens = 10\n",
+        )?;
+        let api_client = ApiClient::new(&config)?;
+        let messages = vec![Message::new(Role::User, &"word ".repeat(100))];
+        assert!(matches!(
+            api_client.get_chat_completion(&messages).await,
+            Err(ApiError::OverBudget(_))
+        ));
+        assert!(matches!(
+            api_client.stream_chat_completion(&messages).await,
+            Err(ApiError::OverBudget(_))
+        ));
+        let requests = [(String::from("request-1"), messages)];
+        assert!(matches!(
+            api_client.submit_batch(&requests).await,
+            Err(ApiError::OverBudget(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_errors_from_the_body() {
+        let response = |status, body: &str| {
+            let response = http::Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_owned())
+                .unwrap();
+            Response::from(response)
+        };
+        let error = r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error"}}"#;
+        let result = check_status(response(401, error)).await;
+        assert!(matches!(
+            result,
+            Err(ApiError::ServiceError(message))
+                if message == "401 Unauthorized: Incorrect API key provided"
+        ));
+        let result = check_status(response(429, "Too many requests")).await;
+        assert!(matches!(
+            result,
+            Err(ApiError::ServiceError(message))
+                if message == "429 Too Many Requests: Too many requests"
+        ));
+        assert!(check_status(response(200, "{}")).await.is_ok());
+    }
+
+    #[test]
+    fn decodes_event_frames_checking_crcs() {
+        let frame = event_frame(
+            &[
+                (":message-type", "event"),
+                (":event-type", "contentBlockDelta"),
+            ],
+            br#"{"delta": {"text": "Hi"}}"#,
+        );
+        let mut buffer = frame[..20].to_vec();
+        assert!(decode_event_frames(&mut buffer).is_empty());
+        buffer.extend_from_slice(&frame[20..]);
+        let items = decode_event_frames(&mut buffer);
+        assert!(matches!(&items[..], [Ok(StreamEvent::Token(text))] if text == "Hi"));
+        assert!(buffer.is_empty());
+
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let items = decode_event_frames(&mut corrupted);
+        assert!(matches!(&items[..], [Err(ApiError::MalformedEventStream)]));
+
+        let mut wrong_length = frame;
+        wrong_length[3] ^= 1;
+        let items = decode_event_frames(&mut wrong_length);
+        assert!(matches!(&items[..], [Err(ApiError::MalformedEventStream)]));
+        assert!(wrong_length.is_empty());
     }
 }
@@ -0,0 +1,103 @@
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// The most characters the "/audio/speech" endpoint accepts at once
+pub const MAX_SPEECH_INPUT: usize = 4096;
+
+/// Commands that play an audio file given as the last argument, tried in order
+/// until one works
+#[cfg(target_os = "macos")]
+const PLAY_COMMANDS: &[&[&str]] = &[&["afplay"]];
+#[cfg(windows)]
+const PLAY_COMMANDS: &[&[&str]] = &[&["cmd", "/C", "start", "/wait", ""]];
+#[cfg(not(any(target_os = "macos", windows)))]
+const PLAY_COMMANDS: &[&[&str]] = &[
+    &["mpv", "--really-quiet", "--no-video"],
+    &["ffplay", "-nodisp", "-autoexit", "-loglevel", "quiet"],
+    &["mpg123", "-q"],
+];
+
+/// Plays an audio file with the platform's own tools, waiting until it's done
+pub fn play_audio(path: &Path) -> io::Result<()> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No audio player found");
+    for command in PLAY_COMMANDS {
+        match Command::new(command[0])
+            .args(&command[1..])
+            .arg(path)
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => last_error = io::Error::other(format!("{}: {}", command[0], status)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+/// Splits text to speak into parts of at most `max` characters, at the end of
+/// sentences where possible, or else between words
+pub fn split_speech_input(text: &str, max: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut length = 0;
+    let sentences = text.split_inclusive(['.', '!', '?', '\n']);
+    for piece in sentences.flat_map(|sentence| split_words(sentence, max)) {
+        let piece_length = piece.chars().count();
+        if length + piece_length > max {
+            if !part.trim().is_empty() {
+                parts.push(part.trim().to_owned());
+            }
+            part.clear();
+            length = 0;
+        }
+        part.push_str(piece);
+        length += piece_length;
+    }
+    if !part.trim().is_empty() {
+        parts.push(part.trim().to_owned());
+    }
+    parts
+}
+
+/// Splits a sentence too long to speak at once into words, and any word that is
+/// still too long anywhere
+fn split_words(sentence: &str, max: usize) -> Vec<&str> {
+    if sentence.chars().count() <= max {
+        return vec![sentence];
+    }
+    let mut pieces = Vec::new();
+    for word in sentence.split_inclusive(char::is_whitespace) {
+        let mut rest = word;
+        while let Some((index, _)) = rest.char_indices().nth(max) {
+            pieces.push(&rest[..index]);
+            rest = &rest[index..];
+        }
+        pieces.push(rest);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_speech_at_sentences() {
+        assert_eq!(
+            split_speech_input("One. Two three! Four?", 12),
+            vec!["One.", "Two three!", "Four?"]
+        );
+        assert_eq!(split_speech_input("One. Two.", 20), vec!["One. Two."]);
+        assert_eq!(
+            split_speech_input("A very long sentence", 8),
+            vec!["A very", "long", "sentence"]
+        );
+        assert_eq!(
+            split_speech_input("abcdefghij", 4),
+            vec!["abcd", "efgh", "ij"]
+        );
+        assert!(split_speech_input(" \n", 4).is_empty());
+    }
+}
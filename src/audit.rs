@@ -0,0 +1,263 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use home::home_dir;
+use serde_json::{json, Value};
+
+use crate::api::Usage;
+use crate::auth::{hex, sha256};
+use crate::config::{AuditMode, Config};
+use crate::session::format_timestamp;
+
+/// Appends a line to "$HOME/.lmc/audit.jsonl" for every request, when the
+/// "audit" setting is on
+#[derive(Clone)]
+pub struct AuditLog {
+    mode: AuditMode,
+    profile: String,
+    model: String,
+}
+
+impl AuditLog {
+    /// None if the requests aren't meant to be audited
+    pub fn new(config: &Config) -> Option<Self> {
+        Some(Self {
+            mode: config.audit?,
+            profile: config.profile.to_owned(),
+            model: config.model.to_owned(),
+        })
+    }
+
+    /// Records a prompt as it's sent, returning the ID of the request for the
+    /// usage reported later, if any
+    pub fn record(&self, prompt: &str) -> Result<String> {
+        let mut bytes = [0; 8];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|error| anyhow!("Could not generate a request ID: {}", error))?;
+        let request = hex(&bytes);
+        append(&self.entry(now(), &request, Some(prompt), None))?;
+        Ok(request)
+    }
+
+    /// Records the usage reported for the response to a request, as a second
+    /// entry with the same ID
+    pub fn record_usage(&self, request: &str, usage: &Usage) -> Result<()> {
+        append(&self.entry(now(), request, None, Some(usage)))
+    }
+
+    fn entry(
+        &self,
+        time: u64,
+        request: &str,
+        prompt: Option<&str>,
+        usage: Option<&Usage>,
+    ) -> Value {
+        let mut entry = json!({
+            "time": time,
+            "request": request,
+            "profile": self.profile,
+            "model": self.model,
+        });
+        match (prompt, self.mode) {
+            (Some(prompt), AuditMode::Hash) => {
+                entry["prompt_sha256"] = json!(hex(&sha256(prompt.as_bytes())))
+            }
+            (Some(prompt), AuditMode::Full) => entry["prompt"] = json!(prompt),
+            (None, _) => {}
+        }
+        if let Some(usage) = usage {
+            entry["usage"] = json!({
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+            });
+        }
+        entry
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn append(entry: &Value) -> Result<()> {
+    let path = audit_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    writeln!(file, "{}", entry).with_context(|| format!("Could not write to {}", path.display()))
+}
+
+pub fn audit_path() -> Result<PathBuf> {
+    let dir = home_dir().context("Could not detect HOME directory")?;
+    Ok(dir.join(".lmc").join("audit.jsonl"))
+}
+
+/// The last `limit` entries of the audit log, oldest first, one per line
+pub fn read_audit_log(limit: usize) -> Result<Vec<String>> {
+    let path = audit_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let log =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    let entries = merge_usage(log.lines().filter(|line| !line.trim().is_empty()));
+    Ok(entries[entries.len().saturating_sub(limit)..]
+        .iter()
+        .map(|entry| match entry {
+            Ok(entry) => describe_entry(entry),
+            Err(line) => format!("(invalid entry) {}", line),
+        })
+        .collect())
+}
+
+/// The entries of the log, with the usage recorded after a request moved into
+/// the entry of the request itself, and the lines that aren't valid JSON as is
+fn merge_usage<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<Result<Value, &'a str>> {
+    let mut entries: Vec<Result<Value, &str>> = Vec::new();
+    for line in lines {
+        match serde_json::from_str::<Value>(line) {
+            Ok(mut entry) if is_usage_entry(&entry) => {
+                let request = entries
+                    .iter_mut()
+                    .rev()
+                    .flatten()
+                    .find(|request| request["request"] == entry["request"]);
+                match request {
+                    Some(request) => request["usage"] = entry["usage"].take(),
+                    None => entries.push(Ok(entry)),
+                }
+            }
+            Ok(entry) => entries.push(Ok(entry)),
+            Err(_) => entries.push(Err(line)),
+        }
+    }
+    entries
+}
+
+fn is_usage_entry(entry: &Value) -> bool {
+    entry["request"].is_string()
+        && entry.get("prompt").is_none()
+        && entry.get("prompt_sha256").is_none()
+}
+
+/// An entry as a line of text, e.g.
+/// "2024-11-20 14:05  default  gpt-4o  120+45 tokens  Why is the sky blue?"
+fn describe_entry(entry: &Value) -> String {
+    let tokens = match (
+        entry["usage"]["prompt_tokens"].as_u64(),
+        entry["usage"]["completion_tokens"].as_u64(),
+    ) {
+        (Some(prompt), Some(completion)) => format!("{}+{} tokens", prompt, completion),
+        _ => String::from("? tokens"),
+    };
+    let prompt = match (entry["prompt"].as_str(), entry["prompt_sha256"].as_str()) {
+        (Some(prompt), _) => excerpt(prompt),
+        (None, Some(hash)) => format!("sha256:{}", &hash[..hash.len().min(16)]),
+        _ => String::new(),
+    };
+    format!(
+        "{}  {}  {}  {}  {}",
+        format_timestamp(entry["time"].as_u64().unwrap_or_default()),
+        entry["profile"].as_str().unwrap_or("?"),
+        entry["model"].as_str().unwrap_or("?"),
+        tokens,
+        prompt
+    )
+}
+
+/// The prompt on a single line, shortened to 60 characters
+fn excerpt(prompt: &str) -> String {
+    let line = prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(60) {
+        Some((index, _)) => format!("{}…", &line[..index]),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_entries() {
+        let log = AuditLog {
+            mode: AuditMode::Hash,
+            profile: String::from("default"),
+            model: String::from("gpt-4o"),
+        };
+        let usage: Usage =
+            serde_json::from_value(json!({"prompt_tokens": 12, "completion_tokens": 5})).unwrap();
+        let entry = log.entry(1732111500, "0123456789abcdef", Some("abc"), Some(&usage));
+        assert_eq!(
+            entry["prompt_sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            describe_entry(&entry),
+            "2024-11-20 14:05  default  gpt-4o  12+5 tokens  sha256:ba7816bf8f01cfea"
+        );
+        let log = AuditLog {
+            mode: AuditMode::Full,
+            ..log
+        };
+        let entry = log.entry(
+            1732111500,
+            "0123456789abcdef",
+            Some("Why is\nthe sky blue?"),
+            None,
+        );
+        assert_eq!(
+            describe_entry(&entry),
+            "2024-11-20 14:05  default  gpt-4o  ? tokens  Why is the sky blue?"
+        );
+    }
+
+    #[test]
+    fn merges_usage_into_requests() {
+        let log = AuditLog {
+            mode: AuditMode::Full,
+            profile: String::from("default"),
+            model: String::from("gpt-4o"),
+        };
+        let usage: Usage =
+            serde_json::from_value(json!({"prompt_tokens": 12, "completion_tokens": 5})).unwrap();
+        let lines = [
+            log.entry(1732111500, "aaaa", Some("First"), None)
+                .to_string(),
+            log.entry(1732111510, "bbbb", Some("Dropped"), None)
+                .to_string(),
+            String::from("not json"),
+            log.entry(1732111520, "aaaa", None, Some(&usage))
+                .to_string(),
+        ];
+        let entries = merge_usage(lines.iter().map(String::as_str));
+        let described: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .as_ref()
+                    .map_or_else(|line| line.to_string(), describe_entry)
+            })
+            .collect();
+        assert_eq!(
+            described,
+            [
+                "2024-11-20 14:05  default  gpt-4o  12+5 tokens  First",
+                "2024-11-20 14:05  default  gpt-4o  ? tokens  Dropped",
+                "not json",
+            ]
+        );
+    }
+}
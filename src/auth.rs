@@ -0,0 +1,273 @@
+use std::env;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+
+const KEYRING_SERVICE: &str = "lmc";
+
+/// Returns the API key stored in the OS keyring for a profile, if any
+#[cfg(not(test))]
+pub fn get_stored_api_key(profile: &str) -> Option<String> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, profile).ok()?;
+    entry.get_password().ok()
+}
+
+/// Never reads the real OS keyring, so tests don't depend on the environment
+#[cfg(test)]
+pub fn get_stored_api_key(_profile: &str) -> Option<String> {
+    None
+}
+
+pub fn set_stored_api_key(profile: &str, key: &str) -> Result<(), keyring::Error> {
+    keyring::Entry::new(KEYRING_SERVICE, profile)?.set_password(key)
+}
+
+pub fn remove_stored_api_key(profile: &str) -> Result<(), keyring::Error> {
+    keyring::Entry::new(KEYRING_SERVICE, profile)?.delete_credential()
+}
+
+/// Obtains bearer tokens with the OAuth2 client credentials flow, caching each
+/// token until shortly before it expires
+pub struct OAuthTokenSource {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl OAuthTokenSource {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Self {
+            token_url: config.oauth_token_url.to_owned()?,
+            client_id: config.oauth_client_id.to_owned()?,
+            client_secret: config.oauth_client_secret.to_owned()?,
+            scope: config.oauth_scope.to_owned(),
+            cached: Mutex::new(None),
+        })
+    }
+
+    pub async fn token(&self, client: &Client) -> Result<String, reqwest::Error> {
+        let mut cached = self.cached.lock().await;
+        if let Some((token, expires_at)) = cached.as_ref() {
+            if Instant::now() < *expires_at {
+                return Ok(token.to_owned());
+            }
+        }
+        let mut params = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+        ];
+        if let Some(scope) = &self.scope {
+            params.push(("scope", scope.as_str()));
+        }
+        let response: TokenResponse = client
+            .post(&self.token_url)
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let lifetime = Duration::from_secs(response.expires_in.unwrap_or(3600));
+        let expires_at = Instant::now() + lifetime.saturating_sub(Duration::from_secs(60));
+        *cached = Some((response.access_token.to_owned(), expires_at));
+        Ok(response.access_token)
+    }
+}
+
+/// AWS credentials, as read from the standard environment variables
+pub struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Signs a request with AWS Signature Version 4, returning the headers to add.
+///
+/// `path` must already be URI-encoded as sent in the request, since AWS services
+/// other than S3 expect each path segment to be encoded a second time.
+pub fn sign_aws_request(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    host: &str,
+    path: &str,
+    payload: &[u8],
+    now: SystemTime,
+) -> Vec<(&'static str, String)> {
+    let amz_date = format_amz_date(now);
+    let date = &amz_date[0..8];
+    let mut headers = vec![("host", host.to_owned()), ("x-amz-date", amz_date.clone())];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.to_owned()));
+    }
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "POST\n{}\n\n{}\n{}\n{}",
+        uri_encode(path, false),
+        canonical_headers,
+        signed_headers,
+        hex(&sha256(payload))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&sha256(canonical_request.as_bytes()))
+    );
+    let signing_key = derive_signing_key(&credentials.secret_access_key, date, region, service);
+    let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    headers.retain(|(name, _)| *name != "host"); // set by the HTTP client
+    headers.push((
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+    headers
+}
+
+fn derive_signing_key(secret: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// Percent-encodes everything except unreserved characters (and `/` unless `encode_slash`)
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Formats a timestamp as "YYYYMMDDTHHMMSSZ"
+fn format_amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // civil-from-days algorithm by Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn sha256_digests() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_digest() {
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signing_key() {
+        let key = derive_signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn amz_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(1440938160);
+        assert_eq!(format_amz_date(time), "20150830T123600Z");
+    }
+}
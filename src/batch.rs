@@ -0,0 +1,246 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api::{ApiError, Completion, Message, Role};
+
+/// A line of the batch input: either a JSON string with the prompt, or an object
+/// with the "prompt" (and optionally its own "system" prompt) or the "messages"
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InputLine {
+    Prompt(String),
+    Object {
+        id: Option<Value>,
+        prompt: Option<String>,
+        system: Option<String>,
+        messages: Option<Vec<Message>>,
+    },
+}
+
+/// A conversation to complete, identified by the input "id" or line number
+#[derive(Debug, PartialEq)]
+pub struct BatchItem {
+    pub id: Value,
+    pub messages: Vec<Message>,
+}
+
+/// A line of the batch output
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    pub fn new(id: Value, completion: Result<Completion, ApiError>) -> Self {
+        match completion {
+            Ok(completion) => Self {
+                id,
+                response: Some(completion.content),
+                finish_reason: completion.finish_reason,
+                error: None,
+            },
+            Err(error) => Self {
+                id,
+                response: None,
+                finish_reason: None,
+                error: Some(error.to_string()),
+            },
+        }
+    }
+}
+
+/// Parses JSONL input into conversations, skipping blank lines. Prompts follow
+/// the few-shot examples, if any, while full "messages" are left as they are.
+pub fn parse_batch(
+    input: &str,
+    system_prompt: Option<&str>,
+    examples: &[Message],
+) -> Result<Vec<BatchItem>> {
+    let mut items = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        let parsed: InputLine = serde_json::from_str(line)
+            .with_context(|| format!("Invalid JSON on line {}", number))?;
+        let (id, system, messages) = match parsed {
+            InputLine::Prompt(prompt) => (None, None, with_examples(examples, prompt)),
+            InputLine::Object {
+                id,
+                prompt,
+                system,
+                messages,
+            } => match (prompt, messages) {
+                (Some(prompt), None) => (id, system, with_examples(examples, prompt)),
+                (None, Some(messages)) => (id, system, messages),
+                _ => bail!(
+                    "Expected either \"prompt\" or \"messages\" on line {}",
+                    number
+                ),
+            },
+        };
+        let system = system.or(system_prompt.map(str::to_owned));
+        let has_system = messages.iter().any(|message| message.role.is_instruction());
+        let messages = match system {
+            Some(system) if !has_system => {
+                let mut all = vec![Message::new(Role::System, &system)];
+                all.extend(messages);
+                all
+            }
+            _ => messages,
+        };
+        items.push(BatchItem {
+            id: id.unwrap_or(Value::from(number)),
+            messages,
+        });
+    }
+    Ok(items)
+}
+
+/// Encodes an id as the "custom_id" string of the Batch API, so that e.g. the
+/// number 1 and the string "1" can be told apart in the results
+pub fn custom_id(id: &Value) -> String {
+    id.to_string()
+}
+
+/// A line of the results (or errors) file of a Batch API job
+#[derive(Debug, Deserialize)]
+struct OutputLine {
+    custom_id: String,
+    response: Option<OutputResponse>,
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputResponse {
+    status_code: u16,
+    body: Value,
+}
+
+/// Converts the results of a Batch API job into the same format as the output of
+/// "lmc batch", sorted by id since jobs complete out of order
+pub fn parse_batch_output(output: &str) -> Result<Vec<BatchResult>> {
+    let mut results = Vec::new();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let parsed: OutputLine = serde_json::from_str(line).context("Invalid batch output")?;
+        let id = serde_json::from_str(&parsed.custom_id).unwrap_or(Value::String(parsed.custom_id));
+        let mut result = BatchResult {
+            id,
+            response: None,
+            finish_reason: None,
+            error: parsed.error.filter(|error| !error.is_null()).map(|error| {
+                let message = error.get("message").and_then(Value::as_str);
+                message.map_or(error.to_string(), str::to_owned)
+            }),
+        };
+        if let Some(response) = parsed.response {
+            let body = &response.body;
+            if response.status_code == 200 {
+                let choice = &body["choices"][0];
+                result.response = choice["message"]["content"].as_str().map(str::to_owned);
+                result.finish_reason = choice["finish_reason"].as_str().map(str::to_owned);
+            } else if result.error.is_none() {
+                let message = body["error"]["message"].as_str().unwrap_or_default();
+                result.error = Some(format!("{}: {}", response.status_code, message));
+            }
+        }
+        results.push(result);
+    }
+    // line numbers first, in order, then any other ids
+    results.sort_by_key(|result| {
+        (
+            result.id.as_u64().is_none(),
+            result.id.as_u64(),
+            result.id.to_string(),
+        )
+    });
+    Ok(results)
+}
+
+fn with_examples(examples: &[Message], prompt: String) -> Vec<Message> {
+    let mut messages = examples.to_vec();
+    messages.push(Message::new(Role::User, &prompt));
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_batch_lines() -> Result<()> {
+        let input = "\"Hello\"\n\n{\"id\": \"b\", \"prompt\": \"Hi\", \"system\": \"Be brief.\"}\n\
+            {\"messages\": [{\"role\": \"user\", \"content\": \"Hey\"}]}\n";
+        let examples = vec![
+            Message::new(Role::User, &String::from("Hallo")),
+            Message::new(Role::Assistant, &String::from("Hello")),
+        ];
+        let items = parse_batch(input, Some("Be nice."), &examples)?;
+        let ids: Vec<&Value> = items.iter().map(|item| &item.id).collect();
+        assert_eq!(
+            ids,
+            vec![&Value::from(1), &Value::from("b"), &Value::from(4)]
+        );
+        assert_eq!(items[0].messages[0].content, "Be nice.");
+        assert_eq!(items[0].messages[1].content, "Hallo");
+        assert_eq!(items[0].messages[3].content, "Hello");
+        assert_eq!(items[1].messages[0].content, "Be brief.");
+        assert_eq!(items[2].messages.len(), 2);
+        assert_eq!(items[2].messages[1].role, Role::User);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_other_roles() -> Result<()> {
+        let input = r#"{"messages": [{"role": "developer", "content": "Be brief."}, {"role": "critic", "content": "Hmm"}, {"role": "user", "content": "Hey"}]}"#;
+        let items = parse_batch(input, Some("Be nice."), &[])?;
+        let roles: Vec<&Role> = items[0]
+            .messages
+            .iter()
+            .map(|message| &message.role)
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                &Role::Developer,
+                &Role::Other(String::from("critic")),
+                &Role::User
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&items[0].messages[1].role)?,
+            "\"critic\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parses_batch_output() -> Result<()> {
+        let output = r#"{"id": "r2", "custom_id": "\"b\"", "response": {"status_code": 400, "body": {"error": {"message": "Bad model"}}}, "error": null}
+{"id": "r1", "custom_id": "1", "response": {"status_code": 200, "body": {"choices": [{"message": {"role": "assistant", "content": "Bonjour"}, "finish_reason": "stop"}]}}, "error": null}
+"#;
+        let results = parse_batch_output(output)?;
+        assert_eq!(results[0].id, Value::from(1));
+        assert_eq!(results[0].response.as_deref(), Some("Bonjour"));
+        assert_eq!(results[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(results[1].id, Value::from("b"));
+        assert_eq!(results[1].error.as_deref(), Some("400: Bad model"));
+        assert_eq!(custom_id(&Value::from("b")), "\"b\"");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(parse_batch("not json", None, &[]).is_err());
+        assert!(parse_batch("{\"id\": 1}", None, &[]).is_err());
+        assert!(parse_batch("{\"prompt\": \"a\", \"messages\": []}", None, &[]).is_err());
+    }
+}
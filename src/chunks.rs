@@ -0,0 +1,79 @@
+/// Estimates the number of tokens in a text, assuming about four characters per
+/// token as is typical for English. Good enough to tell if some input will fit.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Splits text into chunks of at most `max_tokens` (estimated), preferably between
+/// paragraphs, then between lines or words, and only mid-word as a last resort
+pub fn split_into_chunks(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    add_piece(
+        &mut chunks,
+        text,
+        max_tokens.max(1) * 4,
+        &["\n\n", "\n", " "],
+    );
+    chunks.into_iter().map(|(chunk, _)| chunk).collect()
+}
+
+fn add_piece(
+    chunks: &mut Vec<(String, usize)>,
+    piece: &str,
+    max_chars: usize,
+    separators: &[&str],
+) {
+    let length = piece.chars().count();
+    if length <= max_chars {
+        match chunks.last_mut() {
+            Some((chunk, chunk_length)) if *chunk_length + length <= max_chars => {
+                chunk.push_str(piece);
+                *chunk_length += length;
+            }
+            _ => chunks.push((piece.to_owned(), length)),
+        }
+    } else if let Some((separator, others)) = separators.split_first() {
+        for part in piece.split_inclusive(separator) {
+            add_piece(chunks, part, max_chars, others);
+        }
+    } else {
+        let chars: Vec<char> = piece.chars().collect();
+        for part in chars.chunks(max_chars) {
+            chunks.push((part.iter().collect(), part.len()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("Hello"), 2);
+        assert_eq!(estimate_tokens("Hello, world"), 3);
+    }
+
+    #[test]
+    fn splits_at_paragraphs_then_lines() {
+        let text = "First paragraph.\n\nSecond one,\nwith two lines.\n\nThird.";
+        assert_eq!(
+            split_into_chunks(text, 5),
+            vec![
+                "First paragraph.\n\n",
+                "Second one,\n",
+                "with two lines.\n\n",
+                "Third."
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_long_words() {
+        assert_eq!(
+            split_into_chunks("abcdefghij klm", 1),
+            vec!["abcd", "efgh", "ij ", "klm"]
+        );
+    }
+}
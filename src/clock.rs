@@ -0,0 +1,127 @@
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::session::format_timestamp;
+
+/// Names of the days of the week, starting from that of the Unix epoch
+const WEEKDAYS: [&str; 7] = [
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+];
+
+/// Tells the model the local date and time, and the user's locale if known, as
+/// added to the system prompt with "inject_datetime"
+pub fn datetime_context() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (offset, zone) = local_offset(now);
+    describe_datetime(now, offset, zone.as_deref(), locale().as_deref())
+}
+
+fn describe_datetime(now: u64, offset: i64, zone: Option<&str>, locale: Option<&str>) -> String {
+    let local = now.saturating_add_signed(offset);
+    let weekday = WEEKDAYS[(local / 86400 % 7) as usize];
+    let mut timezone = if offset == 0 {
+        String::from("UTC")
+    } else {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let minutes = offset.unsigned_abs() / 60;
+        format!("UTC{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    };
+    if let Some(zone) = zone.filter(|zone| *zone != "UTC") {
+        timezone = format!("{}, {}", timezone, zone);
+    }
+    let mut context = format!(
+        "The current date and time is {}, {} ({}).",
+        weekday,
+        format_timestamp(local),
+        timezone
+    );
+    if let Some(locale) = locale {
+        context = format!("{} The user's locale is {}.", context, locale);
+    }
+    context
+}
+
+/// The offset of local time from UTC, in seconds, and the abbreviation of the
+/// time zone if known
+#[cfg(unix)]
+fn local_offset(now: u64) -> (i64, Option<String>) {
+    let time = now as libc::time_t;
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    if unsafe { libc::localtime_r(&time, tm.as_mut_ptr()) }.is_null() {
+        return (0, None);
+    }
+    let tm = unsafe { tm.assume_init() };
+    let zone = (!tm.tm_zone.is_null()).then(|| {
+        let zone = unsafe { std::ffi::CStr::from_ptr(tm.tm_zone) };
+        zone.to_string_lossy().into_owned()
+    });
+    // tm_gmtoff is a c_long, only 32 bits on some platforms
+    #[allow(clippy::useless_conversion)]
+    let offset = i64::from(tm.tm_gmtoff);
+    (offset, zone)
+}
+
+#[cfg(windows)]
+fn local_offset(_now: u64) -> (i64, Option<String>) {
+    use windows_sys::Win32::System::Time::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+    const TIME_ZONE_ID_INVALID: u32 = u32::MAX;
+    let mut info: TIME_ZONE_INFORMATION = unsafe { std::mem::zeroed() };
+    // the bias is in minutes, from local time to UTC
+    let bias = match unsafe { GetTimeZoneInformation(&mut info) } {
+        TIME_ZONE_ID_INVALID => return (0, None),
+        TIME_ZONE_ID_DAYLIGHT => info.Bias + info.DaylightBias,
+        _ => info.Bias + info.StandardBias,
+    };
+    (-i64::from(bias) * 60, None)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn local_offset(_now: u64) -> (i64, Option<String>) {
+    (0, None)
+}
+
+/// The locale from the environment, e.g. "en_GB" for "en_GB.UTF-8"
+fn locale() -> Option<String> {
+    ["LC_ALL", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .map(|value| {
+            let end = value.find(['.', '@']).unwrap_or(value.len());
+            value[..end].to_owned()
+        })
+        .filter(|locale| locale != "C" && locale != "POSIX")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_local_time() {
+        // 2024-03-01 23:30 UTC
+        let now = 1709335800;
+        assert_eq!(
+            describe_datetime(now, 0, None, None),
+            "The current date and time is Friday, 2024-03-01 23:30 (UTC)."
+        );
+        assert_eq!(
+            describe_datetime(now, 3600, Some("CET"), Some("it_IT")),
+            "The current date and time is Saturday, 2024-03-02 00:30 (UTC+01:00, CET). \
+            The user's locale is it_IT."
+        );
+        assert_eq!(
+            describe_datetime(now, -9000, None, None),
+            "The current date and time is Friday, 2024-03-01 21:00 (UTC-02:30)."
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// The shells "lmc completions" can generate a script for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            _ => Err(format!("Unsupported shell: \"{}\"", value)),
+        }
+    }
+}
+
+/// An option of a command, e.g. "-p, --profile"
+#[derive(Debug, PartialEq)]
+pub struct Flag {
+    pub short: Option<char>,
+    pub long: String,
+    pub description: String,
+}
+
+/// A command with its options and subcommands, as listed by its help text
+#[derive(Debug, PartialEq)]
+pub struct CommandSpec {
+    pub name: String,
+    pub description: String,
+    pub flags: Vec<Flag>,
+    pub commands: Vec<CommandSpec>,
+}
+
+/// Options whose values are profile names
+const PROFILE_FLAGS: &[&str] = &["--profile"];
+
+/// Describes a command and all its subcommands from the "--help" output that
+/// argh generates for each, given the command path, e.g. ["batch", "status"]
+pub fn describe_command(help: &dyn Fn(&[&str]) -> String) -> CommandSpec {
+    describe(help, &[], "lmc", "")
+}
+
+fn describe(
+    help: &dyn Fn(&[&str]) -> String,
+    path: &[&str],
+    name: &str,
+    description: &str,
+) -> CommandSpec {
+    let (flags, names) = parse_help(&help(path));
+    let commands = names
+        .iter()
+        .map(|(name, description)| {
+            let mut sub_path = path.to_vec();
+            sub_path.push(name);
+            describe(help, &sub_path, name, description)
+        })
+        .collect();
+    CommandSpec {
+        name: name.to_owned(),
+        description: description.to_owned(),
+        flags,
+        commands,
+    }
+}
+
+/// Finds the options and subcommands (with their descriptions) in a help text
+fn parse_help(help: &str) -> (Vec<Flag>, Vec<(String, String)>) {
+    let mut flags = Vec::new();
+    let mut commands = Vec::new();
+    let mut section = "";
+    for line in help.lines() {
+        if !line.starts_with(' ') {
+            section = line.trim_end_matches(':');
+            continue;
+        }
+        // continuation lines of long descriptions are indented further
+        if line.starts_with("   ") {
+            continue;
+        }
+        let line = line.trim();
+        let (names, description) = match line.split_once("  ") {
+            Some((names, description)) => (names, description.trim()),
+            None => (line, ""),
+        };
+        match section {
+            "Options" => {
+                let mut flag = Flag {
+                    short: None,
+                    long: String::new(),
+                    description: description.to_owned(),
+                };
+                for name in names.split(", ") {
+                    if let Some(long) = name.strip_prefix("--") {
+                        flag.long = long.to_owned();
+                    } else if let Some(short) = name.strip_prefix('-') {
+                        flag.short = short.chars().next();
+                    }
+                }
+                if !flag.long.is_empty() {
+                    flags.push(flag);
+                }
+            }
+            "Commands" => commands.push((names.to_owned(), description.to_owned())),
+            _ => {}
+        }
+    }
+    (flags, commands)
+}
+
+/// Generates the completion script for a shell, completing "--profile" with
+/// the names of the profiles configured now
+pub fn completion_script(shell: Shell, command: &CommandSpec, profiles: &[String]) -> String {
+    match shell {
+        Shell::Bash => bash_script(command, profiles),
+        // zsh can run bash completion functions
+        Shell::Zsh => format!(
+            "autoload -U +X compinit && compinit\nautoload -U +X bashcompinit && bashcompinit\n{}",
+            bash_script(command, profiles)
+        ),
+        Shell::Fish => fish_script(command, profiles),
+    }
+}
+
+fn bash_script(command: &CommandSpec, profiles: &[String]) -> String {
+    let mut transitions = String::new();
+    let mut words = String::new();
+    add_bash_cases(command, "", &mut transitions, &mut words);
+    let profile_flags = PROFILE_FLAGS
+        .iter()
+        .flat_map(|long| {
+            let short = find_short(command, long.trim_start_matches("--"));
+            [Some(long.to_string()), short.map(|c| format!("-{}", c))]
+        })
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("|");
+    let mut script = String::new();
+    let _ = write!(
+        script,
+        r#"_lmc() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+        {profile_flags})
+            COMPREPLY=($(compgen -W "{profiles}" -- "$cur"))
+            return
+            ;;
+    esac
+    local path="" word words
+    for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        case "$path:$word" in
+{transitions}        esac
+    done
+    case "$path" in
+{words}    esac
+    COMPREPLY=($(compgen -W "$words" -- "$cur"))
+}}
+complete -o default -F _lmc {name}
+"#,
+        profile_flags = profile_flags,
+        profiles = profiles.join(" "),
+        transitions = transitions,
+        words = words,
+        name = command.name,
+    );
+    script
+}
+
+fn add_bash_cases(command: &CommandSpec, path: &str, transitions: &mut String, words: &mut String) {
+    let mut list: Vec<String> = command
+        .flags
+        .iter()
+        .flat_map(|flag| {
+            let short = flag.short.map(|c| format!("-{}", c));
+            [short, Some(format!("--{}", flag.long))]
+        })
+        .flatten()
+        .collect();
+    list.extend(command.commands.iter().map(|sub| sub.name.to_owned()));
+    let _ = writeln!(
+        words,
+        "        \"{}\") words=\"{}\" ;;",
+        path,
+        list.join(" ")
+    );
+    for sub in &command.commands {
+        let sub_path = match path {
+            "" => sub.name.to_owned(),
+            _ => format!("{} {}", path, sub.name),
+        };
+        let _ = writeln!(
+            transitions,
+            "            \"{}:{}\") path=\"{}\" ;;",
+            path, sub.name, sub_path
+        );
+        add_bash_cases(sub, &sub_path, transitions, words);
+    }
+}
+
+fn fish_script(command: &CommandSpec, profiles: &[String]) -> String {
+    let mut script = String::new();
+    add_fish_lines(command, &command.name, false, profiles, &mut script);
+    script
+}
+
+fn add_fish_lines(
+    command: &CommandSpec,
+    program: &str,
+    nested: bool,
+    profiles: &[String],
+    script: &mut String,
+) {
+    let condition = if nested {
+        format!("__fish_seen_subcommand_from {}", command.name)
+    } else {
+        String::from("__fish_use_subcommand")
+    };
+    for flag in &command.flags {
+        let _ = write!(script, "complete -c {} -n '{}'", program, condition);
+        if let Some(short) = flag.short {
+            let _ = write!(script, " -s {}", short);
+        }
+        let _ = write!(script, " -l {}", flag.long);
+        if PROFILE_FLAGS.contains(&format!("--{}", flag.long).as_str()) {
+            let _ = write!(script, " -x -a '{}'", profiles.join(" "));
+        }
+        let _ = writeln!(script, " -d '{}'", fish_escape(&flag.description));
+    }
+    let names: Vec<&str> = command
+        .commands
+        .iter()
+        .map(|sub| sub.name.as_str())
+        .collect();
+    for sub in &command.commands {
+        // subcommands are only offered until one of them has been typed
+        let condition = if nested {
+            format!(
+                "{}; and not __fish_seen_subcommand_from {}",
+                condition,
+                names.join(" ")
+            )
+        } else {
+            condition.to_owned()
+        };
+        let _ = writeln!(
+            script,
+            "complete -c {} -n '{}' -f -a {} -d '{}'",
+            program,
+            condition,
+            sub.name,
+            fish_escape(&sub.description)
+        );
+        add_fish_lines(sub, program, true, profiles, script);
+    }
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn find_short(command: &CommandSpec, long: &str) -> Option<char> {
+    command
+        .flags
+        .iter()
+        .find(|flag| flag.long == long)
+        .and_then(|flag| flag.short)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELP: &str = "Usage: lmc [-p <profile>] [--no-stream] [<command>] [<args>]
+
+Chat with language models.
+
+Options:
+  -p, --profile     configuration profile; default: \"default\"
+  --no-stream       disable response streaming, with a description that is long
+                    enough to wrap
+  --help, help      display usage information
+
+Commands:
+  batch             Run one completion per line of a JSONL file
+  init              Create a starter configuration file
+";
+
+    const BATCH_HELP: &str = "Usage: lmc batch [--output <output>]
+
+Run one completion per line of a JSONL file
+
+Options:
+  --output          JSONL file to write the results to
+  --help, help      display usage information
+";
+
+    fn help(path: &[&str]) -> String {
+        match path {
+            [] => HELP.to_owned(),
+            ["batch"] => BATCH_HELP.to_owned(),
+            _ => String::from(
+                "Usage: lmc init\n\nOptions:\n  --help, help      display usage information\n",
+            ),
+        }
+    }
+
+    #[test]
+    fn describes_commands() {
+        let command = describe_command(&help);
+        assert_eq!(command.name, "lmc");
+        assert_eq!(
+            command.flags[0],
+            Flag {
+                short: Some('p'),
+                long: String::from("profile"),
+                description: String::from("configuration profile; default: \"default\""),
+            }
+        );
+        let longs: Vec<&str> = command
+            .flags
+            .iter()
+            .map(|flag| flag.long.as_str())
+            .collect();
+        assert_eq!(longs, vec!["profile", "no-stream", "help"]);
+        let names: Vec<&str> = command
+            .commands
+            .iter()
+            .map(|sub| sub.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["batch", "init"]);
+        assert_eq!(command.commands[0].flags[0].long, "output");
+    }
+
+    #[test]
+    fn generates_scripts() {
+        let command = describe_command(&help);
+        let profiles = vec![String::from("default"), String::from("groq")];
+        let bash = completion_script(Shell::Bash, &command, &profiles);
+        assert!(
+            bash.contains("--profile|-p)\n            COMPREPLY=($(compgen -W \"default groq\"")
+        );
+        assert!(bash.contains("\":batch\") path=\"batch\" ;;"));
+        assert!(bash.contains("\"batch\") words=\"--output --help\" ;;"));
+        assert!(bash.contains("\"\") words=\"-p --profile --no-stream --help batch init\" ;;"));
+        let fish = completion_script(Shell::Fish, &command, &profiles);
+        assert!(fish.contains(
+            "complete -c lmc -n '__fish_use_subcommand' -s p -l profile -x -a 'default groq'"
+        ));
+        assert!(fish.contains("complete -c lmc -n '__fish_seen_subcommand_from batch' -l output"));
+    }
+}
@@ -1,12 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use argh::FromArgs;
 use home::home_dir;
 use serde::Deserialize;
 
+use crate::api::{Message, Role};
+use crate::auth::get_stored_api_key;
+use crate::completions::Shell;
+use crate::import::ImportFormat;
+use crate::summary::SummaryLength;
+use crate::terminal::ColorMode;
+use crate::theme::Theme;
+
 /// LMC - Large Model Client: interact with LLM APIs from the command line
 #[derive(Debug, FromArgs)]
 pub struct Args {
@@ -26,11 +36,60 @@ pub struct Args {
     #[argh(option, short = 's')]
     pub system_prompt: Option<String>,
 
+    /// file with the initial instructions for the assistant
+    #[argh(option)]
+    pub system_file: Option<String>,
+
     /// parameter passed directly to the API
     #[argh(option, short = 't')]
     pub temperature: Option<f32>,
 
-    /// configuration file; default: "$HOME/.lmc/config.toml"
+    /// random seed, for reproducible responses where supported by the API
+    #[argh(option)]
+    pub seed: Option<u64>,
+
+    /// GBNF grammar file to constrain the response to, for llama.cpp servers
+    #[argh(option)]
+    pub grammar_file: Option<String>,
+
+    /// JSON schema file that the response must conform to
+    #[argh(option)]
+    pub json_schema_file: Option<String>,
+
+    /// HTTP(S) proxy URL, e.g. "http://proxy.example.com:3128"
+    #[argh(option)]
+    pub proxy: Option<String>,
+
+    /// additional CA certificate file (PEM) to trust
+    #[argh(option)]
+    pub ca_cert: Option<String>,
+
+    /// disable TLS certificate validation, e.g. for self-signed certificates
+    #[argh(switch)]
+    pub danger_accept_invalid_certs: Option<bool>,
+
+    /// file or named pipe to write streamed tokens to, as JSON lines
+    #[argh(option)]
+    pub emit_tokens: Option<String>,
+
+    /// file to log every streamed delta to, with its time, as JSON lines
+    #[argh(option)]
+    pub log_stream: Option<String>,
+
+    /// file to write the responses to, as plain text, as well as printing them
+    #[argh(option, short = 'o')]
+    pub output: Option<String>,
+
+    /// command to pipe each response through to display it, e.g. "glow -", while
+    /// the original is kept in the history
+    #[argh(option)]
+    pub post: Option<String>,
+
+    /// enable an experimental feature; can be repeated
+    #[argh(option)]
+    pub experimental: Vec<String>,
+
+    /// configuration file; default: "$LMC_CONFIG", or "$HOME/.lmc/config.toml"
     #[argh(option, short = 'c')]
     pub config: Option<String>,
 
@@ -42,84 +101,1497 @@ pub struct Args {
     #[argh(switch)]
     pub no_stream: Option<bool>,
 
+    /// highlight Markdown in responses
+    #[argh(switch)]
+    pub highlight: Option<bool>,
+
+    /// print the response time and speed in tokens per second
+    #[argh(switch)]
+    pub show_stats: Option<bool>,
+
+    /// print token usage after each response, if reported by the API
+    #[argh(switch)]
+    pub show_usage: Option<bool>,
+
+    /// send a desktop notification when a response takes longer than notify_after
+    #[argh(switch)]
+    pub notify: Option<bool>,
+
+    /// offer to write the file changes in each response to disk
+    #[argh(switch, hidden_help)]
+    pub apply: bool,
+
+    /// append the text in the clipboard to the (first) prompt
+    #[argh(switch, hidden_help)]
+    pub clipboard: bool,
+
+    /// append a file to the (first) prompt, as text even if PDF or DOCX; can be repeated
+    #[argh(option, short = 'f')]
+    pub file: Vec<String>,
+
+    /// read each response aloud
+    #[argh(switch, hidden_help)]
+    pub speak: bool,
+
+    /// save the spoken response to this (mp3) file instead of playing it
+    #[argh(option, hidden_help)]
+    pub speech_file: Option<String>,
+
+    /// start of the response, e.g. "```json" to get JSON in a code block
+    #[argh(option)]
+    pub prefill: Option<String>,
+
+    /// pick a saved session to continue, from a list
+    #[argh(switch, hidden_help)]
+    pub resume: bool,
+
+    /// name of a session to continue and save to, created if missing, so that
+    /// separate runs with piped prompts share the conversation
+    #[argh(option)]
+    pub session: Option<String>,
+
+    /// reject piped input estimated to be longer than this
+    #[argh(option)]
+    pub max_input_tokens: Option<usize>,
+
+    /// process input over max_input_tokens in parts: "concat" or "merge"
+    #[argh(option, hidden_help)]
+    pub chunk: Option<ChunkMode>,
+
+    /// send each line of piped input as a separate prompt, printing one line each
+    #[argh(switch, hidden_help)]
+    pub map: bool,
+
+    /// keep reading piped input, e.g. from "tail -f", sending each new chunk of it
+    /// as the next prompt in the same conversation
+    #[argh(switch, hidden_help)]
+    pub follow: bool,
+
+    /// with --follow, a line that ends each chunk of input
+    #[argh(option, hidden_help)]
+    pub follow_separator: Option<String>,
+
+    /// with --follow and no separator, the seconds without new input that end a
+    /// chunk; default: 2
+    #[argh(option, hidden_help)]
+    pub follow_timeout: Option<f64>,
+
+    /// add the files in this directory to the context, after a tree overview
+    #[argh(option)]
+    pub dir: Option<String>,
+
+    /// only add the files matching this glob with --dir, e.g. "*.rs"; can be repeated
+    #[argh(option)]
+    pub include: Vec<String>,
+
+    /// token budget for --dir, truncating or leaving out files to fit; default: 32000
+    #[argh(option)]
+    pub dir_tokens: Option<usize>,
+
+    /// print the request that would be sent, without sending it
+    #[argh(switch)]
+    pub dry_run: bool,
+
+    /// show how likely each token of the response was, and this many alternatives
+    #[argh(option)]
+    pub logprobs: Option<u8>,
+
     /// display the version
     #[argh(switch, short = 'v', long = "version")]
     pub print_version: bool,
+
+    /// when to use colours: "auto", "always" or "never"; default: "auto"
+    #[argh(option, default = "ColorMode::Auto")]
+    pub color: ColorMode,
+
+    /// display the help of all commands, interactive commands and settings
+    #[argh(switch)]
+    pub help_long: bool,
+
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Audit(AuditCommand),
+    Auth(AuthCommand),
+    Batch(BatchCommand),
+    Chat(ChatCommand),
+    Commit(CommitCommand),
+    Completions(CompletionsCommand),
+    Config(ConfigCommand),
+    Extract(ExtractCommand),
+    History(HistoryCommand),
+    Import(ImportCommand),
+    Init(InitCommand),
+    Models(ModelsCommand),
+    Ping(PingCommand),
+    Proofread(ProofreadCommand),
+    Proxy(ProxyCommand),
+    Review(ReviewCommand),
+    Run(RunCommand),
+    Serve(ServeCommand),
+    Summarize(SummarizeCommand),
+    Sweep(SweepCommand),
+    Tokens(TokensCommand),
+    Transcribe(TranscribeCommand),
+    Translate(TranslateCommand),
+    Warmup(WarmupCommand),
+}
+
+/// Chat interactively, the default when no command is given in a terminal
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "chat")]
+pub struct ChatCommand {
+    /// pick a saved session to continue, from a list
+    #[argh(switch)]
+    pub resume: bool,
+
+    /// offer to write the file changes in each response to disk
+    #[argh(switch)]
+    pub apply: bool,
+
+    /// append the text in the clipboard to the first prompt
+    #[argh(switch)]
+    pub clipboard: bool,
+
+    /// read each response aloud
+    #[argh(switch)]
+    pub speak: bool,
+
+    /// save the spoken response to this (mp3) file instead of playing it
+    #[argh(option)]
+    pub speech_file: Option<String>,
+}
+
+/// Send a single prompt and print the response, the default when stdin is piped
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "run")]
+pub struct RunCommand {
+    /// offer to write the file changes in the response to disk
+    #[argh(switch)]
+    pub apply: bool,
+
+    /// append the text in the clipboard to the prompt
+    #[argh(switch)]
+    pub clipboard: bool,
+
+    /// read the response aloud
+    #[argh(switch)]
+    pub speak: bool,
+
+    /// save the spoken response to this (mp3) file instead of playing it
+    #[argh(option)]
+    pub speech_file: Option<String>,
+
+    /// process input over max_input_tokens in parts: "concat" or "merge"
+    #[argh(option)]
+    pub chunk: Option<ChunkMode>,
+
+    /// send each line of piped input as a separate prompt, printing one line each
+    #[argh(switch)]
+    pub map: bool,
+
+    /// keep reading piped input, e.g. from "tail -f", sending each new chunk of it
+    /// as the next prompt in the same conversation
+    #[argh(switch)]
+    pub follow: bool,
+
+    /// with --follow, a line that ends each chunk of input
+    #[argh(option)]
+    pub follow_separator: Option<String>,
+
+    /// with --follow and no separator, the seconds without new input that end a
+    /// chunk; default: 2
+    #[argh(option)]
+    pub follow_timeout: Option<f64>,
+
+    /// the prompt, followed by any piped input
+    #[argh(positional, greedy)]
+    pub prompt: Vec<String>,
+}
+
+impl Args {
+    /// Takes the options given to "chat" or "run" as if given before the command,
+    /// where they're still accepted, though no longer listed, for compatibility
+    pub fn merge_command_options(&mut self) {
+        match &self.command {
+            Some(Command::Chat(chat)) => {
+                self.resume |= chat.resume;
+                self.apply |= chat.apply;
+                self.clipboard |= chat.clipboard;
+                self.speak |= chat.speak;
+                if chat.speech_file.is_some() {
+                    self.speech_file = chat.speech_file.to_owned();
+                }
+            }
+            Some(Command::Run(run)) => {
+                self.apply |= run.apply;
+                self.clipboard |= run.clipboard;
+                self.speak |= run.speak;
+                self.map |= run.map;
+                self.follow |= run.follow;
+                if run.speech_file.is_some() {
+                    self.speech_file = run.speech_file.to_owned();
+                }
+                if run.chunk.is_some() {
+                    self.chunk = run.chunk;
+                }
+                if run.follow_separator.is_some() {
+                    self.follow_separator = run.follow_separator.to_owned();
+                }
+                if run.follow_timeout.is_some() {
+                    self.follow_timeout = run.follow_timeout;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// List the models available from the API of the selected profile
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "models")]
+pub struct ModelsCommand {}
+
+/// Count the tokens of a text, given as arguments or piped in, with the model's
+/// tokenizer
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "tokens")]
+pub struct TokensCommand {
+    /// the text, instead of piped input
+    #[argh(positional, greedy)]
+    pub text: Vec<String>,
+}
+
+/// Load the model into memory, so that a local server answers the first prompt
+/// without delay
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "warmup")]
+pub struct WarmupCommand {}
+
+/// Check that the API of each profile, or just the one given with -p, can be
+/// reached with its credentials, and how long it takes to answer
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "ping")]
+pub struct PingCommand {}
+
+/// Run one completion per line of a JSONL file, several at a time
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "batch")]
+pub struct BatchCommand {
+    /// JSONL file with a prompt string, or an object with "prompt" or "messages", per
+    /// line
+    #[argh(option)]
+    pub input: Option<String>,
+
+    /// JSONL file to write the results to; default: stdout
+    #[argh(option)]
+    pub output: Option<String>,
+
+    /// number of requests to run in parallel; default: 4
+    #[argh(option, default = "4")]
+    pub concurrency: usize,
+
+    #[argh(subcommand)]
+    pub action: Option<BatchAction>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum BatchAction {
+    Submit(BatchSubmit),
+    Status(BatchStatus),
+    Fetch(BatchFetch),
+}
+
+/// Submit a JSONL file to the provider's Batch API, to be processed within 24 hours
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "submit")]
+pub struct BatchSubmit {
+    /// JSONL file in the same format as "--input"
+    #[argh(positional)]
+    pub input: String,
+}
+
+/// Show the progress of a Batch API job
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "status")]
+pub struct BatchStatus {
+    /// the job id printed by "lmc batch submit"
+    #[argh(positional)]
+    pub id: String,
+}
+
+/// Download the results of a completed Batch API job
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "fetch")]
+pub struct BatchFetch {
+    /// JSONL file to write the results to; default: stdout
+    #[argh(option)]
+    pub output: Option<String>,
+
+    /// the job id printed by "lmc batch submit"
+    #[argh(positional)]
+    pub id: String,
+}
+
+/// Write a commit message for the staged changes ("git diff --cached")
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "commit")]
+pub struct CommitCommand {
+    /// create the commit with the generated message
+    #[argh(switch)]
+    pub commit: bool,
+}
+
+/// Extract data from a text as JSON matching a schema, e.g.
+/// "lmc extract --schema person.json < email.txt"
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "extract")]
+pub struct ExtractCommand {
+    /// JSON schema file describing the data to extract
+    #[argh(option)]
+    pub schema: String,
+
+    /// how many times to ask again after an invalid response; default: 2
+    #[argh(option, default = "2")]
+    pub retries: usize,
+
+    /// the text, instead of piped input
+    #[argh(positional, greedy)]
+    pub text: Vec<String>,
+}
+
+/// Correct the spelling and grammar of a text, given as arguments or piped in,
+/// showing what changed
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "proofread")]
+pub struct ProofreadCommand {
+    /// print the corrected text instead of the changes
+    #[argh(switch)]
+    pub text_only: bool,
+
+    /// the text, instead of piped input
+    #[argh(positional, greedy)]
+    pub text: Vec<String>,
+}
+
+/// Serve an OpenAI-compatible "/v1/chat/completions" endpoint that forwards
+/// requests to the selected profile, for other tools to use
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "proxy")]
+pub struct ProxyCommand {
+    /// port to listen on; default: 8080
+    #[argh(option, default = "8080")]
+    pub port: u16,
+
+    /// address to listen on; default: "127.0.0.1", only reachable from this
+    /// machine
+    #[argh(option, default = "String::from(\"127.0.0.1\")")]
+    pub host: String,
+
+    /// API key the clients must send; default: a random one, printed at startup
+    #[argh(option)]
+    pub token: Option<String>,
+}
+
+/// Answer prompts sent by editors and scripts to a local socket, one line of JSON
+/// each, keeping the conversations and the connection to the API open
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "serve")]
+pub struct ServeCommand {
+    /// path of the Unix socket to listen on; default: "~/.lmc/lmc.sock"
+    #[argh(option)]
+    pub socket: Option<String>,
+}
+
+/// Review the changes in a unified diff, piped in or else from "git diff", and
+/// list the problems found in each file
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "review")]
+pub struct ReviewCommand {
+    /// the commits or range to pass to "git diff", e.g. "main..."; default: "HEAD",
+    /// for all uncommitted changes
+    #[argh(positional, greedy)]
+    pub range: Vec<String>,
+}
+
+/// Summarise a text, given as arguments or piped in, in parts if it's too long
+/// to send at once
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "summarize")]
+pub struct SummarizeCommand {
+    /// length of the summary: "short" (the default), "bullets" or "tl;dr"
+    #[argh(option, default = "SummaryLength::Short")]
+    pub length: SummaryLength,
+
+    /// the text, instead of piped input
+    #[argh(positional, greedy)]
+    pub text: Vec<String>,
+}
+
+/// Translate a text, given as arguments or piped in, e.g.
+/// "lmc translate --to fr < README.md"
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "translate")]
+pub struct TranslateCommand {
+    /// the language to translate into, by name or code, e.g. "fr"
+    #[argh(option)]
+    pub to: String,
+
+    /// the language of the text; default: "auto", to detect it
+    #[argh(option, default = "String::from(\"auto\")")]
+    pub from: String,
+
+    /// the text, instead of piped input
+    #[argh(positional, greedy)]
+    pub text: Vec<String>,
+}
+
+/// Transcribe an audio file, e.g. "lmc transcribe memo.mp3"
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "transcribe")]
+pub struct TranscribeCommand {
+    /// send the transcript as the prompt instead of printing it
+    #[argh(switch)]
+    pub send: bool,
+
+    /// the audio file (wav, mp3, m4a, ...)
+    #[argh(positional)]
+    pub file: PathBuf,
+}
+
+/// Inspect the configuration file
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "config")]
+pub struct ConfigCommand {
+    #[argh(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum ConfigAction {
+    Check(ConfigCheck),
+}
+
+/// Resolve every profile and report any errors
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "check")]
+pub struct ConfigCheck {
+    /// also connect to the API of each valid profile
+    #[argh(switch)]
+    pub ping: bool,
+}
+
+/// Print a shell completion script, e.g. "source <(lmc completions bash)"
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "completions")]
+pub struct CompletionsCommand {
+    /// the shell: "bash", "zsh" or "fish"
+    #[argh(positional)]
+    pub shell: Shell,
+}
+
+/// Create a starter configuration file by answering a few questions
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "init")]
+pub struct InitCommand {
+    /// overwrite the configuration file if it exists
+    #[argh(switch)]
+    pub force: bool,
+}
+
+/// Run the same prompt at several temperatures (and seeds) to compare the responses
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "sweep")]
+pub struct SweepCommand {
+    /// comma-separated temperatures, e.g. "0,0.5,1.0"
+    #[argh(option)]
+    pub temps: String,
+
+    /// comma-separated seeds to try at each temperature
+    #[argh(option)]
+    pub seeds: Option<String>,
+
+    /// the prompt; read from stdin if omitted
+    #[argh(positional)]
+    pub prompt: Option<String>,
+}
+
+/// Manage API keys stored in the OS keyring
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "auth")]
+pub struct AuthCommand {
+    #[argh(subcommand)]
+    pub action: AuthAction,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum AuthAction {
+    Set(AuthSet),
+    Remove(AuthRemove),
+}
+
+/// Store the API key for a profile, read from the terminal or stdin
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "set")]
+pub struct AuthSet {
+    /// profile name
+    #[argh(positional)]
+    pub profile: String,
+}
+
+/// Remove the stored API key for a profile
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "remove")]
+pub struct AuthRemove {
+    /// profile name
+    #[argh(positional)]
+    pub profile: String,
+}
+
+/// Show the most recent requests recorded in the audit log, oldest first
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "audit")]
+pub struct AuditCommand {
+    /// how many requests to show; default: 20
+    #[argh(option, default = "20")]
+    pub limit: usize,
+}
+
+/// Browse saved sessions
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "history")]
+pub struct HistoryCommand {
+    #[argh(subcommand)]
+    pub action: HistoryAction,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum HistoryAction {
+    List(HistoryList),
+    Search(HistorySearch),
+    Show(HistoryShow),
+}
+
+/// List saved sessions, most recent first
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "list")]
+pub struct HistoryList {}
+
+/// Find messages containing some text in all saved sessions
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "search")]
+pub struct HistorySearch {
+    /// text to look for, ignoring case
+    #[argh(positional, greedy)]
+    pub query: Vec<String>,
+}
+
+/// Print a saved conversation, e.g. "lmc history show rust-borrow-checker"
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "show")]
+pub struct HistoryShow {
+    /// the branch to print; default: the one the session was saved on
+    #[argh(option)]
+    pub branch: Option<String>,
+
+    /// show it in a pager, "$PAGER" or "less -R"
+    #[argh(switch)]
+    pub page: bool,
+
+    /// the name of the session
+    #[argh(positional)]
+    pub name: String,
+}
+
+/// Save conversations from another tool as sessions, to continue them with
+/// "lmc chat --resume"
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "import")]
+pub struct ImportCommand {
+    /// format of the file: "chatgpt" (conversations.json), "messages" (JSON) or
+    /// "markdown"; default: detected from the contents
+    #[argh(option)]
+    pub format: Option<ImportFormat>,
+
+    /// name of the session; default: derived from the title
+    #[argh(option)]
+    pub name: Option<String>,
+
+    /// the file to import
+    #[argh(positional)]
+    pub file: String,
+}
+
+/// How to handle piped input that is too long to send at once
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum ChunkMode {
+    /// print the response for each part in turn
+    Concat,
+    /// combine the responses for each part with a final request
+    Merge,
+}
+
+impl FromStr for ChunkMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "concat" => Ok(Self::Concat),
+            "merge" => Ok(Self::Merge),
+            _ => Err(format!("Invalid chunk mode: \"{}\"", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    OpenAi,
+    Azure,
+    Bedrock,
+}
+
+/// What the audit log records of each prompt
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditMode {
+    /// a SHA-256 hash, to tell whether some text was sent without keeping it
+    Hash,
+    /// the whole text
+    Full,
+}
+
+/// The encoding used to count tokens, as named by tiktoken
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum Tokenizer {
+    /// GPT-4o and later
+    #[serde(rename = "o200k_base")]
+    O200k,
+    /// GPT-4 and GPT-3.5
+    #[serde(rename = "cl100k_base")]
+    Cl100k,
+    /// Codex and text-davinci models
+    #[serde(rename = "p50k_base")]
+    P50k,
+    /// GPT-3
+    #[serde(rename = "r50k_base")]
+    R50k,
+}
+
+/// Which OpenAI endpoint to send prompts to: "/chat/completions", or the newer
+/// "/responses" that some models are only available from
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Api {
+    #[default]
+    Chat,
+    Responses,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Config {
+    pub api: Api,
     pub api_url: String,
+    pub apply: bool,
     pub api_key: Option<String>,
+    pub api_version: Option<String>,
+    pub audit: Option<AuditMode>,
+    pub auto_pager: bool,
+    pub budget_cost: Option<f64>,
+    pub budget_tokens: Option<usize>,
+    pub builtin_tools: Vec<String>,
+    pub ca_cert: Option<String>,
+    pub chunk: Option<ChunkMode>,
+    pub clipboard: bool,
+    pub confirm_duplicates: bool,
+    pub danger_accept_invalid_certs: bool,
+    pub deployment: Option<String>,
+    pub dir: Option<String>,
+    pub dir_tokens: Option<usize>,
+    pub dry_run: bool,
+    pub emit_tokens: Option<String>,
+    pub experimental: Vec<String>,
+    pub files: Vec<String>,
+    pub follow: bool,
+    pub follow_separator: Option<String>,
+    pub follow_timeout: Option<f64>,
+    pub grammar_file: Option<String>,
+    pub highlight: bool,
+    pub http2: bool,
+    pub include: Vec<String>,
+    pub inject_datetime: bool,
+    pub inline_images: bool,
+    pub input_price: Option<f64>,
+    pub json_retries: u32,
+    pub json_schema_file: Option<String>,
+    pub keep_alive: Option<String>,
+    pub language: Option<String>,
+    pub log_stream: Option<String>,
+    pub logprobs: Option<u8>,
+    pub map: bool,
+    pub max_file_size: u64,
+    pub max_input_tokens: Option<usize>,
+    pub max_tokens: Option<u32>,
+    pub messages: Vec<Message>,
     pub model: String,
+    pub normalize_output: bool,
+    pub notify: bool,
+    pub notify_after: u64,
+    pub oauth_client_id: Option<String>,
+    pub oauth_client_secret: Option<String>,
+    pub oauth_scope: Option<String>,
+    pub oauth_token_url: Option<String>,
+    pub output: Option<String>,
+    pub pool_idle_timeout: Option<u64>,
+    pub post: Option<String>,
+    pub post_response_hook: Option<String>,
+    pub pre_request_hook: Option<String>,
+    pub prefill: Option<String>,
+    pub profile: String,
+    pub prompt_caching: bool,
+    pub provider: Provider,
+    pub proxy: Option<String>,
+    pub redact: Redaction,
+    pub region: Option<String>,
+    pub response_format: Option<String>,
+    pub resume: bool,
+    pub rpm: Option<u32>,
+    pub seed: Option<u64>,
+    pub session: Option<String>,
+    pub show_stats: bool,
+    pub show_usage: bool,
+    pub speak: bool,
+    pub speech_file: Option<String>,
+    pub speech_model: String,
+    pub speech_voice: String,
+    pub status_line: bool,
     pub stream: bool,
+    pub stream_retries: u32,
+    pub supports_prefill: bool,
     pub system_prompt: Option<String>,
+    pub tcp_keepalive: Option<u64>,
     pub temperature: Option<f32>,
+    pub terminal_title: bool,
+    pub theme: Theme,
+    pub tokenizer: Option<Tokenizer>,
+    pub tpm: Option<u32>,
+    pub transcription_model: String,
+    pub word_wrap: bool,
 }
 
 impl Config {
     fn from_profile(profile: &Profile) -> Result<Self> {
-        ensure!(profile.api_url.is_some(), "No \"api_url\" provided");
-        ensure!(profile.model.is_some(), "No \"model\" provided");
+        let provider = profile.provider.unwrap_or_default();
+        match provider {
+            Provider::OpenAi => {}
+            Provider::Azure => {
+                ensure!(profile.deployment.is_some(), "No \"deployment\" provided");
+                ensure!(profile.api_version.is_some(), "No \"api_version\" provided");
+            }
+            Provider::Bedrock => {
+                ensure!(profile.region.is_some(), "No \"region\" provided");
+                ensure!(
+                    profile.api != Some(Api::Responses),
+                    "The Responses API is not available on Bedrock"
+                );
+            }
+        }
+        ensure!(
+            profile.builtin_tools.is_none() || profile.api == Some(Api::Responses),
+            "\"builtin_tools\" are only available with api = \"responses\""
+        );
+        if profile.oauth_token_url.is_some() {
+            ensure!(
+                profile.oauth_client_id.is_some() && profile.oauth_client_secret.is_some(),
+                "No \"oauth_client_id\" and \"oauth_client_secret\" provided"
+            );
+        }
+        let api_url = match (&profile.api_url, &profile.region) {
+            (None, Some(region)) if provider == Provider::Bedrock => {
+                Some(format!("https://bedrock-runtime.{}.amazonaws.com", region))
+            }
+            _ => profile.api_url.to_owned(),
+        };
+        ensure!(api_url.is_some(), "No \"api_url\" provided");
+        // Azure deployments are bound to a model, so it doesn't need to be specified
+        let model = profile.model.to_owned().or(profile.deployment.to_owned());
+        ensure!(model.is_some(), "No \"model\" provided");
+        if let Some(messages) = &profile.messages {
+            check_examples(messages)?;
+        }
+        ensure!(
+            profile.budget_cost.is_none() || profile.input_price.is_some(),
+            "\"budget_cost\" requires the \"input_price\" of the model"
+        );
+        ensure!(
+            profile.system_prompt.is_none() || profile.system_prompt_file.is_none(),
+            "Only one of \"system_prompt\" and \"system_prompt_file\" can be set"
+        );
+        let system_prompt = match &profile.system_prompt_file {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .with_context(|| format!("Could not read system prompt file \"{}\"", path))?
+                    .trim_end()
+                    .to_owned(),
+            ),
+            None => profile.system_prompt.to_owned(),
+        };
         Ok(Self {
+            api: profile.api.unwrap_or_default(),
             api_key: profile.api_key.to_owned(),
-            api_url: profile.api_url.to_owned().unwrap(),
-            model: profile.model.to_owned().unwrap(),
+            api_url: api_url.unwrap(),
+            apply: false,
+            api_version: profile.api_version.to_owned(),
+            audit: profile.audit,
+            auto_pager: profile.auto_pager.unwrap_or(false),
+            budget_cost: profile.budget_cost,
+            budget_tokens: profile.budget_tokens,
+            builtin_tools: profile.builtin_tools.to_owned().unwrap_or_default(),
+            ca_cert: profile.ca_cert.to_owned(),
+            chunk: None,
+            clipboard: false,
+            confirm_duplicates: profile.confirm_duplicates.unwrap_or(true),
+            danger_accept_invalid_certs: profile.danger_accept_invalid_certs.unwrap_or(false),
+            deployment: profile.deployment.to_owned(),
+            dir: None,
+            dir_tokens: None,
+            dry_run: false,
+            emit_tokens: profile.emit_tokens.to_owned(),
+            experimental: enabled_experimental_features(
+                profile.experimental.as_deref().unwrap_or_default(),
+            )?,
+            files: Vec::new(),
+            follow: false,
+            follow_separator: None,
+            follow_timeout: None,
+            grammar_file: profile.grammar_file.to_owned(),
+            highlight: profile.highlight.unwrap_or(false),
+            http2: profile.http2.unwrap_or(false),
+            include: Vec::new(),
+            inject_datetime: profile.inject_datetime.unwrap_or(false),
+            inline_images: profile.inline_images.unwrap_or(false),
+            input_price: profile.input_price,
+            json_retries: profile.json_retries.unwrap_or(2),
+            json_schema_file: profile.json_schema_file.to_owned(),
+            keep_alive: profile.keep_alive.to_owned(),
+            language: profile.language.to_owned(),
+            log_stream: None,
+            logprobs: None,
+            map: false,
+            max_file_size: profile.max_file_size.unwrap_or(100_000),
+            max_input_tokens: profile.max_input_tokens,
+            max_tokens: profile.max_tokens,
+            messages: profile.messages.to_owned().unwrap_or_default(),
+            model: model.unwrap(),
+            normalize_output: profile.normalize_output.unwrap_or(true),
+            provider,
+            notify: profile.notify.unwrap_or(false),
+            notify_after: profile.notify_after.unwrap_or(10),
+            oauth_client_id: profile.oauth_client_id.to_owned(),
+            oauth_client_secret: profile.oauth_client_secret.to_owned(),
+            oauth_scope: profile.oauth_scope.to_owned(),
+            oauth_token_url: profile.oauth_token_url.to_owned(),
+            output: None,
+            pool_idle_timeout: profile.pool_idle_timeout,
+            post: None,
+            post_response_hook: profile.post_response_hook.to_owned(),
+            pre_request_hook: profile.pre_request_hook.to_owned(),
+            prefill: None,
+            profile: String::new(),
+            prompt_caching: profile.prompt_caching.unwrap_or(false),
+            proxy: profile.proxy.to_owned(),
+            redact: Redaction::default(),
+            region: profile.region.to_owned(),
+            response_format: profile.response_format.to_owned(),
+            resume: false,
+            rpm: profile.rpm,
+            seed: profile.seed,
+            session: None,
+            show_stats: profile.show_stats.unwrap_or(false),
+            show_usage: profile.show_usage.unwrap_or(false),
+            speak: false,
+            speech_file: None,
+            speech_model: profile
+                .speech_model
+                .to_owned()
+                .unwrap_or_else(|| String::from("tts-1")),
+            speech_voice: profile
+                .speech_voice
+                .to_owned()
+                .unwrap_or_else(|| String::from("alloy")),
+            status_line: profile.status_line.unwrap_or(false),
             stream: profile.stream.unwrap_or(true),
-            system_prompt: profile.system_prompt.to_owned(),
+            stream_retries: profile.stream_retries.unwrap_or(2),
+            supports_prefill: profile.supports_prefill.unwrap_or(false),
+            system_prompt,
+            tcp_keepalive: profile.tcp_keepalive,
             temperature: profile.temperature.to_owned(),
+            terminal_title: profile.terminal_title.unwrap_or(true),
+            theme: profile.theme.to_owned().unwrap_or_default(),
+            tokenizer: profile.tokenizer,
+            tpm: profile.tpm,
+            transcription_model: profile
+                .transcription_model
+                .to_owned()
+                .unwrap_or_else(|| String::from("whisper-1")),
+            word_wrap: profile.word_wrap.unwrap_or(true),
         })
     }
-}
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-#[serde(deny_unknown_fields)]
-struct Profile {
-    pub api_key: Option<String>,
-    pub api_url: Option<String>,
-    pub extends: Option<String>,
-    pub model: Option<String>,
-    pub stream: Option<bool>,
-    pub system_prompt: Option<String>,
-    pub temperature: Option<f32>,
-}
+    /// Changes a setting for the rest of the session, e.g. "/set seed 42"
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        match name {
+            "seed" => {
+                self.seed = match value {
+                    "" | "none" | "off" => None,
+                    _ => Some(value.parse().context("Invalid seed")?),
+                }
+            }
+            "prefill" => {
+                ensure!(
+                    value.is_empty() || self.supports_prefill,
+                    "Prefill needs \"supports_prefill = true\" in the profile"
+                );
+                self.prefill = (!value.is_empty()).then(|| value.to_owned())
+            }
+            "temperature" => {
+                self.temperature = match value {
+                    "" | "none" | "off" => None,
+                    _ => {
+                        let temperature: f32 = value.parse().context("Invalid temperature")?;
+                        ensure!(
+                            (0.0..=2.0).contains(&temperature),
+                            "The temperature must be between 0 and 2"
+                        );
+                        Some(temperature)
+                    }
+                }
+            }
+            "max_tokens" => {
+                self.max_tokens = match value {
+                    "" | "none" | "off" => None,
+                    _ => {
+                        let max_tokens = value.parse().context("Invalid max_tokens")?;
+                        ensure!(max_tokens > 0, "max_tokens must be at least 1");
+                        Some(max_tokens)
+                    }
+                }
+            }
+            _ => bail!("Unknown setting: \"{}\"", name),
+        }
+        Ok(())
+    }
 
-impl Profile {
+    /// Whether the response is meant to be JSON, as requested with a schema or
+    /// a "json_object" response format
+    pub fn expects_json(&self) -> bool {
+        self.json_schema_file.is_some()
+            || self
+                .response_format
+                .as_deref()
+                .is_some_and(|kind| kind.starts_with("json"))
+    }
+
+    /// Fails if a request of about this many tokens would go over the budget set
+    /// in the profile, with the reason
+    pub fn check_budget(&self, tokens: usize) -> Result<()> {
+        if let Some(budget_tokens) = self.budget_tokens {
+            ensure!(
+                tokens <= budget_tokens,
+                "The request is about {} tokens, over budget_tokens ({})",
+                tokens,
+                budget_tokens
+            );
+        }
+        if let (Some(budget_cost), Some(input_price)) = (self.budget_cost, self.input_price) {
+            let cost = tokens as f64 * input_price / 1_000_000.0;
+            ensure!(
+                cost <= budget_cost,
+                "The request would cost about {:.2}, over budget_cost ({:.2})",
+                cost,
+                budget_cost
+            );
+        }
+        Ok(())
+    }
+
+    /// The current values of the settings that can be changed with "/set", for
+    /// "/settings", with "none" for those left to the API
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        fn show<T: ToString>(value: Option<T>) -> String {
+            value.map_or(String::from("none"), |value| value.to_string())
+        }
+        vec![
+            ("max_tokens", show(self.max_tokens)),
+            ("prefill", show(self.prefill.as_deref())),
+            ("seed", show(self.seed)),
+            ("temperature", show(self.temperature)),
+        ]
+    }
+}
+
+/// Makes sure the few-shot examples in "messages" are pairs of user prompts and
+/// assistant responses, so that the actual prompt can follow
+fn check_examples(messages: &[Message]) -> Result<()> {
+    for (index, message) in messages.iter().enumerate() {
+        let expected = if index % 2 == 0 {
+            Role::User
+        } else {
+            Role::Assistant
+        };
+        ensure!(
+            message.role == expected,
+            "Expected \"messages\" to alternate \"user\" and \"assistant\" roles, starting with \"user\""
+        );
+    }
+    ensure!(
+        messages.len().is_multiple_of(2),
+        "Expected \"messages\" to end with an \"assistant\" response"
+    );
+    Ok(())
+}
+
+/// Features that are still in progress, only available when explicitly enabled
+/// with "--experimental <name>" or in the "experimental" list of a profile
+#[cfg(not(test))]
+const EXPERIMENTAL_FEATURES: &[&str] = &[];
+
+#[cfg(test)]
+const EXPERIMENTAL_FEATURES: &[&str] = &["time-travel"];
+
+/// The top-level "include" list, "[aliases]" and "[redact]" tables, with every
+/// other table being a profile
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    #[serde(default)]
+    redact: Redaction,
+    #[serde(flatten)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// The "[redact]" table: what to mask in the messages sent, for all profiles
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Redaction {
+    /// names of built-in rules, e.g. "email"
+    #[serde(default)]
+    pub builtin: Vec<String>,
+    /// regular expressions by name, the name being used in the placeholders
+    #[serde(default)]
+    pub patterns: BTreeMap<String, String>,
+    /// put the masked text back in the responses
+    #[serde(default)]
+    pub restore: bool,
+}
+
+impl ConfigFile {
+    /// Adds the profiles of another file, with its settings taking precedence in
+    /// profiles defined in both
+    fn merge_with(&mut self, other: ConfigFile) {
+        self.aliases.extend(other.aliases);
+        self.redact.builtin.extend(other.redact.builtin);
+        self.redact.patterns.extend(other.redact.patterns);
+        self.redact.restore |= other.redact.restore;
+        for (name, profile) in other.profiles {
+            let merged = match self.profiles.remove(&name) {
+                Some(existing) => existing.merge_with(&profile),
+                None => profile,
+            };
+            self.profiles.insert(name, merged);
+        }
+    }
+}
+
+/// Configuration file with settings for a specific project, e.g. its own model
+const PROJECT_CONFIG_FILE: &str = ".lmc.toml";
+
+/// The only settings a project's ".lmc.toml" can have, since just running lmc
+/// in a cloned repository must not send the API key elsewhere with "api_url", or
+/// run commands with "hooks"
+const PROJECT_SETTINGS: &[&str] = &["extends", "model", "system_prompt", "temperature"];
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    pub api: Option<Api>,
+    pub api_key: Option<String>,
+    pub api_url: Option<String>,
+    pub api_version: Option<String>,
+    pub audit: Option<AuditMode>,
+    pub auto_pager: Option<bool>,
+    pub budget_cost: Option<f64>,
+    pub budget_tokens: Option<usize>,
+    pub builtin_tools: Option<Vec<String>>,
+    pub ca_cert: Option<String>,
+    pub confirm_duplicates: Option<bool>,
+    pub danger_accept_invalid_certs: Option<bool>,
+    pub deployment: Option<String>,
+    pub emit_tokens: Option<String>,
+    pub experimental: Option<Vec<String>>,
+    pub extends: Option<String>,
+    pub grammar_file: Option<String>,
+    pub highlight: Option<bool>,
+    pub http2: Option<bool>,
+    pub inject_datetime: Option<bool>,
+    pub inline_images: Option<bool>,
+    pub input_price: Option<f64>,
+    pub json_retries: Option<u32>,
+    pub json_schema_file: Option<String>,
+    pub keep_alive: Option<String>,
+    pub language: Option<String>,
+    pub max_file_size: Option<u64>,
+    pub max_input_tokens: Option<usize>,
+    pub max_tokens: Option<u32>,
+    pub messages: Option<Vec<Message>>,
+    pub model: Option<String>,
+    pub normalize_output: Option<bool>,
+    pub notify: Option<bool>,
+    pub notify_after: Option<u64>,
+    pub oauth_client_id: Option<String>,
+    pub oauth_client_secret: Option<String>,
+    pub oauth_scope: Option<String>,
+    pub oauth_token_url: Option<String>,
+    pub pool_idle_timeout: Option<u64>,
+    pub post_response_hook: Option<String>,
+    pub pre_request_hook: Option<String>,
+    pub prompt_caching: Option<bool>,
+    pub provider: Option<Provider>,
+    pub proxy: Option<String>,
+    pub region: Option<String>,
+    pub response_format: Option<String>,
+    pub rpm: Option<u32>,
+    pub seed: Option<u64>,
+    pub show_stats: Option<bool>,
+    pub show_usage: Option<bool>,
+    pub speech_model: Option<String>,
+    pub speech_voice: Option<String>,
+    pub status_line: Option<bool>,
+    pub stream: Option<bool>,
+    pub stream_retries: Option<u32>,
+    pub supports_prefill: Option<bool>,
+    pub system_prompt: Option<String>,
+    pub system_prompt_file: Option<String>,
+    pub tcp_keepalive: Option<u64>,
+    pub temperature: Option<f32>,
+    pub terminal_title: Option<bool>,
+    pub theme: Option<Theme>,
+    pub tokenizer: Option<Tokenizer>,
+    pub tpm: Option<u32>,
+    pub transcription_model: Option<String>,
+    pub word_wrap: Option<bool>,
+}
+
+impl Profile {
     pub fn new() -> Self {
         Self {
+            api: None,
             api_key: None,
             api_url: None,
+            api_version: None,
+            audit: None,
+            auto_pager: None,
+            budget_cost: None,
+            budget_tokens: None,
+            builtin_tools: None,
+            ca_cert: None,
+            confirm_duplicates: None,
+            danger_accept_invalid_certs: None,
+            deployment: None,
+            emit_tokens: None,
+            experimental: None,
             extends: None,
+            grammar_file: None,
+            highlight: None,
+            http2: None,
+            inject_datetime: None,
+            inline_images: None,
+            input_price: None,
+            json_retries: None,
+            json_schema_file: None,
+            keep_alive: None,
+            language: None,
+            max_file_size: None,
+            max_input_tokens: None,
+            max_tokens: None,
+            messages: None,
             model: None,
+            normalize_output: None,
+            notify: None,
+            notify_after: None,
+            oauth_client_id: None,
+            oauth_client_secret: None,
+            oauth_scope: None,
+            oauth_token_url: None,
+            pool_idle_timeout: None,
+            post_response_hook: None,
+            pre_request_hook: None,
+            prompt_caching: None,
+            provider: None,
+            proxy: None,
+            region: None,
+            response_format: None,
+            rpm: None,
+            seed: None,
+            show_stats: None,
+            show_usage: None,
+            speech_model: None,
+            speech_voice: None,
+            status_line: None,
             stream: None,
+            stream_retries: None,
+            supports_prefill: None,
             system_prompt: None,
+            system_prompt_file: None,
+            tcp_keepalive: None,
             temperature: None,
+            terminal_title: None,
+            theme: None,
+            tokenizer: None,
+            tpm: None,
+            transcription_model: None,
+            word_wrap: None,
         }
     }
     fn merge_with(mut self, other: &Self) -> Self {
+        if let Some(api) = &other.api {
+            self.api = Some(api.to_owned());
+        }
         if let Some(api_key) = &other.api_key {
             self.api_key = Some(api_key.to_owned());
         }
         if let Some(api_url) = &other.api_url {
             self.api_url = Some(api_url.to_owned());
         }
+        if let Some(api_version) = &other.api_version {
+            self.api_version = Some(api_version.to_owned());
+        }
+        if let Some(audit) = &other.audit {
+            self.audit = Some(audit.to_owned());
+        }
+        if let Some(auto_pager) = &other.auto_pager {
+            self.auto_pager = Some(auto_pager.to_owned());
+        }
+        if let Some(budget_cost) = &other.budget_cost {
+            self.budget_cost = Some(budget_cost.to_owned());
+        }
+        if let Some(budget_tokens) = &other.budget_tokens {
+            self.budget_tokens = Some(budget_tokens.to_owned());
+        }
+        if let Some(builtin_tools) = &other.builtin_tools {
+            self.builtin_tools = Some(builtin_tools.to_owned());
+        }
+        if let Some(ca_cert) = &other.ca_cert {
+            self.ca_cert = Some(ca_cert.to_owned());
+        }
+        if let Some(confirm_duplicates) = &other.confirm_duplicates {
+            self.confirm_duplicates = Some(confirm_duplicates.to_owned());
+        }
+        if let Some(danger_accept_invalid_certs) = &other.danger_accept_invalid_certs {
+            self.danger_accept_invalid_certs = Some(danger_accept_invalid_certs.to_owned());
+        }
+        if let Some(deployment) = &other.deployment {
+            self.deployment = Some(deployment.to_owned());
+        }
+        if let Some(emit_tokens) = &other.emit_tokens {
+            self.emit_tokens = Some(emit_tokens.to_owned());
+        }
+        if let Some(experimental) = &other.experimental {
+            self.experimental = Some(experimental.to_owned());
+        }
+        if let Some(grammar_file) = &other.grammar_file {
+            self.grammar_file = Some(grammar_file.to_owned());
+        }
+        if let Some(highlight) = &other.highlight {
+            self.highlight = Some(highlight.to_owned());
+        }
+        if let Some(http2) = &other.http2 {
+            self.http2 = Some(http2.to_owned());
+        }
+        if let Some(inject_datetime) = &other.inject_datetime {
+            self.inject_datetime = Some(inject_datetime.to_owned());
+        }
+        if let Some(inline_images) = &other.inline_images {
+            self.inline_images = Some(inline_images.to_owned());
+        }
+        if let Some(input_price) = &other.input_price {
+            self.input_price = Some(input_price.to_owned());
+        }
+        if let Some(json_retries) = &other.json_retries {
+            self.json_retries = Some(json_retries.to_owned());
+        }
+        if let Some(json_schema_file) = &other.json_schema_file {
+            self.json_schema_file = Some(json_schema_file.to_owned());
+        }
+        if let Some(keep_alive) = &other.keep_alive {
+            self.keep_alive = Some(keep_alive.to_owned());
+        }
+        if let Some(language) = &other.language {
+            self.language = Some(language.to_owned());
+        }
+        if let Some(max_file_size) = &other.max_file_size {
+            self.max_file_size = Some(max_file_size.to_owned());
+        }
+        if let Some(max_input_tokens) = &other.max_input_tokens {
+            self.max_input_tokens = Some(max_input_tokens.to_owned());
+        }
+        if let Some(max_tokens) = &other.max_tokens {
+            self.max_tokens = Some(max_tokens.to_owned());
+        }
+        if let Some(messages) = &other.messages {
+            self.messages = Some(messages.to_owned());
+        }
         if let Some(model) = &other.model {
             self.model = Some(model.to_owned());
         }
         if let Some(extends) = &other.extends {
             self.extends = Some(extends.to_owned());
         }
+        if let Some(normalize_output) = &other.normalize_output {
+            self.normalize_output = Some(normalize_output.to_owned());
+        }
+        if let Some(notify) = &other.notify {
+            self.notify = Some(notify.to_owned());
+        }
+        if let Some(notify_after) = &other.notify_after {
+            self.notify_after = Some(notify_after.to_owned());
+        }
+        if let Some(oauth_client_id) = &other.oauth_client_id {
+            self.oauth_client_id = Some(oauth_client_id.to_owned());
+        }
+        if let Some(oauth_client_secret) = &other.oauth_client_secret {
+            self.oauth_client_secret = Some(oauth_client_secret.to_owned());
+        }
+        if let Some(oauth_scope) = &other.oauth_scope {
+            self.oauth_scope = Some(oauth_scope.to_owned());
+        }
+        if let Some(oauth_token_url) = &other.oauth_token_url {
+            self.oauth_token_url = Some(oauth_token_url.to_owned());
+        }
+        if let Some(pool_idle_timeout) = &other.pool_idle_timeout {
+            self.pool_idle_timeout = Some(pool_idle_timeout.to_owned());
+        }
+        if let Some(post_response_hook) = &other.post_response_hook {
+            self.post_response_hook = Some(post_response_hook.to_owned());
+        }
+        if let Some(pre_request_hook) = &other.pre_request_hook {
+            self.pre_request_hook = Some(pre_request_hook.to_owned());
+        }
+        if let Some(prompt_caching) = &other.prompt_caching {
+            self.prompt_caching = Some(prompt_caching.to_owned());
+        }
+        if let Some(provider) = &other.provider {
+            self.provider = Some(provider.to_owned());
+        }
+        if let Some(proxy) = &other.proxy {
+            self.proxy = Some(proxy.to_owned());
+        }
+        if let Some(region) = &other.region {
+            self.region = Some(region.to_owned());
+        }
+        if let Some(response_format) = &other.response_format {
+            self.response_format = Some(response_format.to_owned());
+        }
+        if let Some(rpm) = &other.rpm {
+            self.rpm = Some(rpm.to_owned());
+        }
+        if let Some(seed) = &other.seed {
+            self.seed = Some(seed.to_owned());
+        }
+        if let Some(show_stats) = &other.show_stats {
+            self.show_stats = Some(show_stats.to_owned());
+        }
+        if let Some(show_usage) = &other.show_usage {
+            self.show_usage = Some(show_usage.to_owned());
+        }
+        if let Some(speech_model) = &other.speech_model {
+            self.speech_model = Some(speech_model.to_owned());
+        }
+        if let Some(speech_voice) = &other.speech_voice {
+            self.speech_voice = Some(speech_voice.to_owned());
+        }
+        if let Some(status_line) = &other.status_line {
+            self.status_line = Some(status_line.to_owned());
+        }
         if let Some(stream) = &other.stream {
             self.stream = Some(stream.to_owned());
         }
-        if let Some(system_prompt) = &other.system_prompt {
-            self.system_prompt = Some(system_prompt.to_owned());
+        if let Some(stream_retries) = &other.stream_retries {
+            self.stream_retries = Some(stream_retries.to_owned());
+        }
+        if let Some(supports_prefill) = &other.supports_prefill {
+            self.supports_prefill = Some(supports_prefill.to_owned());
+        }
+        // a system prompt replaces one read from a file, and vice versa
+        if other.system_prompt.is_some() || other.system_prompt_file.is_some() {
+            self.system_prompt = other.system_prompt.to_owned();
+            self.system_prompt_file = other.system_prompt_file.to_owned();
+        }
+        if let Some(tcp_keepalive) = &other.tcp_keepalive {
+            self.tcp_keepalive = Some(tcp_keepalive.to_owned());
         }
         if let Some(temperature) = &other.temperature {
             self.temperature = Some(temperature.to_owned());
         }
+        if let Some(terminal_title) = &other.terminal_title {
+            self.terminal_title = Some(terminal_title.to_owned());
+        }
+        if let Some(theme) = &other.theme {
+            self.theme = Some(theme.to_owned());
+        }
+        if let Some(tokenizer) = &other.tokenizer {
+            self.tokenizer = Some(tokenizer.to_owned());
+        }
+        if let Some(tpm) = &other.tpm {
+            self.tpm = Some(tpm.to_owned());
+        }
+        if let Some(transcription_model) = &other.transcription_model {
+            self.transcription_model = Some(transcription_model.to_owned());
+        }
+        if let Some(word_wrap) = &other.word_wrap {
+            self.word_wrap = Some(word_wrap.to_owned());
+        }
         self
     }
+    /// Applies "LMC_<SETTING>" environment variables, e.g. LMC_MODEL or
+    /// LMC_TEMPERATURE, parsing each value as in TOML or else taking it as a string
+    fn override_with_env(self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut overrides = toml::Table::new();
+        for key in profile_keys().iter().filter(|key| **key != "extends") {
+            let name = format!("LMC_{}", key.to_uppercase());
+            let Some(value) = var(&name) else {
+                continue;
+            };
+            let parsed = toml::from_str::<toml::Table>(&format!("value = {}", value))
+                .ok()
+                .and_then(|mut table| table.remove("value"));
+            let valid = parsed
+                .into_iter()
+                .chain([toml::Value::String(value.to_owned())])
+                .find(|candidate| {
+                    let table = toml::Table::from_iter([(key.to_string(), candidate.clone())]);
+                    toml::Value::Table(table).try_into::<Profile>().is_ok()
+                })
+                .with_context(|| format!("Invalid value for {}: \"{}\"", name, value))?;
+            overrides.insert(key.to_string(), valid);
+        }
+        let overrides: Profile = toml::Value::Table(overrides).try_into()?;
+        Ok(self.merge_with(&overrides))
+    }
+
     fn override_with_args(mut self, args: &Args) -> Self {
         if let Some(api_key) = &args.api_key {
             self.api_key = Some(api_key.to_owned());
@@ -127,202 +1599,1614 @@ impl Profile {
         if let Some(api_url) = &args.api_url {
             self.api_url = Some(api_url.to_owned());
         }
+        if let Some(ca_cert) = &args.ca_cert {
+            self.ca_cert = Some(ca_cert.to_owned());
+        }
+        if let Some(danger_accept_invalid_certs) = &args.danger_accept_invalid_certs {
+            self.danger_accept_invalid_certs = Some(danger_accept_invalid_certs.to_owned());
+        }
+        if let Some(emit_tokens) = &args.emit_tokens {
+            self.emit_tokens = Some(emit_tokens.to_owned());
+        }
+        if let Some(grammar_file) = &args.grammar_file {
+            self.grammar_file = Some(grammar_file.to_owned());
+        }
+        if let Some(json_schema_file) = &args.json_schema_file {
+            self.json_schema_file = Some(json_schema_file.to_owned());
+        }
+        if let Some(max_input_tokens) = &args.max_input_tokens {
+            self.max_input_tokens = Some(max_input_tokens.to_owned());
+        }
         if let Some(model) = &args.model {
             self.model = Some(model.to_owned());
         }
+        if let Some(highlight) = &args.highlight {
+            self.highlight = Some(highlight.to_owned());
+        }
         if let Some(no_stream) = &args.no_stream {
             self.stream = Some(!no_stream);
         }
+        if let Some(notify) = &args.notify {
+            self.notify = Some(notify.to_owned());
+        }
+        if let Some(proxy) = &args.proxy {
+            self.proxy = Some(proxy.to_owned());
+        }
+        if let Some(seed) = &args.seed {
+            self.seed = Some(seed.to_owned());
+        }
+        if let Some(show_stats) = &args.show_stats {
+            self.show_stats = Some(show_stats.to_owned());
+        }
+        if let Some(show_usage) = &args.show_usage {
+            self.show_usage = Some(show_usage.to_owned());
+        }
         if let Some(system_prompt) = &args.system_prompt {
             self.system_prompt = Some(system_prompt.to_owned());
+            self.system_prompt_file = None;
+        }
+        if let Some(system_file) = &args.system_file {
+            self.system_prompt_file = Some(system_file.to_owned());
+            self.system_prompt = None;
         }
         if let Some(temperature) = &args.temperature {
             self.temperature = Some(temperature.to_owned());
         }
         self
     }
-}
+}
+
+pub fn get_config(args: &Args) -> Result<Config> {
+    get_config_with(args, |name| env::var(name).ok())
+}
+
+/// Like get_config, reading "LMC_PROFILE" and the "LMC_*" settings with the
+/// given lookup rather than from the environment, e.g. none in tests
+pub fn get_config_with(args: &Args, var: impl Fn(&str) -> Option<String>) -> Result<Config> {
+    let ConfigFile {
+        profiles, redact, ..
+    } = parse_config_file(&args.config)?;
+    let prefixed = match args.profile {
+        None => split_model_prefix(&profiles, &args.model),
+        Some(_) => None,
+    };
+    let profile_arg = match &prefixed {
+        Some((profile, _)) => Some(profile.to_owned()),
+        None => args
+            .profile
+            .to_owned()
+            .or_else(|| var("LMC_PROFILE").filter(|value| !value.is_empty())),
+    };
+    let selected = resolve_profile(&profiles, &profile_arg)?;
+    let mut overriden = selected
+        .clone()
+        .override_with_env(var)?
+        .override_with_args(args);
+    if let Some((_, model)) = prefixed {
+        overriden.model = Some(model);
+    }
+    let profile_name = profile_arg.unwrap_or(String::from("default"));
+    if overriden.api_key.is_none() {
+        overriden.api_key = find_stored_api_key(&profiles, profile_name.to_owned());
+    }
+    let mut config = Config::from_profile(&overriden)?;
+    config.apply = args.apply;
+    config.chunk = args.chunk;
+    config.clipboard = args.clipboard;
+    config.dir = args.dir.to_owned();
+    config.dir_tokens = args.dir_tokens;
+    config.dry_run = args.dry_run;
+    if let Some(count) = args.logprobs {
+        ensure!(
+            count <= 20,
+            "At most 20 alternative tokens can be requested"
+        );
+        ensure!(
+            config.provider != Provider::Bedrock,
+            "Bedrock doesn't return logprobs"
+        );
+        config.logprobs = Some(count);
+    }
+    config.files = args.file.to_owned();
+    config.follow = args.follow;
+    config.follow_separator = args.follow_separator.to_owned();
+    config.follow_timeout = args.follow_timeout;
+    config.include = args.include.to_owned();
+    config.log_stream = args.log_stream.to_owned();
+    config.map = args.map;
+    config.output = args.output.to_owned();
+    config.post = args.post.to_owned();
+    if let Some(prefill) = &args.prefill {
+        config.set("prefill", prefill)?;
+    }
+    config.profile = profile_name;
+    config.redact = redact;
+    config.resume = args.resume;
+    config.session = args.session.to_owned();
+    config.speak = args.speak || args.speech_file.is_some();
+    config.speech_file = args.speech_file.to_owned();
+    if !args.experimental.is_empty() {
+        let names = [config.experimental, args.experimental.to_owned()].concat();
+        config.experimental = enabled_experimental_features(&names)?;
+    }
+    Ok(config)
+}
+
+/// Expands "@name" as the first argument, e.g. "lmc @review", into the arguments
+/// of that alias in the "[aliases]" table, followed by any other arguments
+pub fn expand_alias(mut args: Vec<String>) -> Result<Vec<String>> {
+    let Some(name) = args.get(1).and_then(|arg| arg.strip_prefix('@')) else {
+        return Ok(args);
+    };
+    let name = name.to_owned();
+    let config = args
+        .iter()
+        .position(|arg| arg == "-c" || arg == "--config")
+        .and_then(|index| args.get(index + 1))
+        .cloned();
+    let aliases = parse_config_file(&config)?.aliases;
+    let Some(alias) = aliases.get(&name) else {
+        let mut names: Vec<String> = aliases.keys().map(|name| format!("@{}", name)).collect();
+        names.sort();
+        ensure!(
+            !names.is_empty(),
+            "Unknown alias \"{}\", no \"[aliases]\" are defined",
+            name
+        );
+        bail!(
+            "Unknown alias \"{}\", expected one of: {}",
+            name,
+            names.join(", ")
+        );
+    };
+    let expanded = split_arguments(alias).with_context(|| format!("Invalid alias \"{}\"", name))?;
+    args.splice(1..2, expanded);
+    Ok(args)
+}
+
+/// Splits a command line into arguments like a shell would, with single quotes,
+/// double quotes and backslash escapes
+fn split_arguments(line: &str) -> Result<Vec<String>> {
+    let mut arguments = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => arguments.extend(current.take()),
+            '\'' => {
+                let argument = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => argument.push(c),
+                        None => bail!("Unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let argument = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => argument.extend(chars.next()),
+                        Some(c) => argument.push(c),
+                        None => bail!("Unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => current.get_or_insert_with(String::new).extend(chars.next()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    arguments.extend(current);
+    Ok(arguments)
+}
+
+/// The names of the configured profiles, in alphabetical order
+pub fn profile_names(args: &Args) -> Result<Vec<String>> {
+    let mut names: Vec<String> = parse_config_file(&args.config)?
+        .profiles
+        .into_keys()
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Resolves every profile in the configuration file, in alphabetical order,
+/// returning each one's settings or the reason they're invalid
+pub fn check_profiles(args: &Args) -> Result<Vec<(String, Result<Config>)>> {
+    let profiles = parse_config_file(&args.config)?.profiles;
+    let mut names: Vec<&String> = profiles.keys().collect();
+    names.sort();
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            let config =
+                resolve_profile(&profiles, &Some(name.to_owned())).and_then(|mut profile| {
+                    if profile.api_key.is_none() {
+                        profile.api_key = find_stored_api_key(&profiles, name.to_owned());
+                    }
+                    Config::from_profile(&profile)
+                });
+            (name.to_owned(), config)
+        })
+        .collect())
+}
+
+/// The experimental features enabled, without duplicates, rejecting any that
+/// don't exist (anymore)
+fn enabled_experimental_features(names: &[String]) -> Result<Vec<String>> {
+    let mut enabled = names.to_vec();
+    enabled.sort();
+    enabled.dedup();
+    for name in &enabled {
+        ensure!(
+            EXPERIMENTAL_FEATURES.contains(&name.as_str()),
+            "Unknown experimental feature: \"{}\"",
+            name
+        );
+    }
+    Ok(enabled)
+}
+
+/// Looks up a key stored in the OS keyring for the profile or any of its ancestors
+fn find_stored_api_key(profiles: &HashMap<String, Profile>, name: String) -> Option<String> {
+    let mut current = Some(name);
+    while let Some(name) = current {
+        if let Some(key) = get_stored_api_key(&name) {
+            return Some(key);
+        }
+        current = profiles
+            .get(&name)
+            .and_then(|profile| profile.extends.to_owned());
+    }
+    None
+}
+
+/// Splits a "profile/model" argument, if the prefix is the name of a profile
+fn split_model_prefix(
+    profiles: &HashMap<String, Profile>,
+    model_arg: &Option<String>,
+) -> Option<(String, String)> {
+    let (prefix, model) = model_arg.as_deref()?.split_once('/')?;
+    if profiles.contains_key(prefix) && !model.is_empty() {
+        Some((prefix.to_owned(), model.to_owned()))
+    } else {
+        None
+    }
+}
+
+/// A well-known provider offered by "lmc init"
+pub struct Preset {
+    pub name: &'static str,
+    pub title: &'static str,
+    pub api_url: &'static str,
+    pub model: &'static str,
+    pub needs_key: bool,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "ollama",
+        title: "Ollama (local)",
+        api_url: "http://localhost:11434/v1",
+        model: "gemma2:9b",
+        needs_key: false,
+    },
+    Preset {
+        name: "openai",
+        title: "OpenAI",
+        api_url: "https://api.openai.com/v1",
+        model: "gpt-4o-mini",
+        needs_key: true,
+    },
+    Preset {
+        name: "groq",
+        title: "Groq",
+        api_url: "https://api.groq.com/openai/v1",
+        model: "llama-3.1-70b-versatile",
+        needs_key: true,
+    },
+    Preset {
+        name: "mistral",
+        title: "Mistral",
+        api_url: "https://api.mistral.ai/v1",
+        model: "mistral-small-latest",
+        needs_key: true,
+    },
+    Preset {
+        name: "openrouter",
+        title: "OpenRouter",
+        api_url: "https://openrouter.ai/api/v1",
+        model: "meta-llama/llama-3.1-70b-instruct",
+        needs_key: true,
+    },
+];
+
+/// A configuration file with a profile for the provider, used by default
+pub fn starter_config(preset: &Preset, model: &str) -> String {
+    format!(
+        "[default]\nextends = \"{}\"\n\n[{}]\napi_url = \"{}\"\nmodel = {}\n",
+        preset.name,
+        preset.name,
+        preset.api_url,
+        toml::Value::from(model)
+    )
+}
+
+/// Where "lmc init" writes the configuration
+pub fn default_config_path() -> Result<PathBuf> {
+    let home = home_dir().context("Could not detect HOME directory")?;
+    Ok(home.join(".lmc").join("config.toml"))
+}
+
+/// Reads the configuration file given with "--config" or $LMC_CONFIG, or else the
+/// user's one (if any) with the settings of a project's ".lmc.toml" on top
+fn parse_config_file(file: &Option<String>) -> Result<ConfigFile> {
+    let explicit = file.to_owned().or_else(|| {
+        env::var("LMC_CONFIG")
+            .ok()
+            .filter(|value| !value.is_empty())
+    });
+    if let Some(value) = explicit {
+        let path = PathBuf::from(&value);
+        ensure!(
+            fs::exists(&path)?,
+            "Configuration file not found: \"{}\"",
+            value
+        );
+        return read_config_file(&path);
+    }
+    let mut config_file = match user_config_path()? {
+        Some(path) => read_config_file(&path)?,
+        None => ConfigFile::default(),
+    };
+    if let Some(path) = find_project_config(&env::current_dir()?) {
+        check_project_config(&path)?;
+        config_file.merge_with(read_config_file(&path)?);
+    }
+    Ok(config_file)
+}
+
+/// Rejects anything but profiles with the settings allowed in a project's file
+fn check_project_config(path: &Path) -> Result<()> {
+    let source = fs::read_to_string(path)?;
+    let table: toml::Table = toml::from_str(&source)?;
+    for (name, value) in &table {
+        let profile = value
+            .as_table()
+            .filter(|_| !["aliases", "redact"].contains(&name.as_str()));
+        let Some(profile) = profile else {
+            bail!(
+                "Only profiles can be defined in \"{}\", not \"{}\"",
+                path.display(),
+                name
+            );
+        };
+        if let Some(key) = profile
+            .keys()
+            .find(|key| !PROJECT_SETTINGS.contains(&key.as_str()))
+        {
+            bail!(
+                "\"{}\" can't be set in \"{}\", a project's profiles can only set {}",
+                key,
+                path.display(),
+                PROJECT_SETTINGS.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile> {
+    read_with_includes(path, &mut Vec::new())
+}
+
+/// Reads a configuration file after the ones it includes, so that its own
+/// settings take precedence
+fn read_with_includes(path: &Path, including: &mut Vec<PathBuf>) -> Result<ConfigFile> {
+    let canonical = fs::canonicalize(path)
+        .with_context(|| format!("Configuration file not found: \"{}\"", path.display()))?;
+    ensure!(
+        !including.contains(&canonical),
+        "Circular \"include\" of \"{}\"",
+        path.display()
+    );
+    let source = fs::read_to_string(path)?;
+    check_profile_keys(&source)?;
+    let mut config_file: ConfigFile = toml::from_str(&source)?;
+    let mut merged = ConfigFile::default();
+    including.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for profile in config_file.profiles.values_mut() {
+        if let Some(file) = &profile.system_prompt_file {
+            let resolved = resolve_path(dir, file)?;
+            profile.system_prompt_file = Some(resolved.to_string_lossy().into_owned());
+        }
+    }
+    for pattern in &config_file.include {
+        for included in expand_include(dir, pattern)? {
+            merged.merge_with(read_with_includes(&included, including)?);
+        }
+    }
+    including.pop();
+    merged.merge_with(config_file);
+    Ok(merged)
+}
+
+/// Resolves an "include" path relative to the including file's directory, with
+/// "*" and "?" wildcards allowed in the file name, e.g. "work/*.toml"
+fn expand_include(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = resolve_path(dir, pattern)?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !file_name.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+    let parent = path.parent().unwrap_or(dir);
+    let mut paths = Vec::new();
+    if fs::exists(parent)? {
+        for entry in fs::read_dir(parent)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_file() && matches_wildcard(&file_name, &name) {
+                paths.push(entry.path());
+            }
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Resolves a path in a configuration file relative to the file's directory,
+/// or to the home directory if it starts with "~/"
+fn resolve_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    Ok(match path.strip_prefix("~/") {
+        Some(rest) => home_dir()
+            .context("Could not detect HOME directory")?
+            .join(rest),
+        None => dir.join(path),
+    })
+}
+
+fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // positions to backtrack to after the last "*"
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// "$XDG_CONFIG_HOME/lmc/config.toml" or else "$HOME/.lmc/config.toml", whichever
+/// exists first
+pub fn user_config_path() -> Result<Option<PathBuf>> {
+    let xdg_config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute());
+    let candidates = [
+        xdg_config_home.map(|dir| dir.join("lmc").join("config.toml")),
+        Some(default_config_path()?),
+    ];
+    for path in candidates.into_iter().flatten() {
+        if fs::exists(&path)? {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Looks for a ".lmc.toml" in the directory or any of its parents
+fn find_project_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Rejects unknown settings with a clearer error than serde's, naming the profile
+/// and suggesting the closest valid setting, e.g. "system_prompt" for "systemprompt",
+/// and an "[experimental]" table, which would otherwise be taken for a profile
+fn check_profile_keys(source: &str) -> Result<()> {
+    let Ok(table) = toml::from_str::<toml::Table>(source) else {
+        return Ok(()); // the syntax error is reported when parsing again
+    };
+    ensure!(
+        !table.contains_key("experimental"),
+        "There's no \"[experimental]\" table, enable experimental features with an \"experimental\" list in a profile instead"
+    );
+    let known = profile_keys();
+    for (name, value) in &table {
+        let Some(profile) = value
+            .as_table()
+            .filter(|_| !["aliases", "redact"].contains(&name.as_str()))
+        else {
+            continue;
+        };
+        for key in profile.keys() {
+            if known.contains(&key.as_str()) {
+                continue;
+            }
+            let closest = known
+                .iter()
+                .map(|candidate| (edit_distance(key, candidate), candidate))
+                .min()
+                .filter(|(distance, _)| *distance <= key.chars().count() / 3 + 1);
+            match closest {
+                Some((_, suggestion)) => bail!(
+                    "Unknown setting \"{}\" in profile \"{}\", did you mean \"{}\"?",
+                    key,
+                    name,
+                    suggestion
+                ),
+                None => bail!("Unknown setting \"{}\" in profile \"{}\"", key, name),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The settings allowed in a profile, obtained from the serde implementation so
+/// that the list can't get out of date
+pub fn profile_keys() -> &'static [&'static str] {
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(
+            self,
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(serde::de::Error::custom("only the field names are needed"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Profile::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// Levenshtein distance, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn resolve_profile(
+    profiles: &HashMap<String, Profile>,
+    profile_arg: &Option<String>,
+) -> Result<Profile> {
+    let name = profile_arg.to_owned().unwrap_or(String::from("default"));
+    if let Some(selected) = profiles.get(&name) {
+        match selected.extends {
+            None => Ok(selected.to_owned()),
+            Some(_) => {
+                let merged = flatten_profile_hierarchy(profiles, selected)?;
+                Ok(merged)
+            }
+        }
+    } else {
+        match profile_arg {
+            None => Ok(Profile::new()),
+            Some(name) => Err(anyhow!("No such configuration profile: \"{}\"", name)),
+        }
+    }
+}
+
+fn flatten_profile_hierarchy(
+    profiles: &HashMap<String, Profile>,
+    selected: &Profile,
+) -> Result<Profile> {
+    let mut current = selected;
+    let mut hierarchy: Vec<&Profile> = vec![selected];
+    while let Some(name) = &current.extends {
+        let parent = profiles
+            .get(name)
+            .with_context(|| format!("No such configuration profile: \"{}\"", name))?;
+        ensure!(
+            !hierarchy.contains(&parent),
+            "Circular \"extends\" references: \"{}\"",
+            name
+        );
+        hierarchy.push(parent);
+        current = parent;
+    }
+    hierarchy.reverse();
+    let mut merged = Profile::new();
+    for node in hierarchy {
+        merged = merged.merge_with(node);
+    }
+    Ok(merged)
+}
+
+/// The configuration read from a config file with these contents, for the tests
+/// of other modules
+#[cfg(test)]
+pub fn config_from_source(source: &str) -> Result<Config> {
+    use std::io::Write;
+
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(source.as_bytes())?;
+    let path = file.path().to_string_lossy().into_owned();
+    let args = Args::from_args(&["lmc"], &["-c", &path]).map_err(|exit| anyhow!(exit.output))?;
+    get_config_with(&args, |_| None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::theme::Color;
+
+    #[test]
+    fn minimal_default_config() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+"#,
+        )?;
+
+        let args = args_with_config(&config_file)?;
+
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(
+            config,
+            Config {
+                api: Api::Chat,
+                api_key: None,
+                api_url: String::from("http://localhost:11434/v1"),
+                apply: false,
+                api_version: None,
+                audit: None,
+                auto_pager: false,
+                budget_cost: None,
+                budget_tokens: None,
+                builtin_tools: Vec::new(),
+                ca_cert: None,
+                chunk: None,
+                clipboard: false,
+                confirm_duplicates: true,
+                danger_accept_invalid_certs: false,
+                deployment: None,
+                dir: None,
+                dir_tokens: None,
+                dry_run: false,
+                emit_tokens: None,
+                experimental: Vec::new(),
+                files: Vec::new(),
+                follow: false,
+                follow_separator: None,
+                follow_timeout: None,
+                grammar_file: None,
+                highlight: false,
+                http2: false,
+                include: Vec::new(),
+                inject_datetime: false,
+                inline_images: false,
+                input_price: None,
+                json_retries: 2,
+                json_schema_file: None,
+                keep_alive: None,
+                language: None,
+                log_stream: None,
+                logprobs: None,
+                map: false,
+                max_file_size: 100_000,
+                max_input_tokens: None,
+                max_tokens: None,
+                messages: Vec::new(),
+                model: String::from("gemma2:9b"),
+                normalize_output: true,
+                notify: false,
+                notify_after: 10,
+                oauth_client_id: None,
+                oauth_client_secret: None,
+                oauth_scope: None,
+                oauth_token_url: None,
+                output: None,
+                pool_idle_timeout: None,
+                post: None,
+                post_response_hook: None,
+                pre_request_hook: None,
+                prefill: None,
+                profile: String::from("default"),
+                prompt_caching: false,
+                provider: Provider::OpenAi,
+                proxy: None,
+                redact: Redaction::default(),
+                region: None,
+                response_format: None,
+                resume: false,
+                rpm: None,
+                seed: None,
+                session: None,
+                show_stats: false,
+                show_usage: false,
+                speak: false,
+                speech_file: None,
+                speech_model: String::from("tts-1"),
+                speech_voice: String::from("alloy"),
+                status_line: false,
+                stream: true,
+                stream_retries: 2,
+                supports_prefill: false,
+                system_prompt: None,
+                tcp_keepalive: None,
+                temperature: None,
+                terminal_title: true,
+                theme: Theme::default(),
+                tokenizer: None,
+                tpm: None,
+                transcription_model: String::from("whisper-1"),
+                word_wrap: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hierarchical_profiles() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[groq]
+api_url = "https://api.groq.com/openai/v1"
+api_key = "gsk_abc123"
+model = "llama-3.1-8b-instant"
+
+[llama3-70b]
+extends = "groq"
+model = "llama-3.1-70b-versatile"
+system_prompt = "You are a helpful assistant."
+
+[poet]
+extends = "llama3-70b"
+system_prompt = "You are a poet, and will answer any question in rhyme."
+temperature = 1.5
+stream = false
+"#,
+        )?;
+
+        let mut args = args_with_config(&config_file)?;
+        args.profile = Some(String::from("poet"));
+
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(
+            config,
+            Config {
+                api: Api::Chat,
+                api_url: String::from("https://api.groq.com/openai/v1"),
+                apply: false,
+                api_key: Some(String::from("gsk_abc123")),
+                api_version: None,
+                audit: None,
+                auto_pager: false,
+                budget_cost: None,
+                budget_tokens: None,
+                builtin_tools: Vec::new(),
+                ca_cert: None,
+                chunk: None,
+                clipboard: false,
+                confirm_duplicates: true,
+                danger_accept_invalid_certs: false,
+                deployment: None,
+                dir: None,
+                dir_tokens: None,
+                dry_run: false,
+                emit_tokens: None,
+                experimental: Vec::new(),
+                files: Vec::new(),
+                follow: false,
+                follow_separator: None,
+                follow_timeout: None,
+                grammar_file: None,
+                highlight: false,
+                http2: false,
+                include: Vec::new(),
+                inject_datetime: false,
+                inline_images: false,
+                input_price: None,
+                json_retries: 2,
+                json_schema_file: None,
+                keep_alive: None,
+                language: None,
+                log_stream: None,
+                logprobs: None,
+                map: false,
+                max_file_size: 100_000,
+                max_input_tokens: None,
+                max_tokens: None,
+                messages: Vec::new(),
+                model: String::from("llama-3.1-70b-versatile"),
+                normalize_output: true,
+                notify: false,
+                notify_after: 10,
+                oauth_client_id: None,
+                oauth_client_secret: None,
+                oauth_scope: None,
+                oauth_token_url: None,
+                output: None,
+                pool_idle_timeout: None,
+                post: None,
+                post_response_hook: None,
+                pre_request_hook: None,
+                prefill: None,
+                profile: String::from("poet"),
+                prompt_caching: false,
+                provider: Provider::OpenAi,
+                proxy: None,
+                redact: Redaction::default(),
+                region: None,
+                response_format: None,
+                resume: false,
+                rpm: None,
+                seed: None,
+                session: None,
+                show_stats: false,
+                show_usage: false,
+                speak: false,
+                speech_file: None,
+                speech_model: String::from("tts-1"),
+                speech_voice: String::from("alloy"),
+                status_line: false,
+                stream: false,
+                stream_retries: 2,
+                supports_prefill: false,
+                system_prompt: Some(String::from(
+                    "You are a poet, and will answer any question in rhyme."
+                )),
+                tcp_keepalive: None,
+                temperature: Some(1.5),
+                terminal_title: true,
+                theme: Theme::default(),
+                tokenizer: None,
+                tpm: None,
+                transcription_model: String::from("whisper-1"),
+                word_wrap: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn args_take_precedence() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+system_prompt = "You are a helpful assistant."
+"#,
+        )?;
+
+        let mut args = args_with_config(&config_file)?;
+        args.model = Some(String::from("llama3.1:8b"));
+        args.no_stream = Some(true);
+        args.system_prompt = Some(String::from("Summarise the text provided as input."));
+
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(
+            config,
+            Config {
+                api: Api::Chat,
+                api_url: String::from("http://localhost:11434/v1"),
+                apply: false,
+                api_key: None,
+                api_version: None,
+                audit: None,
+                auto_pager: false,
+                budget_cost: None,
+                budget_tokens: None,
+                builtin_tools: Vec::new(),
+                ca_cert: None,
+                chunk: None,
+                clipboard: false,
+                confirm_duplicates: true,
+                danger_accept_invalid_certs: false,
+                deployment: None,
+                dir: None,
+                dir_tokens: None,
+                dry_run: false,
+                emit_tokens: None,
+                experimental: Vec::new(),
+                files: Vec::new(),
+                follow: false,
+                follow_separator: None,
+                follow_timeout: None,
+                grammar_file: None,
+                highlight: false,
+                http2: false,
+                include: Vec::new(),
+                inject_datetime: false,
+                inline_images: false,
+                input_price: None,
+                json_retries: 2,
+                json_schema_file: None,
+                keep_alive: None,
+                language: None,
+                log_stream: None,
+                logprobs: None,
+                map: false,
+                max_file_size: 100_000,
+                max_input_tokens: None,
+                max_tokens: None,
+                messages: Vec::new(),
+                model: String::from("llama3.1:8b"),
+                normalize_output: true,
+                notify: false,
+                notify_after: 10,
+                oauth_client_id: None,
+                oauth_client_secret: None,
+                oauth_scope: None,
+                oauth_token_url: None,
+                output: None,
+                pool_idle_timeout: None,
+                post: None,
+                post_response_hook: None,
+                pre_request_hook: None,
+                prefill: None,
+                profile: String::from("default"),
+                prompt_caching: false,
+                provider: Provider::OpenAi,
+                proxy: None,
+                redact: Redaction::default(),
+                region: None,
+                response_format: None,
+                resume: false,
+                rpm: None,
+                seed: None,
+                session: None,
+                show_stats: false,
+                show_usage: false,
+                speak: false,
+                speech_file: None,
+                speech_model: String::from("tts-1"),
+                speech_voice: String::from("alloy"),
+                status_line: false,
+                stream: false,
+                stream_retries: 2,
+                supports_prefill: false,
+                system_prompt: Some(String::from("Summarise the text provided as input.")),
+                tcp_keepalive: None,
+                temperature: None,
+                terminal_title: true,
+                theme: Theme::default(),
+                tokenizer: None,
+                tpm: None,
+                transcription_model: String::from("whisper-1"),
+                word_wrap: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn model_prefix_selects_profile() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+
+[groq]
+api_url = "https://api.groq.com/openai/v1"
+model = "llama-3.1-8b-instant"
+"#,
+        )?;
+
+        let mut args = args_with_config(&config_file)?;
+        args.model = Some(String::from("groq/llama-3.1-70b-versatile"));
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(config.api_url, "https://api.groq.com/openai/v1");
+        assert_eq!(config.model, "llama-3.1-70b-versatile");
+
+        args.model = Some(String::from("meta-llama/Llama-3.3-70B-Instruct"));
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(config.api_url, "http://localhost:11434/v1");
+        assert_eq!(config.model, "meta-llama/Llama-3.3-70B-Instruct");
+        Ok(())
+    }
+
+    #[test]
+    fn azure_profile() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+provider = "azure"
+api_url = "https://example.openai.azure.com"
+api_key = "abc123"
+deployment = "gpt-4o-mini"
+api_version = "2024-10-21"
+"#,
+        )?;
+
+        let args = args_with_config(&config_file)?;
+
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(
+            config,
+            Config {
+                api: Api::Chat,
+                api_url: String::from("https://example.openai.azure.com"),
+                apply: false,
+                api_key: Some(String::from("abc123")),
+                api_version: Some(String::from("2024-10-21")),
+                audit: None,
+                auto_pager: false,
+                budget_cost: None,
+                budget_tokens: None,
+                builtin_tools: Vec::new(),
+                ca_cert: None,
+                chunk: None,
+                clipboard: false,
+                confirm_duplicates: true,
+                danger_accept_invalid_certs: false,
+                deployment: Some(String::from("gpt-4o-mini")),
+                dir: None,
+                dir_tokens: None,
+                dry_run: false,
+                emit_tokens: None,
+                experimental: Vec::new(),
+                files: Vec::new(),
+                follow: false,
+                follow_separator: None,
+                follow_timeout: None,
+                grammar_file: None,
+                highlight: false,
+                http2: false,
+                include: Vec::new(),
+                inject_datetime: false,
+                inline_images: false,
+                input_price: None,
+                json_retries: 2,
+                json_schema_file: None,
+                keep_alive: None,
+                language: None,
+                log_stream: None,
+                logprobs: None,
+                map: false,
+                max_file_size: 100_000,
+                max_input_tokens: None,
+                max_tokens: None,
+                messages: Vec::new(),
+                model: String::from("gpt-4o-mini"),
+                normalize_output: true,
+                notify: false,
+                notify_after: 10,
+                oauth_client_id: None,
+                oauth_client_secret: None,
+                oauth_scope: None,
+                oauth_token_url: None,
+                output: None,
+                pool_idle_timeout: None,
+                post: None,
+                post_response_hook: None,
+                pre_request_hook: None,
+                prefill: None,
+                profile: String::from("default"),
+                prompt_caching: false,
+                provider: Provider::Azure,
+                proxy: None,
+                redact: Redaction::default(),
+                region: None,
+                response_format: None,
+                resume: false,
+                rpm: None,
+                seed: None,
+                session: None,
+                show_stats: false,
+                show_usage: false,
+                speak: false,
+                speech_file: None,
+                speech_model: String::from("tts-1"),
+                speech_voice: String::from("alloy"),
+                status_line: false,
+                stream: true,
+                stream_retries: 2,
+                supports_prefill: false,
+                system_prompt: None,
+                tcp_keepalive: None,
+                temperature: None,
+                terminal_title: true,
+                theme: Theme::default(),
+                tokenizer: None,
+                tpm: None,
+                transcription_model: String::from("whisper-1"),
+                word_wrap: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bedrock_profile() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+provider = "bedrock"
+region = "eu-west-1"
+model = "anthropic.claude-3-haiku-20240307-v1:0"
+"#,
+        )?;
+
+        let args = args_with_config(&config_file)?;
+
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(
+            config.api_url,
+            String::from("https://bedrock-runtime.eu-west-1.amazonaws.com")
+        );
+        assert_eq!(config.provider, Provider::Bedrock);
+        Ok(())
+    }
+
+    #[test]
+    fn redaction_rules() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[redact]
+builtin = ["email"]
+patterns = { ticket = 'ABC-\d+' }
+
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+"#,
+        )?;
+
+        let config = get_config_with(&args_with_config(&config_file)?, |_| None)?;
+        assert_eq!(
+            config.redact,
+            Redaction {
+                builtin: vec![String::from("email")],
+                patterns: BTreeMap::from([(String::from("ticket"), String::from(r"ABC-\d+"))]),
+                restore: false,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn budget_guard() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "https://api.openai.com/v1"
+model = "gpt-4o"
+budget_tokens = 100000
+budget_cost = 0.5
+input_price = 2.5
+"#,
+        )?;
+
+        let mut config = get_config_with(&args_with_config(&config_file)?, |_| None)?;
+        config.check_budget(100_000)?;
+        assert!(config.check_budget(100_001).is_err());
+        config.budget_tokens = None;
+        config.check_budget(200_000)?;
+        assert!(config.check_budget(200_001).is_err());
+
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "https://api.openai.com/v1"
+model = "gpt-4o"
+budget_cost = 0.5
+"#,
+        )?;
+        assert!(get_config_with(&args_with_config(&config_file)?, |_| None).is_err());
+        Ok(())
+    }
 
-pub fn get_config(args: &Args) -> Result<Config> {
-    let profiles = parse_config_file(&args.config)?;
-    let selected = resolve_profile(&profiles, &args.profile)?;
-    let overriden = selected.clone().override_with_args(args);
-    let config = Config::from_profile(&overriden)?;
-    Ok(config)
-}
+    #[test]
+    fn session_settings() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+supports_prefill = true
+"#,
+        )?;
 
-fn parse_config_file(file: &Option<String>) -> Result<HashMap<String, Profile>> {
-    let path = match file {
-        Some(value) => PathBuf::from(value),
-        None => {
-            let dir = home_dir().context("Could not detect HOME directory")?;
-            dir.join(".lmc").join("config.toml")
-        }
-    };
-    if fs::exists(&path)? {
-        let source = fs::read_to_string(&path)?;
-        let profiles: HashMap<String, Profile> = toml::from_str(&source)?;
-        Ok(profiles)
-    } else {
-        match file {
-            Some(value) => Err(anyhow!("Configuration file not found: \"{}\"", value)),
-            None => Ok(HashMap::new()),
+        let mut config = get_config_with(&args_with_config(&config_file)?, |_| None)?;
+        config.set("seed", "42")?;
+        assert_eq!(config.seed, Some(42));
+        config.set("seed", "off")?;
+        assert_eq!(config.seed, None);
+        assert!(config.set("seed", "abc").is_err());
+        config.set("prefill", "```json")?;
+        assert_eq!(config.prefill.as_deref(), Some("```json"));
+        config.set("prefill", "")?;
+        assert_eq!(config.prefill, None);
+        config.supports_prefill = false;
+        assert!(config.set("prefill", "```json").is_err());
+        config.set("temperature", "0.2")?;
+        for temperature in ["NaN", "inf", "-0.1", "2.5", "warm"] {
+            assert!(config.set("temperature", temperature).is_err());
         }
+        assert_eq!(config.temperature, Some(0.2));
+        config.set("max_tokens", "4000")?;
+        assert!(config.set("max_tokens", "-1").is_err());
+        assert!(config.set("max_tokens", "0").is_err());
+        assert!(config.set("colour", "blue").is_err());
+        assert_eq!(
+            config.settings(),
+            vec![
+                ("max_tokens", String::from("4000")),
+                ("prefill", String::from("none")),
+                ("seed", String::from("none")),
+                ("temperature", String::from("0.2")),
+            ]
+        );
+        Ok(())
     }
-}
 
-fn resolve_profile(
-    profiles: &HashMap<String, Profile>,
-    profile_arg: &Option<String>,
-) -> Result<Profile> {
-    let name = profile_arg.to_owned().unwrap_or(String::from("default"));
-    if let Some(selected) = profiles.get(&name) {
-        match selected.extends {
-            None => Ok(selected.to_owned()),
-            Some(_) => {
-                let merged = flatten_profile_hierarchy(profiles, selected)?;
-                Ok(merged)
-            }
-        }
-    } else {
-        match profile_arg {
-            None => Ok(Profile::new()),
-            Some(name) => Err(anyhow!("No such configuration profile: \"{}\"", name)),
-        }
-    }
-}
+    #[test]
+    fn few_shot_examples() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+system_prompt = "Classify the sentiment."
+messages = [
+    { role = "user", content = "I love it!" },
+    { role = "assistant", content = "positive" },
+]
 
-fn flatten_profile_hierarchy(
-    profiles: &HashMap<String, Profile>,
-    selected: &Profile,
-) -> Result<Profile> {
-    let mut current = selected;
-    let mut hierarchy: Vec<&Profile> = vec![selected];
-    while let Some(name) = &current.extends {
-        let parent = profiles
-            .get(name)
-            .with_context(|| format!("No such configuration profile: \"{}\"", name))?;
-        ensure!(
-            !hierarchy.contains(&parent),
-            "Circular \"extends\" references: \"{}\"",
-            name
+[unanswered]
+extends = "default"
+messages = [{ role = "user", content = "I love it!" }]
+"#,
+        )?;
+
+        let mut args = args_with_config(&config_file)?;
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(
+            config.messages,
+            vec![
+                Message::new(Role::User, &String::from("I love it!")),
+                Message::new(Role::Assistant, &String::from("positive")),
+            ]
         );
-        hierarchy.push(parent);
-        current = parent;
-    }
-    hierarchy.reverse();
-    let mut merged = Profile::new();
-    for node in hierarchy {
-        merged = merged.merge_with(node);
+
+        args.profile = Some(String::from("unanswered"));
+        let result = get_config_with(&args, |_| None);
+        assert!(result.is_err());
+        assert_eq!(
+            format!("{}", result.unwrap_err()),
+            "Expected \"messages\" to end with an \"assistant\" response"
+        );
+        Ok(())
     }
-    Ok(merged)
-}
 
-#[cfg(test)]
-mod tests {
-    use std::io::Write;
+    #[test]
+    fn checks_all_profiles() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
 
-    use tempfile::NamedTempFile;
+[no-model]
+api_url = "http://localhost:11434/v1"
 
-    use super::*;
+[loop]
+extends = "loop"
+"#,
+        )?;
+
+        let results = check_profiles(&args_with_config(&config_file)?)?;
+        let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["default", "loop", "no-model"]);
+        assert!(results[0].1.is_ok());
+        assert_eq!(
+            format!("{}", results[1].1.as_ref().unwrap_err()),
+            "Circular \"extends\" references: \"loop\""
+        );
+        assert_eq!(
+            format!("{}", results[2].1.as_ref().unwrap_err()),
+            "No \"model\" provided"
+        );
+        Ok(())
+    }
 
     #[test]
-    fn minimal_default_config() -> Result<()> {
+    fn unknown_settings() -> Result<()> {
         let config_file = write_temp_config(
             r#"
 [default]
 api_url = "http://localhost:11434/v1"
 model = "gemma2:9b"
+
+[pirate]
+systemprompt = "Talk like a pirate."
 "#,
         )?;
 
-        let args = args_with_config(&config_file)?;
-
-        let config = get_config(&args)?;
+        let result = get_config_with(&args_with_config(&config_file)?, |_| None);
+        assert!(result.is_err());
         assert_eq!(
-            config,
-            Config {
-                api_key: None,
-                api_url: String::from("http://localhost:11434/v1"),
-                model: String::from("gemma2:9b"),
-                stream: true,
-                system_prompt: None,
-                temperature: None,
-            }
+            format!("{}", result.unwrap_err()),
+            "Unknown setting \"systemprompt\" in profile \"pirate\", did you mean \"system_prompt\"?"
         );
+        assert!(profile_keys().contains(&"word_wrap"));
+        assert_eq!(edit_distance("tempreature", "temperature"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
         Ok(())
     }
 
     #[test]
-    fn hierarchical_profiles() -> Result<()> {
-        let config_file = write_temp_config(
+    fn project_config_overrides() -> Result<()> {
+        let mut user: ConfigFile = toml::from_str(
             r#"
-[groq]
-api_url = "https://api.groq.com/openai/v1"
-api_key = "gsk_abc123"
-model = "llama-3.1-8b-instant"
-
-[llama3-70b]
-extends = "groq"
-model = "llama-3.1-70b-versatile"
-system_prompt = "You are a helpful assistant."
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+"#,
+        )?;
+        let project: ConfigFile = toml::from_str(
+            r#"
+[default]
+model = "llama3.1:8b"
 
-[poet]
-extends = "llama3-70b"
-system_prompt = "You are a poet, and will answer any question in rhyme."
-temperature = 1.5
-stream = false
+[reviewer]
+extends = "default"
+system_prompt = "Review the code."
 "#,
         )?;
+        user.merge_with(project);
 
-        let mut args = args_with_config(&config_file)?;
-        args.profile = Some(String::from("poet"));
+        let config = Config::from_profile(&resolve_profile(&user.profiles, &None)?)?;
+        assert_eq!(config.api_url, "http://localhost:11434/v1");
+        assert_eq!(config.model, "llama3.1:8b");
+        assert!(user.profiles.contains_key("reviewer"));
 
-        let config = get_config(&args)?;
+        let dir = tempfile::tempdir()?;
+        let nested = dir.path().join("src").join("bin");
+        fs::create_dir_all(&nested)?;
+        assert_eq!(find_project_config(&nested), None);
+        fs::write(dir.path().join(PROJECT_CONFIG_FILE), "")?;
         assert_eq!(
-            config,
-            Config {
-                api_url: String::from("https://api.groq.com/openai/v1"),
-                api_key: Some(String::from("gsk_abc123")),
-                model: String::from("llama-3.1-70b-versatile"),
-                stream: false,
-                system_prompt: Some(String::from(
-                    "You are a poet, and will answer any question in rhyme."
-                )),
-                temperature: Some(1.5),
-            }
+            find_project_config(&nested),
+            Some(dir.path().join(PROJECT_CONFIG_FILE))
         );
         Ok(())
     }
 
     #[test]
-    fn args_take_precedence() -> Result<()> {
+    fn project_config_settings() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(PROJECT_CONFIG_FILE);
+        fs::write(
+            &path,
+            "[default]\nmodel = \"llama3.1:8b\"\n\n[reviewer]\nextends = \"default\"\n",
+        )?;
+        assert!(check_project_config(&path).is_ok());
+
+        fs::write(&path, "[default]\napi_url = \"https://example.com/v1\"\n")?;
+        let error = check_project_config(&path).unwrap_err().to_string();
+        assert!(error.starts_with("\"api_url\" can't be set in"));
+
+        fs::write(&path, "include = [\"/etc/lmc.toml\"]\n")?;
+        assert!(check_project_config(&path).is_err());
+        fs::write(&path, "[aliases]\nreview = \"--apply\"\n")?;
+        assert!(check_project_config(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn environment_overrides() -> Result<()> {
+        let vars = HashMap::from([
+            ("LMC_MODEL", "llama3.1:8b"),
+            ("LMC_TEMPERATURE", "0.5"),
+            ("LMC_STREAM", "false"),
+            ("LMC_API_KEY", "12345"),
+            ("LMC_EXTENDS", "other"),
+        ]);
+        let lookup = |name: &str| vars.get(name).map(|value| value.to_string());
+        let mut profile = Profile::new();
+        profile.api_url = Some(String::from("http://localhost:11434/v1"));
+        profile.model = Some(String::from("gemma2:9b"));
+
+        let overriden = profile.clone().override_with_env(lookup)?;
+        assert_eq!(overriden.model, Some(String::from("llama3.1:8b")));
+        assert_eq!(overriden.temperature, Some(0.5));
+        assert_eq!(overriden.stream, Some(false));
+        assert_eq!(overriden.api_key, Some(String::from("12345")));
+        assert_eq!(overriden.extends, None);
+
+        let invalid = |name: &str| (name == "LMC_SEED").then(|| String::from("abc"));
+        assert_eq!(
+            format!("{}", profile.override_with_env(invalid).unwrap_err()),
+            "Invalid value for LMC_SEED: \"abc\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn starter_configs() -> Result<()> {
+        let source = starter_config(&PRESETS[2], "llama-3.1-8b-instant");
+        let config_file: ConfigFile = toml::from_str(&source)?;
+        let config = Config::from_profile(&resolve_profile(&config_file.profiles, &None)?)?;
+        assert_eq!(config.api_url, "https://api.groq.com/openai/v1");
+        assert_eq!(config.model, "llama-3.1-8b-instant");
+        Ok(())
+    }
+
+    #[test]
+    fn included_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("work"))?;
+        fs::write(
+            dir.path().join("config.toml"),
+            "include = [\"providers.toml\", \"work/*.toml\"]\n\n[default]\nextends = \"groq\"\n",
+        )?;
+        fs::write(
+            dir.path().join("providers.toml"),
+            "[groq]\napi_url = \"https://api.groq.com/openai/v1\"\nmodel = \"llama3-8b-8192\"\n",
+        )?;
+        fs::write(
+            dir.path().join("work").join("secrets.toml"),
+            "[groq]\napi_key = \"gsk_123\"\n",
+        )?;
+        fs::write(dir.path().join("work").join("notes.txt"), "not toml")?;
+
+        let mut args = new_args();
+        args.config = Some(dir.path().join("config.toml").display().to_string());
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(config.model, "llama3-8b-8192");
+        assert_eq!(config.api_key, Some(String::from("gsk_123")));
+
+        fs::write(
+            dir.path().join("providers.toml"),
+            "include = [\"config.toml\"]\n",
+        )?;
+        assert!(get_config_with(&args, |_| None).is_err());
+
+        assert!(matches_wildcard("*.toml", "work.toml"));
+        assert!(matches_wildcard("a*b?c", "aXXbYc"));
+        assert!(!matches_wildcard("*.toml", "notes.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn theme_colors() -> Result<()> {
         let config_file = write_temp_config(
             r#"
 [default]
 api_url = "http://localhost:11434/v1"
 model = "gemma2:9b"
-system_prompt = "You are a helpful assistant."
+theme = { user = "green", marker = "> " }
 "#,
         )?;
 
-        let mut args = args_with_config(&config_file)?;
-        args.model = Some(String::from("llama3.1:8b"));
-        args.no_stream = Some(true);
-        args.system_prompt = Some(String::from("Summarise the text provided as input."));
+        let config = get_config_with(&args_with_config(&config_file)?, |_| None)?;
+        assert_eq!(config.theme.user, Color::Green);
+        assert_eq!(config.theme.error, Color::Red);
+        assert_eq!(config.theme.marker.as_deref(), Some("> "));
+        Ok(())
+    }
+
+    #[test]
+    fn system_prompt_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("prompts"))?;
+        fs::write(
+            dir.path().join("config.toml"),
+            "[default]\napi_url = \"http://localhost:11434/v1\"\nmodel = \"gemma2:9b\"\n\
+            system_prompt = \"You are a helpful assistant.\"\n\n\
+            [reviewer]\nextends = \"default\"\nsystem_prompt_file = \"prompts/reviewer.md\"\n",
+        )?;
+        fs::write(
+            dir.path().join("prompts").join("reviewer.md"),
+            "# Role\n\nYou review code.\n",
+        )?;
 
-        let config = get_config(&args)?;
+        let mut args = new_args();
+        args.config = Some(dir.path().join("config.toml").display().to_string());
+        args.profile = Some(String::from("reviewer"));
+        let config = get_config_with(&args, |_| None)?;
         assert_eq!(
-            config,
-            Config {
-                api_url: String::from("http://localhost:11434/v1"),
-                api_key: None,
-                model: String::from("llama3.1:8b"),
-                stream: false,
-                system_prompt: Some(String::from("Summarise the text provided as input.")),
-                temperature: None,
-            }
+            config.system_prompt.as_deref(),
+            Some("# Role\n\nYou review code.")
+        );
+
+        args.system_prompt = Some(String::from("Be brief."));
+        let config = get_config_with(&args, |_| None)?;
+        assert_eq!(config.system_prompt.as_deref(), Some("Be brief."));
+
+        args.system_prompt = None;
+        args.system_file = Some(String::from("missing.md"));
+        assert!(get_config_with(&args, |_| None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expands_aliases() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[aliases]
+review = "-p coder -s 'Review this diff' --no-stream"
+
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:2b"
+"#,
+        )?;
+        let path = config_file.path().display().to_string();
+        let args = |list: &[&str]| -> Vec<String> { list.iter().map(|a| a.to_string()).collect() };
+        assert_eq!(
+            expand_alias(args(&["lmc", "@review", "-c", &path, "extra"]))?,
+            args(&[
+                "lmc",
+                "-p",
+                "coder",
+                "-s",
+                "Review this diff",
+                "--no-stream",
+                "-c",
+                &path,
+                "extra"
+            ])
+        );
+        assert_eq!(
+            expand_alias(args(&["lmc", "-c", &path]))?,
+            args(&["lmc", "-c", &path])
+        );
+        assert!(expand_alias(args(&["lmc", "@missing", "-c", &path])).is_err());
+        assert_eq!(
+            split_arguments(r#"-s "say \"hi\"" a\ b ''"#)?,
+            args(&["-s", "say \"hi\"", "a b", ""])
         );
+        assert!(split_arguments("-s 'oops").is_err());
         Ok(())
     }
 
@@ -348,7 +3232,7 @@ model = "llama3.1:8b"
         let mut args = args_with_config(&config_file)?;
         args.profile = Some(String::from("profile-3"));
 
-        let result = get_config(&args);
+        let result = get_config_with(&args, |_| None);
         assert!(result.is_err());
         assert_eq!(
             format!("{}", result.unwrap_err()),
@@ -370,7 +3254,7 @@ model = "gemma2:9b"
         let mut args = args_with_config(&config_file)?;
         args.profile = Some(String::from("superhuman"));
 
-        let result = get_config(&args);
+        let result = get_config_with(&args, |_| None);
         assert!(result.is_err());
         assert_eq!(
             format!("{}", result.unwrap_err()),
@@ -379,6 +3263,82 @@ model = "gemma2:9b"
         Ok(())
     }
 
+    #[test]
+    fn experimental_features() -> Result<()> {
+        let config_file = write_temp_config(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+experimental = []
+
+[future]
+extends = "default"
+experimental = ["time-travel", "time-travel"]
+
+[unknown]
+extends = "default"
+experimental = ["teleportation"]
+"#,
+        )?;
+
+        let mut args = args_with_config(&config_file)?;
+        let config = get_config_with(&args, |_| None)?;
+        assert!(config.experimental.is_empty());
+
+        args.experimental = vec![String::from("time-travel")];
+        assert_eq!(
+            get_config_with(&args, |_| None)?.experimental,
+            vec!["time-travel"]
+        );
+
+        args.experimental = Vec::new();
+        args.profile = Some(String::from("future"));
+        assert_eq!(
+            get_config_with(&args, |_| None)?.experimental,
+            vec!["time-travel"]
+        );
+
+        args.profile = Some(String::from("unknown"));
+        assert_eq!(
+            format!("{}", get_config_with(&args, |_| None).unwrap_err()),
+            "Unknown experimental feature: \"teleportation\""
+        );
+
+        let config_file = write_temp_config(
+            r#"
+[experimental]
+features = ["time-travel"]
+"#,
+        )?;
+        let result = get_config_with(&args_with_config(&config_file)?, |_| None);
+        assert!(format!("{}", result.unwrap_err()).starts_with("There's no \"[experimental]\""));
+        Ok(())
+    }
+
+    #[test]
+    fn command_options() {
+        let parse = |args: &[&str]| {
+            let mut args = Args::from_args(&["lmc"], args).unwrap();
+            args.merge_command_options();
+            args
+        };
+        let args = parse(&["chat", "--resume", "--speech-file", "answer.mp3"]);
+        assert!(args.resume);
+        assert_eq!(args.speech_file.as_deref(), Some("answer.mp3"));
+        let args = parse(&["run", "--follow", "--follow-timeout", "5", "Watch", "this"]);
+        assert!(args.follow);
+        assert_eq!(args.follow_timeout, Some(5.0));
+        let Some(Command::Run(run)) = &args.command else {
+            panic!("Not a run command");
+        };
+        assert_eq!(run.prompt, vec!["Watch", "this"]);
+        // still accepted before the command
+        let args = parse(&["--map", "--apply", "run"]);
+        assert!(args.map && args.apply);
+        assert!(Args::from_args(&["lmc"], &["chat", "--map"]).is_err());
+    }
+
     fn write_temp_config(source: &str) -> Result<NamedTempFile> {
         let mut config_file = NamedTempFile::new()?;
         config_file.write_all(source.as_bytes())?;
@@ -388,11 +3348,49 @@ model = "gemma2:9b"
     fn new_args() -> Args {
         Args {
             api_key: None,
+            apply: false,
             api_url: None,
+            ca_cert: None,
+            chunk: None,
+            clipboard: false,
+            color: ColorMode::Auto,
+            command: None,
             config: None,
+            danger_accept_invalid_certs: None,
+            dir: None,
+            dir_tokens: None,
+            dry_run: false,
+            emit_tokens: None,
+            experimental: Vec::new(),
+            file: Vec::new(),
+            follow: false,
+            follow_separator: None,
+            follow_timeout: None,
+            grammar_file: None,
+            help_long: false,
+            include: Vec::new(),
+            json_schema_file: None,
+            log_stream: None,
+            logprobs: None,
+            map: false,
+            max_input_tokens: None,
             model: None,
+            highlight: None,
             no_stream: None,
+            notify: None,
+            output: None,
+            post: None,
+            prefill: None,
             profile: None,
+            proxy: None,
+            resume: false,
+            seed: None,
+            session: None,
+            show_stats: None,
+            show_usage: None,
+            speak: false,
+            speech_file: None,
+            system_file: None,
             system_prompt: None,
             temperature: None,
             print_version: false,
@@ -1,33 +1,42 @@
-use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
 use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
 use rustyline::history::MemHistory;
-use rustyline::{DefaultEditor, Editor};
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use tokio::sync::mpsc;
+use tokio::time;
 
 pub struct Console {
-    editor: Editor<(), MemHistory>,
+    editor: Editor<InputHelper, MemHistory>,
     continuation: bool,
 }
 
 impl Console {
     pub fn new() -> Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(InputHelper {
+            style: None,
+            files: FilenameCompleter::new(),
+        }));
         Ok(Self {
-            editor: DefaultEditor::new()?,
+            editor,
             continuation: false,
         })
     }
 
-    pub fn read_piped_input(&mut self) -> Result<String> {
-        let mut buffer = String::new();
-        loop {
-            match self.editor.readline("") {
-                Ok(line) => buffer.push_str(&line),
-                Err(ReadlineError::Eof) => break,
-                Err(error) => {
-                    bail!("Failed to read input: {}", error);
-                }
-            }
+    /// Shows the text typed at the prompt in a colour (an ANSI escape sequence)
+    pub fn set_input_style(&mut self, style: &'static str) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.style = Some(style);
         }
-        Ok(buffer)
     }
 
     pub fn read_interactive_input(&mut self) -> Result<Option<String>> {
@@ -56,4 +65,180 @@ impl Console {
         }
         Ok(Some(buffer))
     }
+
+    /// Reads a single line, e.g. the answer to a question, or None on EOF
+    pub fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        match self.editor.readline(prompt) {
+            Ok(line) => Ok(Some(line)),
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => Ok(None),
+            Err(error) => Err(anyhow!("Failed to read input: {}", error)),
+        }
+    }
+
+    pub fn confirm(&mut self, question: &str) -> Result<bool> {
+        match self.editor.readline(&format!("{} [y/N] ", question)) {
+            Ok(answer) => Ok(matches!(answer.trim(), "y" | "Y" | "yes")),
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => Ok(false),
+            Err(error) => Err(anyhow!("Failed to read input: {}", error)),
+        }
+    }
+}
+
+/// Colours the input line as it's edited, and completes "@path" references
+struct InputHelper {
+    style: Option<&'static str>,
+    files: FilenameCompleter,
+}
+
+impl Highlighter for InputHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        match self.style {
+            Some(style) => Cow::Owned(format!("{}{}\x1b[0m", style, line)),
+            None => Cow::Borrowed(line),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _kind: CmdKind) -> bool {
+        self.style.is_some()
+    }
+}
+
+impl Completer for InputHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let word = line[..pos]
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or_default();
+        match word.strip_prefix('@') {
+            Some(path) => {
+                let (start, candidates) = self.files.complete_path(path, path.len())?;
+                Ok((pos - path.len() + start, candidates))
+            }
+            None => Ok((pos, Vec::new())),
+        }
+    }
+}
+
+impl Hinter for InputHelper {
+    type Hint = String;
+}
+
+impl Validator for InputHelper {}
+
+impl Helper for InputHelper {}
+
+/// Reads all of stdin as is, preserving newlines, when it's not a terminal
+pub fn read_piped_input() -> Result<String> {
+    let mut buffer = String::new();
+    io::stdin()
+        .lock()
+        .read_to_string(&mut buffer)
+        .context("Failed to read input")?;
+    Ok(buffer)
+}
+
+/// Reads piped input as it keeps coming, e.g. from "tail -f", in chunks that end
+/// at a separator line, or else when no more input comes for a while
+pub struct InputFollower {
+    lines: mpsc::UnboundedReceiver<io::Result<String>>,
+    separator: Option<String>,
+    timeout: Duration,
+    ended: bool,
+}
+
+impl InputFollower {
+    pub fn start(separator: Option<String>, timeout: Duration) -> Self {
+        let (sender, lines) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self::new(lines, separator, timeout)
+    }
+
+    fn new(
+        lines: mpsc::UnboundedReceiver<io::Result<String>>,
+        separator: Option<String>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            lines,
+            separator,
+            timeout,
+            ended: false,
+        }
+    }
+
+    /// The next chunk of input, waiting for as long as it takes to start, or None
+    /// at the end of the input
+    pub async fn next_chunk(&mut self) -> Result<Option<String>> {
+        let mut chunk = String::new();
+        while !self.ended {
+            let line = if chunk.trim().is_empty() || self.separator.is_some() {
+                self.lines.recv().await
+            } else {
+                match time::timeout(self.timeout, self.lines.recv()).await {
+                    Ok(line) => line,
+                    Err(_) => break,
+                }
+            };
+            let Some(line) = line else {
+                self.ended = true;
+                break;
+            };
+            let line = line.context("Failed to read input")?;
+            if self.separator.as_deref() == Some(line.trim_end()) {
+                if chunk.trim().is_empty() {
+                    continue;
+                }
+                break;
+            }
+            chunk.push_str(&line);
+            chunk.push('\n');
+        }
+        Ok((!chunk.trim().is_empty()).then_some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn follows_input_in_chunks() -> Result<()> {
+        let (sender, lines) = mpsc::unbounded_channel();
+        let separator = Some(String::from("---"));
+        let mut input = InputFollower::new(lines, separator, Duration::from_secs(60));
+        for line in ["a", "b", "---", "---", "c", "---"] {
+            sender.send(Ok(line.to_owned()))?;
+        }
+        assert_eq!(input.next_chunk().await?.as_deref(), Some("a\nb\n"));
+        assert_eq!(input.next_chunk().await?.as_deref(), Some("c\n"));
+        sender.send(Ok(String::from("d")))?;
+        drop(sender);
+        assert_eq!(input.next_chunk().await?.as_deref(), Some("d\n"));
+        assert_eq!(input.next_chunk().await?, None);
+
+        let (sender, lines) = mpsc::unbounded_channel();
+        let mut input = InputFollower::new(lines, None, Duration::from_millis(50));
+        sender.send(Ok(String::from("a")))?;
+        sender.send(Ok(String::from("b")))?;
+        // no more input for a while ends the chunk
+        assert_eq!(input.next_chunk().await?.as_deref(), Some("a\nb\n"));
+        sender.send(Ok(String::from("c")))?;
+        drop(sender);
+        assert_eq!(input.next_chunk().await?.as_deref(), Some("c\n"));
+        assert_eq!(input.next_chunk().await?, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,295 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use zip::ZipArchive;
+
+/// How much of a file is read to tell whether it's text
+const SNIFF_LENGTH: u64 = 8192;
+
+/// The bytes that files of common binary formats start with
+const MAGIC_NUMBERS: &[(&[u8], &str)] = &[
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"SQLite format 3\x00", "application/vnd.sqlite3"),
+];
+
+/// Punctuation that may follow a file reference in a sentence
+const TRAILING_PUNCTUATION: [char; 8] = ['.', ',', ';', ':', '!', '?', ')', '"'];
+
+/// Replaces "@path/to/file" references (at the start of the prompt or after
+/// whitespace) with the contents of the file in a code block, leaving the ones
+/// that don't name a file untouched
+pub fn expand_file_references(prompt: &str, max_size: u64) -> Result<String> {
+    let mut expanded = String::with_capacity(prompt.len());
+    let mut rest = prompt;
+    let mut at_word_start = true;
+    while let Some(c) = rest.chars().next() {
+        if c == '@' && at_word_start {
+            let word_length = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if let Some(path) = file_reference(&rest[1..word_length]) {
+                expanded.truncate(expanded.trim_end_matches([' ', '\t']).len());
+                if !expanded.is_empty() && !expanded.ends_with('\n') {
+                    expanded.push('\n');
+                }
+                expanded.push_str(&fenced_file(path, max_size)?);
+                rest = rest[1 + path.len()..].trim_start_matches([' ', '\t']);
+                if !rest.is_empty() && !rest.starts_with('\n') {
+                    expanded.push('\n');
+                }
+                at_word_start = false;
+                continue;
+            }
+        }
+        expanded.push(c);
+        at_word_start = c.is_whitespace();
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(expanded)
+}
+
+/// The file a word after "@" names, ignoring any punctuation after it, e.g.
+/// "main.rs" in "@main.rs?"
+fn file_reference(word: &str) -> Option<&str> {
+    let mut path = word;
+    while !path.is_empty() {
+        if Path::new(path).is_file() {
+            return Some(path);
+        }
+        path = path.strip_suffix(TRAILING_PUNCTUATION)?;
+    }
+    None
+}
+
+/// Appends the files to a prompt, each in a code block, after a blank line
+pub fn attach_files(prompt: &str, paths: &[String], max_size: u64) -> Result<String> {
+    let mut prompt = prompt.trim_end().to_owned();
+    for path in paths {
+        if !prompt.is_empty() {
+            prompt.push_str("\n\n");
+        }
+        prompt.push_str(&fenced_file(path, max_size)?);
+    }
+    Ok(prompt)
+}
+
+/// The contents of a file in a code block, with the file name as info string
+fn fenced_file(path: &str, max_size: u64) -> Result<String> {
+    let content = read_text(path, max_size)?;
+    let fence = fence_for(&content);
+    Ok(format!(
+        "{}{}\n{}\n{}",
+        fence,
+        path,
+        content.trim_end_matches('\n'),
+        fence
+    ))
+}
+
+/// Reads a file as text, extracting the text of PDF and Word documents, as long
+/// as the text is no larger than `max_size` bytes. Other binary files are refused.
+fn read_text(path: &str, max_size: u64) -> Result<String> {
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(SNIFF_LENGTH).read_to_end(&mut head))
+        .with_context(|| format!("Could not read {}", path))?;
+    let is_docx = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("docx"));
+    let text = match detect_mime_type(&head) {
+        Some("application/pdf") => pdf_text(path)?,
+        Some("application/zip") if is_docx => docx_text(path)?,
+        Some(mime_type) if mime_type.starts_with("image/") => bail!(
+            "{} is an image ({}), which can't be included as text",
+            path,
+            mime_type
+        ),
+        Some(mime_type) => bail!(
+            "{} is a binary file ({}), which can't be included as text",
+            path,
+            mime_type
+        ),
+        None => {
+            let metadata =
+                fs::metadata(path).with_context(|| format!("Could not read {}", path))?;
+            check_size(path, metadata.len(), max_size)?;
+            let bytes = fs::read(path).with_context(|| format!("Could not read {}", path))?;
+            String::from_utf8(bytes)
+                .map_err(|_| anyhow!("{} is not UTF-8 text, which can't be included", path))?
+        }
+    };
+    check_size(path, text.len() as u64, max_size)?;
+    Ok(text)
+}
+
+/// The type of binary files, told by the bytes they start with, or None for
+/// what looks like text
+fn detect_mime_type(head: &[u8]) -> Option<&'static str> {
+    let mime_type = MAGIC_NUMBERS
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+        .map(|(_, mime_type)| *mime_type);
+    let riff_type = head.get(8..12).filter(|_| head.starts_with(b"RIFF"));
+    match (mime_type, riff_type) {
+        (Some(mime_type), _) => Some(mime_type),
+        (None, Some(b"WEBP")) => Some("image/webp"),
+        (None, Some(b"WAVE")) => Some("audio/wav"),
+        (None, Some(b"AVI ")) => Some("video/x-msvideo"),
+        // text hardly ever contains null bytes, while most binary formats do
+        _ if head.contains(&0) => Some("application/octet-stream"),
+        _ => None,
+    }
+}
+
+fn check_size(path: &str, size: u64, max_size: u64) -> Result<()> {
+    ensure!(
+        size <= max_size,
+        "{} is too large to include: {} bytes, max_file_size is {}",
+        path,
+        size,
+        max_size
+    );
+    Ok(())
+}
+
+fn pdf_text(path: &str) -> Result<String> {
+    let pages = pdf_extract::extract_text_by_pages(path)
+        .with_context(|| format!("Could not extract the text of {}", path))?;
+    Ok(with_page_markers(&pages))
+}
+
+fn docx_text(path: &str) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Could not read {}", path))?;
+    let invalid = || format!("{} is not a valid Word document", path);
+    let mut archive = ZipArchive::new(file).with_context(invalid)?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")
+        .with_context(invalid)?
+        .read_to_string(&mut xml)
+        .with_context(invalid)?;
+    document_text(&xml).with_context(invalid)
+}
+
+/// The paragraphs in the XML of a Word document, split into pages where Word
+/// last laid them out, if it did
+fn document_text(xml: &str) -> Result<String> {
+    let mut pages = vec![String::new()];
+    let mut reader = Reader::from_str(xml);
+    let mut in_text = false;
+    loop {
+        let page = pages.last_mut().unwrap();
+        match reader.read_event()? {
+            Event::Start(tag) if tag.name().as_ref() == b"w:t" => in_text = true,
+            Event::End(tag) if tag.name().as_ref() == b"w:t" => in_text = false,
+            Event::End(tag) if tag.name().as_ref() == b"w:p" => page.push('\n'),
+            Event::Empty(tag) => match tag.name().as_ref() {
+                b"w:tab" => page.push('\t'),
+                b"w:br" | b"w:cr" => page.push('\n'),
+                b"w:lastRenderedPageBreak" => pages.push(String::new()),
+                _ => {}
+            },
+            Event::Text(text) if in_text => page.push_str(&text.unescape()?),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(with_page_markers(&pages))
+}
+
+/// Joins the text of the pages of a document, each after a "[Page n]" line
+fn with_page_markers(pages: &[String]) -> String {
+    if pages.len() == 1 {
+        return pages[0].trim().to_owned();
+    }
+    pages
+        .iter()
+        .enumerate()
+        .map(|(index, page)| format!("[Page {}]\n{}", index + 1, page.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// A fence longer than any run of backticks in the text, so that code blocks
+/// inside it don't close it
+pub fn fence_for(text: &str) -> String {
+    let longest_run = text
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    "`".repeat(longest_run.max(2) + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_file_references() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("notes.md");
+        fs::write(&path, "Some code:\n```sh\nls\n```\n")?;
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            expand_file_references(&format!("Fix @{}? Thanks @none", path), 100)?,
+            format!(
+                "Fix\n````{}\nSome code:\n```sh\nls\n```\n````\n? Thanks @none",
+                path
+            )
+        );
+        assert_eq!(
+            expand_file_references("Write to support@example.com", 100)?,
+            "Write to support@example.com"
+        );
+        assert!(expand_file_references(&format!("@{}", path), 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn detects_binary_files() {
+        assert_eq!(detect_mime_type(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(
+            detect_mime_type(b"\x89PNG\r\n\x1a\n\x00\x00"),
+            Some("image/png")
+        );
+        assert_eq!(
+            detect_mime_type(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            detect_mime_type(b"\xca\xfe\xba\xbe\x00\x00"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(detect_mime_type("Ciao, £5\n".as_bytes()), None);
+        assert_eq!(detect_mime_type(b""), None);
+    }
+
+    #[test]
+    fn extracts_word_document_pages() -> Result<()> {
+        let xml = r#"<w:document><w:body>
+            <w:p><w:r>
+                <w:t>Fish &amp; chips</w:t><w:tab/><w:t xml:space="preserve">£5 </w:t>
+            </w:r></w:p>
+            <w:p><w:r><w:lastRenderedPageBreak/><w:t>The end</w:t></w:r></w:p>
+        </w:body></w:document>"#;
+        assert_eq!(
+            document_text(xml)?,
+            "[Page 1]\nFish & chips\t£5\n\n[Page 2]\nThe end"
+        );
+        Ok(())
+    }
+}
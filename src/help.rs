@@ -0,0 +1,264 @@
+use std::fmt::Write;
+
+use crate::completions::CommandSpec;
+
+/// A special prompt in interactive mode, as listed by "/help" and "--help-long"
+pub struct ReplCommand {
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+pub const REPL_COMMANDS: &[ReplCommand] = &[
+    ReplCommand {
+        usage: "/help",
+        description: "list these commands",
+    },
+    ReplCommand {
+        usage: "/quit, /q",
+        description: "exit, like Ctrl+D",
+    },
+    ReplCommand {
+        usage: "/retry, /r",
+        description: "resend the last prompt",
+    },
+    ReplCommand {
+        usage: "/continue, /c",
+        description: "continue a truncated response",
+    },
+    ReplCommand {
+        usage: "/last, /l",
+        description: "show the last response in a pager",
+    },
+    ReplCommand {
+        usage: "/tokens",
+        description: "count the tokens in the conversation",
+    },
+    ReplCommand {
+        usage: "/code [copy] [n] [> file]",
+        description: "list the code blocks in the last response, print, copy or save one",
+    },
+    ReplCommand {
+        usage: "/apply",
+        description: "write the file changes in the last response to disk",
+    },
+    ReplCommand {
+        usage: "/save [name]",
+        description: "save the conversation, and keep saving it after each response",
+    },
+    ReplCommand {
+        usage: "/branch [name]",
+        description: "fork the conversation into a new branch, or list branches",
+    },
+    ReplCommand {
+        usage: "/switch <name>",
+        description: "go back to another branch",
+    },
+    ReplCommand {
+        usage: "/paste [text]",
+        description: "send the clipboard contents, after the optional text",
+    },
+    ReplCommand {
+        usage: "/as <name> <prompt>",
+        description: "send a prompt on behalf of a named participant",
+    },
+    ReplCommand {
+        usage: "/voice <file>",
+        description: "transcribe an audio recording and send it",
+    },
+    ReplCommand {
+        usage: "/set <setting> <value|off>",
+        description: "change temperature, max_tokens or seed for the next requests",
+    },
+    ReplCommand {
+        usage: "/settings",
+        description: "show the current values of the settings",
+    },
+    ReplCommand {
+        usage: "/set <name>=<value>",
+        description: "set a variable, replacing \"{name}\" in the next prompts",
+    },
+    ReplCommand {
+        usage: "/set",
+        description: "list the variables",
+    },
+    ReplCommand {
+        usage: "/prefill [text]",
+        description: "start the next responses with this text, or stop",
+    },
+    ReplCommand {
+        usage: "/snippet save <name> [text]",
+        description: "save a snippet, by default the last prompt",
+    },
+    ReplCommand {
+        usage: "/snippet [name]",
+        description: "send a saved snippet, or list them all",
+    },
+];
+
+/// Example invocations shown at the end of "--help-long"
+const EXAMPLES: &[(&str, &str)] = &[
+    ("lmc", "chat using the \"default\" profile"),
+    (
+        "lmc -p groq -m llama-3.1-8b-instant",
+        "chat with another profile and model",
+    ),
+    (
+        "git diff | lmc -s \"Review this diff\"",
+        "send piped input and print the response",
+    ),
+    (
+        "lmc @review < diff.patch",
+        "run an alias from the \"[aliases]\" table",
+    ),
+    ("lmc init", "write a starter configuration file"),
+    (
+        "lmc config check --ping",
+        "validate all profiles and try each API",
+    ),
+];
+
+/// Lists the interactive commands with their descriptions aligned
+pub fn repl_help() -> String {
+    let width = REPL_COMMANDS
+        .iter()
+        .map(|command| command.usage.len())
+        .max()
+        .unwrap_or_default();
+    let mut help = String::new();
+    for command in REPL_COMMANDS {
+        let _ = writeln!(
+            help,
+            "  {:width$}  {}",
+            command.usage,
+            command.description,
+            width = width
+        );
+    }
+    help
+}
+
+/// The complete reference: the help of every (sub)command, the interactive
+/// commands, the configuration settings and some examples
+pub fn long_help(
+    command: &CommandSpec,
+    help: &dyn Fn(&[&str]) -> String,
+    settings: &[&str],
+) -> String {
+    let mut text = String::new();
+    add_command_help(command, &mut Vec::new(), help, &mut text);
+    let _ = writeln!(text, "Interactive Commands:\n{}", repl_help());
+    let _ = writeln!(
+        text,
+        "Configuration:\n  \
+        Profiles are TOML tables in $XDG_CONFIG_HOME/lmc/config.toml or $HOME/.lmc/config.toml,\n  \
+        or the file given with --config or $LMC_CONFIG, plus any .lmc.toml in the current\n  \
+        directory or its parents. Every setting can also be set with an LMC_<SETTING>\n  \
+        environment variable. The top-level \"include\" list and \"[aliases]\" table are not\n  \
+        profiles.\n\n  \
+        Profile settings:"
+    );
+    let mut settings = settings.to_vec();
+    settings.sort_unstable();
+    for line in settings.chunks(4) {
+        let _ = writeln!(text, "    {}", line.join(", "));
+    }
+    let _ = writeln!(text, "\nExamples:");
+    let width = EXAMPLES
+        .iter()
+        .map(|(example, _)| example.len())
+        .max()
+        .unwrap_or_default();
+    for (example, description) in EXAMPLES {
+        let _ = writeln!(text, "  {:width$}  {}", example, description, width = width);
+    }
+    text
+}
+
+fn add_command_help<'a>(
+    command: &'a CommandSpec,
+    path: &mut Vec<&'a str>,
+    help: &dyn Fn(&[&str]) -> String,
+    text: &mut String,
+) {
+    let _ = writeln!(text, "{}", help(path).trim_end());
+    text.push('\n');
+    for sub in &command.commands {
+        path.push(&sub.name);
+        add_command_help(sub, path, help, text);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn aligns_repl_commands() {
+        let help = repl_help();
+        assert_eq!(help.lines().count(), REPL_COMMANDS.len());
+        let column = help
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .find("list")
+            .unwrap_or_default();
+        assert!(help
+            .lines()
+            .all(|line| line.chars().nth(column - 1) == Some(' ')));
+        assert!(help.contains("  /snippet save <name> [text]  save a snippet"));
+    }
+
+    #[test]
+    fn lists_the_commands_handled() {
+        // the interactive loop matches e.g. `command == "/retry"` or
+        // `command.starts_with("/save ")`
+        let source = include_str!("main.rs");
+        let handled: BTreeSet<&str> = ["command == \"", "command.starts_with(\""]
+            .into_iter()
+            .flat_map(|pattern| source.match_indices(pattern))
+            .map(|(index, pattern)| {
+                let name = &source[index + pattern.len()..];
+                &name[..name.find(['"', ' ']).unwrap_or_default()]
+            })
+            .collect();
+        let listed: BTreeSet<&str> = REPL_COMMANDS
+            .iter()
+            .flat_map(|command| command.usage.split(", "))
+            .map(|usage| usage.split(' ').next().unwrap_or_default())
+            .collect();
+        assert_eq!(handled, listed);
+    }
+
+    #[test]
+    fn includes_every_command() {
+        let spec = CommandSpec {
+            name: String::from("lmc"),
+            description: String::new(),
+            flags: Vec::new(),
+            commands: vec![CommandSpec {
+                name: String::from("init"),
+                description: String::new(),
+                flags: Vec::new(),
+                commands: Vec::new(),
+            }],
+        };
+        let help = |path: &[&str]| {
+            format!(
+                "Usage: {}\n",
+                ["lmc"]
+                    .iter()
+                    .chain(path)
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        };
+        let text = long_help(&spec, &help, &["model", "api_url"]);
+        assert!(text.starts_with("Usage: lmc\n\nUsage: lmc init\n\nInteractive Commands:\n  /help"));
+        assert!(text.contains("    api_url, model\n"));
+        assert!(text.contains("  lmc init  "));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::markdown::is_closing_fence;
+
+const BOLD: &str = "\x1b[1m";
+const CODE: &str = "\x1b[36m";
+const FENCE: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Styles Markdown for the terminal as it is streamed: headings and "**strong**"
+/// text in bold, code blocks and `inline code` in cyan. Each line is written as
+/// soon as its style is certain, so long lines appear as they arrive.
+pub struct Highlighter {
+    line: String,
+    /// bytes of the current line already written
+    written: usize,
+    /// how the written part of the line was styled, "" for prose
+    style: Option<&'static str>,
+    fence: Option<char>,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        Self {
+            line: String::new(),
+            written: 0,
+            style: None,
+            fence: None,
+        }
+    }
+
+    /// Returns the styled text that can be written so far
+    pub fn push(&mut self, text: &str) -> String {
+        let mut output = String::new();
+        for c in text.chars() {
+            if c == '\n' {
+                output.push_str(&self.finish());
+                output.push('\n');
+            } else {
+                self.line.push(c);
+            }
+        }
+        output.push_str(&self.write_partial());
+        output
+    }
+
+    /// Returns the rest of the current line
+    pub fn finish(&mut self) -> String {
+        let line = std::mem::take(&mut self.line);
+        let written = std::mem::take(&mut self.written);
+        match self.style.take() {
+            None => self.highlight_line(&line),
+            Some(style) => style_part(style, &line[written..]),
+        }
+    }
+
+    /// Writes the part of the current line whose style can't change any more:
+    /// headings and code as they come, prose up to any span still open
+    fn write_partial(&mut self) -> String {
+        let style = match self.style {
+            Some(style) => style,
+            None => {
+                let Some(first) = self.line.trim_start().chars().next() else {
+                    return String::new();
+                };
+                match self.fence {
+                    // could be the closing fence
+                    Some(fence) if first == fence => return String::new(),
+                    Some(_) => CODE,
+                    // could be an opening fence
+                    None if first == '`' || first == '~' => return String::new(),
+                    None if first == '#' => BOLD,
+                    None => "",
+                }
+            }
+        };
+        self.style = Some(style);
+        let rest = &self.line[self.written..];
+        let length = match style {
+            "" => stable_length(rest),
+            _ => rest.len(),
+        };
+        let part = style_part(style, &rest[..length]);
+        self.written += length;
+        part
+    }
+
+    fn highlight_line(&mut self, line: &str) -> String {
+        if line.is_empty() {
+            return String::new();
+        }
+        let trimmed = line.trim();
+        match self.fence {
+            Some(fence) if is_closing_fence(trimmed, fence) => {
+                self.fence = None;
+                format!("{}{}{}", FENCE, line, RESET)
+            }
+            Some(_) => format!("{}{}{}", CODE, line, RESET),
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                self.fence = trimmed.chars().next();
+                format!("{}{}{}", FENCE, line, RESET)
+            }
+            None if trimmed.starts_with('#') => format!("{}{}{}", BOLD, line, RESET),
+            None => highlight_inline(line),
+        }
+    }
+}
+
+fn style_part(style: &str, text: &str) -> String {
+    match (style, text) {
+        (_, "") => String::new(),
+        ("", _) => highlight_inline(text),
+        _ => format!("{}{}{}", style, text, RESET),
+    }
+}
+
+/// The length of the start of some prose that more text can't change the style
+/// of, i.e. up to the first "`" or "**" that isn't closed yet
+fn stable_length(text: &str) -> usize {
+    let mut position = 0;
+    loop {
+        let Some(start) = text[position..].find(['`', '*']) else {
+            return text.len();
+        };
+        let rest = &text[position + start..];
+        let marker = if rest.starts_with('`') {
+            "`"
+        } else if rest.starts_with("**") {
+            "**"
+        } else if rest.len() > 1 {
+            // a single "*" is left as it is
+            position += start + 1;
+            continue;
+        } else {
+            // the start of "**" maybe
+            return position + start;
+        };
+        match rest[marker.len()..].find(marker) {
+            Some(end) if end > 0 => position += start + end + 2 * marker.len(),
+            _ => return position + start,
+        }
+    }
+}
+
+/// Styles a whole response at once
+pub fn highlight(text: &str) -> String {
+    let mut highlighter = Highlighter::new();
+    let mut output = highlighter.push(text);
+    output.push_str(&highlighter.finish());
+    output
+}
+
+fn highlight_inline(line: &str) -> String {
+    let mut output = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(['`', '*']) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (marker, style) = if rest.starts_with('`') {
+            ("`", CODE)
+        } else if rest.starts_with("**") {
+            ("**", BOLD)
+        } else {
+            ("*", "")
+        };
+        let end = rest[marker.len()..]
+            .find(marker)
+            .map(|end| end + 2 * marker.len())
+            .filter(|end| *end > 2 * marker.len() && !style.is_empty());
+        match end {
+            Some(end) => {
+                output.push_str(&format!("{}{}{}", style, &rest[..end], RESET));
+                rest = &rest[end..];
+            }
+            None => {
+                output.push_str(marker);
+                rest = &rest[marker.len()..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_markdown() {
+        let text = "# Title\nRun `ls` **now**, * not this\n```sh\nls -l\n```\nDone";
+        assert_eq!(
+            highlight(text),
+            "\x1b[1m# Title\x1b[0m\n\
+            Run \x1b[36m`ls`\x1b[0m \x1b[1m**now**\x1b[0m, * not this\n\
+            \x1b[2m```sh\x1b[0m\n\
+            \x1b[36mls -l\x1b[0m\n\
+            \x1b[2m```\x1b[0m\n\
+            Done"
+        );
+    }
+
+    #[test]
+    fn highlights_split_tokens() {
+        let mut highlighter = Highlighter::new();
+        let tokens = ["Use `c", "argo` to", "\n```\nc", "argo build\n``", "`\n"];
+        let mut output: String = tokens.iter().map(|t| highlighter.push(t)).collect();
+        output.push_str(&highlighter.finish());
+        assert_eq!(
+            output,
+            "Use \x1b[36m`cargo`\x1b[0m to\n\x1b[2m```\x1b[0m\n\x1b[36mc\x1b[0m\x1b[36margo build\x1b[0m\n\x1b[2m```\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn highlights_multibyte_text() {
+        let mut highlighter = Highlighter::new();
+        let tokens = [
+            "# 見出し 🎉\n",
+            "Ünïcödé `日本",
+            "語` and **こん",
+            "にちは**",
+            " 😀",
+        ];
+        let mut output: String = tokens.iter().map(|t| highlighter.push(t)).collect();
+        output.push_str(&highlighter.finish());
+        assert_eq!(
+            output,
+            "\x1b[1m# 見出し 🎉\x1b[0m\nÜnïcödé \x1b[36m`日本語`\x1b[0m and \x1b[1m**こんにちは**\x1b[0m 😀"
+        );
+    }
+
+    #[test]
+    fn writes_lines_as_they_arrive() {
+        let mut highlighter = Highlighter::new();
+        assert_eq!(highlighter.push("Hello wor"), "Hello wor");
+        assert_eq!(highlighter.push("ld, run `car"), "ld, run ");
+        assert_eq!(highlighter.push("go` and *"), "\x1b[36m`cargo`\x1b[0m and ");
+        assert_eq!(highlighter.push("* not"), "");
+        assert_eq!(
+            highlighter.push("e**, 2 * 3"),
+            "\x1b[1m** note**\x1b[0m, 2 * 3"
+        );
+        assert_eq!(highlighter.push("\n## Ti"), "\n\x1b[1m## Ti\x1b[0m");
+        assert_eq!(highlighter.push("tle\n`"), "\x1b[1mtle\x1b[0m\n");
+        assert_eq!(
+            highlighter.push("``\nfn"),
+            "\x1b[2m```\x1b[0m\n\x1b[36mfn\x1b[0m"
+        );
+        assert_eq!(highlighter.push(" main\n`"), "\x1b[36m main\x1b[0m\n");
+        assert_eq!(highlighter.push("``"), "");
+        assert_eq!(highlighter.finish(), "\x1b[2m```\x1b[0m");
+    }
+}
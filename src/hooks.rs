@@ -0,0 +1,60 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use anyhow::{ensure, Context, Result};
+
+/// Runs a hook command through the shell, with the input piped to it, and
+/// returns what it prints. A hook that fails makes the request fail too.
+pub fn run_hook(command: &str, input: &str) -> Result<String> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not run \"{}\"", command))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_owned();
+    // written from another thread, so that a hook printing a lot as it reads
+    // doesn't block on a full pipe
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Could not run \"{}\"", command))?;
+    // the hook may exit without reading all of its input, which is fine
+    let _ = writer.join();
+    ensure!(
+        output.status.success(),
+        "\"{}\" failed ({}): {}",
+        command,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("\"{}\" did not print UTF-8 text", command))
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipes_through_hooks() -> Result<()> {
+        assert_eq!(run_hook("tr a-z A-Z", "hello")?, "HELLO");
+        assert_eq!(run_hook("echo hi", &"x".repeat(1_000_000))?, "hi\n");
+        assert!(run_hook("echo oops >&2; exit 3", "hello").is_err());
+        Ok(())
+    }
+}
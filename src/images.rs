@@ -0,0 +1,225 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Command;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// Maximum size of each piece of image data sent with the kitty protocol
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// An image in a response, either linked or embedded as a data URI
+#[derive(Debug, PartialEq)]
+pub enum Image {
+    Url(String),
+    Data { extension: String, base64: String },
+}
+
+impl Image {
+    /// Turns the embedded data into bytes; None for links, or invalid data
+    pub fn decode(&self) -> Option<Vec<u8>> {
+        match self {
+            Image::Url(_) => None,
+            Image::Data { base64, .. } => STANDARD.decode(base64).ok(),
+        }
+    }
+}
+
+/// The ways some terminals can display images inline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphicsProtocol {
+    Kitty,
+    Iterm,
+    Sixel,
+}
+
+/// Finds the Markdown images linked with "![alt](https://...)" and the data URIs
+/// like "data:image/png;base64,..." anywhere in the text
+pub fn find_images(text: &str) -> Vec<Image> {
+    let mut images = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("![") {
+        rest = &rest[start + 2..];
+        let Some(link) = rest.find("](").map(|end| &rest[end + 2..]) else {
+            break;
+        };
+        let url = link.split([')', ' ']).next().unwrap_or_default();
+        if url.starts_with("https://") || url.starts_with("http://") {
+            images.push(Image::Url(url.to_owned()));
+        }
+    }
+    let mut rest = text;
+    while let Some(start) = rest.find("data:image/") {
+        rest = &rest[start + "data:image/".len()..];
+        let Some((subtype, data)) = rest.split_once(";base64,") else {
+            break;
+        };
+        if subtype.is_empty()
+            || !subtype
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+')
+        {
+            continue;
+        }
+        let length = data
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "+/=".contains(c)))
+            .unwrap_or(data.len());
+        if length > 0 {
+            let extension = match subtype {
+                "jpeg" => "jpg",
+                "svg+xml" => "svg",
+                other => other,
+            };
+            images.push(Image::Data {
+                extension: extension.to_owned(),
+                base64: data[..length].to_owned(),
+            });
+        }
+        rest = &data[length..];
+    }
+    images
+}
+
+/// Guesses from the environment which graphics protocol the terminal supports
+pub fn detect_protocol() -> Option<GraphicsProtocol> {
+    let term = env::var("TERM").unwrap_or_default();
+    let term_program = env::var("TERM_PROGRAM").unwrap_or_default();
+    protocol_for(
+        &term,
+        &term_program,
+        env::var_os("KITTY_WINDOW_ID").is_some(),
+    )
+}
+
+fn protocol_for(term: &str, term_program: &str, kitty: bool) -> Option<GraphicsProtocol> {
+    if kitty || term.contains("kitty") || term_program == "ghostty" {
+        Some(GraphicsProtocol::Kitty)
+    } else if term_program == "iTerm.app" || term_program == "WezTerm" {
+        Some(GraphicsProtocol::Iterm)
+    } else if term.contains("sixel") || term.starts_with("foot") || term_program == "mlterm" {
+        Some(GraphicsProtocol::Sixel)
+    } else {
+        None
+    }
+}
+
+/// Displays an image inline, returning false if the protocol can't show it
+pub fn render_image(bytes: &[u8], protocol: GraphicsProtocol) -> io::Result<bool> {
+    let mut stdout = io::stdout();
+    match protocol {
+        // kitty only takes PNG files as they are
+        GraphicsProtocol::Kitty if !bytes.starts_with(b"\x89PNG") => return Ok(false),
+        GraphicsProtocol::Kitty => write!(stdout, "{}", kitty_sequence(&STANDARD.encode(bytes)))?,
+        GraphicsProtocol::Iterm => write!(
+            stdout,
+            "\x1b]1337;File=inline=1;size={}:{}\x07",
+            bytes.len(),
+            STANDARD.encode(bytes)
+        )?,
+        GraphicsProtocol::Sixel => {
+            let path = save_image(bytes, "img")?;
+            let status = Command::new("img2sixel").arg(&path).status();
+            fs::remove_file(&path)?;
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(_) => return Ok(false),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+    writeln!(stdout)?;
+    stdout.flush()?;
+    Ok(true)
+}
+
+/// Sends the data in pieces, each with "m=1" if more follow
+fn kitty_sequence(base64: &str) -> String {
+    let chunks: Vec<&[u8]> = base64.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    let mut sequence = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let keys = if index == 0 { "f=100,a=T," } else { "" };
+        let more = u8::from(index + 1 < chunks.len());
+        sequence.push_str(&format!(
+            "\x1b_G{}m={};{}\x1b\\",
+            keys,
+            more,
+            String::from_utf8_lossy(chunk)
+        ));
+    }
+    sequence
+}
+
+/// Saves an image to a new file in the temporary directory
+pub fn save_image(bytes: &[u8], extension: &str) -> io::Result<PathBuf> {
+    let dir = env::temp_dir();
+    let mut counter = 1;
+    let mut path = dir.join(format!("lmc-image.{}", extension));
+    while fs::exists(&path)? {
+        counter += 1;
+        path = dir.join(format!("lmc-image-{}.{}", counter, extension));
+    }
+    fs::write(&path, bytes)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_images() {
+        let text = "A cat: ![cat](https://example.com/cat.png \"Cat\")\n\
+            and a dot ![](data:image/png;base64,iVBORw0KGgo=) plus [a link](https://example.com)\n\
+            and data:image/jpeg;base64,/9j/4AAQ== too";
+        assert_eq!(
+            find_images(text),
+            vec![
+                Image::Url(String::from("https://example.com/cat.png")),
+                Image::Data {
+                    extension: String::from("png"),
+                    base64: String::from("iVBORw0KGgo="),
+                },
+                Image::Data {
+                    extension: String::from("jpg"),
+                    base64: String::from("/9j/4AAQ=="),
+                },
+            ]
+        );
+        assert_eq!(find_images("data:image/;base64,abc"), vec![]);
+    }
+
+    #[test]
+    fn detects_protocols() {
+        assert_eq!(
+            protocol_for("xterm-kitty", "", false),
+            Some(GraphicsProtocol::Kitty)
+        );
+        assert_eq!(
+            protocol_for("xterm-256color", "iTerm.app", false),
+            Some(GraphicsProtocol::Iterm)
+        );
+        assert_eq!(
+            protocol_for("foot", "", false),
+            Some(GraphicsProtocol::Sixel)
+        );
+        assert_eq!(
+            protocol_for("xterm-256color", "Apple_Terminal", false),
+            None
+        );
+    }
+
+    #[test]
+    fn splits_kitty_data() {
+        let data = "A".repeat(KITTY_CHUNK_SIZE + 2);
+        let sequence = kitty_sequence(&data);
+        let expected = format!(
+            "\x1b_Gf=100,a=T,m=1;{}\x1b\\\x1b_Gm=0;AA\x1b\\",
+            "A".repeat(KITTY_CHUNK_SIZE)
+        );
+        assert_eq!(sequence, expected);
+        assert_eq!(kitty_sequence("AAAA"), "\x1b_Gf=100,a=T,m=0;AAAA\x1b\\");
+    }
+}
@@ -0,0 +1,293 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::api::{Message, Role};
+
+/// The formats "lmc import" reads conversations from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportFormat {
+    /// "conversations.json" from a ChatGPT data export
+    ChatGpt,
+    /// a list of {"role", "content"} objects, as sent to the API, or an object
+    /// with such a "messages" list
+    Messages,
+    /// a transcript with a heading for each message naming its role, as printed
+    /// by "lmc history show"
+    Markdown,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "chatgpt" => Ok(Self::ChatGpt),
+            "messages" => Ok(Self::Messages),
+            "markdown" => Ok(Self::Markdown),
+            _ => Err(format!("Unsupported format: \"{}\"", value)),
+        }
+    }
+}
+
+/// A conversation found in an imported file
+#[derive(Debug, PartialEq)]
+pub struct Conversation {
+    pub title: Option<String>,
+    /// seconds since the Unix epoch
+    pub created: Option<u64>,
+    pub messages: Vec<Message>,
+}
+
+/// Reads the conversations in a file, telling the format from its contents if
+/// not given
+pub fn parse_conversations(
+    source: &str,
+    format: Option<ImportFormat>,
+) -> Result<Vec<Conversation>> {
+    let json = serde_json::from_str::<Value>(source);
+    let format = match (format, &json) {
+        (Some(format), _) => format,
+        (None, Ok(value)) if is_chatgpt_export(value) => ImportFormat::ChatGpt,
+        (None, Ok(_)) => ImportFormat::Messages,
+        (None, Err(_)) => ImportFormat::Markdown,
+    };
+    let conversations = match format {
+        ImportFormat::Markdown => vec![parse_markdown(source)],
+        _ => {
+            let value = json.context("Invalid JSON")?;
+            if format == ImportFormat::ChatGpt {
+                parse_chatgpt(&value)?
+            } else {
+                vec![parse_messages(&value)?]
+            }
+        }
+    };
+    let conversations: Vec<Conversation> = conversations
+        .into_iter()
+        .filter(|conversation| !conversation.messages.is_empty())
+        .collect();
+    if conversations.is_empty() {
+        bail!("No messages found");
+    }
+    Ok(conversations)
+}
+
+fn is_chatgpt_export(value: &Value) -> bool {
+    let first = value
+        .as_array()
+        .and_then(|list| list.first())
+        .unwrap_or(value);
+    first.get("mapping").is_some()
+}
+
+/// Follows each conversation from its current message back to the first one,
+/// as it was last shown, skipping edited versions and hidden messages
+fn parse_chatgpt(value: &Value) -> Result<Vec<Conversation>> {
+    let list = match value.as_array() {
+        Some(list) => list.iter().collect(),
+        None => vec![value],
+    };
+    let mut conversations = Vec::new();
+    for conversation in list {
+        let mapping = conversation["mapping"]
+            .as_object()
+            .context("Invalid ChatGPT export: no \"mapping\"")?;
+        let mut messages = Vec::new();
+        let mut id = conversation["current_node"].as_str();
+        // guards against cycles in invalid files
+        for _ in 0..mapping.len() {
+            let Some(node) = id.and_then(|id| mapping.get(id)) else {
+                break;
+            };
+            messages.extend(chatgpt_message(&node["message"]));
+            id = node["parent"].as_str();
+        }
+        messages.reverse();
+        conversations.push(Conversation {
+            title: conversation["title"].as_str().map(str::to_owned),
+            created: conversation["create_time"].as_f64().map(|time| time as u64),
+            messages,
+        });
+    }
+    Ok(conversations)
+}
+
+fn chatgpt_message(message: &Value) -> Option<Message> {
+    if message["metadata"]["is_visually_hidden_from_conversation"] == true {
+        return None;
+    }
+    // tool messages can't be sent back without the calls they answer
+    let role = match message["author"]["role"].as_str()? {
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        "system" => Role::System,
+        _ => return None,
+    };
+    let text = message["content"]["parts"]
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_str)
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!text.trim().is_empty()).then(|| Message::new(role, &text))
+}
+
+fn parse_messages(value: &Value) -> Result<Conversation> {
+    let list = value
+        .as_array()
+        .or_else(|| value["messages"].as_array())
+        .context("Expected a list of messages, or an object with a \"messages\" list")?;
+    let mut messages = Vec::new();
+    for (index, item) in list.iter().enumerate() {
+        let role: Role = serde_json::from_value(item["role"].to_owned())
+            .with_context(|| format!("Invalid role in message {}", index + 1))?;
+        // the content may also be a list of parts, of which only text is kept
+        let content = match &item["content"] {
+            Value::String(text) => text.to_owned(),
+            Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        };
+        if content.trim().is_empty() {
+            continue;
+        }
+        let mut message = Message::new(role, &content);
+        message.name = item["name"].as_str().map(str::to_owned);
+        messages.push(message);
+    }
+    Ok(Conversation {
+        title: value["title"].as_str().map(str::to_owned),
+        created: None,
+        messages,
+    })
+}
+
+/// Splits a transcript at the headings naming a role, e.g. "## User" or
+/// "## Assistant (alice)", outside of code blocks. Another heading before the
+/// first message is taken as the title.
+fn parse_markdown(source: &str) -> Conversation {
+    let mut title = None;
+    let mut messages: Vec<Message> = Vec::new();
+    let mut in_code_block = false;
+    for line in source.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        let heading = line
+            .strip_prefix('#')
+            .filter(|_| !in_code_block)
+            .map(|heading| heading.trim_start_matches('#').trim());
+        if let Some(heading) = heading {
+            let (role, name) = match heading.split_once(" (") {
+                Some((role, name)) => (role, name.strip_suffix(')')),
+                None => (heading, None),
+            };
+            let role = match role.to_lowercase().as_str() {
+                "user" => Some(Role::User),
+                "assistant" => Some(Role::Assistant),
+                "system" => Some(Role::System),
+                "developer" => Some(Role::Developer),
+                _ => None,
+            };
+            if let Some(role) = role {
+                let mut message = Message::new(role, &String::new());
+                message.name = name.map(str::to_owned);
+                messages.push(message);
+                continue;
+            }
+            if messages.is_empty() && title.is_none() {
+                title = Some(heading.to_owned());
+                continue;
+            }
+        }
+        if let Some(message) = messages.last_mut() {
+            message.content.push_str(line);
+            message.content.push('\n');
+        }
+    }
+    for message in &mut messages {
+        message.content = message.content.trim().to_owned();
+    }
+    messages.retain(|message| !message.content.is_empty());
+    Conversation {
+        title,
+        created: None,
+        messages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_chatgpt_branches() -> Result<()> {
+        let export = r#"[{
+            "title": "Greetings",
+            "create_time": 1709335800.5,
+            "current_node": "c",
+            "mapping": {
+                "root": {"message": null, "parent": null},
+                "a": {"message": {"author": {"role": "user"}, "content": {"parts": ["Hi"]}}, "parent": "root"},
+                "b": {"message": {"author": {"role": "assistant"}, "content": {"parts": ["Ciao"]}}, "parent": "a"},
+                "c": {"message": {"author": {"role": "assistant"}, "content": {"parts": ["Hello!"]}}, "parent": "a"}
+            }
+        }]"#;
+        let conversations = parse_conversations(export, None)?;
+        assert_eq!(
+            conversations,
+            vec![Conversation {
+                title: Some(String::from("Greetings")),
+                created: Some(1709335800),
+                messages: vec![
+                    Message::new(Role::User, &String::from("Hi")),
+                    Message::new(Role::Assistant, &String::from("Hello!")),
+                ],
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_api_messages() -> Result<()> {
+        let json = r#"{"messages": [
+            {"role": "system", "content": "Be brief"},
+            {"role": "user", "content": [{"type": "text", "text": "Hi"}], "name": "alice"}
+        ]}"#;
+        let messages = &parse_conversations(json, None)?[0].messages;
+        assert_eq!(
+            messages[0],
+            Message::new(Role::System, &String::from("Be brief"))
+        );
+        assert_eq!(
+            messages[1],
+            Message::new(Role::User, &String::from("Hi")).with_name("alice")
+        );
+        assert!(parse_conversations("[]", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn splits_markdown_at_role_headings() -> Result<()> {
+        let transcript = "# Rust questions\n\n## User (alice)\n\nHow do I print?\n\n\
+            ## Assistant\n\nLike this:\n\n```md\n## User\n```\n";
+        let conversation = &parse_conversations(transcript, None)?[0];
+        assert_eq!(conversation.title.as_deref(), Some("Rust questions"));
+        assert_eq!(
+            conversation.messages,
+            vec![
+                Message::new(Role::User, &String::from("How do I print?")).with_name("alice"),
+                Message::new(
+                    Role::Assistant,
+                    &String::from("Like this:\n\n```md\n## User\n```")
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use crate::api::{Message, Role};
+
+/// Value of the "language" setting that follows the language of each prompt
+const AUTO: &str = "auto";
+
+/// The English names of common languages, by their ISO 639-1 code
+const LANGUAGE_CODES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("de", "German"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("hi", "Hindi"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("sv", "Swedish"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("zh", "Chinese"),
+];
+
+/// The instruction added to the system prompt for the "language" setting: to
+/// answer in the language given, or with "auto" in that of the last prompt, if
+/// it can be detected reliably
+pub fn language_instruction(language: &str, messages: &[Message]) -> Option<String> {
+    let language = if language == AUTO {
+        let prompt = messages
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)?;
+        detect_language(&prompt.content)?
+    } else {
+        language
+    };
+    Some(format!("Always answer in {}.", language))
+}
+
+/// The instructions for "lmc translate", with the text to translate sent as the
+/// user message. Languages can be given by name or code, and with "auto" the
+/// source language is detected if possible, or else left to the model.
+pub fn translation_prompt(text: &str, from: &str, to: &str) -> String {
+    let from = if from == AUTO {
+        detect_language(text)
+    } else {
+        Some(language_name(from))
+    };
+    let languages = match from {
+        Some(from) => format!("from {} into {}", from, language_name(to)),
+        None => format!("into {}", language_name(to)),
+    };
+    format!(
+        "Translate the text provided {}. Keep its formatting exactly as it is, including \
+        line breaks, Markdown, code blocks, placeholders and URLs, and translate only the \
+        natural language, not code. Answer with the translation only, without any notes.",
+        languages
+    )
+}
+
+/// The name of a language given by ISO 639-1 code, or as it is
+fn language_name(language: &str) -> &str {
+    LANGUAGE_CODES
+        .iter()
+        .find(|(code, _)| code.eq_ignore_ascii_case(language))
+        .map_or(language, |(_, name)| name)
+}
+
+/// The English name of the language a text is written in
+fn detect_language(text: &str) -> Option<&'static str> {
+    whatlang::detect(text)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang().eng_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(text: &str) -> Vec<Message> {
+        vec![Message::new(Role::User, &String::from(text))]
+    }
+
+    #[test]
+    fn instructs_to_answer_in_language() {
+        assert_eq!(
+            language_instruction("Spanish", &prompt("Hello")),
+            Some(String::from("Always answer in Spanish."))
+        );
+        assert_eq!(
+            language_instruction(
+                "auto",
+                &prompt(
+                    "Ciao, vorrei sapere come si prepara una buona pizza napoletana a casa, e \
+                    quanto tempo deve lievitare l'impasto prima di infornarla."
+                )
+            ),
+            Some(String::from("Always answer in Italian."))
+        );
+        assert_eq!(language_instruction("auto", &prompt("ok")), None);
+        assert_eq!(language_instruction("auto", &[]), None);
+    }
+
+    #[test]
+    fn names_translation_languages() {
+        let prompt = translation_prompt(
+            "Ciao, vorrei sapere come si prepara una buona pizza napoletana a casa, e quanto \
+            tempo deve lievitare l'impasto prima di infornarla.",
+            "auto",
+            "FR",
+        );
+        assert!(prompt.starts_with("Translate the text provided from Italian into French."));
+        let prompt = translation_prompt("ok", "auto", "Brazilian Portuguese");
+        assert!(prompt.starts_with("Translate the text provided into Brazilian Portuguese."));
+    }
+}
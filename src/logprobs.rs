@@ -0,0 +1,118 @@
+use std::fmt::Write;
+
+use crate::api::TokenLogprob;
+
+const HIGH: &str = "\x1b[32m";
+const MEDIUM: &str = "\x1b[33m";
+const LOW: &str = "\x1b[31m";
+
+/// The colour of a token, by how likely the model considered it: green from 90%,
+/// yellow from 50%, red below
+pub fn confidence_style(logprob: f64) -> &'static str {
+    let probability = logprob.exp();
+    if probability >= 0.9 {
+        HIGH
+    } else if probability >= 0.5 {
+        MEDIUM
+    } else {
+        LOW
+    }
+}
+
+/// Lists each token of a response with its probability and the alternatives the
+/// model considered, quoted so that whitespace is visible
+pub fn logprobs_table(tokens: &[TokenLogprob]) -> String {
+    let rows: Vec<(String, String)> = tokens
+        .iter()
+        .map(|token| {
+            let alternatives = token
+                .top_logprobs
+                .iter()
+                .filter(|alternative| alternative.token != token.token)
+                .map(|alternative| {
+                    format!(
+                        "{:?} {}",
+                        alternative.token,
+                        percentage(alternative.logprob)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            (format!("{:?}", token.token), alternatives)
+        })
+        .collect();
+    let width = rows
+        .iter()
+        .map(|(token, _)| token.chars().count())
+        .chain(["Token".len()])
+        .max()
+        .unwrap_or_default();
+    let mut table = String::new();
+    let _ = writeln!(
+        table,
+        "{:width$}  {:>6}  Alternatives",
+        "Token",
+        "Prob",
+        width = width
+    );
+    for ((token, alternatives), logprob) in rows.iter().zip(tokens) {
+        let line = format!(
+            "{:width$}  {:>6}  {}",
+            token,
+            percentage(logprob.logprob),
+            alternatives,
+            width = width
+        );
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table
+}
+
+fn percentage(logprob: f64) -> String {
+    format!("{:.1}%", logprob.exp() * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::AlternativeLogprob;
+
+    fn token(text: &str, logprob: f64, alternatives: &[(&str, f64)]) -> TokenLogprob {
+        TokenLogprob {
+            token: text.to_owned(),
+            logprob,
+            top_logprobs: alternatives
+                .iter()
+                .map(|(token, logprob)| AlternativeLogprob {
+                    token: token.to_string(),
+                    logprob: *logprob,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn colours_by_confidence() {
+        assert_eq!(confidence_style(0.0), HIGH);
+        assert_eq!(confidence_style(0.6_f64.ln()), MEDIUM);
+        assert_eq!(confidence_style(0.2_f64.ln()), LOW);
+    }
+
+    #[test]
+    fn lists_tokens_with_alternatives() {
+        let tokens = vec![
+            token("Hello", 0.0, &[("Hello", 0.0)]),
+            token(
+                " world",
+                0.5_f64.ln(),
+                &[(" world", 0.5_f64.ln()), (" there", 0.25_f64.ln())],
+            ),
+        ];
+        assert_eq!(
+            logprobs_table(&tokens),
+            "Token       Prob  Alternatives\n\
+            \"Hello\"   100.0%\n\
+            \" world\"   50.0%  \" there\" 25.0%\n"
+        );
+    }
+}
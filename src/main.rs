@@ -1,79 +1,1085 @@
 mod api;
+mod audio;
+mod audit;
+mod auth;
+mod batch;
+mod chunks;
+mod clipboard;
+mod clock;
+mod completions;
 mod config;
 mod console;
+mod files;
+mod help;
+mod highlight;
+mod hooks;
+mod images;
+mod import;
+mod language;
+mod logprobs;
+mod markdown;
+mod notify;
+mod output;
+mod pack;
+mod patch;
+mod proxy;
+mod ratelimit;
+mod redact;
+mod review;
+mod schema;
+#[cfg(unix)]
+mod server;
+mod session;
+mod snippets;
+mod summary;
+mod terminal;
+mod theme;
+mod tokens;
 
+use std::env;
+use std::fs;
 use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use anyhow::bail;
-use config::Config;
-use futures_util::StreamExt;
+use anyhow::{anyhow, bail, ensure, Context};
+use argh::FromArgs;
+use config::{
+    Api, AuditCommand, AuthAction, AuthCommand, BatchAction, BatchCommand, ChunkMode, Command,
+    CommitCommand, CompletionsCommand, Config, ConfigAction, ConfigCommand, ExtractCommand,
+    HistoryAction, HistoryCommand, ImportCommand, InitCommand, ProofreadCommand, Provider,
+    ProxyCommand, ReviewCommand, RunCommand, ServeCommand, SummarizeCommand, SweepCommand,
+    TokensCommand, TranscribeCommand, TranslateCommand, PRESETS,
+};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use tokio::net::TcpListener;
 
-use crate::api::{ApiClient, ApiError, Message, Role};
-use crate::console::Console;
+use crate::api::{ApiClient, ApiError, Completion, Message, Role, StreamEvent, Usage};
+use crate::audio::play_audio;
+use crate::audit::{audit_path, read_audit_log};
+use crate::auth::{remove_stored_api_key, set_stored_api_key};
+use crate::batch::{custom_id, parse_batch, parse_batch_output, BatchItem, BatchResult};
+use crate::chunks::{estimate_tokens, split_into_chunks};
+use crate::clipboard::{read_clipboard, write_clipboard};
+use crate::clock::datetime_context;
+use crate::completions::{completion_script, describe_command};
+use crate::console::{read_piped_input, Console, InputFollower};
+use crate::files::{attach_files, expand_file_references, fence_for};
+use crate::help::{long_help, repl_help};
+use crate::highlight::{highlight, Highlighter};
+use crate::hooks::run_hook;
+use crate::images::{detect_protocol, find_images, render_image, save_image, Image};
+use crate::import::parse_conversations;
+use crate::language::{language_instruction, translation_prompt};
+use crate::logprobs::{confidence_style, logprobs_table};
+use crate::markdown::code_blocks;
+use crate::notify::notify;
+use crate::output::{
+    normalize, single_line, wrap, EventLogger, Normalizer, Sink, StreamWriter, TextLogger,
+    TokenEmitter, Wrapper,
+};
+use crate::pack::{pack_directory, DEFAULT_DIR_TOKENS};
+use crate::patch::{diff, find_changes, word_diff};
+use crate::proxy::{generate_token, proxy, ProxyContext};
+use crate::review::{parse_findings, split_diff, Finding, REVIEW_PART_TOKENS};
+use crate::schema::{parse_json_response, validate};
+#[cfg(unix)]
+use crate::server::{bind_socket, default_socket_path, serve};
+use crate::session::{
+    format_timestamp, load_or_create_session, load_session, load_sessions, name_from_title,
+    recover_session, search, transcript, Autosave, Session,
+};
+use crate::snippets::Snippets;
+use crate::summary::{PART_PROMPT, SUMMARY_PART_TOKENS};
+use crate::terminal::{
+    confirm_on_tty, next_key, page, set_color_mode, supports_ansi, terminal_height, terminal_width,
+    use_color, KeyReader, StatusLine, TerminalTitle,
+};
+use crate::theme::{paint, set_theme, theme};
+use crate::tokens::TokenCounter;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args: config::Args = argh::from_env();
+async fn main() {
+    if let Err(error) = run().await {
+        let label = if use_color(&io::stderr()) {
+            "\x1b[1;31mError:\x1b[0m"
+        } else {
+            "Error:"
+        };
+        eprintln!("{} {:?}", label, error);
+        process::exit(1);
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let args = parse_args()?;
+    set_color_mode(args.color);
     if args.print_version {
         println!("{} v{}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
         return Ok(());
     }
-    let config = config::get_config(&args)?;
+    if args.help_long {
+        let spec = describe_command(&command_help);
+        print!(
+            "{}",
+            long_help(&spec, &command_help, config::profile_keys())
+        );
+        return Ok(());
+    }
+    if let Some(Command::Audit(command)) = &args.command {
+        return run_audit_command(command);
+    }
+    if let Some(Command::Auth(command)) = &args.command {
+        return run_auth_command(command);
+    }
+    if let Some(Command::History(command)) = &args.command {
+        return run_history_command(command);
+    }
+    if let Some(Command::Import(command)) = &args.command {
+        return run_import(command);
+    }
+    if let Some(Command::Init(command)) = &args.command {
+        return run_init(command);
+    }
+    if let Some(Command::Completions(command)) = &args.command {
+        return run_completions(&args, command);
+    }
+    if let Some(Command::Config(command)) = &args.command {
+        return run_config_command(&args, command).await;
+    }
+    if let Some(Command::Ping(_)) = &args.command {
+        return run_ping(&args).await;
+    }
+    let mut config = config::get_config(&args)?;
+    if config.dir.is_some() {
+        add_directory_context(&mut config)?;
+    }
+    if let Some(Command::Batch(command)) = &args.command {
+        return run_batch(config, command).await;
+    }
+    if let Some(Command::Commit(command)) = &args.command {
+        return run_commit(config, command).await;
+    }
+    if let Some(Command::Extract(command)) = &args.command {
+        return run_extract(config, command).await;
+    }
+    if let Some(Command::Proofread(command)) = &args.command {
+        return run_proofread(config, command).await;
+    }
+    if let Some(Command::Proxy(command)) = &args.command {
+        return run_proxy(config, command).await;
+    }
+    if let Some(Command::Review(command)) = &args.command {
+        return run_review(config, command).await;
+    }
+    if let Some(Command::Serve(command)) = &args.command {
+        return run_serve(config, command).await;
+    }
+    if let Some(Command::Summarize(command)) = &args.command {
+        return run_summarize(config, command).await;
+    }
+    if let Some(Command::Sweep(command)) = &args.command {
+        return run_sweep(config, command).await;
+    }
+    if let Some(Command::Transcribe(command)) = &args.command {
+        return run_transcribe(config, command).await;
+    }
+    if let Some(Command::Translate(command)) = &args.command {
+        return run_translate(config, command).await;
+    }
+    if let Some(Command::Models(_)) = &args.command {
+        return run_models(config).await;
+    }
+    if let Some(Command::Warmup(_)) = &args.command {
+        return run_warmup(config).await;
+    }
+    if let Some(Command::Tokens(command)) = &args.command {
+        return run_tokens(&config, command);
+    }
+    if let Some(path) = &config.output {
+        // like tee, the file only holds the output of this run
+        fs::File::create(path).with_context(|| format!("Could not create \"{}\"", path))?;
+    }
+    if let Some(Command::Run(command)) = &args.command {
+        return run_prompt(config, command).await;
+    }
+    if let Some(Command::Chat(_)) = &args.command {
+        ensure!(
+            io::stdin().is_terminal(),
+            "Interactive chat needs a terminal, use \"lmc run\" with piped input"
+        );
+    }
     if io::stdin().is_terminal() {
         run_interactive_loop(config).await
+    } else if config.map {
+        run_map(config, "").await
+    } else if config.follow {
+        run_follow(config, "").await
     } else {
-        run_with_piped_input(config).await
+        let user_prompt = read_piped_input()?;
+        run_with_prompt(config, user_prompt).await
+    }
+}
+
+/// Adds the files in the --dir directory after the system prompt, so that they're
+/// part of the context of the whole chat
+fn add_directory_context(config: &mut Config) -> anyhow::Result<()> {
+    let Some(dir) = &config.dir else {
+        return Ok(());
+    };
+    let max_tokens = config.dir_tokens.unwrap_or(DEFAULT_DIR_TOKENS);
+    let pack = pack_directory(dir, &config.include, max_tokens)?;
+    if pack.truncated > 0 || pack.omitted > 0 {
+        themed_eprintln!(
+            "[w] To fit in {} tokens {} of the {} files were truncated and {} left out",
+            max_tokens,
+            pack.truncated,
+            pack.files,
+            pack.omitted
+        );
+    }
+    config.system_prompt = Some(match config.system_prompt.take() {
+        Some(prompt) => format!("{}\n\n{}", prompt, pack.text),
+        None => pack.text,
+    });
+    Ok(())
+}
+
+/// Like argh::from_env, after expanding any "@alias" argument
+fn parse_args() -> anyhow::Result<config::Args> {
+    let strings = config::expand_alias(env::args().collect())?;
+    let strings = split_equals(strings);
+    let strings: Vec<&str> = strings.iter().map(String::as_str).collect();
+    let command = Path::new(strings[0])
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(strings[0]);
+    let mut args = config::Args::from_args(&[command], &strings[1..]).unwrap_or_else(|exit| {
+        match exit.status {
+            Ok(()) => {
+                println!("{}", exit.output);
+                process::exit(0)
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {} --help for more information.",
+                    exit.output, command
+                );
+                process::exit(1)
+            }
+        }
+    });
+    args.merge_command_options();
+    Ok(args)
+}
+
+/// The help text argh generates for a command, e.g. ["batch", "status"]
+fn command_help(path: &[&str]) -> String {
+    let mut strings = path.to_vec();
+    strings.push("--help");
+    match config::Args::from_args(&["lmc"], &strings) {
+        Ok(_) => String::new(),
+        Err(exit) => exit.output,
+    }
+}
+
+fn run_completions(args: &config::Args, command: &CompletionsCommand) -> anyhow::Result<()> {
+    let spec = describe_command(&command_help);
+    let profiles = config::profile_names(args).unwrap_or_else(|error| {
+        themed_eprintln!("[w] Profile names not included: {}", error);
+        Vec::new()
+    });
+    print!("{}", completion_script(command.shell, &spec, &profiles));
+    Ok(())
+}
+
+/// Accepts "--name=value" as well as "--name value", which argh doesn't
+fn split_equals(args: Vec<String>) -> Vec<String> {
+    let mut split = Vec::new();
+    for arg in args {
+        match arg.strip_prefix("--").and_then(|rest| rest.split_once('=')) {
+            Some((name, value))
+                if !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '-') =>
+            {
+                split.push(format!("--{}", name));
+                split.push(value.to_owned());
+            }
+            _ => split.push(arg),
+        }
+    }
+    split
+}
+
+fn run_auth_command(command: &AuthCommand) -> anyhow::Result<()> {
+    match &command.action {
+        AuthAction::Set(set) => {
+            let key = if io::stdin().is_terminal() {
+                rpassword::prompt_password(format!("API key for \"{}\": ", set.profile))?
+            } else {
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)?;
+                line
+            };
+            ensure!(!key.trim().is_empty(), "No API key provided");
+            set_stored_api_key(&set.profile, key.trim())?;
+            println!("[i] Stored API key for profile \"{}\"", set.profile);
+        }
+        AuthAction::Remove(remove) => {
+            remove_stored_api_key(&remove.profile)?;
+            println!("[i] Removed API key for profile \"{}\"", remove.profile);
+        }
+    }
+    Ok(())
+}
+
+/// Handles "lmc init"
+fn run_init(command: &InitCommand) -> anyhow::Result<()> {
+    // an existing file in $XDG_CONFIG_HOME would be read instead of the default one
+    let path = match config::user_config_path()? {
+        Some(path) => path,
+        None => config::default_config_path()?,
+    };
+    ensure!(
+        command.force || !fs::exists(&path)?,
+        "\"{}\" already exists, use --force to overwrite it",
+        path.display()
+    );
+    for (index, preset) in PRESETS.iter().enumerate() {
+        println!("{}) {}", index + 1, preset.title);
     }
+    let mut console = Console::new()?;
+    let Some(answer) = console.read_line("[?] Which provider do you use? [1] ")? else {
+        return Ok(());
+    };
+    let preset = match answer.trim() {
+        "" => &PRESETS[0],
+        answer => answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| PRESETS.get(number.wrapping_sub(1)))
+            .with_context(|| format!("Invalid choice: \"{}\"", answer))?,
+    };
+    let Some(model) = console.read_line(&format!("[?] Model [{}] ", preset.model))? else {
+        return Ok(());
+    };
+    let model = match model.trim() {
+        "" => preset.model,
+        model => model,
+    };
+    if preset.needs_key {
+        let key = rpassword::prompt_password(format!(
+            "[?] API key for {} (stored in the OS keyring, blank to skip): ",
+            preset.title
+        ))?;
+        if !key.trim().is_empty() {
+            set_stored_api_key(preset.name, key.trim())?;
+            println!("[i] Stored API key for profile \"{}\"", preset.name);
+        }
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, config::starter_config(preset, model))?;
+    println!(
+        "[i] Wrote \"{}\", run lmc to start chatting",
+        path.display()
+    );
+    Ok(())
+}
+
+/// The session named with --session, if any, and the conversation to continue:
+/// the one saved in that session, or a new one
+fn open_session(config: &Config) -> anyhow::Result<(Option<Session>, Vec<Message>)> {
+    let Some(name) = &config.session else {
+        return Ok((None, create_messages(config)));
+    };
+    let session = load_or_create_session(name)?;
+    let messages = match session.messages() {
+        messages if messages.is_empty() => create_messages(config),
+        messages => messages,
+    };
+    Ok((Some(session), messages))
 }
 
-fn create_messages(system_prompt: Option<String>) -> Vec<Message> {
-    match system_prompt {
+/// Starts a conversation with the system prompt and few-shot examples, if any
+fn create_messages(config: &Config) -> Vec<Message> {
+    let mut messages = match &config.system_prompt {
         None => Vec::new(),
-        Some(prompt) => vec![Message::new(Role::System, &prompt)],
+        Some(prompt) => vec![Message::new(Role::System, prompt)],
+    };
+    messages.extend(config.messages.iter().cloned());
+    messages
+}
+
+/// Estimates the size of the conversation, for the status line
+fn count_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum()
+}
+
+fn is_repeated_prompt(messages: &[Message], user_prompt: &str) -> bool {
+    messages
+        .iter()
+        .rev()
+        .find(|message| message.role == Role::User)
+        .is_some_and(|message| message.content == user_prompt)
+}
+
+fn report_finish(finish_reason: Option<&str>, usage: Option<&Usage>, config: &Config) {
+    match finish_reason {
+        None | Some("stop") => {}
+        Some("length") => {
+            themed_eprintln!(
+                "[w] The response was truncated at the maximum length, use /continue to resume"
+            )
+        }
+        Some(reason) => themed_eprintln!("[i] Response ended early: \"{}\"", reason),
+    }
+    if let (true, Some(usage)) = (config.show_usage, usage) {
+        themed_eprintln!(
+            "[i] Tokens: {} prompt + {} completion = {} total",
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens
+        );
+        let cached = usage.cached_tokens();
+        if cached > 0 || usage.cache_write_tokens > 0 {
+            themed_eprintln!(
+                "[i] Cache: {} tokens read, {} written",
+                cached,
+                usage.cache_write_tokens
+            );
+        }
+    }
+}
+
+/// Prints how long the response took and how fast it was generated, counting
+/// the tokens reported by the API or else estimating them
+fn report_stats(started: Instant, usage: Option<&Usage>, completion: &str, config: &Config) {
+    if !config.show_stats {
+        return;
+    }
+    let seconds = started.elapsed().as_secs_f64();
+    let tokens = usage.map_or_else(
+        || estimate_tokens(completion),
+        |usage| usage.completion_tokens as usize,
+    );
+    themed_eprintln!(
+        "[i] {} tokens in {:.1}s ({:.1} tokens/s)",
+        tokens,
+        seconds,
+        tokens as f64 / seconds.max(0.001)
+    );
+}
+
+/// Appends a response that wasn't streamed to the "--output" file
+fn save_output(completion: &str, config: &Config) {
+    let Some(path) = &config.output else {
+        return;
+    };
+    let result = TextLogger::open(path).and_then(|mut logger| {
+        logger.write(completion)?;
+        logger.finish()
+    });
+    if let Err(error) = result {
+        themed_eprintln!("[e] Could not write to \"{}\": {}", path, error);
+    }
+}
+
+/// Notifies that the response is ready, if enabled and it took long enough for
+/// the user to have switched to something else
+fn report_finished(started: Instant, config: &Config) {
+    let seconds = started.elapsed().as_secs();
+    if config.notify && seconds >= config.notify_after {
+        notify(&format!("The response is ready, after {}s", seconds));
+    }
+}
+
+/// Instructions for "lmc commit", with the staged diff sent as the user message
+const COMMIT_PROMPT: &str = "Write a commit message for the following diff, \
+following the Conventional Commits format: a subject line like \"fix: handle empty input\" \
+of at most 72 characters, then a blank line and a short body explaining what changed and why, \
+if not obvious. Answer with the commit message only, without any code block or commentary.";
+
+/// Instructions for "lmc extract", followed by the schema, with the text to
+/// extract the data from sent as the user message
+const EXTRACT_PROMPT: &str = "Extract the data described by the following JSON schema from the \
+text provided. Answer with a single JSON value matching the schema, without any code block or \
+commentary. Use null for optional values that aren't in the text, rather than guessing.";
+
+/// Instructions for "lmc proofread", with the text sent as the user message
+const PROOFREAD_PROMPT: &str = "Correct the spelling, grammar and punctuation of the text \
+provided, changing as little as possible: keep its wording, tone, language and formatting (line \
+breaks, Markdown, code blocks) as they are. Answer with the corrected text only, without any \
+comments, even if there is nothing to correct.";
+
+/// Instructions for "lmc review", with the changes to a file sent as the user
+/// message
+const REVIEW_PROMPT: &str = "Review the following changes to a file as an expert code reviewer. \
+Report only real problems in the changed code: bugs, security issues, performance problems and \
+code that is hard to understand or maintain, not matters of style. Write each problem on a line \
+of its own, like \"[high] L42: the index can be out of bounds when the list is empty\", with the \
+severity (high, medium or low) and the line number in the new version of the file. If there are \
+no problems, just write \"No problems found\".";
+
+/// Introduces the partial results of a chunked input, for "--chunk merge"
+const MERGE_PROMPT: &str = "The input was too long, so it was split into parts that were \
+processed separately, with the results below. Combine them into a single, coherent response.";
+
+/// Number of sessions listed by "--resume" at a time
+const PICKER_SIZE: usize = 20;
+
+/// Sent to get a title for a session when saving it
+const TITLE_PROMPT: &str = "Give this conversation a short title of a few words. \
+Answer with the title only.";
+
+/// Sent to ask the model to resume a truncated response
+const CONTINUE_PROMPT: &str = "Continue exactly from where you stopped.";
+
+/// Number of prompts sent at a time with "--map"
+const MAP_CONCURRENCY: usize = 4;
+
+/// The seconds without new input that end a chunk with --follow, by default
+const FOLLOW_TIMEOUT_SECS: f64 = 2.0;
+
+/// Responses longer than this are not dumped straight to the terminal
+const LARGE_OUTPUT_LINES: usize = 1000;
+
+fn print_large_completion(completion: &str) {
+    let lines = completion.lines().count();
+    eprint!(
+        "[?] The response is {} lines long: (p)age, (s)ave to a file, or (d)isplay it? [P/s/d] ",
+        lines
+    );
+    match read_answer().as_str() {
+        "s" => {
+            eprint!("[?] File name: ");
+            let path = read_answer();
+            match fs::write(&path, completion) {
+                Ok(()) => themed_eprintln!("[i] Saved to \"{}\"", path),
+                Err(error) => {
+                    themed_eprintln!("[e] Could not save \"{}\": {}", path, error);
+                    println!("{}", completion);
+                }
+            }
+        }
+        "d" => println!("{}", completion),
+        _ => page(completion).unwrap_or_else(|_| println!("{}", completion)),
+    }
+}
+
+fn read_answer() -> String {
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap_or_default();
+    answer.trim().to_owned()
+}
+
+/// Opens the file of a sink for the streamed response, reporting any error
+fn open_sink<S: Sink + 'static>(
+    path: &str,
+    open: fn(&str) -> io::Result<S>,
+) -> Option<Box<dyn Sink>> {
+    match open(path) {
+        Ok(sink) => Some(Box::new(sink)),
+        Err(error) => {
+            themed_eprintln!("[e] Could not open \"{}\": {}", path, error);
+            None
+        }
     }
 }
 
 async fn get_and_print_completion(
     api_client: &ApiClient,
-    messages: &Vec<Message>,
-    stream: bool,
+    messages: &[Message],
+    config: &Config,
 ) -> Result<String, ApiError> {
-    if stream {
-        let mut stdout = io::stdout();
-        let mut completion = String::new();
-        let mut events = api_client.stream_chat_completion(messages).await?;
-        while let Some(event) = events.next().await {
-            if let Some(token) = event? {
-                completion.push_str(&token);
-                print!("{}", token);
-                stdout.flush().unwrap_or(());
-            }
-        }
-        println!();
+    let request = prefill_request(messages, config);
+    if config.dry_run {
+        let request = api_client.describe_request(config.stream, &request).await?;
+        println!("{}", request);
+        return Ok(String::new());
+    }
+    let started = Instant::now();
+    let highlighted = config.highlight && use_color(&io::stdout());
+    let marker = theme().and_then(|theme| theme.marker.as_deref());
+    let style = theme().map_or("", |theme| theme.assistant_style());
+    // a response to post-process, or to check that it's valid JSON, is only
+    // shown once complete
+    let retry_json = config.expects_json() && config.json_retries > 0;
+    if config.stream && config.post.is_none() && !retry_json {
+        // tokens with logprobs are shown exactly as they arrive
+        let plain = config.logprobs.is_some();
+        let normalizer = (config.normalize_output && !plain).then(Normalizer::new);
+        let wrapper = (config.word_wrap && io::stdout().is_terminal() && !plain).then(Wrapper::new);
+        let highlighter = (highlighted && !plain).then(Highlighter::new);
+        let sinks: Vec<Box<dyn Sink>> = [
+            config
+                .emit_tokens
+                .as_deref()
+                .and_then(|path| open_sink(path, TokenEmitter::open)),
+            config
+                .log_stream
+                .as_deref()
+                .and_then(|path| open_sink(path, EventLogger::open)),
+            config
+                .output
+                .as_deref()
+                .and_then(|path| open_sink(path, TextLogger::open)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let mut writer = StreamWriter::new(normalizer, wrapper, highlighter, sinks);
+        writer.set_style(style);
+        if let Some(marker) = marker {
+            print!("{}", marker);
+        }
+        if let Some(prefill) = &config.prefill {
+            writer.write(prefill.to_owned());
+        }
+        let mut keys = KeyReader::start();
+        let mut finish_reason = None;
+        let mut usage = None;
+        let mut events = api_client.stream_chat_completion(&request).await?;
+        let context_tokens = StatusLine::is_shown().then(|| count_tokens(messages));
+        let mut streamed = 0;
+        let mut retries = 0;
+        let mut received_logprobs = false;
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    None => break,
+                    Some(Err(error))
+                        if error.is_interruption() && retries < config.stream_retries =>
+                    {
+                        retries += 1;
+                        themed_eprintln!(
+                            "\n[w] Connection lost, resuming the response ({} of {})",
+                            retries, config.stream_retries
+                        );
+                        let request = resume_request(messages, writer.text(), config);
+                        events = api_client.stream_chat_completion(&request).await?;
+                    }
+                    Some(event) => match event? {
+                        StreamEvent::Token(token) => {
+                            writer.write(token);
+                            streamed += 1;
+                            if let Some(context_tokens) = context_tokens {
+                                let speed = streamed as f64 / started.elapsed().as_secs_f64();
+                                StatusLine::report(context_tokens + streamed, Some(speed));
+                            }
+                        }
+                        StreamEvent::Logprobs(tokens) => {
+                            streamed += tokens.len();
+                            for token in tokens {
+                                if use_color(&io::stdout()) {
+                                    let style = confidence_style(token.logprob);
+                                    writer.write_styled(token.token, style);
+                                } else {
+                                    writer.write(token.token);
+                                }
+                            }
+                            received_logprobs = true;
+                        }
+                        StreamEvent::Finish(reason) => finish_reason = Some(reason),
+                        StreamEvent::Usage(value) => usage = Some(value),
+                    },
+                },
+                Some(key) = next_key(&mut keys) => {
+                    if key == b' ' {
+                        writer.toggle_pause();
+                    }
+                }
+            }
+        }
+        drop(keys);
+        let completion = writer.finish();
+        if config.logprobs.is_some() && !received_logprobs {
+            themed_eprintln!("[w] The API didn't return any logprobs");
+        }
+        report_finish(finish_reason.as_deref(), usage.as_ref(), config);
+        report_stats(started, usage.as_ref(), &completion, config);
+        report_finished(started, config);
         Ok(completion)
     } else {
-        let completion = api_client.get_chat_completion(messages).await?;
-        println!("{}", completion);
+        let response = if retry_json {
+            get_json_completion(api_client, request, config).await?
+        } else {
+            api_client.get_chat_completion(&request).await?
+        };
+        let mut completion = config.prefill.to_owned().unwrap_or_default() + &response.content;
+        if config.normalize_output {
+            completion = normalize(&completion);
+        }
+        let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+        let display = match terminal_width().filter(|_| config.word_wrap) {
+            Some(width) => wrap(&completion, width),
+            None => completion.to_owned(),
+        };
+        let lines = display.lines().count();
+        let display = if highlighted {
+            highlight(&display)
+        } else {
+            display
+        };
+        let display = format!("{}{}", marker.unwrap_or_default(), paint(&display, style));
+        let processed = config.post.as_deref().and_then(|command| {
+            run_hook(command, &completion)
+                .inspect_err(|error| themed_eprintln!("[w] {:#}", error))
+                .ok()
+        });
+        if let Some(processed) = processed {
+            print!("{}", processed);
+        } else if interactive && lines > LARGE_OUTPUT_LINES {
+            print_large_completion(&completion);
+        } else if interactive && config.auto_pager && terminal_height().is_some_and(|h| lines >= h)
+        {
+            page(&completion).unwrap_or_else(|_| println!("{}", display));
+        } else {
+            println!("{}", display);
+        }
+        if config.logprobs.is_some() {
+            if response.logprobs.is_empty() {
+                themed_eprintln!("[w] The API didn't return any logprobs");
+            } else {
+                eprint!("{}", logprobs_table(&response.logprobs));
+            }
+        }
+        report_finish(
+            response.finish_reason.as_deref(),
+            response.usage.as_ref(),
+            config,
+        );
+        report_stats(started, response.usage.as_ref(), &completion, config);
+        report_finished(started, config);
+        save_output(&completion, config);
         Ok(completion)
     }
 }
 
-async fn run_interactive_loop(config: Config) -> anyhow::Result<()> {
-    let api_client = ApiClient::new(&config);
+/// Asks again, with the parse error, while a response that should be JSON isn't,
+/// up to "json_retries" times
+async fn get_json_completion(
+    api_client: &ApiClient,
+    mut request: Vec<Message>,
+    config: &Config,
+) -> Result<Completion, ApiError> {
+    let prefill = config.prefill.to_owned().unwrap_or_default();
+    let mut retries = 0;
+    loop {
+        let response = api_client.get_chat_completion(&request).await?;
+        let json = prefill.to_owned() + &response.content;
+        let Err(error) = serde_json::from_str::<serde_json::Value>(&json) else {
+            return Ok(response);
+        };
+        if retries == config.json_retries {
+            themed_eprintln!("[w] The response is not valid JSON: {}", error);
+            return Ok(response);
+        }
+        retries += 1;
+        themed_eprintln!(
+            "[w] The response is not valid JSON, asking again ({} of {}): {}",
+            retries,
+            config.json_retries,
+            error
+        );
+        if config.prefill.is_some() {
+            request.pop();
+        }
+        request.push(Message::new(Role::Assistant, &json));
+        request.push(Message::new(
+            Role::User,
+            &format!(
+                "That is not valid JSON: {}. Answer again with valid JSON only.",
+                error
+            ),
+        ));
+        if let Some(prefill) = &config.prefill {
+            request.push(Message::new(Role::Assistant, prefill));
+        }
+    }
+}
+
+async fn run_interactive_loop(mut config: Config) -> anyhow::Result<()> {
+    let mut api_client = ApiClient::new(&config)?;
     let mut console = Console::new()?;
-    let mut messages = create_messages(config.system_prompt);
-    println!(
-        "[i] Chatting with \"{}\" at \"{}\"",
-        config.model, config.api_url
-    );
+    if use_color(&io::stdout()) {
+        console.set_input_style(config.theme.user_style());
+        set_theme(config.theme.to_owned());
+    }
+    let mut snippets = Snippets::load()?;
+    let (named_session, mut messages) = open_session(&config)?;
+    let mut session = Session::new();
+    if let Some(named_session) = named_session {
+        session = named_session;
+        if messages
+            .iter()
+            .any(|message| !message.role.is_instruction())
+        {
+            themed_println!(
+                "[i] Continuing \"{}\" with {} messages",
+                session.name().unwrap_or_default(),
+                messages.len()
+            );
+        }
+    } else if config.resume {
+        match pick_session(&mut console)? {
+            Some(resumed) => {
+                session = resumed;
+                messages = session.messages();
+                themed_println!(
+                    "[i] Resuming \"{}\" with {} messages",
+                    session.name().unwrap_or_default(),
+                    messages.len()
+                );
+            }
+            None => return Ok(()),
+        }
+    } else if let Some(recovered) = recover_session().unwrap_or_else(|error| {
+        themed_eprintln!("[w] Could not look for autosaved conversations: {}", error);
+        None
+    }) {
+        let count = recovered
+            .messages()
+            .iter()
+            .filter(|message| !message.role.is_instruction())
+            .count();
+        let question = format!(
+            "[?] The chat of {} wasn't closed properly, restore its {} messages?",
+            format_timestamp(recovered.updated),
+            count
+        );
+        if console.confirm(&question)? {
+            session = recovered;
+            messages = session.messages();
+        }
+    }
+    if session.seed.is_some() {
+        config.seed = session.seed;
+        api_client.set_config(&config);
+    }
+    let mut autosave = Autosave::start()
+        .map_err(|error| themed_eprintln!("[w] The chat won't be autosaved: {}", error))
+        .ok();
+    let mut title = (config.terminal_title && io::stdout().is_terminal() && supports_ansi())
+        .then(|| TerminalTitle::save(&format!("lmc: {}", config.model)));
+    let model = if use_color(&io::stdout()) {
+        format!("\x1b[1m{}\x1b[0m", config.model)
+    } else {
+        config.model.to_owned()
+    };
+    themed_println!("[i] Chatting with \"{}\" at \"{}\"", model, config.api_url);
+    if !config.experimental.is_empty() {
+        themed_println!(
+            "[i] Experimental features enabled: {}",
+            config.experimental.join(", ")
+        );
+    }
+    let _status = (config.status_line && io::stdout().is_terminal() && supports_ansi())
+        .then(|| StatusLine::show(&config.model));
+    let mut paste_clipboard = config.clipboard;
+    let mut pending_files = !config.files.is_empty();
     loop {
+        StatusLine::report(count_tokens(&messages), None);
+        // "/r" sends the last prompt again, instead of a new one, without the
+        // response it replaces
+        let mut retrying = false;
+        let mut retried = None;
         match console.read_interactive_input()? {
             None => break, // EOF
             Some(command) if command == "/q" || command == "/quit" => break,
+            Some(command) if command == "/help" => {
+                eprint!("{}", repl_help());
+                continue;
+            }
             Some(command) if command == "/r" || command == "/retry" => {
-                if let Some(message) = messages.last() {
-                    if message.role == Role::Assistant {
-                        messages.pop();
+                retrying = true;
+                if messages.last().is_some_and(|m| m.role == Role::Assistant) {
+                    retried = messages.pop();
+                }
+                if !messages.last().is_some_and(|m| m.role == Role::User) {
+                    themed_eprintln!("[e] There is no prompt to retry");
+                    messages.extend(retried);
+                    continue;
+                }
+            }
+            Some(command) if command == "/c" || command == "/continue" => {
+                continue_last_response(&api_client, &mut messages, &config).await;
+                continue;
+            }
+            Some(command) if command == "/l" || command == "/last" => {
+                match messages.last().filter(|m| m.role == Role::Assistant) {
+                    Some(message) => page(&message.content).unwrap_or_else(|error| {
+                        themed_eprintln!("[e] Could not run pager: {}", error)
+                    }),
+                    None => themed_eprintln!("[e] There is no response to show"),
+                }
+                continue;
+            }
+            Some(command) if command == "/tokens" => {
+                match TokenCounter::new(config.tokenizer, &config.model) {
+                    Ok(counter) => themed_println!(
+                        "[i] The conversation is {} tokens long",
+                        counter.count_messages(&messages)
+                    ),
+                    Err(error) => themed_eprintln!("[e] {}", error),
+                }
+                continue;
+            }
+            Some(command) if command == "/code" || command.starts_with("/code ") => {
+                if let Err(error) = run_code_command(&command["/code".len()..], &messages) {
+                    themed_eprintln!("[e] {}", error);
+                }
+                continue;
+            }
+            Some(command) if command == "/apply" => {
+                match messages.last().filter(|m| m.role == Role::Assistant) {
+                    Some(message) => apply_changes(&message.content, |q| console.confirm(q))
+                        .unwrap_or_else(|error| themed_eprintln!("[e] {}", error)),
+                    None => themed_eprintln!("[e] There is no response to apply"),
+                }
+                continue;
+            }
+            Some(command) if command == "/branch" || command.starts_with("/branch ") => {
+                match command["/branch".len()..].trim() {
+                    "" => {
+                        for name in session.branch_names() {
+                            let marker = if *name == session.branch { "*" } else { " " };
+                            println!("{} {}", marker, name);
+                        }
+                    }
+                    name => match session.branch(name, &messages) {
+                        Ok(()) => themed_println!("[i] Switched to new branch \"{}\"", name),
+                        Err(error) => themed_eprintln!("[e] {}", error),
+                    },
+                }
+                continue;
+            }
+            Some(command) if command.starts_with("/switch ") => {
+                let name = command["/switch ".len()..].trim();
+                match session.switch(name, &mut messages) {
+                    Ok(()) => themed_println!("[i] Switched to branch \"{}\"", name),
+                    Err(error) => themed_eprintln!("[e] {}", error),
+                }
+                continue;
+            }
+            Some(command) if command == "/save" || command.starts_with("/save ") => {
+                if session.title.is_none() && !config.dry_run {
+                    session.title = generate_title(&api_client, &messages).await;
+                }
+                let name = match command["/save".len()..].trim() {
+                    "" => name_from_title(session.title.as_deref().unwrap_or_default()),
+                    name => Ok(name.to_owned()),
+                };
+                match name.and_then(|name| session.save_as(&name, &messages).map(|_| name)) {
+                    Ok(name) => themed_println!("[i] Saved session \"{}\"", name),
+                    Err(error) => themed_eprintln!("[e] {}", error),
+                }
+                continue;
+            }
+            Some(command) if command == "/paste" || command.starts_with("/paste ") => {
+                match append_clipboard(command["/paste".len()..].trim()) {
+                    Ok(prompt) => messages.push(Message::new(Role::User, &prompt)),
+                    Err(error) => {
+                        themed_eprintln!("[e] {}", error);
+                        continue;
+                    }
+                }
+            }
+            Some(command) if command.starts_with("/as ") => {
+                let args = command["/as ".len()..].trim_start();
+                match args.split_once(' ') {
+                    Some((name, prompt)) if !prompt.trim().is_empty() => {
+                        let prompt = session.interpolate(prompt.trim());
+                        let message = Message::new(Role::User, &prompt);
+                        messages.push(message.with_name(name));
+                    }
+                    _ => {
+                        themed_eprintln!(
+                            "[e] Expected a name and a prompt, e.g. \"/as alice Hi!\""
+                        );
+                        continue;
+                    }
+                }
+            }
+            Some(command) if command.starts_with("/voice ") => {
+                let path = Path::new(command["/voice ".len()..].trim());
+                match api_client.transcribe(path).await {
+                    Ok(transcript) if !transcript.trim().is_empty() => {
+                        themed_println!("[i] {}", transcript.trim());
+                        messages.push(Message::new(Role::User, &transcript.trim().to_owned()));
+                    }
+                    Ok(_) => {
+                        themed_eprintln!("[e] No speech found in \"{}\"", path.display());
+                        continue;
+                    }
+                    Err(error) => {
+                        themed_eprintln!("[e] {}", error);
+                        continue;
+                    }
+                }
+            }
+            Some(command) if command == "/prefill" || command.starts_with("/prefill ") => {
+                let prefill = command["/prefill".len()..].trim();
+                match config.set("prefill", prefill) {
+                    Ok(()) if prefill.is_empty() => {
+                        themed_println!("[i] Responses are not prefilled")
+                    }
+                    Ok(()) => themed_println!("[i] Responses will start with \"{}\"", prefill),
+                    Err(error) => themed_eprintln!("[e] {}", error),
+                }
+                continue;
+            }
+            Some(command) if command == "/settings" => {
+                for (name, value) in config.settings() {
+                    println!("{} = {}", name, value);
+                }
+                continue;
+            }
+            Some(command) if command == "/set" => {
+                for (name, value) in &session.variables {
+                    println!("{}={}", name, value);
+                }
+                continue;
+            }
+            Some(command) if command.starts_with("/set ") => {
+                let setting = command["/set ".len()..].trim_start();
+                // "/set name=value" sets a variable rather than a setting
+                let variable = setting
+                    .split_once('=')
+                    .filter(|(name, _)| !name.contains(' '));
+                let result = match variable {
+                    Some((name, value)) => session.set_variable(name, value.trim()),
+                    None => {
+                        let (name, value) = setting.split_once(' ').unwrap_or((setting, ""));
+                        config.set(name, value).map(|_| {
+                            if name == "seed" {
+                                session.seed = config.seed;
+                            }
+                            api_client.set_config(&config)
+                        })
+                    }
+                };
+                if let Err(error) = result {
+                    themed_eprintln!("[e] {}", error);
+                }
+                continue;
+            }
+            Some(command) if command == "/snippet" || command.starts_with("/snippet ") => {
+                let args = &command["/snippet".len()..];
+                match run_snippet_command(&mut snippets, args, &messages) {
+                    Ok(Some(prompt)) => {
+                        let prompt = session.interpolate(&prompt);
+                        messages.push(Message::new(Role::User, &prompt));
+                    }
+                    Ok(None) => continue,
+                    Err(error) => {
+                        themed_eprintln!("[e] {}", error);
+                        continue;
                     }
                 }
             }
@@ -81,27 +1087,1478 @@ async fn run_interactive_loop(config: Config) -> anyhow::Result<()> {
                 if user_prompt.is_empty() {
                     continue; // ignore empty lines
                 }
+                let user_prompt = session.interpolate(&snippets.expand(&user_prompt));
+                let mut user_prompt =
+                    match expand_file_references(&user_prompt, config.max_file_size) {
+                        Ok(prompt) => prompt,
+                        Err(error) => {
+                            themed_eprintln!("[e] {}", error);
+                            continue;
+                        }
+                    };
+                if paste_clipboard {
+                    paste_clipboard = false;
+                    match append_clipboard(&user_prompt) {
+                        Ok(prompt) => user_prompt = prompt,
+                        Err(error) => themed_eprintln!("[w] {}", error),
+                    }
+                }
+                if pending_files {
+                    match attach_files(&user_prompt, &config.files, config.max_file_size) {
+                        Ok(prompt) => user_prompt = prompt,
+                        Err(error) => {
+                            themed_eprintln!("[e] {}", error);
+                            continue;
+                        }
+                    }
+                    pending_files = false;
+                }
+                if config.confirm_duplicates
+                    && is_repeated_prompt(&messages, &user_prompt)
+                    && !console.confirm("[?] Same prompt as before, send it again?")?
+                {
+                    continue;
+                }
+                if let Some(title) = &mut title {
+                    title.set_topic(&user_prompt);
+                }
                 messages.push(Message::new(Role::User, &user_prompt));
             }
         }
-        let result = get_and_print_completion(&api_client, &messages, config.stream).await;
+        let over_budget = config.check_budget(count_tokens(&messages)).err();
+        if let Some(error) = &over_budget {
+            if !console.confirm(&format!("[?] {}, send it anyway?", error))? {
+                // the prompt isn't sent
+                restore_unsent(&mut messages, retrying, retried);
+                continue;
+            }
+        }
+        api_client.allow_over_budget(over_budget.is_some());
+        let result = get_and_print_completion(&api_client, &messages, &config).await;
         match result {
-            Ok(completion) => messages.push(Message::new(Role::Assistant, &completion)),
-            Err(error) => eprintln!("[e] {:?}", error),
+            Ok(_) if config.dry_run => {
+                // the prompt wasn't actually sent
+                restore_unsent(&mut messages, retrying, retried);
+            }
+            Ok(completion) => {
+                if config.apply {
+                    apply_changes(&completion, |q| console.confirm(q))
+                        .unwrap_or_else(|error| themed_eprintln!("[e] {}", error));
+                }
+                if config.inline_images && io::stdout().is_terminal() {
+                    show_images(&api_client, &completion).await;
+                }
+                if config.speak {
+                    speak_response(&api_client, &completion, &config).await;
+                }
+                messages.push(Message::new(Role::Assistant, &completion));
+                if let Err(error) = session.save(&messages) {
+                    themed_eprintln!("[e] {}", error);
+                }
+                if let Some(autosave) = &mut autosave {
+                    if let Err(error) = autosave.save(&mut session, &messages) {
+                        themed_eprintln!("[w] Could not autosave the chat: {}", error);
+                    }
+                }
+            }
+            Err(error) => themed_eprintln!("[e] {:?}", error),
         }
     }
     Ok(())
 }
 
-async fn run_with_piped_input(config: Config) -> anyhow::Result<()> {
-    let api_client = ApiClient::new(&config);
-    let mut console = Console::new()?;
-    let mut messages = create_messages(config.system_prompt);
-    let user_prompt = console.read_piped_input()?;
-    if user_prompt.is_empty() {
-        bail!("Expected a prompt to be supplied via stdin but it was empty");
+/// Leaves the conversation as it was before a prompt that wasn't sent after all:
+/// without it, or when retrying with the response it was going to replace
+fn restore_unsent(messages: &mut Vec<Message>, retrying: bool, retried: Option<Message>) {
+    if retrying {
+        messages.extend(retried);
+    } else {
+        messages.pop();
+    }
+}
+
+/// Handles "/snippet [save] <name> [text]", returning the prompt to send if any
+fn run_snippet_command(
+    snippets: &mut Snippets,
+    args: &str,
+    messages: &[Message],
+) -> anyhow::Result<Option<String>> {
+    let args = args.trim();
+    match args.split_once(' ').unwrap_or((args, "")) {
+        ("", _) => {
+            for name in snippets.names() {
+                println!("#{}", name);
+            }
+            Ok(None)
+        }
+        ("save", rest) => {
+            let rest = rest.trim_start();
+            let (name, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            let text = match text.trim_start() {
+                "" => messages
+                    .iter()
+                    .rev()
+                    .find(|message| message.role == Role::User)
+                    .map(|message| message.content.to_owned())
+                    .context("No previous prompt to save")?,
+                text => text.to_owned(),
+            };
+            snippets.save(name, &text)?;
+            themed_println!("[i] Saved snippet \"{}\"", name);
+            Ok(None)
+        }
+        (name, _) => match snippets.get(name) {
+            Some(text) => Ok(Some(text.to_owned())),
+            None => bail!("No such snippet: \"{}\"", name),
+        },
+    }
+}
+
+/// Appends the text in the clipboard to a prompt, after a blank line
+fn append_clipboard(prompt: &str) -> anyhow::Result<String> {
+    let text = read_clipboard().context("Could not read the clipboard")?;
+    ensure!(!text.trim().is_empty(), "The clipboard is empty");
+    let prompt = prompt.trim_end();
+    if prompt.is_empty() {
+        Ok(text)
+    } else {
+        Ok(format!("{}\n\n{}", prompt, text))
+    }
+}
+
+/// Lists the most recent sessions to choose one by number, or narrow down the list
+/// by typing part of a name or title
+fn pick_session(console: &mut Console) -> anyhow::Result<Option<Session>> {
+    let mut sessions = load_sessions()?;
+    ensure!(!sessions.is_empty(), "There are no saved sessions");
+    let mut query = String::new();
+    loop {
+        let candidates: Vec<usize> = (0..sessions.len())
+            .filter(|&index| sessions[index].matches(&query))
+            .take(PICKER_SIZE)
+            .collect();
+        if candidates.is_empty() {
+            themed_eprintln!("[i] No sessions matching \"{}\"", query);
+        }
+        for (number, &index) in candidates.iter().enumerate() {
+            let session = &sessions[index];
+            println!(
+                "{:>3}. {}  {}  {}",
+                number + 1,
+                format_timestamp(session.updated),
+                session.name().unwrap_or_default(),
+                session.title.as_deref().unwrap_or_default()
+            );
+        }
+        let Some(answer) = console.read_line("[?] Session number, or text to search for: ")? else {
+            return Ok(None);
+        };
+        match answer.trim().parse::<usize>() {
+            Ok(number) if (1..=candidates.len()).contains(&number) => {
+                return Ok(Some(sessions.swap_remove(candidates[number - 1])));
+            }
+            _ if answer.trim().is_empty() => return Ok(None),
+            _ => query = answer.trim().to_owned(),
+        }
+    }
+}
+
+/// Asks the model for a short title for the conversation, to name saved sessions
+async fn generate_title(api_client: &ApiClient, messages: &[Message]) -> Option<String> {
+    if !messages.iter().any(|message| message.role == Role::User) {
+        return None;
+    }
+    let mut request = messages.to_vec();
+    request.push(Message::new(Role::User, &String::from(TITLE_PROMPT)));
+    match api_client.get_chat_completion(&request).await {
+        Ok(completion) => {
+            let title = completion.content.lines().next().unwrap_or_default();
+            let title = title.trim().trim_matches(['"', '*', '#', '.', ' ']);
+            (!title.is_empty()).then(|| title.to_owned())
+        }
+        Err(error) => {
+            themed_eprintln!("[w] Could not generate a title: {:?}", error);
+            None
+        }
+    }
+}
+
+/// Handles "/code [copy] [n] [> file]": lists the code blocks in the last
+/// response, or prints, copies or saves the selected one
+fn run_code_command(args: &str, messages: &[Message]) -> anyhow::Result<()> {
+    let response = messages
+        .last()
+        .filter(|message| message.role == Role::Assistant)
+        .context("There is no response to extract code from")?;
+    let blocks = code_blocks(&response.content);
+    ensure!(!blocks.is_empty(), "No code blocks in the last response");
+    let (selection, path) = match args.split_once('>') {
+        Some((selection, path)) => (selection.trim(), Some(path.trim())),
+        None => (args.trim(), None),
+    };
+    let (copy, selection) = match selection.strip_prefix("copy") {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => (true, rest.trim()),
+        _ => (false, selection),
+    };
+    ensure!(
+        !copy || path.is_none(),
+        "A code block can't be copied and saved at once"
+    );
+    if selection.is_empty() && path.is_none() && !copy {
+        for (index, block) in blocks.iter().enumerate() {
+            let first_line = block.code.lines().next().unwrap_or_default();
+            println!("[{}] {} {}", index + 1, block.language, first_line.trim());
+        }
+        return Ok(());
+    }
+    let number: usize = match selection {
+        "" => 1,
+        _ => selection.parse().context("Invalid code block number")?,
+    };
+    let block = number
+        .checked_sub(1)
+        .and_then(|index| blocks.get(index))
+        .with_context(|| format!("No code block number {}", number))?;
+    match path {
+        Some(path) => {
+            ensure!(!path.is_empty(), "No file name given");
+            fs::write(path, &block.code).with_context(|| format!("Could not save \"{}\"", path))?;
+            themed_println!("[i] Saved to \"{}\"", path);
+        }
+        None if copy => {
+            write_clipboard(&block.code).context("Could not copy to the clipboard")?;
+            themed_println!("[i] Copied code block {} to the clipboard", number);
+        }
+        None => print!("{}", block.code),
+    }
+    Ok(())
+}
+
+/// Shows the file changes found in a response and writes them to disk if confirmed
+fn apply_changes(
+    response: &str,
+    mut confirm: impl FnMut(&str) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let changes = find_changes(response)?;
+    if changes.is_empty() {
+        themed_eprintln!("[i] No file changes found in the response");
+        return Ok(());
+    }
+    for change in &changes {
+        match (&change.original, &change.updated) {
+            (Some(original), Some(updated)) => {
+                themed_eprintln!("[i] Changes to \"{}\":", change.path.display());
+                eprint!("{}", diff(original, updated));
+            }
+            (Some(_), None) => themed_eprintln!("[w] Delete \"{}\"", change.path.display()),
+            (None, _) => themed_eprintln!("[i] New file \"{}\"", change.path.display()),
+        }
+    }
+    if confirm("[?] Apply these changes?")? {
+        for change in &changes {
+            change.write()?;
+            match change.updated {
+                Some(_) => themed_eprintln!("[i] Saved \"{}\"", change.path.display()),
+                None => themed_eprintln!("[i] Deleted \"{}\"", change.path.display()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The conversation as sent, with the instruction to answer in the configured
+/// "language" and the current date and time ("inject_datetime") added to the
+/// system prompt
+fn context_request(messages: &[Message], config: &Config) -> Vec<Message> {
+    let mut request = messages.to_vec();
+    let language = config.language.as_deref();
+    let context: Vec<String> = [
+        language.and_then(|language| language_instruction(language, messages)),
+        config.inject_datetime.then(datetime_context),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if !context.is_empty() {
+        let context = context.join("\n");
+        match request.iter_mut().find(|m| m.role.is_instruction()) {
+            Some(message) => message.content = format!("{}\n\n{}", message.content, context),
+            None => request.insert(0, Message::new(Role::System, &context)),
+        }
+    }
+    request
+}
+
+/// Adds the start of the response given with "--prefill" or "/prefill" as a final
+/// assistant message, for the model to continue
+fn prefill_request(messages: &[Message], config: &Config) -> Vec<Message> {
+    let mut request = context_request(messages, config);
+    if let Some(prefill) = &config.prefill {
+        request.push(Message::new(Role::Assistant, prefill));
+    }
+    request
+}
+
+/// Asks to resume a response interrupted by a network error: APIs that support
+/// prefill continue the partial response as the final message, others are asked
+/// to continue it
+fn resume_request(messages: &[Message], partial: &str, config: &Config) -> Vec<Message> {
+    let mut request = context_request(messages, config);
+    if !partial.is_empty() {
+        request.push(Message::new(Role::Assistant, &partial.to_owned()));
+        if !config.supports_prefill {
+            request.push(Message::new(Role::User, &String::from(CONTINUE_PROMPT)));
+        }
+    }
+    request
+}
+
+/// Asks the model to resume its last response, appending the new text to it rather
+/// than adding a separate turn to the conversation
+async fn continue_last_response(api_client: &ApiClient, messages: &mut [Message], config: &Config) {
+    if !messages.last().is_some_and(|m| m.role == Role::Assistant) {
+        themed_eprintln!("[e] There is no response to continue");
+        return;
+    }
+    let mut request = messages.to_vec();
+    request.push(Message::new(Role::User, &String::from(CONTINUE_PROMPT)));
+    // the response being continued already starts with any prefill
+    let config = Config {
+        prefill: None,
+        ..config.clone()
+    };
+    match get_and_print_completion(api_client, &request, &config).await {
+        Ok(completion) => {
+            if let Some(last) = messages.last_mut() {
+                last.content.push_str(&completion);
+            }
+        }
+        Err(error) => themed_eprintln!("[e] {:?}", error),
+    }
+}
+
+async fn run_sweep(config: Config, command: &SweepCommand) -> anyhow::Result<()> {
+    let temperatures: Vec<f32> = parse_list(&command.temps)?;
+    let seeds: Vec<Option<u64>> = match &command.seeds {
+        Some(seeds) => parse_list(seeds)?.into_iter().map(Some).collect(),
+        None => vec![config.seed],
+    };
+    let user_prompt = match &command.prompt {
+        Some(prompt) => prompt.to_owned(),
+        None if io::stdin().is_terminal() => bail!("Expected a prompt argument or stdin input"),
+        None => read_piped_input()?,
+    };
+    let mut messages = create_messages(&config);
+    messages.push(Message::new(Role::User, &user_prompt));
+    for temperature in temperatures {
+        for seed in &seeds {
+            let mut run_config = config.clone();
+            run_config.temperature = Some(temperature);
+            run_config.seed = *seed;
+            match seed {
+                Some(seed) => println!("[i] temperature = {}, seed = {}", temperature, seed),
+                None => println!("[i] temperature = {}", temperature),
+            }
+            let api_client = ApiClient::new(&run_config)?;
+            if let Err(error) = get_and_print_completion(&api_client, &messages, &run_config).await
+            {
+                themed_eprintln!("[e] {:?}", error);
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
+
+async fn run_batch(config: Config, command: &BatchCommand) -> anyhow::Result<()> {
+    let api_client = ApiClient::new(&config)?;
+    match &command.action {
+        Some(BatchAction::Submit(submit)) => {
+            let items = read_batch_input(&submit.input, &config)?;
+            let requests: Vec<(String, Vec<Message>)> = items
+                .into_iter()
+                .map(|item| (custom_id(&item.id), item.messages))
+                .collect();
+            let job = api_client.submit_batch(&requests).await?;
+            println!("{}", job.id);
+            themed_eprintln!(
+                "[i] Submitted {} prompts, check with \"lmc batch status {}\"",
+                requests.len(),
+                job.id
+            );
+        }
+        Some(BatchAction::Status(status)) => {
+            let job = api_client.get_batch(&status.id).await?;
+            match job.request_counts {
+                Some(counts) => println!(
+                    "{}: {} of {} done, {} failed",
+                    job.status, counts.completed, counts.total, counts.failed
+                ),
+                None => println!("{}", job.status),
+            }
+        }
+        Some(BatchAction::Fetch(fetch)) => {
+            let job = api_client.get_batch(&fetch.id).await?;
+            match job.status.as_str() {
+                // expired and cancelled batches have the results of the requests done by then
+                "completed" | "expired" | "cancelled" => {}
+                "failed" => {
+                    let errors = job.errors.map(|errors| errors.data).unwrap_or_default();
+                    for error in &errors {
+                        match error.line {
+                            Some(line) => themed_eprintln!("[e] Line {}: {}", line, error.message),
+                            None => themed_eprintln!("[e] {}", error.message),
+                        }
+                    }
+                    bail!("The batch failed");
+                }
+                status => bail!("The batch is not completed yet: \"{}\"", status),
+            }
+            let mut content = String::new();
+            for file_id in job.output_file_id.iter().chain(&job.error_file_id) {
+                content.push_str(&api_client.get_file_content(file_id).await?);
+                content.push('\n');
+            }
+            let mut output = open_batch_output(&fetch.output)?;
+            let results = parse_batch_output(&content)?;
+            for result in &results {
+                writeln!(output, "{}", serde_json::to_string(result)?)?;
+            }
+            ensure!(
+                job.status == "completed",
+                "The batch is {}, with only {} results",
+                job.status,
+                results.len()
+            );
+        }
+        None => {
+            let input = command
+                .input
+                .as_deref()
+                .context("Expected an --input file, or a batch subcommand")?;
+            let items = read_batch_input(input, &config)?;
+            if config.dry_run {
+                for item in items {
+                    println!(
+                        "{}",
+                        api_client.describe_request(false, &item.messages).await?
+                    );
+                }
+                return Ok(());
+            }
+            let mut output = open_batch_output(&command.output)?;
+            let count = items.len();
+            // results are written in the input order, as soon as all the previous ones are
+            let mut results = stream::iter(items)
+                .map(|item| {
+                    let api_client = &api_client;
+                    async move {
+                        let completion = api_client.get_chat_completion(&item.messages).await;
+                        BatchResult::new(item.id, completion)
+                    }
+                })
+                .buffered(command.concurrency.max(1));
+            let mut failed = 0;
+            while let Some(result) = results.next().await {
+                if let Some(error) = &result.error {
+                    themed_eprintln!("[e] {}: {}", result.id, error);
+                    failed += 1;
+                }
+                writeln!(output, "{}", serde_json::to_string(&result)?)?;
+                output.flush()?;
+            }
+            themed_eprintln!("[i] Completed {} of {} prompts", count - failed, count);
+        }
+    }
+    Ok(())
+}
+
+fn read_batch_input(path: &str, config: &Config) -> anyhow::Result<Vec<BatchItem>> {
+    let input = fs::read_to_string(path).with_context(|| format!("Could not read \"{}\"", path))?;
+    parse_batch(&input, config.system_prompt.as_deref(), &config.messages)
+}
+
+fn open_batch_output(path: &Option<String>) -> anyhow::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(
+            fs::File::create(path).with_context(|| format!("Could not create \"{}\"", path))?,
+        ),
+        None => Box::new(io::stdout()),
+    })
+}
+
+async fn run_config_command(args: &config::Args, command: &ConfigCommand) -> anyhow::Result<()> {
+    match &command.action {
+        ConfigAction::Check(check) => {
+            print_profile_checks(config::check_profiles(args)?, check.ping).await
+        }
+    }
+}
+
+/// Handles "lmc ping", for the profile given with "-p" or else all of them
+async fn run_ping(args: &config::Args) -> anyhow::Result<()> {
+    let results = match &args.profile {
+        Some(name) => vec![(name.to_owned(), config::get_config(args))],
+        None => config::check_profiles(args)?,
+    };
+    print_profile_checks(results, true).await
+}
+
+/// Prints whether each profile is valid and, if pinging, its API can be reached
+async fn print_profile_checks(
+    results: Vec<(String, anyhow::Result<Config>)>,
+    ping_api: bool,
+) -> anyhow::Result<()> {
+    ensure!(!results.is_empty(), "No profiles found");
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let mut errors = 0;
+    for (name, result) in &results {
+        let (status, details) = match result {
+            Ok(config) if ping_api => ping(config).await,
+            Ok(config) => ("ok", format!("{} at {}", config.model, config.api_url)),
+            Err(error) => ("error", error.to_string()),
+        };
+        if status == "error" {
+            errors += 1;
+        }
+        println!("{:width$}  {:7}  {}", name, status, details);
+    }
+    ensure!(
+        errors == 0,
+        "{} of {} profiles have errors",
+        errors,
+        results.len()
+    );
+    Ok(())
+}
+
+/// Checks that the API of a profile can be reached, with its credentials
+async fn ping(config: &Config) -> (&'static str, String) {
+    let api_client = match ApiClient::new(config) {
+        Ok(api_client) => api_client,
+        Err(error) => return ("error", error.to_string()),
+    };
+    let start = Instant::now();
+    match api_client.list_models().await {
+        Ok(models) => {
+            let elapsed = start.elapsed().as_millis();
+            if models.is_empty() || models.contains(&config.model) {
+                (
+                    "ok",
+                    format!("{} at {} ({} ms)", config.model, config.api_url, elapsed),
+                )
+            } else {
+                (
+                    "warning",
+                    format!(
+                        "{} not listed at {} ({} ms)",
+                        config.model, config.api_url, elapsed
+                    ),
+                )
+            }
+        }
+        // some APIs can't list models, but can still answer a minimal prompt
+        Err(ApiError::Unsupported(_)) => match api_client.warm_up().await {
+            Ok(()) => (
+                "ok",
+                format!(
+                    "{} at {} ({} ms)",
+                    config.model,
+                    config.api_url,
+                    start.elapsed().as_millis()
+                ),
+            ),
+            Err(ApiError::Unsupported(_)) => (
+                "skipped",
+                format!("{} at {} (can't be pinged)", config.model, config.api_url),
+            ),
+            Err(error) => ("error", error.to_string()),
+        },
+        Err(error) => ("error", error.to_string()),
+    }
+}
+
+fn run_audit_command(command: &AuditCommand) -> anyhow::Result<()> {
+    let entries = read_audit_log(command.limit)?;
+    if entries.is_empty() {
+        themed_eprintln!("[i] No requests recorded in {}", audit_path()?.display());
+    }
+    for entry in entries {
+        println!("{}", entry);
+    }
+    Ok(())
+}
+
+fn run_history_command(command: &HistoryCommand) -> anyhow::Result<()> {
+    match &command.action {
+        HistoryAction::List(_) => {
+            for session in load_sessions()? {
+                println!(
+                    "{}  {}  {}",
+                    format_timestamp(session.updated),
+                    session.name().unwrap_or_default(),
+                    session.title.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        HistoryAction::Search(search_args) => {
+            let query = search_args.query.join(" ");
+            ensure!(!query.trim().is_empty(), "No search text provided");
+            let sessions = load_sessions()?;
+            let matches = search(&sessions, &query);
+            if matches.is_empty() {
+                themed_eprintln!("[i] No messages found containing \"{}\"", query);
+            }
+            for found in matches {
+                println!(
+                    "{} ({}) {:?}: {}",
+                    found.session.name().unwrap_or_default(),
+                    format_timestamp(found.session.updated),
+                    found.message.role,
+                    found.excerpt
+                );
+            }
+        }
+        HistoryAction::Show(show) => {
+            let session = load_session(&show.name)?;
+            let messages = match &show.branch {
+                Some(branch) => session
+                    .branches
+                    .get(branch)
+                    .with_context(|| format!("No such branch: \"{}\"", branch))?
+                    .to_vec(),
+                None => session.messages(),
+            };
+            let text = transcript(&messages);
+            let text = if use_color(&io::stdout()) {
+                highlight(&text)
+            } else {
+                text
+            };
+            if show.page {
+                page(&text)?;
+            } else {
+                print!("{}", text);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_import(command: &ImportCommand) -> anyhow::Result<()> {
+    let source = fs::read_to_string(&command.file)
+        .with_context(|| format!("Could not read \"{}\"", command.file))?;
+    let conversations = parse_conversations(&source, command.format)?;
+    ensure!(
+        command.name.is_none() || conversations.len() == 1,
+        "The file has {} conversations, --name can only be used for one",
+        conversations.len()
+    );
+    for conversation in conversations {
+        let title = conversation.title.as_deref().unwrap_or("imported");
+        let name = match &command.name {
+            Some(name) => {
+                ensure!(
+                    load_session(name).is_err(),
+                    "Session \"{}\" already exists",
+                    name
+                );
+                name.to_owned()
+            }
+            None => name_from_title(title)?,
+        };
+        let mut session = Session::new();
+        session.title = conversation.title.to_owned();
+        if let Some(created) = conversation.created {
+            session.created = created;
+        }
+        session.save_as(&name, &conversation.messages)?;
+        themed_eprintln!(
+            "[i] Saved \"{}\" with {} messages",
+            name,
+            conversation.messages.len()
+        );
+    }
+    Ok(())
+}
+
+async fn run_commit(config: Config, command: &CommitCommand) -> anyhow::Result<()> {
+    let output = process::Command::new("git")
+        .args(["diff", "--cached"])
+        .output()
+        .context("Could not run git")?;
+    ensure!(
+        output.status.success(),
+        "git diff failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let diff = String::from_utf8_lossy(&output.stdout);
+    ensure!(!diff.trim().is_empty(), "No staged changes to commit");
+    let messages = vec![
+        Message::new(Role::System, &String::from(COMMIT_PROMPT)),
+        Message::new(Role::User, &diff.into_owned()),
+    ];
+    let api_client = ApiClient::new(&config)?;
+    let completion = get_and_print_completion(&api_client, &messages, &config).await?;
+    if command.commit {
+        // some models wrap the message in a code block despite being asked not to
+        let message = match code_blocks(&completion).into_iter().next() {
+            Some(block) if completion.trim_start().starts_with("```") => block.code,
+            _ => completion,
+        };
+        let mut git = process::Command::new("git")
+            .args(["commit", "--file", "-"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Could not run git")?;
+        if let Some(mut stdin) = git.stdin.take() {
+            stdin.write_all(message.trim().as_bytes())?;
+        }
+        ensure!(git.wait()?.success(), "git commit failed");
+    }
+    Ok(())
+}
+
+async fn run_extract(mut config: Config, command: &ExtractCommand) -> anyhow::Result<()> {
+    let source = fs::read_to_string(&command.schema)
+        .with_context(|| format!("Could not read \"{}\"", command.schema))?;
+    let schema: serde_json::Value = serde_json::from_str(&source)
+        .with_context(|| format!("Invalid JSON schema in \"{}\"", command.schema))?;
+    let text = if command.text.is_empty() {
+        read_piped_input()?
+    } else {
+        command.text.join(" ")
+    };
+    ensure!(!text.trim().is_empty(), "No text to extract data from");
+    // where supported, the response is constrained to the schema as well
+    if config.provider != Provider::Bedrock && config.api != Api::Responses {
+        config.json_schema_file = Some(command.schema.to_owned());
+    }
+    let api_client = ApiClient::new(&config)?;
+    let mut messages = vec![
+        Message::new(
+            Role::System,
+            &format!("{}\n\n{}", EXTRACT_PROMPT, source.trim()),
+        ),
+        Message::new(Role::User, &text),
+    ];
+    let mut errors = Vec::new();
+    for attempt in 0..=command.retries {
+        if attempt > 0 {
+            themed_eprintln!(
+                "[w] Invalid response, asking again ({} of {})",
+                attempt,
+                command.retries
+            );
+        }
+        let completion = api_client.get_chat_completion(&messages).await?;
+        errors = match parse_json_response(&completion.content) {
+            Ok(value) => validate(&value, &schema),
+            Err(error) => vec![error.to_string()],
+        };
+        if errors.is_empty() {
+            let value = parse_json_response(&completion.content)?;
+            println!("{}", serde_json::to_string_pretty(&value)?);
+            return Ok(());
+        }
+        messages.push(Message::new(Role::Assistant, &completion.content));
+        messages.push(Message::new(
+            Role::User,
+            &format!(
+                "That doesn't match the schema:\n- {}\nAnswer again with the corrected JSON only.",
+                errors.join("\n- ")
+            ),
+        ));
+    }
+    bail!("No valid JSON in the response: {}", errors.join("; "))
+}
+
+/// Displays the images in a response, if the terminal supports it, or else saves
+/// the embedded ones to files (the linked ones are visible already)
+async fn show_images(api_client: &ApiClient, completion: &str) {
+    let protocol = detect_protocol();
+    for image in find_images(completion) {
+        let bytes = match (&image, protocol) {
+            (Image::Url(url), Some(_)) => match api_client.download(url).await {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    themed_eprintln!("[w] Could not download \"{}\": {}", url, error);
+                    continue;
+                }
+            },
+            (Image::Url(_), None) => continue,
+            (Image::Data { .. }, _) => match image.decode() {
+                Some(bytes) => bytes,
+                None => continue,
+            },
+        };
+        let rendered = match protocol {
+            Some(protocol) => render_image(&bytes, protocol).unwrap_or(false),
+            None => false,
+        };
+        if !rendered {
+            let extension = match &image {
+                Image::Data { extension, .. } => extension.as_str(),
+                Image::Url(url) => url
+                    .rsplit('.')
+                    .next()
+                    .filter(|e| e.len() <= 4)
+                    .unwrap_or("img"),
+            };
+            match save_image(&bytes, extension) {
+                Ok(path) => themed_println!("[i] Image saved to \"{}\"", path.display()),
+                Err(error) => themed_eprintln!("[e] Could not save image: {}", error),
+            }
+        }
+    }
+}
+
+/// Reads a response aloud, or saves the audio to the "--speech-file"
+async fn speak_response(api_client: &ApiClient, completion: &str, config: &Config) {
+    let result = async {
+        let audio = api_client.speak(completion).await?;
+        match &config.speech_file {
+            Some(path) => {
+                fs::write(path, audio).with_context(|| format!("Could not write \"{}\"", path))?;
+                themed_eprintln!("[i] Saved the spoken response to \"{}\"", path);
+            }
+            None => {
+                let path = env::temp_dir().join("lmc-speech.mp3");
+                fs::write(&path, audio)?;
+                play_audio(&path).with_context(|| {
+                    format!("Could not play the audio, saved to \"{}\"", path.display())
+                })?;
+            }
+        }
+        anyhow::Ok(())
+    };
+    if let Err(error) = result.await {
+        themed_eprintln!("[e] {}", error);
+    }
+}
+
+/// Prints the transcript of an audio file, or sends it as the prompt with "--send"
+async fn run_transcribe(config: Config, command: &TranscribeCommand) -> anyhow::Result<()> {
+    let api_client = ApiClient::new(&config)?;
+    let transcript = api_client.transcribe(&command.file).await?;
+    if !command.send {
+        println!("{}", transcript.trim());
+        return Ok(());
+    }
+    ensure!(
+        !transcript.trim().is_empty(),
+        "No speech found in \"{}\"",
+        command.file.display()
+    );
+    let mut messages = create_messages(&config);
+    messages.push(Message::new(Role::User, &transcript.trim().to_owned()));
+    get_and_print_completion(&api_client, &messages, &config).await?;
+    Ok(())
+}
+
+fn parse_list<T: FromStr>(list: &str) -> anyhow::Result<Vec<T>> {
+    list.split(',')
+        .map(|item| {
+            let item = item.trim();
+            item.parse()
+                .map_err(|_| anyhow!("Invalid value in list: \"{}\"", item))
+        })
+        .collect()
+}
+
+/// Handles "lmc run <prompt>", with any piped input added after the prompt
+async fn run_prompt(config: Config, command: &RunCommand) -> anyhow::Result<()> {
+    let mut user_prompt = command.prompt.join(" ");
+    if config.map {
+        return run_map(config, &user_prompt).await;
+    }
+    if config.follow {
+        return run_follow(config, &user_prompt).await;
+    }
+    if !io::stdin().is_terminal() {
+        let input = read_piped_input()?;
+        user_prompt = if user_prompt.is_empty() {
+            input
+        } else {
+            format!("{}\n\n{}", user_prompt, input)
+        };
+    }
+    run_with_prompt(config, user_prompt).await
+}
+
+async fn run_models(config: Config) -> anyhow::Result<()> {
+    let api_client = ApiClient::new(&config)?;
+    let mut models = api_client.list_models().await?;
+    models.sort();
+    for model in models {
+        println!("{}", model);
+    }
+    Ok(())
+}
+
+fn run_tokens(config: &Config, command: &TokensCommand) -> anyhow::Result<()> {
+    let text = if command.text.is_empty() {
+        read_piped_input()?
+    } else {
+        command.text.join(" ")
+    };
+    let counter = TokenCounter::new(config.tokenizer, &config.model)?;
+    println!("{}", counter.count(&text));
+    Ok(())
+}
+
+async fn run_review(config: Config, command: &ReviewCommand) -> anyhow::Result<()> {
+    let diff = if command.range.is_empty() && !io::stdin().is_terminal() {
+        read_piped_input()?
+    } else {
+        let range = if command.range.is_empty() {
+            vec![String::from("HEAD")]
+        } else {
+            command.range.to_owned()
+        };
+        let output = process::Command::new("git")
+            .arg("diff")
+            .args(range)
+            .output()
+            .context("Could not run git")?;
+        ensure!(
+            output.status.success(),
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let max_tokens = config.max_input_tokens.unwrap_or(REVIEW_PART_TOKENS);
+    let parts = split_diff(&diff, max_tokens);
+    ensure!(!parts.is_empty(), "No changes to review");
+    let requests: Vec<Vec<Message>> = parts
+        .iter()
+        .map(|part| {
+            let fence = fence_for(&part.diff);
+            let changes = format!("{}diff\n{}{}", fence, part.diff, fence);
+            vec![
+                Message::new(Role::System, &String::from(REVIEW_PROMPT)),
+                Message::new(Role::User, &changes),
+            ]
+        })
+        .collect();
+    let api_client = ApiClient::new(&config)?;
+    if config.dry_run {
+        for messages in &requests {
+            println!("{}", api_client.describe_request(false, messages).await?);
+        }
+        return Ok(());
+    }
+    themed_eprintln!("[i] Reviewing {} parts", requests.len());
+    let reviews: Vec<Result<String, ApiError>> = stream::iter(requests)
+        .map(|messages| {
+            let api_client = &api_client;
+            async move {
+                let completion = api_client.get_chat_completion(&messages).await?;
+                Ok(completion.content)
+            }
+        })
+        .buffered(MAP_CONCURRENCY)
+        .collect()
+        .await;
+    // the findings for each file, in the order of the diff
+    let mut files: Vec<(&str, Vec<Finding>)> = Vec::new();
+    let mut failed = 0;
+    for (part, review) in parts.iter().zip(reviews) {
+        let findings = match review {
+            Ok(review) => parse_findings(&review),
+            Err(error) => {
+                themed_eprintln!("[e] {}: {}", part.path, error);
+                failed += 1;
+                continue;
+            }
+        };
+        match files.iter_mut().find(|(path, _)| *path == part.path) {
+            Some((_, file_findings)) => file_findings.extend(findings),
+            None => files.push((part.path.as_str(), findings)),
+        }
+    }
+    let color = use_color(&io::stdout());
+    let mut count = 0;
+    for (path, mut findings) in files {
+        if findings.is_empty() {
+            continue;
+        }
+        findings.sort_by_key(|finding| finding.line);
+        println!("{}", path);
+        for finding in &findings {
+            let marker = format!("[{}]", finding.severity.name());
+            let marker = if color {
+                paint(&marker, finding.severity.style())
+            } else {
+                marker
+            };
+            match finding.line {
+                Some(line) => println!("  {} L{}: {}", marker, line, finding.message),
+                None => println!("  {} {}", marker, finding.message),
+            }
+        }
+        count += findings.len();
+    }
+    themed_eprintln!("[i] {} problems found", count);
+    ensure!(failed == 0, "{} of the parts could not be reviewed", failed);
+    Ok(())
+}
+
+async fn run_proofread(config: Config, command: &ProofreadCommand) -> anyhow::Result<()> {
+    let text = if command.text.is_empty() {
+        read_piped_input()?
+    } else {
+        command.text.join(" ")
+    };
+    let text = text.trim_end();
+    ensure!(!text.is_empty(), "No text to proofread");
+    let messages = vec![
+        Message::new(Role::System, &String::from(PROOFREAD_PROMPT)),
+        Message::new(Role::User, &text.to_owned()),
+    ];
+    let api_client = ApiClient::new(&config)?;
+    if config.dry_run {
+        println!("{}", api_client.describe_request(false, &messages).await?);
+        return Ok(());
+    }
+    let corrected = api_client.get_chat_completion(&messages).await?.content;
+    if command.text_only {
+        println!("{}", corrected);
+    } else if corrected == text {
+        themed_eprintln!("[i] No corrections");
+    } else {
+        println!("{}", word_diff(text, &corrected, use_color(&io::stdout())));
+    }
+    Ok(())
+}
+
+/// Summarises the parts of a text too long to send at once, then the summaries
+/// of the parts, and so on until they fit in a single request
+async fn run_summarize(config: Config, command: &SummarizeCommand) -> anyhow::Result<()> {
+    let mut text = if command.text.is_empty() {
+        read_piped_input()?
+    } else {
+        command.text.join(" ")
+    };
+    ensure!(!text.trim().is_empty(), "No text to summarise");
+    let api_client = ApiClient::new(&config)?;
+    let max_tokens = config.max_input_tokens.unwrap_or(SUMMARY_PART_TOKENS);
+    let prompt = command.length.prompt();
+    let prompt_tokens = estimate_tokens(PART_PROMPT).max(estimate_tokens(prompt));
+    ensure!(
+        prompt_tokens < max_tokens,
+        "max_input_tokens is too low to summarise"
+    );
+    while estimate_tokens(&text) > max_tokens - prompt_tokens {
+        let parts = split_into_chunks(&text, max_tokens - prompt_tokens);
+        let requests: Vec<Vec<Message>> = parts
+            .into_iter()
+            .map(|part| {
+                vec![
+                    Message::new(Role::System, &String::from(PART_PROMPT)),
+                    Message::new(Role::User, &part),
+                ]
+            })
+            .collect();
+        if config.dry_run {
+            for messages in &requests {
+                println!("{}", api_client.describe_request(false, messages).await?);
+            }
+            return Ok(());
+        }
+        themed_eprintln!("[i] Summarising {} parts", requests.len());
+        let summaries: Vec<String> = stream::iter(requests)
+            .map(|messages| {
+                let api_client = &api_client;
+                async move {
+                    let completion = api_client.get_chat_completion(&messages).await?;
+                    Ok::<_, ApiError>(completion.content)
+                }
+            })
+            .buffered(MAP_CONCURRENCY)
+            .try_collect()
+            .await?;
+        let combined = summaries.join("\n\n---\n\n");
+        ensure!(
+            estimate_tokens(&combined) < estimate_tokens(&text),
+            "The summaries of the parts are no shorter than the text"
+        );
+        text = combined;
+    }
+    let messages = vec![
+        Message::new(Role::System, &String::from(prompt)),
+        Message::new(Role::User, &text),
+    ];
+    get_and_print_completion(&api_client, &messages, &config).await?;
+    Ok(())
+}
+
+async fn run_translate(mut config: Config, command: &TranslateCommand) -> anyhow::Result<()> {
+    let text = if command.text.is_empty() {
+        read_piped_input()?
+    } else {
+        command.text.join(" ")
+    };
+    ensure!(!text.trim().is_empty(), "No text to translate");
+    // the "language" setting would contradict the target language
+    config.language = None;
+    let messages = vec![
+        Message::new(
+            Role::System,
+            &translation_prompt(&text, &command.from, &command.to),
+        ),
+        Message::new(Role::User, &text),
+    ];
+    let api_client = ApiClient::new(&config)?;
+    get_and_print_completion(&api_client, &messages, &config).await?;
+    Ok(())
+}
+
+async fn run_warmup(config: Config) -> anyhow::Result<()> {
+    let api_client = ApiClient::new(&config)?;
+    let start = Instant::now();
+    api_client.warm_up().await?;
+    themed_eprintln!(
+        "[i] {} is ready ({:.1}s)",
+        config.model,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Handles "lmc proxy", until interrupted with Ctrl-C
+async fn run_proxy(config: Config, command: &ProxyCommand) -> anyhow::Result<()> {
+    let api_client = ApiClient::new(&config)?;
+    let token = match &command.token {
+        Some(token) => token.to_owned(),
+        None => generate_token()?,
+    };
+    let address = (command.host.as_str(), command.port);
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Could not listen on {}:{}", command.host, command.port))?;
+    themed_eprintln!(
+        "[i] Forwarding to {} on http://{}:{}/v1, with API key {}",
+        config.model,
+        command.host,
+        command.port,
+        token
+    );
+    let context = ProxyContext {
+        api_client: &api_client,
+        initial: &create_messages(&config),
+        model: &config.model,
+        host: &command.host,
+        token: &token,
+    };
+    proxy(listener, &context).await
+}
+
+/// Handles "lmc serve", until interrupted with Ctrl-C
+#[cfg(unix)]
+async fn run_serve(config: Config, command: &ServeCommand) -> anyhow::Result<()> {
+    let path = match &command.socket {
+        Some(path) => path.into(),
+        None => default_socket_path()?,
+    };
+    let api_client = ApiClient::new(&config)?;
+    let listener = bind_socket(&path).await?;
+    themed_eprintln!("[i] Serving {} on {}", config.model, path.display());
+    let result = serve(listener, &api_client, &create_messages(&config)).await;
+    let _ = fs::remove_file(&path);
+    result
+}
+
+#[cfg(not(unix))]
+async fn run_serve(_config: Config, _command: &ServeCommand) -> anyhow::Result<()> {
+    bail!("\"lmc serve\" needs Unix sockets, which this platform doesn't support")
+}
+
+async fn run_with_prompt(config: Config, mut user_prompt: String) -> anyhow::Result<()> {
+    let api_client = ApiClient::new(&config)?;
+    let (mut session, mut messages) = open_session(&config)?;
+    if config.clipboard {
+        user_prompt = append_clipboard(&user_prompt)?;
+    }
+    user_prompt = attach_files(&user_prompt, &config.files, config.max_file_size)?;
+    if user_prompt.trim().is_empty() {
+        bail!("Expected a prompt as an argument or via stdin but it was empty");
+    }
+    ensure!(
+        config.chunk.is_none() || config.max_input_tokens.is_some(),
+        "--chunk requires max_input_tokens to be set"
+    );
+    if let Some(max_input_tokens) = config.max_input_tokens {
+        let system_tokens = config.system_prompt.as_deref().map_or(0, estimate_tokens);
+        let input_tokens = estimate_tokens(&user_prompt);
+        if system_tokens + input_tokens > max_input_tokens {
+            let Some(mode) = config.chunk else {
+                bail!(
+                    "The input is about {} tokens long, over max_input_tokens ({}); use --chunk to process it in parts",
+                    input_tokens,
+                    max_input_tokens
+                );
+            };
+            ensure!(
+                system_tokens < max_input_tokens,
+                "The system prompt alone is over max_input_tokens"
+            );
+            ensure!(session.is_none(), "--chunk can't be used with --session");
+            let chunks = split_into_chunks(&user_prompt, max_input_tokens - system_tokens);
+            return run_chunked(&api_client, &config, chunks, mode).await;
+        }
     }
     messages.push(Message::new(Role::User, &user_prompt));
-    get_and_print_completion(&api_client, &messages, config.stream).await?;
+    let completion = get_and_print_completion(&api_client, &messages, &config).await?;
+    if let Some(session) = session.as_mut().filter(|_| !config.dry_run) {
+        messages.push(Message::new(Role::Assistant, &completion));
+        session.save(&messages)?;
+    }
+    if config.apply {
+        // stdin is the prompt, so ask on the terminal instead
+        apply_changes(&completion, |q| Ok(confirm_on_tty(q)?))?;
+    }
+    if config.inline_images && io::stdout().is_terminal() {
+        show_images(&api_client, &completion).await;
+    }
+    if config.speak && !config.dry_run {
+        speak_response(&api_client, &completion, &config).await;
+    }
     Ok(())
 }
+
+/// Sends each chunk of piped input, as it keeps coming, as the next prompt in the
+/// same conversation, the first one after the prompt given as arguments if any
+async fn run_follow(config: Config, prompt: &str) -> anyhow::Result<()> {
+    ensure!(!io::stdin().is_terminal(), "--follow needs piped input");
+    let seconds = config.follow_timeout.unwrap_or(FOLLOW_TIMEOUT_SECS);
+    ensure!(
+        seconds.is_finite() && seconds > 0.0,
+        "--follow-timeout must be a positive number of seconds"
+    );
+    let api_client = ApiClient::new(&config)?;
+    let mut input = InputFollower::start(
+        config.follow_separator.to_owned(),
+        Duration::from_secs_f64(seconds),
+    );
+    let (mut session, mut messages) = open_session(&config)?;
+    // the system prompt and any few-shot examples are never trimmed
+    let kept = create_messages(&config).len().min(messages.len());
+    let mut first = true;
+    while let Some(chunk) = input.next_chunk().await? {
+        let user_prompt = if prompt.is_empty() || !first {
+            chunk
+        } else {
+            format!("{}\n\n{}", prompt, chunk)
+        };
+        messages.push(Message::new(Role::User, &user_prompt));
+        if let Some(max_tokens) = config.max_input_tokens {
+            trim_conversation(&mut messages, kept, max_tokens);
+        }
+        let result = get_and_print_completion(&api_client, &messages, &config).await;
+        // a failed request shouldn't stop the following ones
+        match result {
+            Ok(completion) => {
+                first = false;
+                messages.push(Message::new(Role::Assistant, &completion));
+                if let Some(session) = session.as_mut().filter(|_| !config.dry_run) {
+                    session.save(&messages)?;
+                }
+            }
+            Err(error) => {
+                themed_eprintln!("[e] {}", error);
+                messages.pop();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drops the oldest turns after the first "kept" messages until the conversation
+/// fits in max_tokens, or only the last prompt is left
+fn trim_conversation(messages: &mut Vec<Message>, kept: usize, max_tokens: usize) {
+    while count_tokens(messages) > max_tokens && messages.len() > kept + 1 {
+        // up to the next prompt, so that the conversation still alternates
+        let end = messages[kept + 1..]
+            .iter()
+            .position(|message| message.role == Role::User)
+            .map_or(messages.len() - 1, |index| kept + 1 + index);
+        messages.drain(kept..end);
+    }
+}
+
+/// Sends each line of piped input as a prompt of its own, after the prompt given
+/// as arguments if any, printing each response on one line in the input order
+async fn run_map(config: Config, prompt: &str) -> anyhow::Result<()> {
+    ensure!(!io::stdin().is_terminal(), "--map needs piped input");
+    ensure!(
+        config.session.is_none(),
+        "--map can't be used with --session"
+    );
+    let api_client = ApiClient::new(&config)?;
+    let input = read_piped_input()?;
+    let requests: Vec<Option<Vec<Message>>> = input
+        .lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                return None;
+            }
+            let mut messages = create_messages(&config);
+            let user_prompt = if prompt.is_empty() {
+                line.to_owned()
+            } else {
+                format!("{}\n\n{}", prompt, line)
+            };
+            messages.push(Message::new(Role::User, &user_prompt));
+            Some(prefill_request(&messages, &config))
+        })
+        .collect();
+    if config.dry_run {
+        for messages in requests.iter().flatten() {
+            println!("{}", api_client.describe_request(false, messages).await?);
+        }
+        return Ok(());
+    }
+    let count = requests.len();
+    // empty lines are kept as they are, so that the output lines match the input
+    let mut results = stream::iter(requests)
+        .map(|messages| {
+            let api_client = &api_client;
+            async move {
+                match messages {
+                    Some(messages) => api_client
+                        .get_chat_completion(&messages)
+                        .await
+                        .map(|completion| completion.content),
+                    None => Ok(String::new()),
+                }
+            }
+        })
+        .buffered(MAP_CONCURRENCY);
+    let prefill = config.prefill.as_deref().unwrap_or_default();
+    let mut stdout = io::stdout();
+    let mut line = 0;
+    let mut failed = 0;
+    while let Some(result) = results.next().await {
+        line += 1;
+        let output = match result {
+            Ok(completion) if completion.is_empty() => completion,
+            Ok(completion) => single_line(&(prefill.to_owned() + &completion)),
+            Err(error) => {
+                themed_eprintln!("[e] Line {}: {}", line, error);
+                failed += 1;
+                String::new()
+            }
+        };
+        writeln!(stdout, "{}", output)?;
+        stdout.flush()?;
+        save_output(&output, &config);
+    }
+    ensure!(failed == 0, "{} of {} lines failed", failed, count);
+    Ok(())
+}
+
+/// Sends each chunk of a long input with the same system prompt, either printing
+/// each response in turn or combining them with one more request at the end
+async fn run_chunked(
+    api_client: &ApiClient,
+    config: &Config,
+    chunks: Vec<String>,
+    mode: ChunkMode,
+) -> anyhow::Result<()> {
+    let count = chunks.len();
+    let mut results = Vec::with_capacity(count);
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        themed_eprintln!("[i] Processing part {} of {}", index + 1, count);
+        let mut messages = create_messages(config);
+        messages.push(Message::new(Role::User, &chunk));
+        match mode {
+            ChunkMode::Concat => {
+                get_and_print_completion(api_client, &messages, config).await?;
+            }
+            ChunkMode::Merge if config.dry_run => {
+                println!("{}", api_client.describe_request(false, &messages).await?);
+            }
+            ChunkMode::Merge => {
+                let completion = api_client.get_chat_completion(&messages).await?;
+                results.push(completion.content);
+            }
+        }
+    }
+    if mode == ChunkMode::Merge {
+        themed_eprintln!("[i] Merging the results");
+        let mut messages = create_messages(config);
+        let prompt = format!("{}\n\n{}", MERGE_PROMPT, results.join("\n\n---\n\n"));
+        messages.push(Message::new(Role::User, &prompt));
+        get_and_print_completion(api_client, &messages, config).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_unsent_prompts() {
+        let system = Message::new(Role::System, &String::from("Be brief."));
+        let prompt = Message::new(Role::User, &String::from("Hi"));
+        let response = Message::new(Role::Assistant, &String::from("Hello"));
+        let mut messages = vec![system.clone(), prompt.clone()];
+        restore_unsent(&mut messages, false, None);
+        assert_eq!(messages, vec![system.clone()]);
+        // "/r" after a failed request
+        let mut messages = vec![system.clone(), prompt.clone()];
+        restore_unsent(&mut messages, true, None);
+        assert_eq!(messages, vec![system.clone(), prompt.clone()]);
+        let mut messages = vec![system.clone(), prompt.clone()];
+        restore_unsent(&mut messages, true, Some(response.clone()));
+        assert_eq!(messages, vec![system, prompt, response]);
+    }
+
+    #[test]
+    fn trims_the_oldest_turns() {
+        let message = |role, text: &str| Message::new(role, &text.repeat(40));
+        let mut messages = vec![
+            message(Role::System, "Be brief. "),
+            message(Role::User, "Example. "),
+            message(Role::Assistant, "Answer. "),
+            message(Role::User, "First. "),
+            message(Role::Assistant, "Reply. "),
+            message(Role::User, "Second. "),
+            message(Role::Assistant, "Reply. "),
+            message(Role::User, "Third. "),
+        ];
+        let all = messages.clone();
+        trim_conversation(&mut messages, 3, count_tokens(&all));
+        assert_eq!(messages, all);
+        trim_conversation(&mut messages, 3, count_tokens(&all) - 1);
+        assert_eq!(messages, [&all[..3], &all[5..]].concat());
+        trim_conversation(&mut messages, 3, 0);
+        assert_eq!(messages, [&all[..3], &all[7..]].concat());
+    }
+
+    #[test]
+    fn saves_output() -> anyhow::Result<()> {
+        let mut config = config::config_from_source(
+            r#"
+[default]
+api_url = "http://localhost:11434/v1"
+model = "gemma2:9b"
+"#,
+        )?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("output.txt");
+        config.output = Some(path.to_string_lossy().into_owned());
+        fs::File::create(&path)?;
+        save_output("Hello", &config);
+        save_output("World", &config);
+        assert_eq!(fs::read_to_string(&path)?, "Hello\nWorld\n");
+        Ok(())
+    }
+}
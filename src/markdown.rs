@@ -0,0 +1,87 @@
+/// A fenced code block found in a Markdown response
+#[derive(Debug, PartialEq)]
+pub struct CodeBlock {
+    pub language: String,
+    pub code: String,
+    /// The last non-blank line before the block, which often names the file
+    pub caption: String,
+}
+
+/// Extracts the fenced code blocks (``` or ~~~) from Markdown text, including an
+/// unterminated last one, e.g. in a truncated response
+pub fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(char, CodeBlock)> = None;
+    let mut caption = "";
+    for line in text.lines() {
+        let trimmed = line.trim();
+        match &mut current {
+            None => {
+                if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                    let fence = trimmed.chars().next().unwrap_or_default();
+                    let info = trimmed.trim_start_matches(fence);
+                    let block = CodeBlock {
+                        language: info
+                            .split_whitespace()
+                            .next()
+                            .unwrap_or_default()
+                            .to_owned(),
+                        code: String::new(),
+                        caption: caption.to_owned(),
+                    };
+                    current = Some((fence, block));
+                } else if !trimmed.is_empty() {
+                    caption = trimmed;
+                }
+            }
+            Some((fence, _)) if is_closing_fence(trimmed, *fence) => {
+                if let Some((_, block)) = current.take() {
+                    blocks.push(block);
+                }
+                caption = "";
+            }
+            Some((_, block)) => {
+                block.code.push_str(line);
+                block.code.push('\n');
+            }
+        }
+    }
+    if let Some((_, block)) = current {
+        blocks.push(block);
+    }
+    blocks
+}
+
+pub fn is_closing_fence(line: &str, fence: char) -> bool {
+    line.len() >= 3 && line.chars().all(|c| c == fence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_code_blocks() {
+        let text = "Try this:\n```python\nprint('hi')\n```\nor\n\n~~~\n$ echo hi\n~~~\n```rust\nfn main() {}";
+        assert_eq!(
+            code_blocks(text),
+            vec![
+                CodeBlock {
+                    language: String::from("python"),
+                    code: String::from("print('hi')\n"),
+                    caption: String::from("Try this:"),
+                },
+                CodeBlock {
+                    language: String::new(),
+                    code: String::from("$ echo hi\n"),
+                    caption: String::from("or"),
+                },
+                CodeBlock {
+                    language: String::from("rust"),
+                    code: String::from("fn main() {}\n"),
+                    caption: String::new(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::{self, Write};
+use std::process::Command;
+
+/// Commands that show a desktop notification with the message given as the last
+/// argument, tried in order until one works
+#[cfg(target_os = "macos")]
+const NOTIFY_COMMANDS: &[&[&str]] = &[&[
+    "osascript",
+    "-e",
+    "on run argv",
+    "-e",
+    "display notification (item 1 of argv) with title \"lmc\"",
+    "-e",
+    "end run",
+]];
+#[cfg(windows)]
+const NOTIFY_COMMANDS: &[&[&str]] = &[];
+#[cfg(not(any(target_os = "macos", windows)))]
+const NOTIFY_COMMANDS: &[&[&str]] = &[&["notify-send", "--app-name=lmc", "lmc"]];
+
+/// Shows a desktop notification with the platform's own tools
+fn send_notification(message: &str) -> io::Result<()> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No notification tool found");
+    for command in NOTIFY_COMMANDS {
+        match Command::new(command[0])
+            .args(&command[1..])
+            .arg(message)
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => last_error = io::Error::other(format!("{}: {}", command[0], status)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+/// Lets the user know that something finished, with a desktop notification if
+/// possible or else the terminal bell
+pub fn notify(message: &str) {
+    if send_notification(message).is_err() {
+        let mut stderr = io::stderr();
+        let _ = stderr.write_all(b"\x07");
+        let _ = stderr.flush();
+    }
+}
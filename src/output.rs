@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use unicode_width::UnicodeWidthStr;
+
+use crate::highlight::Highlighter;
+use crate::terminal::terminal_width;
+use crate::theme::paint;
+
+/// Maximum number of consecutive newlines, i.e. at most two blank lines
+const MAX_NEWLINES: usize = 3;
+
+/// Tidies up model output as it is streamed: strips carriage returns,
+/// collapses long runs of blank lines and drops leading/trailing newlines
+/// (the caller is expected to terminate the output with a single newline).
+pub struct Normalizer {
+    pending_newlines: usize,
+    started: bool,
+}
+
+impl Normalizer {
+    pub fn new() -> Self {
+        Self {
+            pending_newlines: 0,
+            started: false,
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> String {
+        let mut output = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '\r' => {}
+                '\n' => self.pending_newlines += 1,
+                _ => {
+                    if self.started {
+                        let newlines = self.pending_newlines.min(MAX_NEWLINES);
+                        output.push_str(&"\n".repeat(newlines));
+                    }
+                    self.pending_newlines = 0;
+                    self.started = true;
+                    output.push(c);
+                }
+            }
+        }
+        output
+    }
+}
+
+pub fn normalize(text: &str) -> String {
+    Normalizer::new().push(text)
+}
+
+/// Soft-wraps prose at word boundaries as it is streamed, rather than letting the
+/// terminal break lines mid-word, while leaving fenced code blocks untouched
+pub struct Wrapper {
+    width: usize,
+    column: usize,
+    line: String,
+    spaces: String,
+    word: String,
+    in_code: bool,
+}
+
+impl Wrapper {
+    pub fn new() -> Self {
+        Self {
+            width: usize::MAX,
+            column: 0,
+            line: String::new(),
+            spaces: String::new(),
+            word: String::new(),
+            in_code: false,
+        }
+    }
+
+    /// Words are held back until complete, so they can be moved to the next line
+    pub fn push(&mut self, text: &str, width: usize) -> String {
+        self.width = width;
+        let mut output = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c == '\n' {
+                if !self.in_code {
+                    self.flush_word(&mut output);
+                }
+                if self.is_fence() {
+                    self.in_code = !self.in_code;
+                }
+                self.spaces.clear();
+                self.line.clear();
+                self.column = 0;
+                output.push(c);
+            } else if self.in_code {
+                self.line.push(c);
+                output.push(c);
+            } else {
+                self.line.push(c);
+                if c.is_whitespace() {
+                    self.flush_word(&mut output);
+                    self.spaces.push(c);
+                } else {
+                    self.word.push(c);
+                }
+            }
+        }
+        output
+    }
+
+    pub fn finish(&mut self) -> String {
+        let mut output = String::new();
+        self.flush_word(&mut output);
+        output
+    }
+
+    fn is_fence(&self) -> bool {
+        self.line.trim_start().starts_with("```")
+    }
+
+    fn flush_word(&mut self, output: &mut String) {
+        if self.word.is_empty() {
+            return;
+        }
+        let spaces = display_width(&self.spaces);
+        let length = display_width(&self.word);
+        if self.column > 0 && self.column + spaces + length > self.width {
+            output.push('\n');
+            self.column = 0;
+        } else {
+            output.push_str(&self.spaces);
+            self.column += spaces;
+        }
+        output.push_str(&self.word);
+        self.column += length;
+        self.spaces.clear();
+        self.word.clear();
+    }
+}
+
+/// The number of terminal columns some text takes up, with East Asian wide
+/// characters and most emoji taking two, and combining characters none
+fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+pub fn wrap(text: &str, width: usize) -> String {
+    let mut wrapper = Wrapper::new();
+    wrapper.push(text, width) + &wrapper.finish()
+}
+
+/// Joins the lines of a response with spaces, for one line of output per prompt
+pub fn single_line(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Somewhere a streamed response goes besides the terminal, e.g. a log file
+pub trait Sink {
+    /// Receives each token as it's shown
+    fn write(&mut self, token: &str) -> io::Result<()>;
+
+    /// Called at the end of the response
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Writes streamed tokens as JSON lines, e.g. to a named pipe read by another program
+pub struct TokenEmitter {
+    path: String,
+    file: File,
+}
+
+impl TokenEmitter {
+    /// The file is opened once and reused for all the responses, until writing to
+    /// it fails, e.g. when the reader of a named pipe goes away
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut files = emitter_files().lock().unwrap();
+        let file = match files.get(path) {
+            Some(file) => file.try_clone()?,
+            None => {
+                let file = open_without_waiting(path)?;
+                files.insert(path.to_owned(), file.try_clone()?);
+                file
+            }
+        };
+        let path = path.to_owned();
+        Ok(Self { path, file })
+    }
+
+    fn emit(&mut self, line: Value) -> io::Result<()> {
+        let result = writeln!(self.file, "{}", line);
+        if result.is_err() {
+            emitter_files().lock().unwrap().remove(&self.path);
+        }
+        result
+    }
+}
+
+impl Sink for TokenEmitter {
+    fn write(&mut self, token: &str) -> io::Result<()> {
+        self.emit(json!({ "token": token }))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.emit(json!({ "done": true }))
+    }
+}
+
+fn emitter_files() -> &'static Mutex<HashMap<String, File>> {
+    static FILES: OnceLock<Mutex<HashMap<String, File>>> = OnceLock::new();
+    FILES.get_or_init(Default::default)
+}
+
+/// Opens a file for appending, failing rather than waiting when it's a named
+/// pipe without a reader. Writes then block as usual, keeping every token.
+#[cfg(unix)]
+fn open_without_waiting(path: &str) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    unsafe {
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK);
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn open_without_waiting(path: &str) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Logs each streamed delta with the milliseconds since the request was sent, as
+/// JSON lines for "--log-stream", e.g. to compare the latency of providers
+pub struct EventLogger {
+    file: File,
+    started: Instant,
+}
+
+impl EventLogger {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+        writeln!(file, "{}", json!({ "ms": 0, "start": time }))?;
+        Ok(Self {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    fn elapsed(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+impl Sink for EventLogger {
+    fn write(&mut self, token: &str) -> io::Result<()> {
+        let line = json!({ "ms": self.elapsed(), "delta": token });
+        writeln!(self.file, "{}", line)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let line = json!({ "ms": self.elapsed(), "done": true });
+        writeln!(self.file, "{}", line)
+    }
+}
+
+/// Appends the plain text of each response to a file, for "--output"
+pub struct TextLogger {
+    file: File,
+}
+
+impl TextLogger {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self { file })
+    }
+}
+
+impl Sink for TextLogger {
+    fn write(&mut self, token: &str) -> io::Result<()> {
+        self.file.write_all(token.as_bytes())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.file)
+    }
+}
+
+/// Prints a streamed response to stdout as tokens arrive, holding them back while
+/// paused, and returns the complete text at the end
+pub struct StreamWriter {
+    completion: String,
+    normalizer: Option<Normalizer>,
+    wrapper: Option<Wrapper>,
+    highlighter: Option<Highlighter>,
+    sinks: Vec<Box<dyn Sink>>,
+    held_back: Option<String>,
+    style: &'static str,
+}
+
+impl StreamWriter {
+    pub fn new(
+        normalizer: Option<Normalizer>,
+        wrapper: Option<Wrapper>,
+        highlighter: Option<Highlighter>,
+        sinks: Vec<Box<dyn Sink>>,
+    ) -> Self {
+        Self {
+            completion: String::new(),
+            normalizer,
+            wrapper,
+            highlighter,
+            sinks,
+            held_back: None,
+            style: "",
+        }
+    }
+
+    /// Shows the response in a colour (an ANSI escape sequence), e.g. the theme's
+    pub fn set_style(&mut self, style: &'static str) {
+        self.style = style;
+    }
+
+    pub fn write(&mut self, token: String) {
+        let token = match &mut self.normalizer {
+            Some(normalizer) => normalizer.push(&token),
+            None => token,
+        };
+        self.completion.push_str(&token);
+        for sink in &mut self.sinks {
+            sink.write(&token).unwrap_or(());
+        }
+        let token = match &mut self.wrapper {
+            Some(wrapper) => wrapper.push(&token, terminal_width().unwrap_or(usize::MAX)),
+            None => token,
+        };
+        let token = match &mut self.highlighter {
+            Some(highlighter) => highlighter.push(&token),
+            None => token,
+        };
+        self.output(&token);
+    }
+
+    /// Writes a token in a colour (an ANSI escape sequence), as it is: for
+    /// responses shown token by token rather than as Markdown
+    pub fn write_styled(&mut self, token: String, style: &str) {
+        self.completion.push_str(&token);
+        for sink in &mut self.sinks {
+            sink.write(&token).unwrap_or(());
+        }
+        self.output(&format!("{}{}\x1b[0m", style, token));
+    }
+
+    fn output(&mut self, text: &str) {
+        let text = paint(text, self.style);
+        match &mut self.held_back {
+            Some(held_back) => held_back.push_str(&text),
+            None => print_and_flush(&text),
+        }
+    }
+
+    /// The response received so far
+    pub fn text(&self) -> &str {
+        &self.completion
+    }
+
+    pub fn toggle_pause(&mut self) {
+        match self.held_back.take() {
+            Some(held_back) => print_and_flush(&held_back),
+            None => self.held_back = Some(String::new()),
+        }
+    }
+
+    pub fn finish(mut self) -> String {
+        if let Some(held_back) = self.held_back.take() {
+            print!("{}", held_back);
+        }
+        let rest = match &mut self.wrapper {
+            Some(wrapper) => wrapper.finish(),
+            None => String::new(),
+        };
+        let rest = match &mut self.highlighter {
+            Some(highlighter) => highlighter.push(&rest) + &highlighter.finish(),
+            None => rest,
+        };
+        println!("{}", paint(&rest, self.style));
+        for sink in &mut self.sinks {
+            sink.finish().unwrap_or(());
+        }
+        self.completion
+    }
+}
+
+fn print_and_flush(text: &str) {
+    print!("{}", text);
+    io::stdout().flush().unwrap_or(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_carriage_returns() {
+        assert_eq!(normalize("one\r\ntwo\rthree\r\n"), "one\ntwothree");
+    }
+
+    #[test]
+    fn joins_lines() {
+        assert_eq!(single_line("positive\n"), "positive");
+        assert_eq!(single_line("  one\n\ntwo\r\n three "), "one two three");
+    }
+
+    #[test]
+    fn logs_stream_events() -> io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let path = file.path().to_str().unwrap_or_default();
+        let mut logger = EventLogger::open(path)?;
+        logger.write("Hello")?;
+        logger.finish()?;
+        let log = std::fs::read_to_string(path)?;
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap_or_default())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0]["start"].is_u64());
+        assert_eq!(lines[1]["delta"], "Hello");
+        assert!(lines[1]["ms"].is_u64());
+        assert_eq!(lines[2]["done"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn collapses_blank_lines() {
+        assert_eq!(
+            normalize("\n\none\n\n\n\n\n\ntwo\n\nthree\n\n"),
+            "one\n\n\ntwo\n\nthree"
+        );
+    }
+
+    #[test]
+    fn wraps_prose_at_word_boundaries() {
+        assert_eq!(
+            wrap("The quick brown fox jumps over the lazy dog", 16),
+            "The quick brown\nfox jumps over\nthe lazy dog"
+        );
+    }
+
+    #[test]
+    fn leaves_code_blocks_untouched() {
+        let text =
+            "Some text here\n```\nlet long_line = some_function(argument);\n```\nMore text here";
+        assert_eq!(
+            wrap(text, 10),
+            "Some text\nhere\n```\nlet long_line = some_function(argument);\n```\nMore text\nhere"
+        );
+    }
+
+    #[test]
+    fn wraps_split_tokens() {
+        let mut wrapper = Wrapper::new();
+        let tokens = ["Hel", "lo wo", "rld, ", "how are", " you?"];
+        let mut output: String = tokens.iter().map(|t| wrapper.push(t, 12)).collect();
+        output.push_str(&wrapper.finish());
+        assert_eq!(output, "Hello world,\nhow are you?");
+    }
+
+    #[test]
+    fn wraps_wide_characters() {
+        assert_eq!(display_width("héllo"), 5);
+        assert_eq!(display_width("日本語 🎉"), 9);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("한국어 ✨"), 9);
+        assert_eq!(
+            wrap("日本語 の テキスト です", 10),
+            "日本語 の\nテキスト\nです"
+        );
+        assert_eq!(wrap("🎉🎉 🎉🎉 🎉🎉", 9), "🎉🎉 🎉🎉\n🎉🎉");
+    }
+
+    #[test]
+    fn handles_multibyte_tokens() {
+        let mut normalizer = Normalizer::new();
+        let mut wrapper = Wrapper::new();
+        let tokens = ["Ça", " va, ", "😀", "\r\n\n\n\n", "東京", "は晴れ", " ✨"];
+        let mut output = String::new();
+        for token in tokens {
+            output.push_str(&wrapper.push(&normalizer.push(token), 8));
+        }
+        output.push_str(&wrapper.finish());
+        assert_eq!(output, "Ça va,\n😀\n\n\n東京は晴れ\n✨");
+    }
+
+    #[test]
+    fn handles_split_tokens() {
+        let mut normalizer = Normalizer::new();
+        let tokens = ["Hello", "\n\n", "\n\n\r", "\n", "world", "\n\n"];
+        let output: String = tokens.iter().map(|t| normalizer.push(t)).collect();
+        assert_eq!(output, "Hello\n\n\nworld");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn emits_tokens_without_waiting_for_a_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipe = dir.path().join("tokens");
+        let path = std::ffi::CString::new(pipe.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        assert!(TokenEmitter::open(pipe.to_str().unwrap()).is_err());
+
+        let file = dir.path().join("tokens.jsonl");
+        let path = file.to_str().unwrap();
+        for token in ["Hello", " world"] {
+            let mut emitter = TokenEmitter::open(path).unwrap();
+            emitter.write(token).unwrap();
+            emitter.finish().unwrap();
+        }
+        let lines = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(lines.lines().count(), 4);
+        assert!(lines.starts_with("{\"token\":\"Hello\"}\n{\"done\":true}\n"));
+    }
+}
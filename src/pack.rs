@@ -0,0 +1,211 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+
+use crate::chunks::estimate_tokens;
+use crate::files::fence_for;
+
+/// The token budget for the files added with --dir, unless set with --dir-tokens
+pub const DEFAULT_DIR_TOKENS: usize = 32_000;
+
+/// Files are given at least this many tokens, or left out altogether
+const MIN_FILE_TOKENS: usize = 100;
+
+/// The files in a directory, packed into text for the context
+pub struct Pack {
+    pub text: String,
+    pub files: usize,
+    pub truncated: usize,
+    pub omitted: usize,
+}
+
+/// Reads the text files in a directory, except hidden ones and those ignored by
+/// ".gitignore" files, and only those matching one of the `include` globs if any
+pub fn pack_directory(dir: &str, include: &[String], max_tokens: usize) -> Result<Pack> {
+    let mut overrides = OverrideBuilder::new(dir);
+    for glob in include {
+        overrides
+            .add(glob)
+            .with_context(|| format!("Invalid glob: \"{}\"", glob))?;
+    }
+    let walker = WalkBuilder::new(dir)
+        .overrides(overrides.build()?)
+        .require_git(false)
+        .sort_by_file_path(|a, b| a.cmp(b))
+        .build();
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        // binary files, or anything else that isn't UTF-8 text, are skipped
+        let Ok(content) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let path = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        files.push((relative_path(path), content));
+    }
+    ensure!(!files.is_empty(), "No files to add in \"{}\"", dir);
+    Ok(pack_files(dir, &files, max_tokens))
+}
+
+/// The path with "/" separators on any platform
+fn relative_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Lists the files in a tree, then gives each one a code block, truncating the
+/// largest ones or leaving them out to keep within the budget
+fn pack_files(dir: &str, files: &[(String, String)], max_tokens: usize) -> Pack {
+    let mut text = format!(
+        "Files in {}:\n\n{}",
+        dir,
+        tree_overview(files.iter().map(|(path, _)| path.as_str()))
+    );
+    // the name and fences of each file take a few tokens as well
+    let overhead = estimate_tokens(&text)
+        + files
+            .iter()
+            .map(|(path, _)| estimate_tokens(path) + 4)
+            .sum::<usize>();
+    let sizes: Vec<usize> = files
+        .iter()
+        .map(|(_, content)| estimate_tokens(content))
+        .collect();
+    let allocation = allocate_tokens(&sizes, max_tokens.saturating_sub(overhead));
+    let mut truncated = 0;
+    let mut omitted = Vec::new();
+    for (((path, content), &size), tokens) in files.iter().zip(&sizes).zip(allocation) {
+        if tokens == 0 && size > 0 {
+            omitted.push(path.as_str());
+            continue;
+        }
+        let mut content = content.trim_end_matches('\n').to_owned();
+        if tokens < size {
+            let kept = truncate_lines(&content, tokens * 4);
+            let more_lines = content[kept.len()..].lines().count();
+            content = format!("{}\n[... {} more lines]", kept, more_lines);
+            truncated += 1;
+        }
+        let fence = fence_for(&content);
+        text.push_str(&format!("\n{}{}\n{}\n{}\n", fence, path, content, fence));
+    }
+    if !omitted.is_empty() {
+        text.push_str(&format!("\nLeft out for length: {}\n", omitted.join(", ")));
+    }
+    Pack {
+        text,
+        files: files.len() - omitted.len(),
+        truncated,
+        omitted: omitted.len(),
+    }
+}
+
+/// Indents the names of files and directories by depth, given paths sorted so
+/// that the contents of each directory are listed together
+fn tree_overview<'a>(paths: impl Iterator<Item = &'a str>) -> String {
+    let mut tree = String::new();
+    let mut previous: Vec<&str> = Vec::new();
+    for path in paths {
+        let (dirs, name) = match path.rsplit_once('/') {
+            Some((dirs, name)) => (dirs.split('/').collect(), name),
+            None => (Vec::new(), path),
+        };
+        let common = dirs
+            .iter()
+            .zip(&previous)
+            .take_while(|(dir, previous)| dir == previous)
+            .count();
+        for (depth, dir) in dirs.iter().enumerate().skip(common) {
+            tree.push_str(&format!("{}{}/\n", "  ".repeat(depth), dir));
+        }
+        tree.push_str(&format!("{}{}\n", "  ".repeat(dirs.len()), name));
+        previous = dirs;
+    }
+    tree
+}
+
+/// Shares the tokens among files, from the smallest up, so that small files are
+/// kept whole and larger ones cut to equal parts. When the parts would be too
+/// small to be useful the largest files get nothing instead.
+fn allocate_tokens(sizes: &[usize], budget: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&index| sizes[index]);
+    let mut kept = order.len();
+    loop {
+        let mut allocation = vec![0; sizes.len()];
+        let mut remaining = budget;
+        for (position, &index) in order[..kept].iter().enumerate() {
+            let share = remaining / (kept - position);
+            allocation[index] = sizes[index].min(share);
+            remaining -= allocation[index];
+        }
+        let useful = order[..kept].iter().all(|&index| {
+            allocation[index] == sizes[index] || allocation[index] >= MIN_FILE_TOKENS
+        });
+        if useful || kept == 0 {
+            return allocation;
+        }
+        kept -= 1;
+    }
+}
+
+/// The start of the text up to `max_chars`, ending at a line break if possible
+fn truncate_lines(text: &str, max_chars: usize) -> &str {
+    let end = text
+        .char_indices()
+        .nth(max_chars)
+        .map_or(text.len(), |(index, _)| index);
+    match text[..end].rfind('\n') {
+        Some(line_end) => &text[..line_end],
+        None => &text[..end],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_tokens_to_small_files_first() {
+        assert_eq!(
+            allocate_tokens(&[300, 50, 2000, 1000], 1000),
+            vec![300, 50, 325, 325]
+        );
+        assert_eq!(
+            allocate_tokens(&[300, 50, 2000, 1000], 300),
+            vec![125, 50, 0, 125]
+        );
+    }
+
+    #[test]
+    fn packs_directory_with_tree() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("src/api"))?;
+        fs::create_dir_all(dir.path().join("target"))?;
+        fs::write(dir.path().join(".gitignore"), "target/\n")?;
+        fs::write(dir.path().join("Cargo.toml"), "[package]\n")?;
+        fs::write(dir.path().join("src/api/mod.rs"), "mod api;\n")?;
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}\n")?;
+        fs::write(dir.path().join("target/main.rs"), "fn main() {}\n")?;
+        let dir = dir.path().to_str().unwrap();
+        let pack = pack_directory(dir, &[String::from("*.rs")], 1000)?;
+        assert_eq!(
+            pack.text,
+            format!(
+                "Files in {}:\n\nsrc/\n  api/\n    mod.rs\n  main.rs\n\n\
+                ```src/api/mod.rs\nmod api;\n```\n\n```src/main.rs\nfn main() {{}}\n```\n",
+                dir
+            )
+        );
+        assert_eq!(pack.files, 2);
+        Ok(())
+    }
+}
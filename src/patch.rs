@@ -0,0 +1,479 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::markdown::code_blocks;
+use crate::theme::paint;
+
+/// Number of unchanged lines shown around each change in a diff
+const DIFF_CONTEXT: usize = 2;
+
+/// New content for a file, as proposed by a response, or none if it's deleted
+#[derive(Debug, PartialEq)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub original: Option<String>,
+    pub updated: Option<String>,
+}
+
+impl FileChange {
+    pub fn write(&self) -> Result<()> {
+        let Some(updated) = &self.updated else {
+            return fs::remove_file(&self.path)
+                .with_context(|| format!("Could not delete \"{}\"", self.path.display()));
+        };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, updated)
+            .with_context(|| format!("Could not write \"{}\"", self.path.display()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Patch {
+    Diff { path: String, hunks: Vec<Hunk> },
+    Replace { path: String, content: String },
+    Delete { path: String },
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Hunk {
+    start: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+/// Finds the file changes in a response, either unified diffs in "diff" code blocks
+/// (or the whole response, if it has no code blocks) or code blocks introduced by
+/// a file name, e.g. "**src/main.rs**"
+pub fn find_changes(response: &str) -> Result<Vec<FileChange>> {
+    let mut changes = Vec::new();
+    for patch in parse_patches(response) {
+        let path = match &patch {
+            Patch::Diff { path, .. } | Patch::Replace { path, .. } | Patch::Delete { path } => {
+                check_path(path)?
+            }
+        };
+        let original = match fs::exists(&path)? {
+            true => Some(fs::read_to_string(&path)?),
+            false => None,
+        };
+        let updated = match patch {
+            Patch::Diff { hunks, .. } => Some(
+                apply_hunks(original.as_deref().unwrap_or_default(), &hunks)
+                    .with_context(|| format!("Could not apply diff to \"{}\"", path.display()))?,
+            ),
+            Patch::Replace { content, .. } => Some(content),
+            // there's nothing to do for a file that doesn't exist (anymore)
+            Patch::Delete { .. } if original.is_none() => continue,
+            Patch::Delete { .. } => None,
+        };
+        changes.push(FileChange {
+            path,
+            original,
+            updated,
+        });
+    }
+    Ok(changes)
+}
+
+/// Only allows paths within the current directory
+fn check_path(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    ensure!(
+        path.components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir)),
+        "Refusing to write outside the current directory: \"{}\"",
+        path.display()
+    );
+    Ok(path.to_owned())
+}
+
+fn parse_patches(response: &str) -> Vec<Patch> {
+    let blocks = code_blocks(response);
+    if blocks.is_empty() {
+        return parse_diff(response);
+    }
+    let mut patches = Vec::new();
+    for block in blocks {
+        if block.language == "diff" || block.language == "patch" {
+            patches.extend(parse_diff(&block.code));
+        } else if let Some(path) = file_name_in(&block.caption) {
+            patches.push(Patch::Replace {
+                path: path.to_owned(),
+                content: block.code,
+            });
+        }
+    }
+    patches
+}
+
+/// Extracts a file name from a line like "**src/main.rs**", "File: main.py" or
+/// "Update `lib.rs` as follows:", i.e. either the whole line or an emphasized part
+fn file_name_in(caption: &str) -> Option<&str> {
+    let is_markup = |c: char| c.is_whitespace() || "*`#:".contains(c);
+    let caption = caption.trim_matches(is_markup);
+    let after_colon = caption.rsplit_once(':').map(|(_, name)| name);
+    let emphasized = caption.split('`').skip(1).step_by(2);
+    let bold = caption.split("**").skip(1).step_by(2);
+    [caption]
+        .into_iter()
+        .chain(after_colon)
+        .chain(emphasized)
+        .chain(bold)
+        .map(|name| name.trim_matches(is_markup))
+        .find(|name| {
+            !name.is_empty()
+                && !name.contains(char::is_whitespace)
+                && !name.ends_with('.')
+                && (name.contains('.') || name.contains('/'))
+        })
+}
+
+fn parse_diff(text: &str) -> Vec<Patch> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut patches = Vec::new();
+    let mut current: Option<(String, Vec<Hunk>)> = None;
+    for (i, line) in lines.iter().enumerate() {
+        let is_header = |prefix| line.starts_with(prefix);
+        if is_header("--- ")
+            && lines
+                .get(i + 1)
+                .is_some_and(|next| next.starts_with("+++ "))
+        {
+            continue;
+        } else if is_header("+++ ") && i > 0 && lines[i - 1].starts_with("--- ") {
+            if let Some((path, hunks)) = current.take() {
+                patches.push(Patch::Diff { path, hunks });
+            }
+            match strip_diff_path(&line[4..]) {
+                // the lines removed from a deleted file don't need to be checked
+                path if path == "/dev/null" => patches.push(Patch::Delete {
+                    path: strip_diff_path(&lines[i - 1][4..]),
+                }),
+                path => current = Some((path, Vec::new())),
+            }
+        } else if let Some(range) = line.strip_prefix("@@ ") {
+            if let Some((_, hunks)) = &mut current {
+                hunks.push(Hunk {
+                    start: parse_hunk_start(range),
+                    ..Hunk::default()
+                });
+            }
+        } else if let Some(hunk) = current.as_mut().and_then(|(_, hunks)| hunks.last_mut()) {
+            match line.chars().next() {
+                Some('+') => hunk.new.push(line[1..].to_owned()),
+                Some('-') => hunk.old.push(line[1..].to_owned()),
+                Some(' ') | None => {
+                    // models often drop the leading space of empty context lines
+                    let context = line.get(1..).unwrap_or_default();
+                    hunk.old.push(context.to_owned());
+                    hunk.new.push(context.to_owned());
+                }
+                _ => {} // e.g. "\\ No newline at end of file"
+            }
+        }
+    }
+    if let Some((path, hunks)) = current {
+        patches.push(Patch::Diff { path, hunks });
+    }
+    patches
+}
+
+/// Removes the "a/" or "b/" prefix added by git and any timestamp after a tab
+fn strip_diff_path(path: &str) -> String {
+    let path = path.split('\t').next().unwrap_or_default().trim();
+    let stripped = path.strip_prefix("b/").or_else(|| path.strip_prefix("a/"));
+    stripped.unwrap_or(path).to_owned()
+}
+
+/// Parses the original line number from "-12,7 +12,8 @@"
+fn parse_hunk_start(range: &str) -> usize {
+    range
+        .trim_start_matches('-')
+        .split([',', ' '])
+        .next()
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Applies hunks by looking for their original lines rather than trusting the line
+/// numbers, which models often get wrong
+fn apply_hunks(original: &str, hunks: &[Hunk]) -> Result<String> {
+    let mut lines: Vec<String> = original.lines().map(str::to_owned).collect();
+    let mut cursor = 0;
+    for hunk in hunks {
+        let position = if hunk.old.is_empty() {
+            hunk.start.min(lines.len())
+        } else {
+            match find_lines(&lines, &hunk.old, cursor).or(find_lines(&lines, &hunk.old, 0)) {
+                Some(position) => position,
+                None => bail!(
+                    "Lines not found: {:?}",
+                    hunk.old.first().unwrap_or(&String::new())
+                ),
+            }
+        };
+        lines.splice(
+            position..position + hunk.old.len(),
+            hunk.new.iter().cloned(),
+        );
+        cursor = position + hunk.new.len();
+    }
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() && (original.is_empty() || original.ends_with('\n')) {
+        updated.push('\n');
+    }
+    Ok(updated)
+}
+
+fn find_lines(lines: &[String], wanted: &[String], from: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(wanted.len())?;
+    (from..=last).find(|&i| {
+        lines[i..i + wanted.len()]
+            .iter()
+            .zip(wanted)
+            .all(|(line, wanted)| line.trim_end() == wanted.trim_end())
+    })
+}
+
+/// Shows the changed lines, prefixed with "-" and "+", with some context around them
+pub fn diff(original: &str, updated: &str) -> String {
+    let old: Vec<&str> = original.lines().collect();
+    let new: Vec<&str> = updated.lines().collect();
+    // only compare what's between the common prefix and suffix, to keep the table small
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let mut lines: Vec<(char, &str)> = old[..prefix].iter().map(|line| (' ', *line)).collect();
+    lines.extend(diff_lines(old_middle, new_middle));
+    lines.extend(old[old.len() - suffix..].iter().map(|line| (' ', *line)));
+
+    let changed: Vec<usize> = (0..lines.len()).filter(|&i| lines[i].0 != ' ').collect();
+    let mut output = String::new();
+    let mut shown_until = 0;
+    for (i, (sign, line)) in lines.iter().enumerate() {
+        let near_change = changed
+            .iter()
+            .any(|&c| i + DIFF_CONTEXT >= c && i <= c + DIFF_CONTEXT);
+        if near_change {
+            if i > shown_until {
+                output.push_str("...\n");
+            }
+            output.push_str(&format!("{}{}\n", sign, line));
+            shown_until = i + 1;
+        }
+    }
+    output
+}
+
+/// Shows the whole text with the changes marked word by word: removed text in red
+/// and struck through, added text in green, or without colours like git's
+/// "[-removed-]{+added+}"
+pub fn word_diff(original: &str, updated: &str, color: bool) -> String {
+    let old: Vec<&str> = original.split_inclusive('\n').collect();
+    let new: Vec<&str> = updated.split_inclusive('\n').collect();
+    let mut output = String::new();
+    // words are only compared within the lines that changed, to keep the table small
+    let (mut removed, mut added) = (String::new(), String::new());
+    for (sign, line) in diff_lines(&old, &new) {
+        match sign {
+            '-' => removed.push_str(line),
+            '+' => added.push_str(line),
+            _ => {
+                output.push_str(&diff_words(&removed, &added, color));
+                removed.clear();
+                added.clear();
+                output.push_str(line);
+            }
+        }
+    }
+    output.push_str(&diff_words(&removed, &added, color));
+    output
+}
+
+fn diff_words(old: &str, new: &str, color: bool) -> String {
+    let mut output = String::new();
+    let mut run = (' ', String::new());
+    for (sign, word) in diff_lines(&split_words(old), &split_words(new)) {
+        if sign != run.0 {
+            output.push_str(&mark_change(run.0, &run.1, color));
+            run = (sign, String::new());
+        }
+        run.1.push_str(word);
+    }
+    output.push_str(&mark_change(run.0, &run.1, color));
+    output
+}
+
+fn mark_change(sign: char, text: &str, color: bool) -> String {
+    match (sign, color) {
+        (' ', _) => text.to_owned(),
+        ('-', true) => paint(text, "\x1b[9;31m"),
+        ('-', false) => format!("[-{}-]", text),
+        (_, true) => paint(text, "\x1b[32m"),
+        (_, false) => format!("{{+{}+}}", text),
+    }
+}
+
+/// Splits text into words, runs of whitespace and single other characters, which
+/// together make up the whole text
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let same_kind = |next: char| {
+            (c.is_alphanumeric() && next.is_alphanumeric())
+                || (c.is_whitespace() && next.is_whitespace())
+        };
+        match chars.peek() {
+            Some(&(_, next)) if same_kind(next) => {}
+            _ => {
+                let end = index + c.len_utf8();
+                words.push(&text[start..end]);
+                start = end;
+            }
+        }
+    }
+    words
+}
+
+/// Diffs two lists of lines via their longest common subsequence
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_diffs_and_named_code_blocks() {
+        let response = "Change this:\n```diff\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,2 +1,2 @@\n fn one() {}\n-fn two() {}\n+fn three() {}\n```\n\nand add **notes.txt**:\n```\nhello\n```\n\nFor example:\n```\nignored\n```";
+        assert_eq!(
+            parse_patches(response),
+            vec![
+                Patch::Diff {
+                    path: String::from("src/lib.rs"),
+                    hunks: vec![Hunk {
+                        start: 1,
+                        old: vec![String::from("fn one() {}"), String::from("fn two() {}")],
+                        new: vec![String::from("fn one() {}"), String::from("fn three() {}")],
+                    }],
+                },
+                Patch::Replace {
+                    path: String::from("notes.txt"),
+                    content: String::from("hello\n"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_file_names_in_captions() {
+        assert_eq!(file_name_in("**src/main.rs**"), Some("src/main.rs"));
+        assert_eq!(file_name_in("File: main.py"), Some("main.py"));
+        assert_eq!(
+            file_name_in("Then update `lib.rs` as follows:"),
+            Some("lib.rs")
+        );
+        assert_eq!(file_name_in("Here is the updated code:"), None);
+        assert_eq!(file_name_in("This is version 1.2."), None);
+    }
+
+    #[test]
+    fn parses_deleted_files() {
+        let response = "--- a/old.rs\n+++ /dev/null\n@@ -1 +0,0 @@\n-fn old() {}\n\
+            --- a/lib.rs\n+++ b/lib.rs\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(
+            parse_patches(response),
+            vec![
+                Patch::Delete {
+                    path: String::from("old.rs"),
+                },
+                Patch::Diff {
+                    path: String::from("lib.rs"),
+                    hunks: vec![Hunk {
+                        start: 1,
+                        old: vec![String::from("a")],
+                        new: vec![String::from("b")],
+                    }],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_hunks_with_wrong_line_numbers() -> Result<()> {
+        let hunks = parse_diff("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n b\n-c\n+C\n\n");
+        let Some(Patch::Diff { hunks, .. }) = hunks.into_iter().next() else {
+            panic!("no diff");
+        };
+        assert_eq!(apply_hunks("a\nb\nc\n\nd\n", &hunks)?, "a\nb\nC\n\nd\n");
+        assert!(apply_hunks("x\ny\n", &hunks).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shows_changes_with_context() {
+        assert_eq!(
+            diff("1\n2\n3\n4\n5\n6\n7\n", "1\n2\n3\n4\nfive\n6\n7\n"),
+            "...\n 3\n 4\n-5\n+five\n 6\n 7\n"
+        );
+    }
+
+    #[test]
+    fn marks_changed_words() {
+        assert_eq!(
+            word_diff(
+                "Their going home.\nSee you tomorow!\n\nBye",
+                "They're going home.\nSee you tomorrow!\n\nBye",
+                false
+            ),
+            "[-Their-]{+They're+} going home.\nSee you [-tomorow-]{+tomorrow+}!\n\nBye"
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_current_directory() {
+        assert!(check_path("src/main.rs").is_ok());
+        assert!(check_path("../main.rs").is_err());
+        assert!(check_path("/etc/passwd").is_err());
+    }
+}
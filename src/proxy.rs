@@ -0,0 +1,474 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal;
+
+use crate::api::{ApiClient, Completion, Message, StreamEvent};
+
+/// Requests with a larger body are rejected
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+/// Requests with a longer request or header line are rejected
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// Requests with more headers are rejected
+const MAX_HEADERS: usize = 100;
+
+/// Host names that always refer to this machine
+const LOCAL_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// A request to "/v1/chat/completions", of which only the messages and whether
+/// to stream the response are used: the model and its settings are the profile's
+#[derive(Debug, Deserialize)]
+struct ProxyRequest {
+    messages: Vec<Value>,
+    #[serde(default)]
+    stream: bool,
+}
+
+/// As much of an HTTP request as the proxy needs
+struct HttpRequest {
+    method: String,
+    path: String,
+    host: Option<String>,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// The details each response of the proxy shares
+pub struct ProxyContext<'a> {
+    pub api_client: &'a ApiClient,
+    /// the initial messages, e.g. the system prompt, added to requests without
+    /// instructions of their own
+    pub initial: &'a [Message],
+    pub model: &'a str,
+    /// the address listened on, which clients may use as the "Host" too
+    pub host: &'a str,
+    /// the API key clients must send, as "Authorization: Bearer <token>"
+    pub token: &'a str,
+}
+
+/// A random API key for the clients of the proxy
+pub fn generate_token() -> Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|error| anyhow!("Could not generate an API key: {}", error))?;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("lmc-{}", hex))
+}
+
+/// Answers OpenAI-style chat completion requests with the selected profile, for
+/// all the clients connected to the listener at the same time, until
+/// interrupted with Ctrl-C
+pub async fn proxy(listener: TcpListener, context: &ProxyContext<'_>) -> Result<()> {
+    let mut clients = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                clients.push(handle_connection(stream, context));
+            }
+            Some(result) = clients.next(), if !clients.is_empty() => {
+                if let Err(error) = result {
+                    eprintln!("[w] {:#}", error);
+                }
+            }
+            _ = signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+/// Serves a single request, then closes the connection
+async fn handle_connection(mut stream: TcpStream, context: &ProxyContext<'_>) -> Result<()> {
+    let start = Instant::now();
+    let request = read_request(&mut BufReader::new(&mut stream)).await;
+    let request = match request {
+        Ok(request) => request,
+        Err(error) => {
+            let message = format!("{:#}", error);
+            return respond(&mut stream, 400, &error_body(&message)).await;
+        }
+    };
+    // clients may be configured with a base URL with or without "/v1"
+    let path = request.path.split('?').next().unwrap_or_default();
+    let status = match (request.method.as_str(), path.trim_start_matches("/v1")) {
+        // e.g. a web page whose host name was made to point to this machine
+        _ if !is_allowed_host(request.host.as_deref(), context.host) => {
+            let message = "Only requests to this machine are allowed";
+            respond(&mut stream, 403, &error_body(message)).await?;
+            403
+        }
+        _ if request.authorization != Some(format!("Bearer {}", context.token)) => {
+            let message = "Invalid API key, use the one lmc proxy printed at startup";
+            respond(&mut stream, 401, &error_body(message)).await?;
+            401
+        }
+        ("POST", "/chat/completions") => {
+            chat_completions(&mut stream, &request.body, context).await?
+        }
+        ("GET", "/models") => {
+            let models = json!({
+                "object": "list",
+                "data": [{"id": context.model, "object": "model", "owned_by": "lmc"}],
+            });
+            respond(&mut stream, 200, &models).await?;
+            200
+        }
+        _ => {
+            let message = format!("Unsupported endpoint: {} {}", request.method, path);
+            respond(&mut stream, 404, &error_body(&message)).await?;
+            404
+        }
+    };
+    eprintln!(
+        "[i] {} {} {} ({:.1}s)",
+        request.method,
+        path,
+        status,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Reads the request line, the headers and a body of the length given by
+/// "Content-Length", within limits that keep clients from using up the memory
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<HttpRequest> {
+    let mut line = String::new();
+    read_line(reader, &mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("Invalid request line: \"{}\"", line.trim());
+    };
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        host: None,
+        authorization: None,
+        body: Vec::new(),
+    };
+    let mut length = 0;
+    for count in 0.. {
+        line.clear();
+        read_line(reader, &mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        ensure!(count < MAX_HEADERS, "Too many headers");
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("Invalid header: \"{}\"", header);
+        };
+        let value = value.trim();
+        match name.trim().to_lowercase().as_str() {
+            "content-length" => length = value.parse().context("Invalid Content-Length")?,
+            "transfer-encoding" if value != "identity" => {
+                bail!("Unsupported Transfer-Encoding: \"{}\"", value)
+            }
+            "host" => request.host = Some(value.to_owned()),
+            "authorization" => request.authorization = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+    ensure!(length <= MAX_BODY_SIZE, "The request body is too large");
+    // as it comes, rather than all allocated up front for any Content-Length
+    reader
+        .take(length as u64)
+        .read_to_end(&mut request.body)
+        .await?;
+    ensure!(
+        request.body.len() == length,
+        "The request body is incomplete"
+    );
+    Ok(request)
+}
+
+/// Reads a line of at most MAX_LINE_LENGTH bytes
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), line: &mut String) -> Result<()> {
+    let limit = MAX_LINE_LENGTH as u64 + 1;
+    reader.take(limit).read_line(line).await?;
+    ensure!(
+        line.len() <= MAX_LINE_LENGTH,
+        "The request has a line that is too long"
+    );
+    Ok(())
+}
+
+/// Whether the "Host" header names this machine, or the address listened on,
+/// which when it's any address of the machine could be all sorts of names
+fn is_allowed_host(host: Option<&str>, listened: &str) -> bool {
+    let Some(host) = host else {
+        return false;
+    };
+    // without the port, also after an IPv6 address in brackets
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    LOCAL_HOSTS.contains(&name)
+        || name == listened
+        || name.trim_matches(['[', ']']) == listened
+        || listened == "0.0.0.0"
+        || listened == "::"
+}
+
+async fn chat_completions(
+    stream: &mut TcpStream,
+    body: &[u8],
+    context: &ProxyContext<'_>,
+) -> Result<u16> {
+    let request = serde_json::from_slice(body)
+        .context("Invalid request")
+        .and_then(|request: ProxyRequest| {
+            Ok((chat_messages(&request, context.initial)?, request.stream))
+        });
+    let (messages, streamed) = match request {
+        Ok(request) => request,
+        Err(error) => {
+            respond(stream, 400, &error_body(&format!("{:#}", error))).await?;
+            return Ok(400);
+        }
+    };
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let id = format!("chatcmpl-lmc-{}", created);
+    if streamed {
+        return stream_completion(stream, &messages, &id, created, context).await;
+    }
+    match context.api_client.get_chat_completion(&messages).await {
+        Ok(completion) => {
+            let body = completion_body(&completion, &id, created, context.model);
+            respond(stream, 200, &body).await?;
+            Ok(200)
+        }
+        Err(error) => {
+            respond(stream, 502, &error_body(&format!("{:#}", error))).await?;
+            Ok(502)
+        }
+    }
+}
+
+/// Sends the response as server-sent events, one for each token, as the
+/// OpenAI API does, returning the status sent
+async fn stream_completion(
+    stream: &mut TcpStream,
+    messages: &[Message],
+    id: &str,
+    created: u64,
+    context: &ProxyContext<'_>,
+) -> Result<u16> {
+    let mut events = match context.api_client.stream_chat_completion(messages).await {
+        Ok(events) => events,
+        Err(error) => {
+            respond(stream, 502, &error_body(&format!("{:#}", error))).await?;
+            return Ok(502);
+        }
+    };
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    let role = json!({"role": "assistant", "content": ""});
+    send_event(stream, &chunk_body(id, created, context.model, role, None)).await?;
+    let mut finish_reason = String::from("stop");
+    while let Some(event) = events.next().await {
+        let content = match event {
+            Ok(StreamEvent::Token(text)) => text,
+            Ok(StreamEvent::Logprobs(tokens)) => {
+                tokens.into_iter().map(|token| token.token).collect()
+            }
+            Ok(StreamEvent::Finish(reason)) => {
+                finish_reason = reason;
+                continue;
+            }
+            Ok(StreamEvent::Usage(_)) => continue,
+            Err(error) => {
+                // the status has been sent already, so the error can only be an event
+                send_event(stream, &error_body(&format!("{:#}", error))).await?;
+                return Ok(200);
+            }
+        };
+        let delta = json!({ "content": content });
+        send_event(stream, &chunk_body(id, created, context.model, delta, None)).await?;
+    }
+    let end = chunk_body(id, created, context.model, json!({}), Some(&finish_reason));
+    send_event(stream, &end).await?;
+    stream.write_all(b"data: [DONE]\n\n").await?;
+    Ok(200)
+}
+
+/// The messages to send for a request: the initial ones, unless the request has
+/// instructions of its own, followed by those of the request, with any content
+/// given as a list of parts joined into text
+fn chat_messages(request: &ProxyRequest, initial: &[Message]) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    for message in &request.messages {
+        let mut message = message.to_owned();
+        if let Some(parts) = message["content"].as_array() {
+            let text: Vec<&str> = parts
+                .iter()
+                .filter_map(|part| part["text"].as_str())
+                .collect();
+            message["content"] = json!(text.join("\n"));
+        } else if message["content"].is_null() {
+            // e.g. assistant messages with tool calls only
+            message["content"] = json!("");
+        }
+        messages.push(serde_json::from_value::<Message>(message).context("Invalid message")?);
+    }
+    ensure!(!messages.is_empty(), "No messages in the request");
+    if messages.iter().any(|message| message.role.is_instruction()) {
+        return Ok(messages);
+    }
+    Ok(initial.iter().cloned().chain(messages).collect())
+}
+
+/// A completion as the OpenAI API returns it
+fn completion_body(completion: &Completion, id: &str, created: u64, model: &str) -> Value {
+    let usage = completion.usage.as_ref().map(|usage| {
+        json!({
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "total_tokens": usage.prompt_tokens + usage.completion_tokens,
+        })
+    });
+    json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": completion.content},
+            "finish_reason": completion.finish_reason.as_deref().unwrap_or("stop"),
+        }],
+        "usage": usage,
+    })
+}
+
+fn chunk_body(
+    id: &str,
+    created: u64,
+    model: &str,
+    delta: Value,
+    finish_reason: Option<&str>,
+) -> Value {
+    json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+    })
+}
+
+fn error_body(message: &str) -> Value {
+    json!({"error": {"message": message, "type": "lmc_proxy_error"}})
+}
+
+async fn send_event(stream: &mut TcpStream, data: &Value) -> Result<()> {
+    stream
+        .write_all(format!("data: {}\n\n", data).as_bytes())
+        .await?;
+    Ok(())
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Bad Gateway",
+    };
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::Role;
+
+    use super::*;
+
+    #[test]
+    fn adds_the_system_prompt_unless_given() -> Result<()> {
+        let initial = vec![Message::new(Role::System, &String::from("Be brief."))];
+        let request: ProxyRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": [{"type": "text", "text": "Hi"}]}],
+        }))?;
+        let messages = chat_messages(&request, &initial)?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "Be brief.");
+        assert_eq!(messages[1], Message::new(Role::User, &String::from("Hi")));
+        let request: ProxyRequest = serde_json::from_value(json!({
+            "messages": [
+                {"role": "system", "content": "Be verbose."},
+                {"role": "user", "content": "Hi"},
+            ],
+            "stream": true,
+        }))?;
+        let messages = chat_messages(&request, &initial)?;
+        assert_eq!(messages[0].content, "Be verbose.");
+        assert_eq!(messages.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_http_requests() -> Result<()> {
+        let raw = "POST /v1/chat/completions HTTP/1.1\r\nHost: localhost:8080\r\n\
+            Authorization: Bearer lmc-123\r\ncontent-length: 2\r\n\r\n{}";
+        let request = read_request(&mut BufReader::new(raw.as_bytes())).await?;
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/chat/completions");
+        assert_eq!(request.host.as_deref(), Some("localhost:8080"));
+        assert_eq!(request.authorization.as_deref(), Some("Bearer lmc-123"));
+        assert_eq!(request.body, b"{}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_http_requests() {
+        let read =
+            |raw: String| async move { read_request(&mut BufReader::new(raw.as_bytes())).await };
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert!(read(long_line).await.is_err());
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: 1\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(read(many_headers).await.is_err());
+        let short_body = String::from("POST / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n{}");
+        assert!(read(short_body).await.is_err());
+    }
+
+    #[test]
+    fn allows_local_hosts_only() {
+        assert!(is_allowed_host(Some("localhost:8080"), "127.0.0.1"));
+        assert!(is_allowed_host(Some("127.0.0.1"), "127.0.0.1"));
+        assert!(is_allowed_host(Some("[::1]:8080"), "127.0.0.1"));
+        assert!(is_allowed_host(Some("192.168.1.2:8080"), "192.168.1.2"));
+        assert!(is_allowed_host(Some("lmc.example.com"), "0.0.0.0"));
+        assert!(!is_allowed_host(
+            Some("attacker.example.com:8080"),
+            "127.0.0.1"
+        ));
+        assert!(!is_allowed_host(None, "127.0.0.1"));
+    }
+}
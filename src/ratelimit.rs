@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+/// Length of the sliding window that "rpm" and "tpm" limits apply to
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Client-side limit on requests and (estimated) tokens per minute, waiting as
+/// long as necessary before each request to stay within the provider's limits
+pub struct RateLimiter {
+    rpm: Option<u32>,
+    tpm: Option<u32>,
+    /// time and tokens of the requests sent in the last minute
+    sent: Mutex<VecDeque<(Instant, usize)>>,
+}
+
+impl RateLimiter {
+    pub fn new(rpm: Option<u32>, tpm: Option<u32>) -> Option<Self> {
+        (rpm.is_some() || tpm.is_some()).then(|| Self {
+            rpm,
+            tpm,
+            sent: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Waits until a request with this many tokens can be sent, then records it
+    pub async fn acquire(&self, tokens: usize) {
+        // holding the lock while waiting keeps concurrent requests in order
+        let mut sent = self.sent.lock().await;
+        loop {
+            let now = Instant::now();
+            while sent.front().is_some_and(|(time, _)| now - *time >= WINDOW) {
+                sent.pop_front();
+            }
+            match wait_time(&sent, now, self.rpm, self.tpm, tokens) {
+                None => break,
+                Some(wait) => {
+                    eprintln!(
+                        "[i] Waiting {}s to stay within the rate limit",
+                        wait.as_secs_f32().ceil()
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+        sent.push_back((Instant::now(), tokens));
+    }
+}
+
+/// How long until the oldest requests fall out of the window, making room for a
+/// new one; None if it can be sent right away
+fn wait_time(
+    sent: &VecDeque<(Instant, usize)>,
+    now: Instant,
+    rpm: Option<u32>,
+    tpm: Option<u32>,
+    tokens: usize,
+) -> Option<Duration> {
+    // the number of oldest requests that need to expire first
+    let mut expiring = 0;
+    if let Some(rpm) = rpm {
+        expiring = (sent.len() + 1).saturating_sub(rpm.max(1) as usize);
+    }
+    if let Some(tpm) = tpm {
+        let mut total: usize = sent.iter().map(|(_, tokens)| tokens).sum::<usize>() + tokens;
+        let mut count = 0;
+        // a request over the limit on its own is sent once the window is empty
+        while total > tpm as usize && count < sent.len() {
+            total -= sent[count].1;
+            count += 1;
+        }
+        expiring = expiring.max(count);
+    }
+    if expiring == 0 {
+        return None;
+    }
+    let (time, _) = sent[expiring - 1];
+    Some((time + WINDOW).saturating_duration_since(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_requests_per_minute() {
+        let start = Instant::now();
+        let sent = VecDeque::from([(start, 10), (start + Duration::from_secs(10), 10)]);
+        let now = start + Duration::from_secs(20);
+        assert_eq!(wait_time(&sent, now, Some(3), None, 10), None);
+        assert_eq!(
+            wait_time(&sent, now, Some(2), None, 10),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(
+            wait_time(&sent, now, Some(1), None, 10),
+            Some(Duration::from_secs(50))
+        );
+    }
+
+    #[test]
+    fn waits_for_tokens_per_minute() {
+        let start = Instant::now();
+        let sent = VecDeque::from([(start, 600), (start + Duration::from_secs(10), 300)]);
+        let now = start + Duration::from_secs(30);
+        assert_eq!(wait_time(&sent, now, None, Some(1000), 100), None);
+        assert_eq!(
+            wait_time(&sent, now, None, Some(1000), 200),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            wait_time(&sent, now, None, Some(1000), 5000),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(
+            wait_time(&VecDeque::new(), now, None, Some(1000), 5000),
+            None
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::config::Redaction;
+
+/// The rules that can be enabled by name in the "[redact]" table's "builtin" list
+const BUILTIN_RULES: &[(&str, &str)] = &[
+    ("email", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+    (
+        "api_key",
+        concat!(
+            r"\b(?:sk-[A-Za-z0-9_-]{20,}|gh[pousr]_[A-Za-z0-9]{36}|github_pat_[A-Za-z0-9_]{20,}",
+            r"|xox[abpr]-[A-Za-z0-9-]{10,}|AIza[0-9A-Za-z_-]{35})"
+        ),
+    ),
+    ("aws_access_key", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+    (
+        "aws_secret_key",
+        r"(?i)aws_secret_access_key\W{1,4}([A-Za-z0-9/+=]{40})",
+    ),
+    ("ipv4", r"\b(?:\d{1,3}\.){3}\d{1,3}\b"),
+];
+
+/// Replaces sensitive text in the messages sent with placeholders like
+/// "[EMAIL_1]", always the same one for the same text, and can put the text
+/// back in the responses
+pub struct Redactor {
+    /// the placeholder prefix of each rule, e.g. "EMAIL", with its expression
+    rules: Vec<(String, Regex)>,
+    /// the placeholders used so far, with the text each one replaced
+    placeholders: Vec<(String, String)>,
+    restore: bool,
+}
+
+impl Redactor {
+    /// None if no rules are configured
+    pub fn new(redaction: &Redaction) -> Result<Option<Self>> {
+        let mut rules = Vec::new();
+        for name in &redaction.builtin {
+            let (_, pattern) = BUILTIN_RULES
+                .iter()
+                .find(|(builtin, _)| builtin == name)
+                .with_context(|| format!("Unknown redaction rule: \"{}\"", name))?;
+            rules.push((name.to_uppercase(), Regex::new(pattern)?));
+        }
+        for (name, pattern) in &redaction.patterns {
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("Invalid redaction pattern \"{}\"", name))?;
+            rules.push((name.to_uppercase(), regex));
+        }
+        Ok((!rules.is_empty()).then_some(Self {
+            rules,
+            placeholders: Vec::new(),
+            restore: redaction.restore,
+        }))
+    }
+
+    /// Replaces the text matching any rule, or only the part matching the first
+    /// group in the expression if it has one
+    pub fn redact(&mut self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for (prefix, regex) in &self.rules {
+            let mut redacted = String::with_capacity(text.len());
+            let mut last = 0;
+            for captures in regex.captures_iter(&text) {
+                let Some(matched) = captures.get(1).or_else(|| captures.get(0)) else {
+                    continue;
+                };
+                redacted.push_str(&text[last..matched.start()]);
+                redacted.push_str(&placeholder(
+                    &mut self.placeholders,
+                    prefix,
+                    matched.as_str(),
+                ));
+                last = matched.end();
+            }
+            redacted.push_str(&text[last..]);
+            text = redacted;
+        }
+        text
+    }
+
+    /// Something to put the redacted text back in a response, even as it's streamed,
+    /// unless the placeholders are meant to stay
+    pub fn restorer(&self) -> Option<Restorer> {
+        self.restore.then(|| Restorer {
+            placeholders: self.placeholders.to_owned(),
+            pending: String::new(),
+        })
+    }
+}
+
+/// The placeholder for some text, reusing the one it got before if any
+fn placeholder(placeholders: &mut Vec<(String, String)>, prefix: &str, text: &str) -> String {
+    if let Some((placeholder, _)) = placeholders.iter().find(|(_, original)| original == text) {
+        return placeholder.to_owned();
+    }
+    let start = format!("[{}_", prefix);
+    let count = placeholders
+        .iter()
+        .filter_map(|(placeholder, _)| placeholder.strip_prefix(&start))
+        .filter(|number| {
+            number
+                .trim_end_matches(']')
+                .chars()
+                .all(|c| c.is_ascii_digit())
+        })
+        .count();
+    let placeholder = format!("[{}_{}]", prefix, count + 1);
+    placeholders.push((placeholder.to_owned(), text.to_owned()));
+    placeholder
+}
+
+/// Replaces placeholders with the text they stand for in a streamed response,
+/// holding back what could be the start of one until it's complete
+pub struct Restorer {
+    placeholders: Vec<(String, String)>,
+    pending: String,
+}
+
+impl Restorer {
+    pub fn push(&mut self, text: &str) -> String {
+        let mut output = String::with_capacity(text.len());
+        for c in text.chars() {
+            if self.pending.is_empty() {
+                if c == '[' {
+                    self.pending.push(c);
+                } else {
+                    output.push(c);
+                }
+                continue;
+            }
+            self.pending.push(c);
+            if c == ']' {
+                let original = self
+                    .placeholders
+                    .iter()
+                    .find(|(placeholder, _)| *placeholder == self.pending)
+                    .map(|(_, original)| original);
+                output.push_str(original.unwrap_or(&self.pending));
+                self.pending.clear();
+            } else if !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+                // not a placeholder after all
+                self.pending.pop();
+                output.push_str(&self.pending);
+                self.pending.clear();
+                output.push_str(&self.push(&c.to_string()));
+            }
+        }
+        output
+    }
+
+    /// Whatever was held back at the end of the response
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn redacts_and_restores() -> Result<()> {
+        let mut redactor = Redactor::new(&Redaction {
+            builtin: vec![String::from("email"), String::from("aws_secret_key")],
+            patterns: BTreeMap::from([(String::from("ticket"), String::from(r"ABC-\d+"))]),
+            restore: true,
+        })?
+        .unwrap();
+        let key = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+        let prompt = format!(
+            "Email ann@example.com and bob@example.com about ABC-12, \
+            cc ann@example.com. aws_secret_access_key = {}",
+            key
+        );
+        assert_eq!(
+            redactor.redact(&prompt),
+            "Email [EMAIL_1] and [EMAIL_2] about [TICKET_1], \
+            cc [EMAIL_1]. aws_secret_access_key = [AWS_SECRET_KEY_1]"
+        );
+        let mut restorer = redactor.restorer().unwrap();
+        let streamed: String = ["Dear [", "EMAIL", "_2], [A] [TICKET_1", "] [EMAIL_3"]
+            .iter()
+            .map(|token| restorer.push(token))
+            .collect();
+        assert_eq!(
+            streamed + &restorer.finish(),
+            "Dear bob@example.com, [A] ABC-12 [EMAIL_3"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use crate::chunks::estimate_tokens;
+
+/// Each request of a review has the changes to one file, or to part of a file
+/// if they are over this many tokens
+pub const REVIEW_PART_TOKENS: usize = 8_000;
+
+/// How serious a problem found in a review is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    High,
+    Medium,
+    Low,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Medium => "medium",
+            Self::Low => "low",
+        }
+    }
+
+    /// The ANSI style of the severity marker
+    pub fn style(self) -> &'static str {
+        match self {
+            Self::High => "\x1b[1;31m",
+            Self::Medium => "\x1b[33m",
+            Self::Low => "\x1b[36m",
+        }
+    }
+}
+
+/// A problem reported in a review, at a line of the new version of a file
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+/// The changes to one file in a unified diff, or part of them
+#[derive(Debug, PartialEq)]
+pub struct FileDiff {
+    pub path: String,
+    pub diff: String,
+}
+
+/// Splits a unified diff into the changes of each file, and those into groups
+/// of hunks of at most about `max_tokens`
+pub fn split_diff(diff: &str, max_tokens: usize) -> Vec<FileDiff> {
+    let mut files: Vec<(String, Vec<String>)> = Vec::new();
+    // without git's "diff --git" lines, files start with their "---" and "+++" lines
+    let git = diff.starts_with("diff --git ") || diff.contains("\ndiff --git ");
+    let mut lines = diff.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        let starts_file = if git {
+            line.starts_with("diff --git ")
+        } else {
+            line.starts_with("--- ")
+                && lines.peek().is_some_and(|next| next.starts_with("+++ "))
+                && files.last().is_none_or(|(_, parts)| parts.len() > 1)
+        };
+        if starts_file {
+            files.push((String::new(), vec![String::new()]));
+        }
+        let Some((path, parts)) = files.last_mut() else {
+            // anything before the first file, like a commit message, is skipped
+            continue;
+        };
+        let header = parts.len() == 1;
+        if let Some(new_path) = line.strip_prefix("+++ ").filter(|_| header) {
+            // deleted files only have the old path
+            let new_path = new_path.trim_end();
+            if new_path != "/dev/null" {
+                *path = new_path.strip_prefix("b/").unwrap_or(new_path).to_owned();
+            }
+        } else if let Some(old_path) = line.strip_prefix("--- ").filter(|_| header) {
+            let old_path = old_path.trim_end();
+            *path = old_path.strip_prefix("a/").unwrap_or(old_path).to_owned();
+        }
+        if line.starts_with("@@") {
+            parts.push(String::new());
+        }
+        parts.last_mut().unwrap().push_str(line);
+    }
+    let mut diffs = Vec::new();
+    for (path, parts) in files {
+        // the header of the file comes first, then its hunks
+        let mut parts = parts.into_iter();
+        let header = parts.next().unwrap_or_default();
+        let mut diff = header.to_owned();
+        for hunk in parts {
+            let too_long = estimate_tokens(&diff) + estimate_tokens(&hunk) > max_tokens;
+            if too_long && diff.len() > header.len() {
+                diffs.push(FileDiff {
+                    path: path.to_owned(),
+                    diff,
+                });
+                diff = header.to_owned();
+            }
+            diff.push_str(&hunk);
+        }
+        diffs.push(FileDiff { path, diff });
+    }
+    diffs
+}
+
+/// Reads the findings in a review written as asked, one per line, e.g.
+/// "[high] L42: the index can be out of bounds", ignoring anything else
+pub fn parse_findings(review: &str) -> Vec<Finding> {
+    review
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim().trim_start_matches(['-', '*', ' ']);
+            let (severity, rest) = line.strip_prefix('[')?.split_once(']')?;
+            let severity = match severity.trim().to_lowercase().as_str() {
+                "high" => Severity::High,
+                "medium" => Severity::Medium,
+                "low" => Severity::Low,
+                _ => return None,
+            };
+            let rest = rest.trim();
+            let location = rest
+                .strip_prefix('L')
+                .and_then(|rest| rest.split_once(':'))
+                .and_then(|(number, message)| Some((number.trim().parse().ok()?, message)));
+            let (line, message) = match location {
+                Some((number, message)) => (Some(number), message.trim()),
+                None => (None, rest),
+            };
+            Some(Finding {
+                severity,
+                line,
+                message: message.to_owned(),
+            })
+        })
+        .filter(|finding| !finding.message.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_diff_by_file_and_hunk() {
+        let diff = "commit abc\n\n    Fix it\n\n\
+            diff --git a/src/a.rs b/src/a.rs\n--- a/src/a.rs\n+++ b/src/a.rs\n\
+            @@ -1 +1 @@\n-a\n+A\n@@ -9 +9 @@\n-b\n+B\n\
+            diff --git a/old.txt b/old.txt\ndeleted file mode 100644\n\
+            --- a/old.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n--- not a header\n";
+        let files = split_diff(diff, 1000);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "src/a.rs");
+        assert!(files[0].diff.ends_with("+B\n"));
+        assert_eq!(files[1].path, "old.txt");
+        assert!(files[1].diff.ends_with("--- not a header\n"));
+        let parts = split_diff(diff, 12);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[1].diff,
+            "diff --git a/src/a.rs b/src/a.rs\n--- a/src/a.rs\n+++ b/src/a.rs\n\
+            @@ -9 +9 @@\n-b\n+B\n"
+        );
+    }
+
+    #[test]
+    fn parses_findings() {
+        let review = "Here is my review:\n\
+            - [HIGH] L42: `items[0]` panics when the list is empty\n\
+            [low] The name `x` is unclear\n\
+            [nit] L3: trailing space";
+        assert_eq!(
+            parse_findings(review),
+            vec![
+                Finding {
+                    severity: Severity::High,
+                    line: Some(42),
+                    message: String::from("`items[0]` panics when the list is empty"),
+                },
+                Finding {
+                    severity: Severity::Low,
+                    line: None,
+                    message: String::from("The name `x` is unclear"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::markdown::code_blocks;
+
+/// Parses a response that should be JSON, even if the model wrapped it in a
+/// code block
+pub fn parse_json_response(response: &str) -> Result<Value> {
+    let response = response.trim();
+    let json = match code_blocks(response).into_iter().next() {
+        Some(block) if response.starts_with("```") => block.code,
+        _ => response.to_owned(),
+    };
+    serde_json::from_str(&json).context("The response is not valid JSON")
+}
+
+/// Checks a value against a JSON schema, returning a description of each
+/// problem found. Only the common keywords are supported: "type", "enum",
+/// "const", "properties", "required", "additionalProperties", "items",
+/// "anyOf", "oneOf", "allOf", the bounds of numbers, strings and arrays, and
+/// "$ref" to definitions in the same schema. Any others are ignored.
+pub fn validate(value: &Value, schema: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    check(value, schema, schema, "$", &mut errors);
+    errors
+}
+
+fn check(value: &Value, schema: &Value, root: &Value, path: &str, errors: &mut Vec<String>) {
+    let Some(schema) = schema.as_object() else {
+        // "true" allows anything, "false" nothing
+        if schema == false {
+            errors.push(format!("{}: not allowed", path));
+        }
+        return;
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match resolve(root, reference) {
+            Some(target) => check(value, target, root, path, errors),
+            None => errors.push(format!("{}: unknown $ref \"{}\"", path, reference)),
+        }
+    }
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            errors.push(format!(
+                "{}: expected {}, found {}",
+                path,
+                types.join(" or "),
+                type_name(value)
+            ));
+            // the other keywords would only report the same mistake again
+            return;
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            errors.push(format!(
+                "{}: {} is not one of {}",
+                path,
+                value,
+                Value::Array(allowed.to_owned())
+            ));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if value != constant {
+            errors.push(format!("{}: expected {}, found {}", path, constant, value));
+        }
+    }
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(options) = schema.get(keyword).and_then(Value::as_array) {
+            let matches = options
+                .iter()
+                .filter(|option| {
+                    let mut option_errors = Vec::new();
+                    check(value, option, root, path, &mut option_errors);
+                    option_errors.is_empty()
+                })
+                .count();
+            if matches == 0 || (keyword == "oneOf" && matches > 1) {
+                errors.push(format!("{}: does not match {}", path, keyword));
+            }
+        }
+    }
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for option in all {
+            check(value, option, root, path, errors);
+        }
+    }
+    match value {
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(name) {
+                        errors.push(format!("{}: missing \"{}\"", path, name));
+                    }
+                }
+            }
+            for (name, property) in object {
+                let property_path = format!("{}.{}", path, name);
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(property_schema) => {
+                        check(property, property_schema, root, &property_path, errors)
+                    }
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            errors.push(format!("{}: unexpected property", property_path))
+                        }
+                        Some(additional) => {
+                            check(property, additional, root, &property_path, errors)
+                        }
+                        None => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_bounds(
+                items.len() as f64,
+                schema,
+                "minItems",
+                "maxItems",
+                path,
+                errors,
+            );
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, index);
+                    check(item, item_schema, root, &item_path, errors);
+                }
+            }
+        }
+        Value::String(text) => {
+            let length = text.chars().count() as f64;
+            check_bounds(length, schema, "minLength", "maxLength", path, errors);
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            check_bounds(number, schema, "minimum", "maximum", path, errors);
+        }
+        _ => {}
+    }
+}
+
+fn check_bounds(
+    actual: f64,
+    schema: &serde_json::Map<String, Value>,
+    min: &str,
+    max: &str,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(bound) = schema.get(min).and_then(Value::as_f64) {
+        if actual < bound {
+            errors.push(format!("{}: below {} {}", path, min, bound));
+        }
+    }
+    if let Some(bound) = schema.get(max).and_then(Value::as_f64) {
+        if actual > bound {
+            errors.push(format!("{}: above {} {}", path, max, bound));
+        }
+    }
+}
+
+/// The part of the schema a "$ref" like "#/$defs/address" points to
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    root.pointer(reference.strip_prefix('#')?)
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        "number" => value.is_number(),
+        name => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn validates_against_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "emails": {"type": "array", "items": {"$ref": "#/$defs/email"}},
+                "role": {"enum": ["admin", "user"]}
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "$defs": {"email": {"type": "string"}}
+        });
+        let person = json!({"name": "Ann", "age": 42, "emails": ["ann@example.com"]});
+        assert!(validate(&person, &schema).is_empty());
+        let invalid = json!({"name": "", "age": 4.5, "emails": [1], "role": "guest", "x": 0});
+        assert_eq!(
+            validate(&invalid, &schema),
+            vec![
+                "$.age: expected integer, found number",
+                "$.emails[0]: expected string, found number",
+                "$.name: below minLength 1",
+                "$.role: \"guest\" is not one of [\"admin\",\"user\"]",
+                "$.x: unexpected property",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json_in_code_blocks() -> Result<()> {
+        assert_eq!(
+            parse_json_response("```json\n{\"a\": 1}\n```")?,
+            json!({"a": 1})
+        );
+        assert_eq!(parse_json_response(" [1, 2]\n")?, json!([1, 2]));
+        assert!(parse_json_response("Here you go: {}").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use home::home_dir;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal;
+
+use crate::api::{ApiClient, Message, Role, StreamEvent};
+
+/// A line sent by a client of "lmc serve", e.g.
+/// {"prompt": "What is a monad?", "conversation": "editor"}
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct ServerRequest {
+    pub prompt: String,
+    /// name of the conversation to continue, so that clients can keep theirs apart
+    #[serde(default = "default_conversation")]
+    pub conversation: String,
+    /// forget the conversation before this prompt
+    #[serde(default)]
+    pub reset: bool,
+}
+
+/// A line sent back: each token of the response as it arrives, then the whole
+/// response, or an error, e.g. {"type": "token", "text": "A monad"}
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerReply {
+    Token { text: String },
+    Done { content: String },
+    Error { message: String },
+}
+
+impl ServerReply {
+    /// The reply as a line of JSON, with the newline that ends it
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+}
+
+fn default_conversation() -> String {
+    String::from("default")
+}
+
+pub fn default_socket_path() -> Result<PathBuf> {
+    let dir = home_dir().context("Could not detect HOME directory")?;
+    Ok(dir.join(".lmc").join("lmc.sock"))
+}
+
+/// Listens on a Unix socket only the current user can connect to, replacing the
+/// file left behind by a server that didn't stop cleanly
+pub async fn bind_socket(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        ensure!(
+            UnixStream::connect(path).await.is_err(),
+            "lmc is already serving on {}",
+            path.display()
+        );
+        fs::remove_file(path).with_context(|| format!("Could not remove {}", path.display()))?;
+    }
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    // the socket is created with the permissions the umask leaves, so it's never
+    // open to others, not even until they're changed
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("Could not listen on {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answers the requests of all the clients connected to the listener at the
+/// same time, until interrupted with Ctrl-C. Each conversation starts with the
+/// initial messages, e.g. the system prompt.
+pub async fn serve(
+    listener: UnixListener,
+    api_client: &ApiClient,
+    initial: &[Message],
+) -> Result<()> {
+    let conversations = Mutex::new(HashMap::new());
+    let mut clients = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let (reader, writer) = stream.into_split();
+                clients.push(serve_client(reader, writer, api_client, initial, &conversations));
+            }
+            Some(result) = clients.next(), if !clients.is_empty() => {
+                // a client that went away doesn't affect the others
+                if let Err(error) = result {
+                    eprintln!("[w] {:#}", error);
+                }
+            }
+            _ = signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+async fn serve_client(
+    reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    api_client: &ApiClient,
+    initial: &[Message],
+    conversations: &Mutex<HashMap<String, Vec<Message>>>,
+) -> Result<()> {
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<ServerRequest>(&line) {
+            Ok(request) => answer(request, api_client, initial, conversations, &mut writer).await,
+            Err(error) => ServerReply::Error {
+                message: format!("Invalid request: {}", error),
+            },
+        };
+        writer.write_all(reply.to_line().as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Streams the response to a prompt to the client, returning the reply that
+/// ends it. Only the prompts answered successfully are kept in the conversation.
+async fn answer(
+    request: ServerRequest,
+    api_client: &ApiClient,
+    initial: &[Message],
+    conversations: &Mutex<HashMap<String, Vec<Message>>>,
+    writer: &mut OwnedWriteHalf,
+) -> ServerReply {
+    let mut messages = {
+        let mut conversations = conversations.lock().unwrap();
+        if request.reset {
+            conversations.remove(&request.conversation);
+        }
+        match conversations.get(&request.conversation) {
+            Some(messages) => messages.to_owned(),
+            None => initial.to_vec(),
+        }
+    };
+    messages.push(Message::new(Role::User, &request.prompt));
+    match stream_response(api_client, &messages, writer).await {
+        Ok(content) => {
+            messages.push(Message::new(Role::Assistant, &content));
+            let mut conversations = conversations.lock().unwrap();
+            conversations.insert(request.conversation, messages);
+            ServerReply::Done { content }
+        }
+        Err(error) => ServerReply::Error {
+            message: format!("{:#}", error),
+        },
+    }
+}
+
+async fn stream_response(
+    api_client: &ApiClient,
+    messages: &[Message],
+    writer: &mut OwnedWriteHalf,
+) -> Result<String> {
+    let mut events = api_client.stream_chat_completion(messages).await?;
+    let mut content = String::new();
+    while let Some(event) = events.next().await {
+        let texts = match event? {
+            StreamEvent::Token(text) => vec![text],
+            StreamEvent::Logprobs(tokens) => tokens.into_iter().map(|token| token.token).collect(),
+            StreamEvent::Finish(_) | StreamEvent::Usage(_) => Vec::new(),
+        };
+        for text in texts {
+            content.push_str(&text);
+            let reply = ServerReply::Token { text };
+            writer.write_all(reply.to_line().as_bytes()).await?;
+        }
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_a_private_socket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lmc").join("lmc.sock");
+        let listener = bind_socket(&path).await?;
+        let mode = |path: &Path| fs::metadata(path).map(|metadata| metadata.permissions().mode());
+        assert_eq!(mode(&path)? & 0o777, 0o600);
+        assert_eq!(mode(&dir.path().join("lmc"))? & 0o777, 0o700);
+        assert!(bind_socket(&path).await.is_err());
+        drop(listener);
+        // the file left behind is replaced
+        bind_socket(&path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn reads_requests_and_writes_replies() {
+        let request: ServerRequest = serde_json::from_str(r#"{"prompt": "Hi"}"#).unwrap();
+        assert_eq!(
+            request,
+            ServerRequest {
+                prompt: String::from("Hi"),
+                conversation: String::from("default"),
+                reset: false,
+            }
+        );
+        let reply = ServerReply::Token {
+            text: String::from("Hello"),
+        };
+        assert_eq!(reply.to_line(), "{\"type\":\"token\",\"text\":\"Hello\"}\n");
+    }
+}
@@ -0,0 +1,684 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, TryLockError};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{process, thread};
+
+use anyhow::{ensure, Context, Result};
+use home::home_dir;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Message, Role};
+
+const MAIN_BRANCH: &str = "main";
+
+/// Maximum length of session names derived from titles
+const MAX_SLUG_LENGTH: usize = 40;
+
+/// Approximate length of the search results excerpts, in characters
+const EXCERPT_LENGTH: usize = 100;
+
+/// A conversation, possibly forked into several branches, that can be saved to
+/// "$HOME/.lmc/sessions/<name>.json" and continued later
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Session {
+    #[serde(skip)]
+    name: Option<String>,
+    /// held while a session is continued with --session, so that another lmc
+    /// continuing it waits rather than overwriting its changes
+    #[serde(skip)]
+    lock: Option<File>,
+    /// seconds since the Unix epoch
+    pub created: u64,
+    pub updated: u64,
+    #[serde(default)]
+    pub title: Option<String>,
+    pub branch: String,
+    pub branches: BTreeMap<String, Vec<Message>>,
+    /// values set with "/set name=value", for "{name}" in prompts
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// pinned with "/set seed", for the rest of the session when it's resumed too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        let now = now();
+        Self {
+            name: None,
+            lock: None,
+            created: now,
+            updated: now,
+            title: None,
+            branch: String::from(MAIN_BRANCH),
+            branches: BTreeMap::from([(String::from(MAIN_BRANCH), Vec::new())]),
+            variables: BTreeMap::new(),
+            seed: None,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The conversation in the current branch
+    pub fn messages(&self) -> Vec<Message> {
+        self.branches.get(&self.branch).cloned().unwrap_or_default()
+    }
+
+    /// Whether the query characters appear in order, ignoring case, in the name
+    /// or title of the session, e.g. "rbc" matches "rust-borrow-checker"
+    pub fn matches(&self, query: &str) -> bool {
+        let name = self.name.as_deref().unwrap_or_default();
+        let title = self.title.as_deref().unwrap_or_default();
+        is_subsequence(&query.to_lowercase(), &name.to_lowercase())
+            || is_subsequence(&query.to_lowercase(), &title.to_lowercase())
+    }
+
+    pub fn branch_names(&self) -> impl Iterator<Item = &String> {
+        self.branches.keys()
+    }
+
+    /// Forks the conversation so far into a new branch, and switches to it
+    pub fn branch(&mut self, name: &str, messages: &[Message]) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid branch name: \"{}\"", name);
+        ensure!(
+            !self.branches.contains_key(name),
+            "Branch \"{}\" already exists",
+            name
+        );
+        self.branches
+            .insert(self.branch.to_owned(), messages.to_vec());
+        self.branches.insert(name.to_owned(), messages.to_vec());
+        self.branch = name.to_owned();
+        Ok(())
+    }
+
+    /// Keeps the current conversation in its branch and loads another one instead
+    pub fn switch(&mut self, name: &str, messages: &mut Vec<Message>) -> Result<()> {
+        let target = self
+            .branches
+            .get(name)
+            .with_context(|| format!("No such branch: \"{}\"", name))?
+            .to_vec();
+        self.branches
+            .insert(self.branch.to_owned(), std::mem::replace(messages, target));
+        self.branch = name.to_owned();
+        Ok(())
+    }
+
+    /// Sets a variable, or removes it if the value is empty
+    pub fn set_variable(&mut self, name: &str, value: &str) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid variable name: \"{}\"", name);
+        if value.is_empty() {
+            self.variables.remove(name);
+        } else {
+            self.variables.insert(name.to_owned(), value.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Replaces "{name}" with the value of the variable, leaving any other text in
+    /// braces, e.g. code, untouched
+    pub fn interpolate(&self, prompt: &str) -> String {
+        let mut interpolated = String::with_capacity(prompt.len());
+        let mut rest = prompt;
+        while let Some(start) = rest.find('{') {
+            interpolated.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let variable = after
+                .find('}')
+                .and_then(|end| Some((end, self.variables.get(&after[..end])?)));
+            match variable {
+                Some((end, value)) => {
+                    interpolated.push_str(value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    interpolated.push('{');
+                    rest = after;
+                }
+            }
+        }
+        interpolated.push_str(rest);
+        interpolated
+    }
+
+    /// Saves the session under a new name, then keeps saving it there
+    pub fn save_as(&mut self, name: &str, messages: &[Message]) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid session name: \"{}\"", name);
+        self.name = Some(name.to_owned());
+        self.save(messages)
+    }
+
+    /// Saves the session if it has a name, i.e. it was saved or loaded before
+    pub fn save(&mut self, messages: &[Message]) -> Result<()> {
+        self.save_in(&sessions_dir()?, messages)
+    }
+
+    /// Writes the session to a temporary file first, so that it's never read
+    /// half written
+    fn save_in(&mut self, dir: &Path, messages: &[Message]) -> Result<()> {
+        let Some(name) = &self.name else {
+            return Ok(());
+        };
+        let path = dir.join(format!("{}.json", name));
+        self.updated = now();
+        self.branches
+            .insert(self.branch.to_owned(), messages.to_vec());
+        fs::create_dir_all(dir)?;
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)
+            .and_then(|()| fs::rename(&temp_path, &path))
+            .with_context(|| format!("Could not save \"{}\"", path.display()))
+    }
+}
+
+/// A copy of the conversation of a running chat, rewritten after each response
+/// to "$HOME/.lmc/autosave/<pid>.json" so that it can be restored if lmc crashes
+/// or is killed. "<pid>.lock" stays locked while lmc runs, and both files are
+/// removed on exit.
+pub struct Autosave {
+    path: PathBuf,
+    lock_path: PathBuf,
+    lock: File,
+}
+
+/// The contents of an autosave file
+#[derive(Deserialize)]
+struct Snapshot {
+    name: Option<String>,
+    session: Session,
+}
+
+impl Autosave {
+    pub fn start() -> Result<Self> {
+        Self::start_in(&autosave_dir()?)
+    }
+
+    fn start_in(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.json", process::id()));
+        let lock_path = path.with_extension("lock");
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("Could not create \"{}\"", lock_path.display()))?;
+        lock.try_lock()?;
+        Ok(Self {
+            path,
+            lock_path,
+            lock,
+        })
+    }
+
+    /// Writes the snapshot to a temporary file first, so that the previous one
+    /// stays whole if lmc is killed while writing
+    pub fn save(&mut self, session: &mut Session, messages: &[Message]) -> Result<()> {
+        session.updated = now();
+        session
+            .branches
+            .insert(session.branch.to_owned(), messages.to_vec());
+        let snapshot = serde_json::json!({ "name": session.name, "session": session });
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string(&snapshot)?)
+            .with_context(|| format!("Could not write \"{}\"", temp_path.display()))?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        // after a panic the conversation is kept, to be restored
+        if !thread::panicking() {
+            let _ = fs::remove_file(&self.path);
+            let _ = self.lock.unlock();
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+/// Finds the most recent conversation autosaved by an lmc that didn't exit
+/// cleanly, i.e. whose lock file is no longer locked, and removes its files
+pub fn recover_session() -> Result<Option<Session>> {
+    recover_session_in(&autosave_dir()?)
+}
+
+fn recover_session_in(dir: &Path) -> Result<Option<Session>> {
+    if !fs::exists(dir)? {
+        return Ok(None);
+    }
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let lock_path = path.with_extension("lock");
+        if fs::exists(&lock_path)? {
+            match File::open(&lock_path)?.try_lock_shared() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => continue, // still running
+                Err(TryLockError::Error(error)) => return Err(error.into()),
+            }
+        }
+        let source = fs::read_to_string(&path)?;
+        match serde_json::from_str::<Snapshot>(&source) {
+            Ok(snapshot) => snapshots.push((path, snapshot)),
+            Err(_) => remove_autosave(&path)?,
+        }
+    }
+    snapshots.sort_by_key(|(_, snapshot)| Reverse(snapshot.session.updated));
+    let Some((path, snapshot)) = snapshots.into_iter().next() else {
+        return Ok(None);
+    };
+    remove_autosave(&path)?;
+    let mut session = snapshot.session;
+    session.name = snapshot.name;
+    Ok(Some(session))
+}
+
+/// Removes an autosave file, with its lock file and any unfinished temporary file
+fn remove_autosave(path: &Path) -> Result<()> {
+    for path in [
+        path.to_owned(),
+        path.with_extension("lock"),
+        path.with_extension("json.tmp"),
+    ] {
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Loads all saved sessions, most recently updated first
+pub fn load_sessions() -> Result<Vec<Session>> {
+    let dir = sessions_dir()?;
+    if !fs::exists(&dir)? {
+        return Ok(Vec::new());
+    }
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let source = fs::read_to_string(&path)?;
+            let mut session: Session = serde_json::from_str(&source)
+                .with_context(|| format!("Invalid session file \"{}\"", path.display()))?;
+            session.name = Some(name.to_owned());
+            sessions.push(session);
+        }
+    }
+    sessions.sort_by_key(|session| Reverse(session.updated));
+    Ok(sessions)
+}
+
+/// Loads a saved session by name
+pub fn load_session(name: &str) -> Result<Session> {
+    load_session_in(&sessions_dir()?, name)
+}
+
+fn load_session_in(dir: &Path, name: &str) -> Result<Session> {
+    ensure!(is_valid_name(name), "Invalid session name: \"{}\"", name);
+    let path = dir.join(format!("{}.json", name));
+    let source =
+        fs::read_to_string(&path).with_context(|| format!("No such session: \"{}\"", name))?;
+    let mut session: Session = serde_json::from_str(&source)
+        .with_context(|| format!("Invalid session file \"{}\"", path.display()))?;
+    session.name = Some(name.to_owned());
+    Ok(session)
+}
+
+/// Loads a saved session by name, or starts a new one to be saved under it,
+/// first waiting for any other lmc continuing the same session to finish
+pub fn load_or_create_session(name: &str) -> Result<Session> {
+    load_or_create_session_in(&sessions_dir()?, name)
+}
+
+fn load_or_create_session_in(dir: &Path, name: &str) -> Result<Session> {
+    ensure!(is_valid_name(name), "Invalid session name: \"{}\"", name);
+    fs::create_dir_all(dir)?;
+    let lock_path = dir.join(format!("{}.lock", name));
+    let lock = File::create(&lock_path)
+        .with_context(|| format!("Could not create \"{}\"", lock_path.display()))?;
+    lock.lock()?;
+    let mut session = if fs::exists(dir.join(format!("{}.json", name)))? {
+        load_session_in(dir, name)?
+    } else {
+        let mut session = Session::new();
+        session.name = Some(name.to_owned());
+        session
+    };
+    session.lock = Some(lock);
+    Ok(session)
+}
+
+/// Formats a conversation as Markdown, with a heading for each message naming
+/// its role and participant, if any
+pub fn transcript(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let role = match &message.role {
+                Role::Other(role) => role.to_owned(),
+                role => format!("{:?}", role),
+            };
+            let heading = match &message.name {
+                Some(name) => format!("{} ({})", role, name),
+                None => role,
+            };
+            format!("## {}\n\n{}\n", heading, message.content.trim_end())
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A message containing the query, in a saved session
+pub struct SearchMatch<'a> {
+    pub session: &'a Session,
+    pub message: &'a Message,
+    pub excerpt: String,
+}
+
+/// Finds the messages containing the query (ignoring case) in any branch of the
+/// sessions, only once each even if they are shared by several branches
+pub fn search<'a>(sessions: &'a [Session], query: &str) -> Vec<SearchMatch<'a>> {
+    let query = query.to_lowercase();
+    let mut matches = Vec::new();
+    for session in sessions {
+        let mut seen = HashSet::new();
+        for message in session.branches.values().flatten() {
+            if let Some(excerpt) = find_excerpt(&message.content, &query) {
+                if seen.insert(&message.content) {
+                    matches.push(SearchMatch {
+                        session,
+                        message,
+                        excerpt,
+                    });
+                }
+            }
+        }
+    }
+    matches
+}
+
+/// Returns the line containing the (lowercase) query, shortened to about
+/// EXCERPT_LENGTH characters around it
+fn find_excerpt(text: &str, query: &str) -> Option<String> {
+    let line = text
+        .lines()
+        .find(|line| line.to_lowercase().contains(query))?;
+    let chars: Vec<char> = line.trim().chars().collect();
+    let lowercase: String = chars.iter().collect::<String>().to_lowercase();
+    let byte_position = lowercase.find(query).unwrap_or(0);
+    let position = lowercase[..byte_position].chars().count();
+    let start = position.saturating_sub(EXCERPT_LENGTH / 2);
+    let end = (start + EXCERPT_LENGTH).min(chars.len());
+    let start = end.saturating_sub(EXCERPT_LENGTH);
+    let mut excerpt: String = chars[start..end].iter().collect();
+    if start > 0 {
+        excerpt.insert(0, '…');
+    }
+    if end < chars.len() {
+        excerpt.push('…');
+    }
+    Some(excerpt)
+}
+
+/// Formats seconds since the Unix epoch as "YYYY-MM-DD HH:MM" (UTC)
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let minutes = timestamp % 86400 / 60;
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// Turns a title into a session name, e.g. "Rust's Borrow Checker" into
+/// "rust-s-borrow-checker", adding a number if that name is already taken
+pub fn name_from_title(title: &str) -> Result<String> {
+    let slug = slugify(title);
+    let base = if slug.is_empty() { "session" } else { &slug };
+    let mut name = base.to_owned();
+    let mut counter = 1;
+    while fs::exists(session_path(&name)?)? {
+        counter += 1;
+        name = format!("{}-{}", base, counter);
+    }
+    Ok(name)
+}
+
+fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if slug.chars().count() > MAX_SLUG_LENGTH {
+        // cut at the last whole word
+        slug = slug.chars().take(MAX_SLUG_LENGTH + 1).collect();
+        if let Some(end) = slug.rfind('-') {
+            slug.truncate(end);
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+fn sessions_dir() -> Result<PathBuf> {
+    let dir = home_dir().context("Could not detect HOME directory")?;
+    Ok(dir.join(".lmc").join("sessions"))
+}
+
+fn autosave_dir() -> Result<PathBuf> {
+    let dir = home_dir().context("Could not detect HOME directory")?;
+    Ok(dir.join(".lmc").join("autosave"))
+}
+
+fn session_path(name: &str) -> Result<PathBuf> {
+    Ok(sessions_dir()?.join(format!("{}.json", name)))
+}
+
+fn is_subsequence(query: &str, text: &str) -> bool {
+    let mut text = text.chars();
+    query
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .all(|q| text.any(|c| c == q))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches_and_switches() -> Result<()> {
+        let mut session = Session::new();
+        let mut messages = vec![Message::new(Role::User, &String::from("Hi"))];
+        session.branch("formal", &messages)?;
+        messages.push(Message::new(Role::User, &String::from("Good day")));
+
+        session.switch("main", &mut messages)?;
+        assert_eq!(messages.len(), 1);
+        session.switch("formal", &mut messages)?;
+        assert_eq!(messages.len(), 2);
+
+        assert!(session.branch("main", &messages).is_err());
+        assert!(session.switch("casual", &mut messages).is_err());
+        assert_eq!(
+            session.branch_names().collect::<Vec<_>>(),
+            vec!["formal", "main"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn autosaves_and_recovers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut autosave = Autosave::start_in(dir.path())?;
+        let mut session = Session::new();
+        let messages = vec![Message::new(Role::User, &String::from("Hi"))];
+        autosave.save(&mut session, &messages)?;
+        autosave.save(&mut session, &messages)?;
+        assert!(!fs::exists(
+            dir.path().join(format!("{}.json.tmp", process::id()))
+        )?);
+        // not while the chat is still running
+        assert!(recover_session_in(dir.path())?.is_none());
+
+        autosave.lock.unlock()?;
+        let recovered = recover_session_in(dir.path())?.unwrap();
+        assert_eq!(recovered.messages(), messages);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        assert!(recover_session_in(dir.path())?.is_none());
+
+        let mut autosave = Autosave::start_in(dir.path())?;
+        autosave.save(&mut session, &messages)?;
+        drop(autosave);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn continues_sessions_one_at_a_time() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut session = load_or_create_session_in(dir.path(), "notes")?;
+        assert!(session.messages().is_empty());
+        let messages = vec![
+            Message::new(Role::User, &String::from("Hi")),
+            Message::new(Role::Assistant, &String::from("Hello")),
+        ];
+        session.save_in(dir.path(), &messages)?;
+        let lock = File::open(dir.path().join("notes.lock"))?;
+        assert!(matches!(lock.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(session);
+
+        let session = load_or_create_session_in(dir.path(), "notes")?;
+        assert_eq!(session.name(), Some("notes"));
+        assert_eq!(session.messages(), messages);
+        assert!(!fs::exists(dir.path().join("notes.json.tmp"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn saves_the_pinned_seed() -> Result<()> {
+        let mut session = Session::new();
+        let json = serde_json::to_string(&session)?;
+        assert!(!json.contains("seed"));
+        session.seed = Some(42);
+        let json = serde_json::to_string(&session)?;
+        assert_eq!(serde_json::from_str::<Session>(&json)?.seed, Some(42));
+        Ok(())
+    }
+
+    #[test]
+    fn interpolates_variables() -> Result<()> {
+        let mut session = Session::new();
+        session.set_variable("ticket", "ABC-123")?;
+        session.set_variable("file", "{ticket}.md")?;
+        assert_eq!(
+            session.interpolate("Fix {ticket} in {file}: fn f() { {} } {unknown"),
+            "Fix ABC-123 in {ticket}.md: fn f() { {} } {unknown"
+        );
+        session.set_variable("ticket", "")?;
+        assert_eq!(session.interpolate("{ticket}"), "{ticket}");
+        assert!(session.set_variable("two words", "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matches_names_and_titles() {
+        let mut session = Session::new();
+        session.name = Some(String::from("rust-borrow-checker"));
+        session.title = Some(String::from("Fighting the Borrow Checker"));
+        assert!(session.matches("rbc"));
+        assert!(session.matches("Fight"));
+        assert!(session.matches("borrow check"));
+        assert!(!session.matches("python"));
+    }
+
+    #[test]
+    fn finds_excerpts() {
+        let text = format!(
+            "First line\n{} Needle {}\nLast line",
+            "a".repeat(80),
+            "b".repeat(80)
+        );
+        let excerpt = find_excerpt(&text, "needle").unwrap();
+        assert_eq!(excerpt.chars().count(), EXCERPT_LENGTH + 2);
+        assert!(excerpt.starts_with("…aaa") && excerpt.ends_with("bbb…"));
+        assert!(excerpt.contains("Needle"));
+        assert_eq!(
+            find_excerpt("short NEEDLE", "needle").unwrap(),
+            "short NEEDLE"
+        );
+        assert_eq!(find_excerpt("nothing here", "needle"), None);
+    }
+
+    #[test]
+    fn formats_transcripts() {
+        let messages = vec![
+            Message::new(Role::System, &String::from("Be brief")),
+            Message::new(Role::User, &String::from("Hi\n")).with_name("alice"),
+            Message::new(Role::Assistant, &String::from("Hello!")),
+        ];
+        assert_eq!(
+            transcript(&messages),
+            "## System\n\nBe brief\n\n## User (alice)\n\nHi\n\n## Assistant\n\nHello!\n"
+        );
+    }
+
+    #[test]
+    fn slugifies_titles() {
+        assert_eq!(slugify("Rust's Borrow Checker"), "rust-s-borrow-checker");
+        assert_eq!(slugify("  \"Café --- Menu\"!  "), "café-menu");
+        assert_eq!(
+            slugify("A very long title about many different things at once"),
+            "a-very-long-title-about-many-different"
+        );
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1732111500), "2024-11-20 14:05");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00");
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{ensure, Context, Result};
+use home::home_dir;
+
+/// Reusable prompt fragments, persisted in "$HOME/.lmc/snippets.toml"
+pub struct Snippets {
+    path: PathBuf,
+    entries: BTreeMap<String, String>,
+}
+
+impl Snippets {
+    pub fn load() -> Result<Self> {
+        let dir = home_dir().context("Could not detect HOME directory")?;
+        let path = dir.join(".lmc").join("snippets.toml");
+        let entries = if fs::exists(&path)? {
+            let source = fs::read_to_string(&path)?;
+            toml::from_str(&source).context("Invalid snippets file")?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, entries })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn save(&mut self, name: &str, text: &str) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid snippet name: \"{}\"", name);
+        self.entries.insert(name.to_owned(), text.to_owned());
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, toml::to_string(&self.entries)?)?;
+        Ok(())
+    }
+
+    pub fn expand(&self, prompt: &str) -> String {
+        expand_snippets(prompt, &self.entries)
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+/// Replaces "#name" references (at the start of the prompt or after whitespace)
+/// with the text of the corresponding snippet, leaving unknown names untouched
+fn expand_snippets(prompt: &str, entries: &BTreeMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(prompt.len());
+    let mut rest = prompt;
+    let mut at_word_start = true;
+    while let Some(c) = rest.chars().next() {
+        if c == '#' && at_word_start {
+            let name_length = rest[1..]
+                .find(|c: char| !is_name_char(c))
+                .unwrap_or(rest.len() - 1);
+            if let Some(text) = entries.get(&rest[1..1 + name_length]) {
+                expanded.push_str(text);
+                rest = &rest[1 + name_length..];
+                at_word_start = false;
+                continue;
+            }
+        }
+        expanded.push(c);
+        at_word_start = c.is_whitespace();
+        rest = &rest[c.len_utf8()..];
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_known_snippets() {
+        let entries = BTreeMap::from([
+            (String::from("tldr"), String::from("Summarise in one line.")),
+            (String::from("py"), String::from("Answer in Python.")),
+        ]);
+        assert_eq!(
+            expand_snippets("#tldr\nWhat is #py? #unknown issue#py #py", &entries),
+            "Summarise in one line.\nWhat is Answer in Python.? #unknown issue#py Answer in Python."
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+/// Inputs are summarised in parts of this many tokens, unless max_input_tokens
+/// is set
+pub const SUMMARY_PART_TOKENS: usize = 8_000;
+
+/// Instructions to summarise each part of an input too long to summarise at once
+pub const PART_PROMPT: &str = "The text provided is one part of a longer text. Summarise it in \
+a few paragraphs, keeping every important point, name and figure, so that the summaries of all \
+parts can later be combined. Answer with the summary only.";
+
+/// The kinds of summaries "lmc summarize" can write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SummaryLength {
+    /// a paragraph or two
+    Short,
+    /// a list of the key points
+    Bullets,
+    /// a single sentence
+    TlDr,
+}
+
+impl FromStr for SummaryLength {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "short" => Ok(Self::Short),
+            "bullets" => Ok(Self::Bullets),
+            "tl;dr" | "tldr" => Ok(Self::TlDr),
+            _ => Err(format!("Unsupported length: \"{}\"", value)),
+        }
+    }
+}
+
+impl SummaryLength {
+    /// The instructions for the final summary, with the text (or the summaries
+    /// of its parts) sent as the user message
+    pub fn prompt(self) -> &'static str {
+        match self {
+            Self::Short => {
+                "Summarise the text provided in one or two short paragraphs, covering its \
+                main points. Answer with the summary only."
+            }
+            Self::Bullets => {
+                "Summarise the text provided as a Markdown list of its key points, one short \
+                sentence each, most important first. Answer with the list only."
+            }
+            Self::TlDr => {
+                "Summarise the text provided in a single sentence of at most 30 words. Answer \
+                with the sentence only."
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lengths() {
+        assert_eq!("tl;dr".parse(), Ok(SummaryLength::TlDr));
+        assert_eq!("bullets".parse(), Ok(SummaryLength::Bullets));
+        assert!("long".parse::<SummaryLength>().is_err());
+    }
+}
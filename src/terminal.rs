@@ -0,0 +1,383 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use terminal_size::{terminal_size, Height, Width};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// Reads single key presses from the terminal in a background thread, without
+/// waiting for Enter, until dropped. Only supported on Unix for now.
+pub struct KeyReader {
+    receiver: UnboundedReceiver<u8>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    #[cfg(unix)]
+    original: libc::termios,
+}
+
+impl KeyReader {
+    #[cfg(unix)]
+    pub fn start() -> Option<Self> {
+        let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) } != 0 {
+            return None; // not a terminal
+        }
+        let original = unsafe { original.assume_init() };
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return None;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_requested = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut fds = libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // poll with a timeout so we never hold on to keys meant for the next prompt
+            while !stop_requested.load(Ordering::Relaxed) {
+                if unsafe { libc::poll(&mut fds, 1, 100) } <= 0 {
+                    continue;
+                }
+                let mut byte = 0u8;
+                let count =
+                    unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+                if count != 1 || sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Some(Self {
+            receiver,
+            stop,
+            thread: Some(thread),
+            original,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn start() -> Option<Self> {
+        None
+    }
+
+    pub async fn next_key(&mut self) -> Option<u8> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for KeyReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Waits for the next key press, or forever if no reader is available
+pub async fn next_key(reader: &mut Option<KeyReader>) -> Option<u8> {
+    match reader {
+        Some(reader) => reader.next_key().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// When to style the output with colours, bold text etc.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorMode {
+    /// only when writing to a terminal, and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("Invalid color mode: \"{}\"", value)),
+        }
+    }
+}
+
+static COLOR_MODE: OnceLock<ColorMode> = OnceLock::new();
+
+pub fn set_color_mode(mode: ColorMode) {
+    COLOR_MODE.set(mode).unwrap_or(());
+}
+
+/// Whether to style the output written to a stream, e.g. io::stdout(), following
+/// the --color setting and the NO_COLOR convention (https://no-color.org)
+pub fn use_color(stream: &impl IsTerminal) -> bool {
+    match COLOR_MODE.get().copied().unwrap_or_default() {
+        ColorMode::Never => false,
+        ColorMode::Always => supports_ansi(),
+        ColorMode::Auto => {
+            let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+            stream.is_terminal() && !no_color && supports_ansi()
+        }
+    }
+}
+
+static ANSI: OnceLock<bool> = OnceLock::new();
+
+/// Whether stdout understands ANSI escape codes for styles and titles, which on
+/// Windows consoles needs virtual terminal processing to be enabled first
+pub fn supports_ansi() -> bool {
+    *ANSI.get_or_init(enable_virtual_terminal)
+}
+
+#[cfg(windows)]
+fn enable_virtual_terminal() -> bool {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+        STD_OUTPUT_HANDLE,
+    };
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        // fails on consoles older than Windows 10
+        GetConsoleMode(handle, &mut mode) != 0
+            && SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+#[cfg(not(windows))]
+fn enable_virtual_terminal() -> bool {
+    true
+}
+
+/// Sets the terminal window title (OSC 0) to the topic of the chat, restoring the
+/// previous title on drop via the xterm title stack
+pub struct TerminalTitle {
+    titled: bool,
+}
+
+impl TerminalTitle {
+    pub fn save(initial: &str) -> Self {
+        print!("\x1b[22;0t");
+        let title = Self { titled: false };
+        title.write(initial);
+        title
+    }
+
+    /// Uses the first prompt of the chat as the title, ignoring later ones
+    pub fn set_topic(&mut self, prompt: &str) {
+        if !self.titled {
+            self.titled = true;
+            self.write(&format!("lmc: {}", prompt));
+        }
+    }
+
+    fn write(&self, title: &str) {
+        let line = title.lines().next().unwrap_or_default();
+        let mut clean: String = line.chars().filter(|c| !c.is_control()).take(60).collect();
+        if line.chars().count() > 60 {
+            clean.push('…');
+        }
+        print!("\x1b]0;{}\x07", clean);
+        io::stdout().flush().unwrap_or(());
+    }
+}
+
+impl Drop for TerminalTitle {
+    fn drop(&mut self) {
+        print!("\x1b[23;0t");
+        io::stdout().flush().unwrap_or(());
+    }
+}
+
+/// Minimum time between redraws of the status line while a response streams
+const STATUS_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Status {
+    model: String,
+    context_tokens: usize,
+    speed: Option<f64>,
+    drawn: Option<Instant>,
+}
+
+static STATUS: Mutex<Option<Status>> = Mutex::new(None);
+
+/// Keeps the bottom line of the terminal, outside the scrolling region, for the
+/// model, the (estimated) tokens in the conversation and the generation speed,
+/// until dropped
+pub struct StatusLine;
+
+impl StatusLine {
+    pub fn show(model: &str) -> Self {
+        // make room for it without overwriting the last line of output
+        print!("\n\x1b[1A");
+        let status = Status {
+            model: model.to_owned(),
+            context_tokens: 0,
+            speed: None,
+            drawn: None,
+        };
+        *STATUS.lock().unwrap_or_else(|error| error.into_inner()) = Some(status);
+        Self
+    }
+
+    /// Redraws the status line if shown, keeping the last speed if None; while
+    /// streaming it's redrawn at most every STATUS_INTERVAL
+    pub fn report(context_tokens: usize, speed: Option<f64>) {
+        let mut status = STATUS.lock().unwrap_or_else(|error| error.into_inner());
+        let Some(status) = status.as_mut() else {
+            return;
+        };
+        status.context_tokens = context_tokens;
+        if speed.is_some() {
+            status.speed = speed;
+            if status
+                .drawn
+                .is_some_and(|drawn| drawn.elapsed() < STATUS_INTERVAL)
+            {
+                return;
+            }
+        }
+        let (Some(width), Some(height)) = (terminal_width(), terminal_height()) else {
+            return;
+        };
+        let text: String = format_status(status).chars().take(width).collect();
+        print!(
+            "\x1b7\x1b[1;{}r\x1b[{};1H\x1b[2K\x1b[7m{:width$}\x1b[0m\x1b8",
+            height.saturating_sub(1),
+            height,
+            text,
+            width = width
+        );
+        io::stdout().flush().unwrap_or(());
+        status.drawn = Some(Instant::now());
+    }
+
+    pub fn is_shown() -> bool {
+        STATUS
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .is_some()
+    }
+}
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        STATUS
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .take();
+        let height = terminal_height().unwrap_or(1);
+        print!("\x1b7\x1b[r\x1b[{};1H\x1b[2K\x1b8", height);
+        io::stdout().flush().unwrap_or(());
+    }
+}
+
+fn format_status(status: &Status) -> String {
+    let mut text = format!(
+        " {} | context: ~{} tokens",
+        status.model, status.context_tokens
+    );
+    if let Some(speed) = status.speed {
+        text.push_str(&format!(" | {:.1} tokens/s", speed));
+    }
+    text
+}
+
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+static RESIZED: AtomicBool = AtomicBool::new(true);
+/// Whether RESIZED is kept up to date by a task listening for SIGWINCH
+static WATCHING: AtomicBool = AtomicBool::new(false);
+static WATCH_RESIZE: Once = Once::new();
+
+/// Listens for SIGWINCH in a task of the running Tokio runtime, if any, which
+/// adds to other handlers, e.g. rustyline's, rather than replacing them
+#[cfg(unix)]
+fn watch_resize() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    let Ok(mut resizes) = signal(SignalKind::window_change()) else {
+        return;
+    };
+    runtime.spawn(async move {
+        while resizes.recv().await.is_some() {
+            RESIZED.store(true, Ordering::Relaxed);
+        }
+    });
+    WATCHING.store(true, Ordering::Relaxed);
+}
+
+#[cfg(not(unix))]
+fn watch_resize() {}
+
+/// Returns the width of the terminal in columns, querying it again only after
+/// the window has been resized (SIGWINCH), or None if stdout is not a terminal
+pub fn terminal_width() -> Option<usize> {
+    WATCH_RESIZE.call_once(watch_resize);
+    // without SIGWINCH there is no way to tell, so always query it again
+    if RESIZED.swap(!WATCHING.load(Ordering::Relaxed), Ordering::Relaxed) {
+        let width = terminal_size().map_or(0, |(Width(width), _)| width as usize);
+        WIDTH.store(width, Ordering::Relaxed);
+    }
+    Some(WIDTH.load(Ordering::Relaxed)).filter(|width| *width > 0)
+}
+
+/// Returns the height of the terminal in rows, or None if stdout is not a terminal
+pub fn terminal_height() -> Option<usize> {
+    terminal_size().map(|(_, Height(height))| height as usize)
+}
+
+/// Asks a yes/no question on the terminal, even when stdin is redirected
+pub fn confirm_on_tty(question: &str) -> io::Result<bool> {
+    let tty = File::open(if cfg!(windows) { "CONIN$" } else { "/dev/tty" })?;
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Displays text through "$PAGER", defaulting to "less -R"
+pub fn page(text: &str) -> io::Result<()> {
+    let pager = env::var("PAGER").unwrap_or_default();
+    let pager = if pager.trim().is_empty() {
+        "less -R"
+    } else {
+        &pager
+    };
+    let mut parts = pager.split_whitespace();
+    let program = parts.next().unwrap_or("less");
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(error) = stdin.write_all(text.as_bytes()) {
+            // the user may quit the pager before reading everything
+            if error.kind() != io::ErrorKind::BrokenPipe {
+                return Err(error);
+            }
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+const RESET: &str = "\x1b[0m";
+
+/// A text style of the theme
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    /// the terminal's default
+    None,
+    Bold,
+    Dim,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    fn code(self) -> &'static str {
+        match self {
+            Color::None => "",
+            Color::Bold => "\x1b[1m",
+            Color::Dim => "\x1b[2m",
+            Color::Black => "\x1b[30m",
+            Color::Red => "\x1b[31m",
+            Color::Green => "\x1b[32m",
+            Color::Yellow => "\x1b[33m",
+            Color::Blue => "\x1b[34m",
+            Color::Magenta => "\x1b[35m",
+            Color::Cyan => "\x1b[36m",
+            Color::White => "\x1b[37m",
+        }
+    }
+}
+
+/// How the parts of an interactive chat are told apart: the colours of the
+/// prompts typed, the responses and the "[i]", "[w]" and "[e]" messages, and a
+/// marker shown before each response
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub user: Color,
+    pub assistant: Color,
+    pub info: Color,
+    pub warning: Color,
+    pub error: Color,
+    pub marker: Option<String>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            user: Color::Cyan,
+            assistant: Color::None,
+            info: Color::Dim,
+            warning: Color::Yellow,
+            error: Color::Red,
+            marker: None,
+        }
+    }
+}
+
+impl Theme {
+    /// Colours a message line by its "[e]", "[w]" or other prefix
+    pub fn message(&self, line: &str) -> String {
+        let prefix = line.trim_start();
+        let color = if prefix.starts_with("[e]") {
+            self.error
+        } else if prefix.starts_with("[w]") {
+            self.warning
+        } else {
+            self.info
+        };
+        paint(line, color.code())
+    }
+
+    /// The escape sequence of the colour of the text typed at the prompt
+    pub fn user_style(&self) -> &'static str {
+        self.user.code()
+    }
+
+    pub fn assistant_style(&self) -> &'static str {
+        self.assistant.code()
+    }
+}
+
+/// Applies a style to text that may have styles of its own, e.g. highlighted
+/// Markdown, restoring it after each of their resets
+pub fn paint(text: &str, style: &str) -> String {
+    if style.is_empty() || text.is_empty() {
+        return text.to_owned();
+    }
+    let restored = format!("{}{}", RESET, style);
+    format!("{}{}{}", style, text.replace(RESET, &restored), RESET)
+}
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Enables the theme, in interactive mode when colours are used
+pub fn set_theme(theme: Theme) {
+    THEME.set(theme).unwrap_or(());
+}
+
+/// The theme enabled, if any
+pub fn theme() -> Option<&'static Theme> {
+    THEME.get()
+}
+
+/// Colours a message line according to the theme, if enabled
+pub fn themed(line: &str) -> String {
+    match theme() {
+        Some(theme) => theme.message(line),
+        None => line.to_owned(),
+    }
+}
+
+/// Like println!, for a "[i]", "[w]" or "[e]" message coloured by the theme
+#[macro_export]
+macro_rules! themed_println {
+    ($($arg:tt)*) => {
+        println!("{}", $crate::theme::themed(&format!($($arg)*)))
+    };
+}
+
+/// Like eprintln!, for a "[i]", "[w]" or "[e]" message coloured by the theme
+#[macro_export]
+macro_rules! themed_eprintln {
+    ($($arg:tt)*) => {
+        eprintln!("{}", $crate::theme::themed(&format!($($arg)*)))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours_messages_by_prefix() {
+        let theme = Theme::default();
+        assert_eq!(theme.message("[e] Failed"), "\x1b[31m[e] Failed\x1b[0m");
+        assert_eq!(theme.message("[w] Careful"), "\x1b[33m[w] Careful\x1b[0m");
+        assert_eq!(theme.message("[i] Saved"), "\x1b[2m[i] Saved\x1b[0m");
+        let plain = Theme {
+            info: Color::None,
+            ..Theme::default()
+        };
+        assert_eq!(plain.message("[i] Saved"), "[i] Saved");
+        assert_eq!(
+            theme.message("\n[w] Retrying"),
+            "\x1b[33m\n[w] Retrying\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn restores_style_after_resets() {
+        assert_eq!(
+            paint("a \x1b[1mbold\x1b[0m word", "\x1b[32m"),
+            "\x1b[32ma \x1b[1mbold\x1b[0m\x1b[32m word\x1b[0m"
+        );
+        assert_eq!(paint("text", ""), "text");
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use tiktoken_rs::CoreBPE;
+
+use crate::api::Message;
+use crate::config::Tokenizer;
+
+/// Tokens added to each message by the chat format, as OpenAI counts them
+const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Tokens that start the response
+const REPLY_TOKENS: usize = 3;
+
+/// Counts tokens exactly like a tiktoken encoding does: the configured one, or
+/// else the one of the model if it's an OpenAI model, or else "cl100k_base" as
+/// an approximation for other model families
+pub struct TokenCounter {
+    bpe: CoreBPE,
+}
+
+impl TokenCounter {
+    pub fn new(tokenizer: Option<Tokenizer>, model: &str) -> Result<Self> {
+        let bpe = match tokenizer {
+            Some(Tokenizer::O200k) => tiktoken_rs::o200k_base()?,
+            Some(Tokenizer::Cl100k) => tiktoken_rs::cl100k_base()?,
+            Some(Tokenizer::P50k) => tiktoken_rs::p50k_base()?,
+            Some(Tokenizer::R50k) => tiktoken_rs::r50k_base()?,
+            None => match tiktoken_rs::get_bpe_from_model(model) {
+                Ok(bpe) => bpe,
+                Err(_) => tiktoken_rs::cl100k_base()?,
+            },
+        };
+        Ok(Self { bpe })
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+
+    /// Counts the tokens a conversation takes as the prompt of a request
+    pub fn count_messages(&self, messages: &[Message]) -> usize {
+        let content: usize = messages
+            .iter()
+            .map(|message| {
+                let name = message.name.as_deref().map_or(0, |name| self.count(name));
+                TOKENS_PER_MESSAGE + self.count(&message.content) + name
+            })
+            .sum();
+        content + REPLY_TOKENS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Role;
+
+    #[test]
+    fn counts_tokens() -> Result<()> {
+        let counter = TokenCounter::new(Some(Tokenizer::Cl100k), "")?;
+        assert_eq!(counter.count("Hello, world"), 3);
+        let messages = vec![
+            Message::new(Role::System, &String::from("Hello, world")),
+            Message::new(Role::User, &String::from("Hello, world")),
+        ];
+        assert_eq!(counter.count_messages(&messages), 3 + 3 + 3 + 3 + 3);
+        Ok(())
+    }
+}